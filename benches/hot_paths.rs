@@ -0,0 +1,158 @@
+//! Timings of the passes every frame or every run goes through.
+//!
+//! `cargo bench` keeps the last results in target/criterion and reports the change against
+//! them. To compare against a fixed commit instead, save a baseline there with
+//! `cargo bench -- --save-baseline main` and check later commits with
+//! `cargo bench -- --baseline main`.
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use glam::Vec3;
+#[cfg(feature = "gpu")]
+use gravity_output::GpuCompute;
+use gravity_output::initial_conditions::{GalaxySettings, InitialConditions};
+use gravity_output::output::{OutputLimit, write_frame_group};
+use gravity_output::particle::Gravity;
+use gravity_output::schedule::Phase;
+use gravity_output::settings::{Settings, init_particles};
+use gravity_output::{CpuBackend, ForceBackend, ForceBackendKind, Particle};
+use std::hint::black_box;
+
+/// Particle counts for the force passes, all-pairs grows with the square so the top is modest
+const FORCE_COUNTS: [usize; 3] = [256, 1024, 4096];
+
+fn settings(num_particles: usize) -> Settings {
+    Settings {
+        num_particles,
+        seed: Some(1),
+        force_backend: ForceBackendKind::Cpu,
+        ..Settings::default()
+    }
+}
+
+fn get_influence(c: &mut Criterion) {
+    let settings = settings(4096);
+    let gravity = Gravity::new(&settings);
+    let particles: Vec<Particle> = init_particles(&settings).unwrap().iter().collect();
+    let target = particles[0];
+    let mut group = c.benchmark_group("get_influence");
+    group.throughput(Throughput::Elements(particles.len() as u64));
+    group.bench_function("slice_4096", |b| {
+        b.iter(|| {
+            particles
+                .iter()
+                .map(|other| black_box(&target).get_influence(other, &gravity))
+                .sum::<Vec3>()
+        })
+    });
+    group.finish();
+}
+
+fn cpu_forces(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cpu_all_pairs");
+    for count in FORCE_COUNTS {
+        let settings = settings(count);
+        let particles = init_particles(&settings).unwrap();
+        let backend = CpuBackend::new(&settings);
+        group.throughput(Throughput::Elements((count * count) as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(count),
+            &particles,
+            |b, particles| b.iter(|| backend.compute_forces(particles)),
+        );
+    }
+    group.finish();
+}
+
+/// Upload, dispatch and readback through `ForceBackend::compute_forces`. Left out without a
+/// usable adapter, so the other groups still run.
+#[cfg(feature = "gpu")]
+fn gpu_forces(c: &mut Criterion) {
+    let mut group = c.benchmark_group("gpu_compute_forces");
+    for count in FORCE_COUNTS {
+        let mut settings = Settings {
+            force_backend: ForceBackendKind::Gpu,
+            ..settings(count)
+        };
+        settings.adapter.allow_software_adapter = true;
+        let gpu = match pollster::block_on(GpuCompute::new(&settings)) {
+            Ok(gpu) => gpu,
+            Err(e) => {
+                println!("skipping gpu_compute_forces, no GPU: {}", e);
+                break;
+            }
+        };
+        let particles = init_particles(&settings).unwrap();
+        group.throughput(Throughput::Elements((count * count) as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(count),
+            &particles,
+            |b, particles| b.iter(|| gpu.compute_forces(particles)),
+        );
+    }
+    group.finish();
+}
+
+/// Serializing and gzipping one batch into a file, as the writer thread does
+fn write_batch(c: &mut Criterion) {
+    let dir = std::env::temp_dir().join("gravity-output-bench-write");
+    std::fs::create_dir_all(&dir).unwrap();
+    let settings = Settings {
+        frames_per_file: 10,
+        out_path: dir.clone(),
+        ..settings(10_000)
+    };
+    let particles = init_particles(&settings).unwrap();
+    let frames = vec![particles.pos.clone(); settings.frames_per_file];
+    let output = OutputLimit::new(&settings, 0);
+    let phase = Phase::at(&settings, 0);
+    let hash = settings.hash();
+
+    let mut group = c.benchmark_group("write_frame_group");
+    group.throughput(Throughput::Bytes(
+        (frames.len() * settings.num_particles * std::mem::size_of::<Vec3>()) as u64,
+    ));
+    group.bench_function("10x10000", |b| {
+        b.iter_batched_ref(
+            || frames.clone(),
+            |frames| write_frame_group(&settings, &hash, frames, None, &0, &output, phase).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+fn init_generators(c: &mut Criterion) {
+    let mut group = c.benchmark_group("init_particles");
+    let sphere = settings(12_000);
+    group.bench_function("sphere_12000", |b| {
+        b.iter(|| init_particles(&sphere).unwrap())
+    });
+    let galaxy = Settings {
+        initial_conditions: InitialConditions::Galaxy(GalaxySettings::default()),
+        ..settings(12_000)
+    };
+    group.bench_function("galaxy_12000", |b| {
+        b.iter(|| init_particles(&galaxy).unwrap())
+    });
+    group.finish();
+}
+
+#[cfg(feature = "gpu")]
+criterion_group!(
+    benches,
+    get_influence,
+    cpu_forces,
+    gpu_forces,
+    write_batch,
+    init_generators
+);
+#[cfg(not(feature = "gpu"))]
+criterion_group!(
+    benches,
+    get_influence,
+    cpu_forces,
+    write_batch,
+    init_generators
+);
+criterion_main!(benches);
@@ -0,0 +1,384 @@
+//! The gzipped batch files gravity-output writes its frames to, for the simulation and anything
+//! reading them back.
+//!
+//! A batch file is gzip around an 8 byte header, the number of frames and the number of
+//! particles per frame as little endian u32s, followed by each frame's positions as f32 x, y, z
+//! triples in particle id order. Run details go in the gzip header comment as JSON, see
+//! `BatchComment`, so the Unity player and data_analyzer.py can keep reading the plain layout.
+
+use flate2::GzBuilder;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use glam::Vec3;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+pub mod units;
+
+pub use flate2::Compression;
+use units::UnitSystem;
+
+/// Bytes before the first frame: frames in the file and particles per frame, both u32
+pub const HEADER_SIZE: usize = 8;
+
+/// The 8 byte header at the start of a batch file's contents
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Header {
+    pub num_frames: usize,
+    pub num_particles: usize,
+}
+
+impl Header {
+    /// Bytes of the whole file's contents, uncompressed
+    pub fn file_size(&self) -> usize {
+        HEADER_SIZE + self.num_frames * self.frame_size()
+    }
+
+    pub fn frame_size(&self) -> usize {
+        self.num_particles * std::mem::size_of::<Vec3>()
+    }
+
+    fn encode(&self) -> io::Result<[u8; HEADER_SIZE]> {
+        let field = |value: usize, name: &str| {
+            u32::try_from(value).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} {} don't fit in a batch file header", value, name),
+                )
+            })
+        };
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[..4].copy_from_slice(&field(self.num_frames, "frames")?.to_le_bytes());
+        bytes[4..].copy_from_slice(&field(self.num_particles, "particles")?.to_le_bytes());
+        Ok(bytes)
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Header, String> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(format!("{} bytes is too short for the header", bytes.len()));
+        }
+        let field = |index: usize| {
+            u32::from_le_bytes(bytes[index * 4..index * 4 + 4].try_into().unwrap()) as usize
+        };
+        Ok(Header {
+            num_frames: field(0),
+            num_particles: field(1),
+        })
+    }
+}
+
+/// Run details kept as JSON in the gzip header comment, so the 8 byte header the Unity player
+/// and data_analyzer.py read stays as it was. Files from older runs have none.
+#[derive(Serialize, Deserialize, Default, PartialEq, Debug)]
+pub struct BatchComment {
+    /// The hash of the settings that wrote the file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub units: Option<UnitSystem>,
+}
+
+impl BatchComment {
+    fn parse(comment: &[u8]) -> BatchComment {
+        serde_json::from_slice(comment).unwrap_or_default()
+    }
+}
+
+/// One batch file read back: its header, then every frame's positions in particle id order
+pub struct Batch {
+    pub num_particles: usize,
+    pub frames: Vec<Vec<Vec3>>,
+    pub comment: BatchComment,
+}
+
+/// Writes one batch file's frames into `W`, checking they match the header they were started
+/// with
+pub struct BatchEncoder<W: Write> {
+    encoder: GzEncoder<W>,
+    header: Header,
+    frames_written: usize,
+}
+
+impl<W: Write> BatchEncoder<W> {
+    /// Start a file of `header.num_frames` frames, writing the header
+    pub fn new(
+        writer: W,
+        header: Header,
+        comment: &BatchComment,
+        compression: Compression,
+    ) -> io::Result<BatchEncoder<W>> {
+        let mut encoder = GzBuilder::new()
+            .comment(serde_json::to_string(comment).unwrap_or_default())
+            .write(writer, compression);
+        encoder.write_all(&header.encode()?)?;
+        Ok(BatchEncoder {
+            encoder,
+            header,
+            frames_written: 0,
+        })
+    }
+
+    pub fn write_frame(&mut self, positions: &[Vec3]) -> io::Result<()> {
+        if self.frames_written == self.header.num_frames {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the header says {} frames", self.header.num_frames),
+            ));
+        }
+        if positions.len() != self.header.num_particles {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "a frame of {} particles in a file of {}",
+                    positions.len(),
+                    self.header.num_particles
+                ),
+            ));
+        }
+        self.encoder.write_all(bytemuck::cast_slice(positions))?;
+        self.frames_written += 1;
+        Ok(())
+    }
+
+    /// Finish the gzip stream once every frame is written, giving back the writer
+    pub fn finish(self) -> io::Result<W> {
+        if self.frames_written != self.header.num_frames {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} frames written, the header says {}",
+                    self.frames_written, self.header.num_frames
+                ),
+            ));
+        }
+        self.encoder.finish()
+    }
+}
+
+/// Name of the file batch `batch_num` is written to, with the settings hash in it for
+/// hash_in_file_names
+pub fn batch_file_name(batch_num: usize, hash: Option<&str>) -> String {
+    match hash {
+        Some(hash) => format!("batch_{}_{:04}.bin.gz", hash, batch_num),
+        None => format!("batch_{:04}.bin.gz", batch_num),
+    }
+}
+
+/// Batch files in `dir` with their batch numbers, in order, named with a settings hash or not
+pub fn list_batches(dir: &Path) -> Result<Vec<(usize, PathBuf)>, String> {
+    let entries =
+        std::fs::read_dir(dir).map_err(|e| format!("Could not read {}: {}", dir.display(), e))?;
+    let mut batches: Vec<(usize, PathBuf)> = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let name = path.file_name()?.to_str()?;
+            let stem = name.strip_prefix("batch_")?.strip_suffix(".bin.gz")?;
+            let number = stem.rsplit_once('_').map_or(stem, |(_, number)| number);
+            let batch_num = number.parse().ok()?;
+            Some((batch_num, path))
+        })
+        .collect();
+    batches.sort();
+    Ok(batches)
+}
+
+pub fn read_batch(path: &Path) -> Result<Batch, String> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+    decode_batch(file).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Read a whole batch file from `reader`, gzip and all
+pub fn decode_batch(reader: impl Read) -> Result<Batch, String> {
+    let mut bytes = Vec::new();
+    let mut decoder = GzDecoder::new(reader);
+    decoder
+        .read_to_end(&mut bytes)
+        .map_err(|e| format!("could not decompress: {}", e))?;
+    let mut batch = parse_batch(&bytes)?;
+    if let Some(comment) = decoder.header().and_then(|header| header.comment()) {
+        batch.comment = BatchComment::parse(comment);
+    }
+    Ok(batch)
+}
+
+/// Frames from a batch file's decompressed contents
+pub fn parse_batch(bytes: &[u8]) -> Result<Batch, String> {
+    let header = Header::parse(bytes)?;
+    if bytes.len() != header.file_size() {
+        return Err(format!(
+            "{} frames of {} particles need {} bytes, the file has {}",
+            header.num_frames,
+            header.num_particles,
+            header.file_size(),
+            bytes.len()
+        ));
+    }
+
+    let frames = bytes[HEADER_SIZE..]
+        .chunks_exact(header.frame_size().max(1))
+        .take(header.num_frames)
+        .map(bytemuck::pod_collect_to_vec)
+        .collect();
+    Ok(Batch {
+        num_particles: header.num_particles,
+        frames,
+        comment: BatchComment::default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use units::{LengthUnit, MassUnit, TimeUnit};
+
+    fn frames() -> Vec<Vec<Vec3>> {
+        vec![
+            vec![Vec3::new(1.0, 2.0, 3.0), Vec3::new(-1.0, 0.5, 0.0)],
+            vec![Vec3::new(1.5, 2.0, 3.0), Vec3::new(-1.0, 0.25, 4.0)],
+        ]
+    }
+
+    fn encode(frames: &[Vec<Vec3>], comment: &BatchComment) -> Vec<u8> {
+        let header = Header {
+            num_frames: frames.len(),
+            num_particles: frames[0].len(),
+        };
+        let mut encoder =
+            BatchEncoder::new(Vec::new(), header, comment, Compression::fast()).unwrap();
+        for frame in frames {
+            encoder.write_frame(frame).unwrap();
+        }
+        encoder.finish().unwrap()
+    }
+
+    fn decompress(bytes: &[u8]) -> Vec<u8> {
+        let mut contents = Vec::new();
+        GzDecoder::new(bytes).read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let comment = BatchComment {
+            settings_hash: Some("0123abcd".to_string()),
+            units: Some(UnitSystem {
+                length: LengthUnit::Pc,
+                mass: MassUnit::Msun,
+                time: TimeUnit::Myr,
+            }),
+        };
+        let batch = decode_batch(encode(&frames(), &comment).as_slice()).unwrap();
+        assert_eq!(batch.num_particles, 2);
+        assert_eq!(batch.frames, frames());
+        assert_eq!(batch.comment, comment);
+    }
+
+    #[test]
+    fn the_header_is_the_plain_layout() {
+        let contents = decompress(&encode(&frames(), &BatchComment::default()));
+        assert_eq!(contents[..4], 2u32.to_le_bytes());
+        assert_eq!(contents[4..8], 2u32.to_le_bytes());
+        assert_eq!(contents[8..12], 1.0f32.to_le_bytes());
+        assert_eq!(contents.len(), HEADER_SIZE + 2 * 2 * 12);
+    }
+
+    #[test]
+    fn comments_are_optional() {
+        let comment = BatchComment {
+            settings_hash: Some("0123abcd".to_string()),
+            units: None,
+        };
+        let json = serde_json::to_string(&comment).unwrap();
+        assert_eq!(BatchComment::parse(json.as_bytes()), comment);
+        assert_eq!(BatchComment::parse(b"not json"), BatchComment::default());
+
+        // files from before the comment was written
+        let contents = decompress(&encode(&frames(), &BatchComment::default()));
+        let mut plain = GzEncoder::new(Vec::new(), Compression::fast());
+        plain.write_all(&contents).unwrap();
+        let batch = decode_batch(plain.finish().unwrap().as_slice()).unwrap();
+        assert_eq!(batch.comment, BatchComment::default());
+        assert_eq!(batch.frames, frames());
+    }
+
+    #[test]
+    fn frames_must_match_the_header() {
+        let header = Header {
+            num_frames: 1,
+            num_particles: 2,
+        };
+        let comment = BatchComment::default();
+        let mut encoder =
+            BatchEncoder::new(Vec::new(), header, &comment, Compression::fast()).unwrap();
+        assert!(encoder.write_frame(&[Vec3::ONE; 3]).is_err());
+        encoder.write_frame(&[Vec3::ONE; 2]).unwrap();
+        assert!(encoder.write_frame(&[Vec3::ONE; 2]).is_err());
+
+        let encoder = BatchEncoder::new(Vec::new(), header, &comment, Compression::fast());
+        assert!(encoder.unwrap().finish().is_err());
+    }
+
+    #[test]
+    fn hashed_and_plain_names_are_listed() {
+        let dir = std::env::temp_dir().join("gravity-output-format-list-batches");
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in [
+            batch_file_name(1, Some("0123abcd")),
+            batch_file_name(0, None),
+            "batch_notes.txt".to_string(),
+        ] {
+            std::fs::write(dir.join(name), []).unwrap();
+        }
+        let batches = list_batches(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let names: Vec<(usize, String)> = batches
+            .into_iter()
+            .map(|(batch_num, path)| {
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                (batch_num, name)
+            })
+            .collect();
+        assert_eq!(
+            names,
+            [
+                (0, "batch_0000.bin.gz".to_string()),
+                (1, "batch_0123abcd_0001.bin.gz".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let contents = decompress(&encode(
+            &vec![vec![Vec3::ONE; 4]; 3],
+            &BatchComment::default(),
+        ));
+        assert!(parse_batch(&contents[..contents.len() - 1]).is_err());
+        assert!(parse_batch(&contents[..5]).is_err());
+        let mut longer = contents.clone();
+        longer.push(0);
+        assert!(parse_batch(&longer).is_err());
+    }
+
+    #[test]
+    fn corrupted_files_are_errors() {
+        let file = encode(&vec![vec![Vec3::ONE; 4]; 3], &BatchComment::default());
+        // cut off mid stream
+        assert!(decode_batch(&file[..file.len() / 2]).is_err());
+        // a flipped byte fails gzip's crc
+        let mut flipped = file.clone();
+        let middle = flipped.len() / 2;
+        flipped[middle] ^= 0xff;
+        assert!(decode_batch(flipped.as_slice()).is_err());
+        assert!(decode_batch(&b"not a batch file"[..]).is_err());
+
+        // a header promising more frames than there are
+        let mut contents = decompress(&file);
+        contents[..4].copy_from_slice(&4u32.to_le_bytes());
+        let error = parse_batch(&contents).err().unwrap();
+        assert!(error.contains("need"), "{}", error);
+    }
+}
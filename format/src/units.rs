@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+
+/// Newton's constant in m^3 kg^-1 s^-2, CODATA 2018
+pub const G_SI: f64 = 6.67430e-11;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LengthUnit {
+    M,
+    Km,
+    Au,
+    Pc,
+    Kpc,
+    Mpc,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MassUnit {
+    Kg,
+    Mearth,
+    Mjup,
+    Msun,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeUnit {
+    S,
+    Day,
+    Yr,
+    Kyr,
+    Myr,
+    Gyr,
+}
+
+/// Fully resolved units, recorded in the manifest and the batch files so analysis scripts can
+/// label their axes
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct UnitSystem {
+    pub length: LengthUnit,
+    pub mass: MassUnit,
+    pub time: TimeUnit,
+}
+
+impl LengthUnit {
+    /// Metres in one of these
+    pub fn si(self) -> f64 {
+        const PARSEC: f64 = 3.085_677_581_491_367e16;
+        match self {
+            LengthUnit::M => 1.0,
+            LengthUnit::Km => 1e3,
+            LengthUnit::Au => 1.495_978_707e11,
+            LengthUnit::Pc => PARSEC,
+            LengthUnit::Kpc => PARSEC * 1e3,
+            LengthUnit::Mpc => PARSEC * 1e6,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            LengthUnit::M => "m",
+            LengthUnit::Km => "km",
+            LengthUnit::Au => "au",
+            LengthUnit::Pc => "pc",
+            LengthUnit::Kpc => "kpc",
+            LengthUnit::Mpc => "Mpc",
+        }
+    }
+}
+
+impl MassUnit {
+    /// Kilograms in one of these, the IAU nominal values
+    pub fn si(self) -> f64 {
+        match self {
+            MassUnit::Kg => 1.0,
+            MassUnit::Mearth => 5.9722e24,
+            MassUnit::Mjup => 1.898_13e27,
+            MassUnit::Msun => 1.988_47e30,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            MassUnit::Kg => "kg",
+            MassUnit::Mearth => "Mearth",
+            MassUnit::Mjup => "Mjup",
+            MassUnit::Msun => "Msun",
+        }
+    }
+}
+
+impl TimeUnit {
+    /// Seconds in one of these, with Julian years
+    pub fn si(self) -> f64 {
+        const YEAR: f64 = 365.25 * 86400.0;
+        match self {
+            TimeUnit::S => 1.0,
+            TimeUnit::Day => 86400.0,
+            TimeUnit::Yr => YEAR,
+            TimeUnit::Kyr => YEAR * 1e3,
+            TimeUnit::Myr => YEAR * 1e6,
+            TimeUnit::Gyr => YEAR * 1e9,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            TimeUnit::S => "s",
+            TimeUnit::Day => "day",
+            TimeUnit::Yr => "yr",
+            TimeUnit::Kyr => "kyr",
+            TimeUnit::Myr => "Myr",
+            TimeUnit::Gyr => "Gyr",
+        }
+    }
+}
+
+impl UnitSystem {
+    /// G in length^3 mass^-1 time^-2 of these units
+    pub fn gravitational_constant(&self) -> f64 {
+        G_SI * self.mass.si() * self.time.si().powi(2) / self.length.si().powi(3)
+    }
+}
+
+impl std::fmt::Display for UnitSystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}, {}, {}",
+            self.length.symbol(),
+            self.mass.symbol(),
+            self.time.symbol()
+        )
+    }
+}
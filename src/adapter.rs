@@ -0,0 +1,247 @@
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "gpu")]
+use tracing::{info, warn};
+
+/// Which GPU to run on. Leaving everything unset keeps wgpu's HighPerformance pick.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct AdapterSettings {
+    /// Pick an adapter on this backend. Every backend is still initialized, see
+    /// `Settings::gpu_backend` to skip the others entirely.
+    pub backend: Option<GpuBackend>,
+    /// Case-insensitive substring of the adapter name
+    pub name: Option<String>,
+    /// Index into the adapter list printed at startup
+    pub index: Option<usize>,
+    /// Accept software rasterizers like lavapipe, llvmpipe or WARP when there's no real GPU.
+    /// Also enabled by the GRAVITY_ALLOW_SOFTWARE_ADAPTER environment variable.
+    #[serde(default)]
+    pub allow_software_adapter: bool,
+}
+
+#[cfg(feature = "gpu")]
+impl AdapterSettings {
+    fn is_default(&self) -> bool {
+        self.backend.is_none() && self.name.is_none() && self.index.is_none()
+    }
+
+    fn software_allowed(&self) -> bool {
+        self.allow_software_adapter
+            || std::env::var("GRAVITY_ALLOW_SOFTWARE_ADAPTER")
+                .is_ok_and(|value| !matches!(value.as_str(), "" | "0" | "false"))
+    }
+}
+
+#[cfg(feature = "gpu")]
+fn is_software(info: &wgpu::AdapterInfo) -> bool {
+    info.device_type == wgpu::DeviceType::Cpu
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum GpuBackend {
+    Vulkan,
+    Dx12,
+    Metal,
+    Gl,
+}
+
+#[cfg(feature = "gpu")]
+impl GpuBackend {
+    pub fn to_wgpu(self) -> wgpu::Backend {
+        match self {
+            GpuBackend::Vulkan => wgpu::Backend::Vulkan,
+            GpuBackend::Dx12 => wgpu::Backend::Dx12,
+            GpuBackend::Metal => wgpu::Backend::Metal,
+            GpuBackend::Gl => wgpu::Backend::Gl,
+        }
+    }
+}
+
+/// Instance with only `backend` enabled, or every backend wgpu was built with.
+#[cfg(feature = "gpu")]
+pub fn create_instance(backend: Option<GpuBackend>) -> wgpu::Instance {
+    let backends = backend.map_or(wgpu::Backends::all(), |backend| backend.to_wgpu().into());
+    wgpu::Instance::new(&wgpu::InstanceDescriptor {
+        backends,
+        ..Default::default()
+    })
+}
+
+/// What gets recorded about the adapter in the run manifest
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AdapterSummary {
+    pub name: String,
+    pub backend: String,
+    pub device_type: String,
+    pub driver: String,
+    pub driver_info: String,
+}
+
+#[cfg(feature = "gpu")]
+impl AdapterSummary {
+    pub fn from_info(info: &wgpu::AdapterInfo) -> AdapterSummary {
+        AdapterSummary {
+            name: info.name.clone(),
+            backend: format!("{:?}", info.backend),
+            device_type: format!("{:?}", info.device_type),
+            driver: info.driver.clone(),
+            driver_info: info.driver_info.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "gpu")]
+fn describe(index: usize, info: &wgpu::AdapterInfo) -> String {
+    format!(
+        "  [{}] {} ({:?}, {:?}, driver: {} {})",
+        index, info.name, info.backend, info.device_type, info.driver, info.driver_info
+    )
+}
+
+/// One line per adapter for error messages, "(none)" when there are none
+#[cfg(feature = "gpu")]
+fn describe_all(adapters: &[wgpu::Adapter]) -> String {
+    if adapters.is_empty() {
+        return " (none)".to_string();
+    }
+    adapters
+        .iter()
+        .enumerate()
+        .map(|(index, adapter)| format!("\n{}", describe(index, &adapter.get_info())))
+        .collect()
+}
+
+/// Every adapter `instance` offers. A browser doesn't list them, only the one it picks.
+#[cfg(feature = "gpu")]
+async fn all_adapters(instance: &wgpu::Instance) -> Vec<wgpu::Adapter> {
+    #[cfg(not(target_arch = "wasm32"))]
+    return instance.enumerate_adapters(wgpu::Backends::all());
+    #[cfg(target_arch = "wasm32")]
+    instance
+        .request_adapter(&wgpu::RequestAdapterOptions::default())
+        .await
+        .into_iter()
+        .collect()
+}
+
+/// Enumerate every adapter, print the list, and pick one according to `settings`.
+///
+/// `forced_backend` is the backend `instance` was restricted to, if any. When it has no
+/// adapters at all the error lists what the other backends offer instead.
+#[cfg(feature = "gpu")]
+pub async fn select_adapter(
+    instance: &wgpu::Instance,
+    settings: &AdapterSettings,
+    forced_backend: Option<GpuBackend>,
+) -> Result<wgpu::Adapter, String> {
+    let adapters = all_adapters(instance).await;
+
+    info!("Available adapters:");
+    for (index, adapter) in adapters.iter().enumerate() {
+        info!("{}", describe(index, &adapter.get_info()));
+    }
+
+    if let Some(backend) = forced_backend
+        && adapters.is_empty()
+    {
+        let others = all_adapters(&create_instance(None)).await;
+        return Err(format!(
+            "gpu_backend {:?} has no adapters, the other backends offer:{}",
+            backend,
+            describe_all(&others)
+        ));
+    }
+
+    let adapter = if settings.is_default() {
+        default_adapter(instance, settings, &adapters).await?
+    } else {
+        matching_adapter(settings, adapters)?
+    };
+
+    let info = adapter.get_info();
+    if is_software(&info) {
+        warn!(
+            "{} is a software adapter, expect it to be much slower than a GPU",
+            info.name
+        );
+    }
+    Ok(adapter)
+}
+
+/// wgpu's HighPerformance pick, falling back to a software adapter only when allowed.
+#[cfg(feature = "gpu")]
+async fn default_adapter(
+    instance: &wgpu::Instance,
+    settings: &AdapterSettings,
+    adapters: &[wgpu::Adapter],
+) -> Result<wgpu::Adapter, String> {
+    let allow_software = settings.software_allowed();
+    let found = instance
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            ..Default::default()
+        })
+        .await;
+
+    match found {
+        Ok(adapter) if allow_software || !is_software(&adapter.get_info()) => Ok(adapter),
+        Ok(adapter) => Err(format!(
+            "only found the software adapter {}, set adapter.allow_software_adapter or \
+             GRAVITY_ALLOW_SOFTWARE_ADAPTER=1 to use it",
+            adapter.get_info().name
+        )),
+        Err(_) if allow_software => instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::None,
+                force_fallback_adapter: true,
+                compatible_surface: None,
+            })
+            .await
+            .map_err(|e| {
+                format!(
+                    "no compatible adapter found, not even a software one ({}), candidates:{}",
+                    e,
+                    describe_all(adapters)
+                )
+            }),
+        Err(e) => Err(format!(
+            "no compatible adapter found ({}), candidates:{}",
+            e,
+            describe_all(adapters)
+        )),
+    }
+}
+
+/// The first adapter matching every criterion set in `settings`.
+#[cfg(feature = "gpu")]
+fn matching_adapter(
+    settings: &AdapterSettings,
+    adapters: Vec<wgpu::Adapter>,
+) -> Result<wgpu::Adapter, String> {
+    let name = settings.name.as_ref().map(|name| name.to_lowercase());
+    let matches = |index: usize, info: &wgpu::AdapterInfo| {
+        settings.index.is_none_or(|wanted| wanted == index)
+            && settings
+                .backend
+                .is_none_or(|backend| backend.to_wgpu() == info.backend)
+            && name
+                .as_ref()
+                .is_none_or(|name| info.name.to_lowercase().contains(name))
+    };
+
+    let chosen = adapters
+        .iter()
+        .enumerate()
+        .position(|(index, adapter)| matches(index, &adapter.get_info()));
+
+    match chosen {
+        Some(index) => Ok(adapters.into_iter().nth(index).unwrap()),
+        None => Err(format!(
+            "no adapter matches backend={:?} name={:?} index={:?}, candidates:{}",
+            settings.backend,
+            settings.name,
+            settings.index,
+            describe_all(&adapters)
+        )),
+    }
+}
@@ -0,0 +1,134 @@
+use glam::Vec3;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use super::Particle;
+use super::ParticleSet;
+use super::backend::ForceBackend;
+use super::gpu::GpuCompute;
+use super::pipeline_cache::cache_dir;
+use super::settings::Settings;
+
+/// Workgroup sizes `--bench-kernel` tries, the tile width follows the workgroup size
+const CANDIDATES: [u32; 6] = [32, 64, 96, 128, 256, 512];
+
+/// Timed force passes per candidate, after one untimed warm-up pass
+const BENCH_REPEATS: usize = 5;
+
+/// Workgroup sizes picked by `--bench-kernel`, keyed by adapter and particle count
+fn tuning_path() -> Option<PathBuf> {
+    Some(cache_dir()?.join("tuning.json"))
+}
+
+fn tuning_key(info: &wgpu::AdapterInfo, num_particles: usize) -> String {
+    format!("{} ({:?}) / {}", info.name, info.backend, num_particles)
+}
+
+fn load_tuning() -> BTreeMap<String, u32> {
+    tuning_path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+/// Workgroup size `--bench-kernel` found fastest on this adapter for this particle count
+pub fn cached_workgroup_size(info: &wgpu::AdapterInfo, num_particles: usize) -> Option<u32> {
+    load_tuning().get(&tuning_key(info, num_particles)).copied()
+}
+
+fn save_workgroup_size(
+    info: &wgpu::AdapterInfo,
+    num_particles: usize,
+    workgroup_size: u32,
+) -> Result<PathBuf, String> {
+    let path = tuning_path().ok_or("no cache directory to save the tuning in")?;
+    let mut tuning = load_tuning();
+    tuning.insert(tuning_key(info, num_particles), workgroup_size);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Could not create {}: {}", dir.display(), e))?;
+    }
+    let json = serde_json::to_string_pretty(&tuning).map_err(|e| e.to_string())?;
+    std::fs::write(&path, json)
+        .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// Deterministic uniform ball, so every candidate times the same work
+fn synthetic_particles(settings: &Settings) -> ParticleSet {
+    (0..settings.num_particles)
+        .map(|i| {
+            // golden angle spiral over radius shells
+            let t = i as f32 / settings.num_particles.max(1) as f32;
+            let theta = i as f32 * 2.399_963;
+            let z = 1.0 - 2.0 * ((i as f32 * 0.618_034) % 1.0);
+            let ring = (1.0 - z * z).sqrt();
+            let pos =
+                Vec3::new(ring * theta.cos(), ring * theta.sin(), z) * settings.arena * t.cbrt();
+            Particle::new(settings.mass, pos, Vec3::ZERO, Vec3::ZERO)
+        })
+        .collect()
+}
+
+/// `--bench-kernel`: time the configured force pass at every candidate workgroup size on
+/// synthetic particles, and cache the fastest for this adapter and particle count.
+pub fn bench_kernel(settings: &Settings) -> Result<(), String> {
+    let particles = synthetic_particles(settings);
+    let mut best: Option<(u32, f64)> = None;
+    let mut adapter_info = None;
+
+    for workgroup_size in CANDIDATES {
+        let candidate = Settings {
+            workgroup_size: Some(workgroup_size),
+            ..settings.clone()
+        };
+        let gpu = match pollster::block_on(GpuCompute::new(&candidate)) {
+            Ok(gpu) => gpu,
+            // too big for this device, the rest are bigger still
+            Err(e) if adapter_info.is_some() => {
+                println!("workgroup_size {}: skipped ({})", workgroup_size, e);
+                break;
+            }
+            Err(e) => {
+                println!("workgroup_size {}: skipped ({})", workgroup_size, e);
+                continue;
+            }
+        };
+
+        // the first pass includes pipeline warm-up and first-use allocations
+        gpu.compute_forces(&particles);
+        let start = Instant::now();
+        for _ in 0..BENCH_REPEATS {
+            gpu.compute_forces(&particles);
+        }
+        let ms = start.elapsed().as_secs_f64() * 1000.0 / BENCH_REPEATS as f64;
+        if gpu.is_lost() {
+            println!("workgroup_size {}: device lost, skipped", workgroup_size);
+            continue;
+        }
+
+        println!(
+            "workgroup_size {}: {:.3} ms per force pass",
+            workgroup_size, ms
+        );
+        if best.is_none_or(|(_, best_ms)| ms < best_ms) {
+            best = Some((workgroup_size, ms));
+        }
+        adapter_info = Some(gpu.adapter_info.clone());
+    }
+
+    let (Some((workgroup_size, ms)), Some(info)) = (best, adapter_info) else {
+        return Err("no workgroup size worked on this device".to_string());
+    };
+    println!(
+        "Fastest: workgroup_size {} at {:.3} ms per force pass for {} particles",
+        workgroup_size, ms, settings.num_particles
+    );
+    let path = save_workgroup_size(&info, settings.num_particles, workgroup_size)?;
+    println!(
+        "Saved to {}, used whenever workgroup_size isn't set",
+        path.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,412 @@
+use futures::future::BoxFuture;
+use glam::Vec3;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "gpu")]
+use std::ops::Range;
+#[cfg(feature = "gpu")]
+use tracing::Instrument;
+use tracing::{info, trace_span, warn};
+
+use super::ParticleSet;
+use super::adapter::AdapterSettings;
+use super::error::Error;
+#[cfg(feature = "gpu")]
+use super::gpu::GpuCompute;
+use super::particle::Gravity;
+use super::settings::Settings;
+use super::tree::{BarnesHutSettings, ForceMethod, Octree, TreeParams};
+
+/// Which force backend to run. `Auto` tries the GPU and falls back to the CPU, which is all
+/// there is without the `gpu` feature.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ForceBackendKind {
+    #[default]
+    Auto,
+    Cpu,
+    #[cfg(feature = "gpu")]
+    Gpu,
+}
+
+/// Output of a force pass, one entry per target particle
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct Forces {
+    pub force: Vec<Vec3>,
+    /// Softened gravitational potential, -sum of G * m_j / r_ij over every other particle.
+    /// Per unit mass, so a particle's potential energy is `mass * potential`.
+    pub potential: Vec<f32>,
+}
+
+impl Forces {
+    /// Unpack GPU results, xyz = force and w = potential
+    pub fn from_vec4s(values: &[[f32; 4]]) -> Forces {
+        Forces {
+            force: values.iter().map(|v| Vec3::new(v[0], v[1], v[2])).collect(),
+            potential: values.iter().map(|v| v[3]).collect(),
+        }
+    }
+}
+
+/// Something that can compute the force every particle experiences from every other one.
+pub trait ForceBackend: Send + Sync {
+    /// Human readable description for the startup log and manifest
+    fn name(&self) -> String;
+
+    /// Force on and potential at every target particle
+    fn compute_forces(&self, particles: &ParticleSet) -> Forces;
+
+    /// `compute_forces` as a future that has to be run with `drive`. GPU backends submit their
+    /// work on the first poll, so anything polled alongside it overlaps with the device.
+    fn compute_forces_async<'a>(&'a self, particles: &'a ParticleSet) -> BoxFuture<'a, Forces> {
+        Box::pin(std::future::ready(self.compute_forces(particles)))
+    }
+
+    /// Host memory a force pass allocates besides the `Forces` it returns, for the memory plan
+    /// printed at startup
+    fn scratch_bytes(&self, _num_particles: usize) -> u64 {
+        0
+    }
+
+    /// The GPU backend can also keep state on the device and integrate there
+    #[cfg(feature = "gpu")]
+    fn as_gpu(&self) -> Option<&GpuCompute> {
+        None
+    }
+
+    /// A lost device returns garbage, and the backend has to be recreated
+    fn is_lost(&self) -> bool {
+        false
+    }
+
+    /// Why the backend stopped working when recreating it wouldn't help
+    fn failure(&self) -> Option<Error> {
+        None
+    }
+}
+
+/// Times a lost GPU gets recreated within one batch before the run gives up
+pub(crate) const MAX_DEVICE_RESETS: usize = 3;
+
+/// Run `future` to completion on the current thread.
+///
+/// Readbacks only register a map callback rather than blocking, and each GPU's poll thread
+/// runs it, so CPU work joined with a readback runs while the device is busy and the thread
+/// only sleeps when nothing else can progress.
+pub fn drive<F: Future>(future: F) -> F::Output {
+    futures::executor::block_on(future)
+}
+
+/// One entry of `Settings::devices` for splitting the force pass across several GPUs
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeviceSettings {
+    #[serde(flatten)]
+    pub adapter: AdapterSettings,
+    /// Share of the target particles relative to the other devices
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+/// Build the backend asked for in settings, printing which one is active. Only fails for
+/// force_backend gpu, auto falls back to the CPU.
+pub fn create_backend(settings: &Settings) -> Result<Box<dyn ForceBackend>, Error> {
+    #[cfg(feature = "gpu")]
+    return pollster::block_on(create_backend_async(settings));
+    // nothing to wait for without a device
+    #[cfg(not(feature = "gpu"))]
+    futures::executor::block_on(create_backend_async(settings))
+}
+
+/// `create_backend` for callers that can't block while the device opens, eg. in the browser
+pub async fn create_backend_async(settings: &Settings) -> Result<Box<dyn ForceBackend>, Error> {
+    let mut on_cpu = settings.force_backend == ForceBackendKind::Cpu;
+    let backend: Box<dyn ForceBackend> = match settings.force_backend {
+        ForceBackendKind::Cpu => Box::new(CpuBackend::new(settings)),
+        #[cfg(feature = "gpu")]
+        ForceBackendKind::Gpu => create_gpu_backend(settings).await?,
+        #[cfg(feature = "gpu")]
+        ForceBackendKind::Auto => match create_gpu_backend(settings).await {
+            Ok(gpu) => gpu,
+            Err(e) => {
+                warn!("{}, falling back to CPU", e);
+                on_cpu = true;
+                Box::new(CpuBackend::new(settings))
+            }
+        },
+        #[cfg(not(feature = "gpu"))]
+        ForceBackendKind::Auto => {
+            on_cpu = true;
+            Box::new(CpuBackend::new(settings))
+        }
+    };
+
+    info!("Force backend: {}", backend.name());
+    if settings.deterministic && !on_cpu {
+        warn!(
+            "deterministic: the GPU's force sums can still differ between drivers and devices, \
+             only force_backend cpu is bit-reproducible"
+        );
+    }
+    Ok(backend)
+}
+
+#[cfg(feature = "gpu")]
+async fn create_gpu_backend(settings: &Settings) -> Result<Box<dyn ForceBackend>, Error> {
+    match settings.devices.as_slice() {
+        [] => Ok(Box::new(GpuCompute::new(settings).await?)),
+        // a single configured device behaves exactly like the plain adapter setting
+        [device] => Ok(Box::new(
+            GpuCompute::with_adapter(settings, &device.adapter, 0..settings.num_particles).await?,
+        )),
+        _ if settings.force_method != ForceMethod::Direct => Err(Error::Gpu(
+            "barnes_hut can't be split across several devices yet".to_string(),
+        )),
+        devices => Ok(Box::new(MultiGpuBackend::new(settings, devices).await?)),
+    }
+}
+
+/// Forces on the CPU with rayon, all pairs or through a Barnes-Hut tree.
+pub struct CpuBackend {
+    gravity: Gravity,
+    barnes_hut: Option<(BarnesHutSettings, TreeParams)>,
+    /// Sum every particle's forces on its own, see `Settings::deterministic`
+    deterministic: bool,
+}
+
+impl CpuBackend {
+    pub fn new(settings: &Settings) -> CpuBackend {
+        let barnes_hut = match &settings.force_method {
+            ForceMethod::Direct => None,
+            ForceMethod::BarnesHut(barnes_hut) => {
+                Some((*barnes_hut, TreeParams::new(settings, barnes_hut)))
+            }
+        };
+        CpuBackend {
+            gravity: Gravity::new(settings),
+            barnes_hut,
+            deterministic: settings.deterministic,
+        }
+    }
+}
+
+impl ForceBackend for CpuBackend {
+    fn name(&self) -> String {
+        format!("CPU ({} threads)", rayon::current_num_threads())
+    }
+
+    fn scratch_bytes(&self, num_particles: usize) -> u64 {
+        if self.barnes_hut.is_some() || self.deterministic {
+            return 0;
+        }
+        // a force vec per rayon job, see the fold below. Jobs are split off about once per
+        // thread, more when work gets stolen.
+        (rayon::current_num_threads() * num_particles * 16) as u64
+    }
+
+    fn compute_forces(&self, particles: &ParticleSet) -> Forces {
+        let _span = trace_span!("compute_forces", backend = "cpu").entered();
+        if let Some((barnes_hut, params)) = &self.barnes_hut {
+            return Octree::build(particles, barnes_hut.leaf_size).forces(params);
+        }
+
+        let n = particles.len();
+        let (pos, mass) = (&particles.pos, &particles.mass);
+
+        if self.deterministic {
+            // each particle adds up the others in index order by itself, so how rayon splits the
+            // work can't change the sums. Twice the pair evaluations of the fold below.
+            let (force, potential) = (0..n)
+                .into_par_iter()
+                .map(|i| {
+                    let mut force = Vec3::ZERO;
+                    let mut potential = 0.0;
+                    for j in (0..n).filter(|&j| j != i) {
+                        force += self.gravity.force(pos[i], mass[i], pos[j], mass[j]);
+                        potential += self.gravity.potential(pos[i], pos[j], mass[j]);
+                    }
+                    (force, potential)
+                })
+                .unzip();
+            return Forces { force, potential };
+        }
+
+        let empty = || Forces {
+            force: vec![Vec3::ZERO; n],
+            potential: vec![0.0; n],
+        };
+
+        // Each pair is only evaluated once (Newton's third law), so every rayon job accumulates
+        // into its own force vec and the vecs are summed at the end.
+        (0..n)
+            .into_par_iter()
+            .fold(empty, |mut forces, i| {
+                for j in (i + 1)..n {
+                    let force = self.gravity.force(pos[i], mass[i], pos[j], mass[j]);
+                    forces.force[i] += force;
+                    forces.force[j] -= force;
+                    forces.potential[i] += self.gravity.potential(pos[i], pos[j], mass[j]);
+                    forces.potential[j] += self.gravity.potential(pos[j], pos[i], mass[i]);
+                }
+                forces
+            })
+            .reduce(empty, |mut a, b| {
+                a.force.iter_mut().zip(b.force).for_each(|(a, b)| *a += b);
+                a.potential
+                    .iter_mut()
+                    .zip(b.potential)
+                    .for_each(|(a, b)| *a += b);
+                a
+            })
+    }
+}
+
+#[cfg(feature = "gpu")]
+impl ForceBackend for GpuCompute {
+    fn name(&self) -> String {
+        format!(
+            "GPU ({}, {:?})",
+            self.adapter_info.name, self.adapter_info.backend
+        )
+    }
+
+    fn compute_forces(&self, particles: &ParticleSet) -> Forces {
+        drive(GpuCompute::compute_forces_async(self, particles))
+    }
+
+    fn compute_forces_async<'a>(&'a self, particles: &'a ParticleSet) -> BoxFuture<'a, Forces> {
+        // from submission to readback, including the CPU work joined with it
+        let span = trace_span!("compute_forces", backend = "gpu");
+        Box::pin(GpuCompute::compute_forces_async(self, particles).instrument(span))
+    }
+
+    fn scratch_bytes(&self, num_particles: usize) -> u64 {
+        // the packed readback, before it's unpacked into the forces
+        num_particles as u64 * 16
+    }
+
+    fn as_gpu(&self) -> Option<&GpuCompute> {
+        Some(self)
+    }
+
+    fn is_lost(&self) -> bool {
+        GpuCompute::is_lost(self)
+    }
+
+    fn failure(&self) -> Option<Error> {
+        GpuCompute::failure(self).map(Error::ForcePass)
+    }
+}
+
+/// Splits the target particles across several devices, each computing forces for its slice
+/// against the full source set.
+#[cfg(feature = "gpu")]
+pub struct MultiGpuBackend {
+    devices: Vec<GpuCompute>,
+}
+
+#[cfg(feature = "gpu")]
+impl MultiGpuBackend {
+    async fn new(
+        settings: &Settings,
+        devices: &[DeviceSettings],
+    ) -> Result<MultiGpuBackend, Error> {
+        let ranges = split_by_weight(
+            settings.num_particles,
+            &devices.iter().map(|d| d.weight).collect::<Vec<_>>(),
+        )
+        .map_err(Error::Gpu)?;
+
+        let mut opened = Vec::with_capacity(devices.len());
+        for (device, range) in devices.iter().zip(ranges) {
+            opened.push(GpuCompute::with_adapter(settings, &device.adapter, range).await?);
+        }
+        Ok(MultiGpuBackend { devices: opened })
+    }
+}
+
+/// Contiguous ranges covering `0..count` sized proportionally to `weights`.
+#[cfg(feature = "gpu")]
+fn split_by_weight(count: usize, weights: &[f32]) -> Result<Vec<Range<usize>>, String> {
+    let total: f32 = weights.iter().sum();
+    if weights.iter().any(|w| !w.is_finite() || *w < 0.0) || total <= 0.0 {
+        return Err(format!("invalid device weights {:?}", weights));
+    }
+
+    let mut ranges = Vec::with_capacity(weights.len());
+    let mut start = 0;
+    let mut cumulative = 0.0;
+    for (i, weight) in weights.iter().enumerate() {
+        cumulative += weight;
+        let end = if i + 1 == weights.len() {
+            count
+        } else {
+            ((cumulative / total) * count as f32).round() as usize
+        };
+        ranges.push(start..end.max(start));
+        start = end.max(start);
+    }
+    Ok(ranges)
+}
+
+#[cfg(feature = "gpu")]
+impl ForceBackend for MultiGpuBackend {
+    fn name(&self) -> String {
+        let names: Vec<String> = self
+            .devices
+            .iter()
+            .map(|gpu| format!("{} [{:?}]", gpu.adapter_info.name, gpu.targets))
+            .collect();
+        format!("Multi-GPU ({})", names.join(", "))
+    }
+
+    fn compute_forces(&self, particles: &ParticleSet) -> Forces {
+        drive(ForceBackend::compute_forces_async(self, particles))
+    }
+
+    fn compute_forces_async<'a>(&'a self, particles: &'a ParticleSet) -> BoxFuture<'a, Forces> {
+        Box::pin(
+            async move {
+                // every device submits before any is waited on, so the dispatches overlap
+                let slices = futures::future::join_all(
+                    self.devices
+                        .iter()
+                        .map(|gpu| gpu.compute_forces_async(particles)),
+                )
+                .await;
+
+                // ranges are contiguous and in order, so stitching is a concat
+                Forces {
+                    force: slices
+                        .iter()
+                        .flat_map(|s| s.force.iter().copied())
+                        .collect(),
+                    potential: slices
+                        .iter()
+                        .flat_map(|s| s.potential.iter().copied())
+                        .collect(),
+                }
+            }
+            .instrument(trace_span!("compute_forces", backend = "multi_gpu")),
+        )
+    }
+
+    fn scratch_bytes(&self, num_particles: usize) -> u64 {
+        // every device's readback and forces, before they're stitched together
+        num_particles as u64 * 32
+    }
+
+    fn is_lost(&self) -> bool {
+        self.devices.iter().any(|gpu| gpu.is_lost())
+    }
+
+    fn failure(&self) -> Option<Error> {
+        self.devices
+            .iter()
+            .find_map(|gpu| gpu.failure())
+            .map(Error::ForcePass)
+    }
+}
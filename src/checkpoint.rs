@@ -0,0 +1,240 @@
+use glam::Vec3;
+use std::path::Path;
+use tracing::warn;
+
+use super::Particle;
+use super::ParticleSet;
+use super::error::Error;
+
+/// Written next to the batches every checkpoint_every batches and when a run stops early
+pub const CHECKPOINT_FILE: &str = "checkpoint.bin";
+/// The checkpoint before the newest, kept in case the newest is unusable
+pub const PREVIOUS_CHECKPOINT_FILE: &str = "checkpoint.prev.bin";
+
+const MAGIC: &[u8; 8] = b"GRAVCKP2";
+
+/// Words per particle: mass, pos, vel, acc, group, id
+const PARTICLE_WORDS: usize = 12;
+
+/// Magic, next batch, next frame, particle count and settings hash
+const HEADER_SIZE: usize = 8 + 8 + 8 + 8 + HASH_SIZE;
+
+/// `Settings::hash` is 8 hex digits
+const HASH_SIZE: usize = 8;
+
+/// Full particle state at a batch boundary. Unlike the batch files it has velocities,
+/// accelerations and masses, so a run carries on from it exactly. Nothing is random past the
+/// initial conditions, so there's no RNG state to keep.
+pub struct Checkpoint {
+    /// First batch not written yet
+    pub next_batch: usize,
+    /// First frame not simulated yet, the start of `next_batch`
+    pub next_frame: usize,
+    /// `Settings::hash` of the run it's from
+    pub settings_hash: String,
+    /// In their vec order, which differs from id order once sorted
+    pub particles: ParticleSet,
+}
+
+impl Checkpoint {
+    /// Write `<out_path>/checkpoint.bin`, moving the one there to checkpoint.prev.bin. Goes
+    /// through a temporary file, so a run killed mid-write leaves the earlier checkpoints intact.
+    pub fn save(
+        out_path: &Path,
+        settings_hash: &str,
+        next_batch: usize,
+        next_frame: usize,
+        particles: &ParticleSet,
+    ) -> Result<(), Error> {
+        let path = out_path.join(CHECKPOINT_FILE);
+        let temp = out_path.join(format!("{}.tmp", CHECKPOINT_FILE));
+        let bytes = encode(settings_hash, next_batch, next_frame, particles);
+        std::fs::write(&temp, bytes)
+            .and_then(|_| match path.exists() {
+                true => std::fs::rename(&path, out_path.join(PREVIOUS_CHECKPOINT_FILE)),
+                false => Ok(()),
+            })
+            .and_then(|_| std::fs::rename(&temp, &path))
+            .map_err(Error::io("write", &path))
+    }
+
+    /// The checkpoints in `dir` that can be read, newest first. Unreadable ones are skipped
+    /// with a warning.
+    pub fn load_all(dir: &Path) -> Vec<Checkpoint> {
+        [CHECKPOINT_FILE, PREVIOUS_CHECKPOINT_FILE]
+            .iter()
+            .map(|name| dir.join(name))
+            .filter(|path| path.exists())
+            .filter_map(|path| {
+                let checkpoint = std::fs::read(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|bytes| decode(&bytes));
+                checkpoint
+                    .inspect_err(|e| warn!("Ignoring {}: {}", path.display(), e))
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+/// Bytes of a checkpoint of `num_particles`
+pub(crate) fn encoded_size(num_particles: usize) -> usize {
+    HEADER_SIZE + num_particles * PARTICLE_WORDS * 4
+}
+
+fn encode(
+    settings_hash: &str,
+    next_batch: usize,
+    next_frame: usize,
+    particles: &ParticleSet,
+) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(encoded_size(particles.len()));
+    bytes.extend(MAGIC);
+    bytes.extend((next_batch as u64).to_le_bytes());
+    bytes.extend((next_frame as u64).to_le_bytes());
+    bytes.extend((particles.len() as u64).to_le_bytes());
+    let mut hash = [b' '; HASH_SIZE];
+    for (byte, digit) in hash.iter_mut().zip(settings_hash.bytes()) {
+        *byte = digit;
+    }
+    bytes.extend(hash);
+    for particle in particles.iter() {
+        let mut words = [0u32; PARTICLE_WORDS];
+        words[0] = particle.mass().to_bits();
+        for (index, vector) in [particle.pos(), particle.vel(), particle.acc()]
+            .iter()
+            .enumerate()
+        {
+            for axis in 0..3 {
+                words[1 + index * 3 + axis] = vector[axis].to_bits();
+            }
+        }
+        words[10] = particle.group();
+        words[11] = particle.id();
+        for word in words {
+            bytes.extend(word.to_le_bytes());
+        }
+    }
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Result<Checkpoint, String> {
+    if bytes.len() < HEADER_SIZE || &bytes[..8] != MAGIC {
+        return Err("not a checkpoint file".to_string());
+    }
+    let header = |index: usize| {
+        u64::from_le_bytes(bytes[8 + index * 8..16 + index * 8].try_into().unwrap()) as usize
+    };
+    let (next_batch, next_frame, count) = (header(0), header(1), header(2));
+    let settings_hash = String::from_utf8_lossy(&bytes[8 + 3 * 8..HEADER_SIZE])
+        .trim_end()
+        .to_string();
+    let expected = HEADER_SIZE + count * PARTICLE_WORDS * 4;
+    if bytes.len() != expected {
+        return Err(format!(
+            "{} particles need {} bytes, the file has {}",
+            count,
+            expected,
+            bytes.len()
+        ));
+    }
+
+    let particles = bytes[HEADER_SIZE..]
+        .chunks_exact(PARTICLE_WORDS * 4)
+        .map(|chunk| {
+            let word = |index: usize| {
+                u32::from_le_bytes(chunk[index * 4..index * 4 + 4].try_into().unwrap())
+            };
+            let vector = |first: usize| {
+                Vec3::new(
+                    f32::from_bits(word(first)),
+                    f32::from_bits(word(first + 1)),
+                    f32::from_bits(word(first + 2)),
+                )
+            };
+            Particle::new(f32::from_bits(word(0)), vector(1), vector(4), vector(7))
+                .with_group(word(10))
+                .with_id(word(11))
+        })
+        .collect();
+    Ok(Checkpoint {
+        next_batch,
+        next_frame,
+        settings_hash,
+        particles,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn particles_round_trip_exactly() {
+        let particle = Particle::new(
+            3.5,
+            Vec3::new(1.0, -2.0, 1e-7),
+            Vec3::new(0.1, 0.2, 0.3),
+            Vec3::new(-5.0, 0.0, f32::MIN_POSITIVE),
+        )
+        .with_group(2)
+        .with_id(41);
+        let checkpoint = decode(&encode(
+            "0123abcd",
+            7,
+            700,
+            &ParticleSet::from(vec![particle, Particle::new_zero()]),
+        ))
+        .unwrap();
+
+        assert_eq!(checkpoint.next_batch, 7);
+        assert_eq!(checkpoint.next_frame, 700);
+        assert_eq!(checkpoint.settings_hash, "0123abcd");
+        assert_eq!(checkpoint.particles.len(), 2);
+        let read = checkpoint.particles.get(0);
+        assert_eq!(
+            (
+                read.mass(),
+                read.pos(),
+                read.vel(),
+                read.acc(),
+                read.group(),
+                read.id()
+            ),
+            (
+                particle.mass(),
+                particle.pos(),
+                particle.vel(),
+                particle.acc(),
+                2,
+                41
+            )
+        );
+    }
+
+    #[test]
+    fn truncated_checkpoints_are_errors() {
+        let particles = ParticleSet::from(vec![Particle::new_zero()]);
+        let bytes = encode("0123abcd", 1, 100, &particles);
+        assert!(decode(&bytes[..bytes.len() - 4]).is_err());
+        assert!(decode(b"GRAVCKP").is_err());
+    }
+
+    #[test]
+    fn saving_keeps_the_previous_checkpoint() {
+        let dir = std::env::temp_dir().join("gravity-output-checkpoint-rotation");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let particles = ParticleSet::from(vec![Particle::new_zero()]);
+        for batch in 1..=3 {
+            Checkpoint::save(&dir, "0123abcd", batch, batch * 10, &particles).unwrap();
+        }
+        std::fs::write(dir.join(CHECKPOINT_FILE), b"GRAVCKP2 cut off").unwrap();
+        let checkpoints = Checkpoint::load_all(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // the newest is unreadable, the one before it is still there
+        let batches: Vec<usize> = checkpoints.iter().map(|c| c.next_batch).collect();
+        assert_eq!(batches, [2]);
+    }
+}
@@ -0,0 +1,327 @@
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::path::PathBuf;
+
+use super::convert::ConvertFormat;
+use super::progress::ProgressFormat;
+use super::settings::{Settings, SettingsFormat};
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "GPU n-body gravity simulation, writing frames to gzipped binary files",
+    args_conflicts_with_subcommands = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Only log warnings and errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Log more: -v for per-batch details like GPU timings, -vv for everything
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+    /// Also log JSON lines to this file, relative to the output directory
+    #[arg(long, global = true)]
+    pub log_file: Option<PathBuf>,
+    /// Record where the time goes, per batch and per phase, to this Chrome trace file for
+    /// Perfetto. Relative to the working directory.
+    #[arg(long, global = true)]
+    pub trace_file: Option<PathBuf>,
+    /// `run` flags, for the deprecated bare invocation
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Simulate with the settings file, environment and flags
+    Run(RunArgs),
+    /// Ask for the main settings and write the settings file
+    Init {
+        /// Format of the settings file to write
+        #[arg(long = "settings-format", value_enum, default_value_t = SettingsFormat::Json)]
+        format: SettingsFormat,
+    },
+    /// Continue an interrupted run from its output directory
+    Resume {
+        /// Output directory with the run's manifest.json and batch files
+        dir: PathBuf,
+        /// New frames_total, to extend a finished run
+        #[arg(long = "frames")]
+        frames_total: Option<usize>,
+        /// Take the settings from the working directory, environment and settings file instead
+        /// of the run's settings.resolved.json, printing what changed
+        #[arg(long)]
+        allow_changed: bool,
+    },
+    /// Print the headers, frame counts and basic statistics of a batch file or output directory
+    Inspect {
+        /// Batch file or output directory
+        path: PathBuf,
+    },
+    /// Convert a batch file to another format
+    Convert {
+        /// Batch file to read
+        input: PathBuf,
+        #[arg(long, value_enum)]
+        to: ConvertFormat,
+        /// File to write, the input with the new format's extension by default
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+}
+
+/// `run` flags. Every value given here overrides the `GRAVITY_<FIELD>` environment variables,
+/// which override settings.toml or settings.json, which override the defaults. Settings without
+/// a flag can still be set through the environment or the file.
+#[derive(Args)]
+pub struct RunArgs {
+    /// Number of particles
+    #[arg(long = "particles", default_value_t = Settings::default().num_particles)]
+    num_particles: usize,
+    /// Frames to simulate
+    #[arg(long = "frames", default_value_t = Settings::default().frames_total)]
+    frames_total: usize,
+    /// Frames per output file
+    #[arg(long, default_value_t = Settings::default().frames_per_file)]
+    frames_per_file: usize,
+    /// Time step per frame
+    #[arg(long, default_value_t = Settings::default().dt)]
+    dt: f32,
+    /// Radius of the initial particle sphere
+    #[arg(long, default_value_t = Settings::default().arena)]
+    arena: f32,
+    /// Gravitational constant
+    #[arg(long = "g", default_value_t = Settings::default().g_const)]
+    g_const: f32,
+    /// Plummer softening length
+    #[arg(long, default_value_t = Settings::default().softening)]
+    softening: f32,
+    /// Mass of each particle
+    #[arg(long, default_value_t = Settings::default().mass)]
+    mass: f32,
+    /// Scale on the initial orbital velocity
+    #[arg(long, default_value_t = Settings::default().init_vel)]
+    init_vel: f32,
+    /// Directory the frame files are written to, relative to the working directory
+    #[arg(long = "output", default_value = "output")]
+    out_path: PathBuf,
+    /// Load nbody.wgsl from this file and reload it between batches when it changes
+    #[arg(long)]
+    shader_path: Option<PathBuf>,
+    /// Threads for the CPU work, 0 for every core
+    #[arg(long = "threads")]
+    cpu_threads: Option<usize>,
+    /// Override any setting, nested ones by their dotted path, eg.
+    /// `--set initial_conditions.galaxy.disk.count=20000`. Repeatable, applied in order after
+    /// the other flags.
+    #[arg(long = "set", value_name = "PATH=VALUE")]
+    pub set: Vec<String>,
+    /// Settings file to load instead of settings.toml or settings.json in the working
+    /// directory. Unlike those it has to exist. `-` reads them from stdin.
+    #[arg(long = "settings", env = "GRAVITY_SETTINGS")]
+    pub settings_path: Option<PathBuf>,
+    /// Apply this entry of the settings file's `profiles` over the rest of it
+    #[arg(long, env = "GRAVITY_PROFILE")]
+    pub profile: Option<String>,
+    /// Format of the default settings file written when there is none, and of settings read
+    /// from stdin
+    #[arg(long, value_enum, default_value_t = SettingsFormat::Json)]
+    pub settings_format: SettingsFormat,
+    /// Print the GPU memory estimate for the settings and exit
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Time the force kernel at several workgroup sizes, cache the fastest and exit
+    #[arg(long)]
+    pub bench_kernel: bool,
+    /// Start over in an output directory that already holds a run, deleting its batch files
+    /// and checkpoints
+    #[arg(long)]
+    pub force: bool,
+    /// How progress is reported: log lines, or a JSON object per event on stdout
+    #[arg(long, value_enum, default_value_t = ProgressFormat::Console)]
+    pub progress_format: ProgressFormat,
+    /// Log a line per batch instead of drawing a progress bar. There's only a bar when stdout
+    /// is a terminal anyway.
+    #[arg(long)]
+    pub no_progress: bool,
+    /// Open a window drawing the particles as they're simulated, on the run's GPU. Closing it
+    /// leaves the run going. Needs a build with the preview feature.
+    #[arg(long)]
+    pub preview: bool,
+    /// Draw at most one frame in this many in the preview window
+    #[arg(long, default_value_t = 10)]
+    pub preview_every: usize,
+    /// Ids of the arguments given on the command line rather than defaulted
+    #[arg(skip)]
+    given: Vec<String>,
+}
+
+/// Copy each listed field from the command line into the settings if it was given there
+macro_rules! override_given {
+    ($cli:expr, $settings:expr, $given:expr, $($field:ident),+) => {
+        $(
+            if $cli.was_given(stringify!($field)) {
+                $settings.$field = $cli.$field.clone();
+                $given.push(stringify!($field));
+            }
+        )+
+    };
+}
+
+impl Cli {
+    /// Parse the process arguments, exiting with usage on unknown flags or bad values
+    pub fn parse_args() -> Cli {
+        Cli::from_matches(Cli::command().get_matches())
+    }
+
+    fn from_matches(matches: ArgMatches) -> Cli {
+        let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        let run_matches = matches.subcommand_matches("run").unwrap_or(&matches);
+        let given = run_matches
+            .ids()
+            .filter(|id| run_matches.value_source(id.as_str()) == Some(ValueSource::CommandLine))
+            .map(|id| id.to_string())
+            .collect();
+        match &mut cli.command {
+            Some(Command::Run(run)) => run.given = given,
+            _ => cli.run.given = given,
+        }
+        cli
+    }
+
+    /// Flags for a run, all defaults for the subcommands that don't take them
+    pub fn run_args(&self) -> &RunArgs {
+        match &self.command {
+            Some(Command::Run(run)) => run,
+            _ => &self.run,
+        }
+    }
+}
+
+impl RunArgs {
+    fn was_given(&self, id: &str) -> bool {
+        self.given.iter().any(|given| given == id)
+    }
+
+    /// Override `settings` with everything given on the command line, returning the names of
+    /// the fields changed
+    pub fn apply(&self, settings: &mut Settings) -> Vec<&'static str> {
+        let mut given = Vec::new();
+        override_given!(
+            self,
+            settings,
+            given,
+            num_particles,
+            frames_total,
+            frames_per_file,
+            dt,
+            arena,
+            g_const,
+            softening,
+            mass,
+            init_vel,
+            out_path
+        );
+        if let Some(shader_path) = &self.shader_path {
+            settings.shader_path = Some(shader_path.clone());
+            given.push("shader_path");
+        }
+        if let Some(cpu_threads) = self.cpu_threads {
+            settings.cpu_threads = Some(cpu_threads);
+            given.push("cpu_threads");
+        }
+        given
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        // clap's own checks on the derived command, eg. conflicting ids
+        Cli::command().debug_assert();
+        let matches = Cli::command()
+            .try_get_matches_from(std::iter::once("gravity-output").chain(args.iter().copied()))?;
+        Ok(Cli::from_matches(matches))
+    }
+
+    #[test]
+    fn only_given_flags_override() {
+        let cli = parse(&["--particles", "2000", "--g", "0.5", "--output", "runs/a"]).unwrap();
+        let mut settings = Settings {
+            dt: 0.25,
+            ..Settings::default()
+        };
+        let given = cli.run_args().apply(&mut settings);
+
+        assert_eq!(given, ["num_particles", "g_const", "out_path"]);
+        assert_eq!(settings.num_particles, 2000);
+        assert_eq!(settings.g_const, 0.5);
+        assert_eq!(settings.out_path, PathBuf::from("runs/a"));
+        // from the file, untouched by the flag's default
+        assert_eq!(settings.dt, 0.25);
+    }
+
+    #[test]
+    fn unknown_flags_are_errors() {
+        assert!(parse(&["--partcles", "10"]).is_err());
+        assert!(parse(&["--particles", "many"]).is_err());
+        assert!(parse(&["inspect", "output", "--particles", "10"]).is_err());
+    }
+
+    #[test]
+    fn output_flag_shapes() {
+        use clap::error::ErrorKind;
+        let out_path = |args: &[&str]| {
+            let mut settings = Settings::default();
+            parse(args).map(|cli| {
+                cli.run_args().apply(&mut settings);
+                settings.out_path
+            })
+        };
+        assert_eq!(
+            out_path(&["run", "--output=runs/a"]).unwrap(),
+            PathBuf::from("runs/a")
+        );
+        assert_eq!(
+            out_path(&["--output", "runs/b"]).unwrap(),
+            PathBuf::from("runs/b")
+        );
+        // without the flag the settings file's out_path stays
+        assert_eq!(out_path(&["run"]).unwrap(), Settings::default().out_path);
+
+        let error = |args: &[&str]| out_path(args).map(|_| ()).unwrap_err().kind();
+        assert_eq!(error(&["run", "--output"]), ErrorKind::InvalidValue);
+        assert_eq!(
+            error(&["run", "--output", "a", "--output", "b"]),
+            ErrorKind::ArgumentConflict
+        );
+        assert_eq!(error(&["run", "--ouput", "a"]), ErrorKind::UnknownArgument);
+        assert_eq!(error(&["run", "--output="]), ErrorKind::InvalidValue);
+    }
+
+    #[test]
+    fn run_flags_with_and_without_the_subcommand() {
+        for args in [&["run", "--dt", "0.5"][..], &["--dt", "0.5"]] {
+            let cli = parse(args).unwrap();
+            let mut settings = Settings::default();
+            assert_eq!(cli.run_args().apply(&mut settings), ["dt"]);
+            assert_eq!(settings.dt, 0.5);
+        }
+        let cli = parse(&["run", "--threads", "4"]).unwrap();
+        let mut settings = Settings::default();
+        assert_eq!(cli.run_args().apply(&mut settings), ["cpu_threads"]);
+        assert_eq!(settings.cpu_threads, Some(4));
+        let cli = parse(&["resume", "output", "--frames", "200"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Resume {
+                frames_total: Some(200),
+                ..
+            })
+        ));
+    }
+}
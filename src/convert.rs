@@ -0,0 +1,143 @@
+use gravity_output_format::{self as format, Batch};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Formats `convert` writes
+#[derive(Clone, Copy, PartialEq, Debug, clap::ValueEnum)]
+pub enum ConvertFormat {
+    /// One `frame,particle,x,y,z` row per particle per frame
+    Csv,
+    /// NumPy float32 array shaped (frames, particles, 3)
+    Npy,
+}
+
+impl ConvertFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ConvertFormat::Csv => "csv",
+            ConvertFormat::Npy => "npy",
+        }
+    }
+}
+
+/// `convert`: write a batch file out as `format`
+pub fn convert(input: &Path, format: ConvertFormat, output: Option<&Path>) -> Result<(), String> {
+    let batch = format::read_batch(input)?;
+    let output = match output {
+        Some(output) => output.to_path_buf(),
+        None => default_output(input, format),
+    };
+    let file = File::create(&output)
+        .map_err(|e| format!("Could not create {}: {}", output.display(), e))?;
+    let mut writer = BufWriter::new(file);
+    match format {
+        ConvertFormat::Csv => write_csv(&batch, &mut writer),
+        ConvertFormat::Npy => write_npy(&batch, &mut writer),
+    }
+    .and_then(|_| writer.flush())
+    .map_err(|e| format!("Could not write {}: {}", output.display(), e))?;
+    println!(
+        "Wrote {} frames of {} particles to {}",
+        batch.frames.len(),
+        batch.num_particles,
+        output.display()
+    );
+    Ok(())
+}
+
+/// batch_0003.bin.gz becomes batch_0003.csv next to it
+fn default_output(input: &Path, format: ConvertFormat) -> PathBuf {
+    let name = input
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("batch");
+    let stem = name.strip_suffix(".bin.gz").unwrap_or(name);
+    input.with_file_name(format!("{}.{}", stem, format.extension()))
+}
+
+fn write_csv(batch: &Batch, writer: &mut impl Write) -> std::io::Result<()> {
+    writeln!(writer, "frame,particle,x,y,z")?;
+    for (frame_index, frame) in batch.frames.iter().enumerate() {
+        for (particle, pos) in frame.iter().enumerate() {
+            writeln!(
+                writer,
+                "{},{},{},{},{}",
+                frame_index, particle, pos.x, pos.y, pos.z
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Version 1.0 of the .npy format: magic, header length, a dict literal padded so the data
+/// starts 64-byte aligned, then the raw little-endian data
+fn write_npy(batch: &Batch, writer: &mut impl Write) -> std::io::Result<()> {
+    const MAGIC: &[u8] = b"\x93NUMPY\x01\x00";
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}, 3), }}",
+        batch.frames.len(),
+        batch.num_particles
+    );
+    // magic, the u16 length and the header including its newline fill whole 64-byte blocks
+    let unpadded = MAGIC.len() + 2 + header.len() + 1;
+    header.push_str(&" ".repeat(unpadded.next_multiple_of(64) - unpadded));
+    header.push('\n');
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    for frame in &batch.frames {
+        writer.write_all(bytemuck::cast_slice(frame))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Vec3;
+
+    fn batch() -> Batch {
+        Batch {
+            num_particles: 2,
+            frames: vec![
+                vec![Vec3::new(1.0, 2.0, 3.0), Vec3::new(4.0, 5.0, 6.0)],
+                vec![Vec3::new(1.5, 2.5, 3.5), Vec3::new(4.5, 5.5, 6.5)],
+            ],
+            comment: Default::default(),
+        }
+    }
+
+    #[test]
+    fn csv_has_a_row_per_particle_per_frame() {
+        let mut csv = Vec::new();
+        write_csv(&batch(), &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "frame,particle,x,y,z");
+        assert_eq!(lines[4], "1,1,4.5,5.5,6.5");
+    }
+
+    #[test]
+    fn npy_header_is_aligned() {
+        let mut npy = Vec::new();
+        write_npy(&batch(), &mut npy).unwrap();
+        let header_len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+        let data_start = 10 + header_len;
+        assert_eq!(data_start % 64, 0);
+        assert_eq!(npy[data_start - 1], b'\n');
+        assert!(String::from_utf8_lossy(&npy[10..data_start]).contains("'shape': (2, 2, 3)"));
+        assert_eq!(npy.len() - data_start, 2 * 2 * 3 * 4);
+        assert_eq!(&npy[data_start..data_start + 4], 1.0f32.to_le_bytes());
+    }
+
+    #[test]
+    fn default_output_replaces_the_extension() {
+        assert_eq!(
+            default_output(Path::new("runs/batch_0003.bin.gz"), ConvertFormat::Npy),
+            PathBuf::from("runs/batch_0003.npy")
+        );
+    }
+}
@@ -0,0 +1,1191 @@
+use bytemuck::{Pod, Zeroable};
+use glam::{DVec3, Vec3};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+use super::ParticleSet;
+use super::error::Error;
+use super::grid;
+use super::particle;
+use super::settings::{Integrator, Settings};
+
+/// Particles per partial sum in `chunked_sum`
+const ENERGY_CHUNK: usize = 4096;
+
+/// `chunk` over the first `len` particles, a fixed ENERGY_CHUNK of them at a time in parallel,
+/// and the partial sums added with `add` in order. rayon's reduce doesn't promise an order, so
+/// this is what keeps f64 sums from depending on the thread count.
+fn chunked_sum<T: Send>(
+    len: usize,
+    zero: T,
+    chunk: impl Fn(Range<usize>) -> T + Sync,
+    add: impl Fn(T, T) -> T,
+) -> T {
+    let chunks: Vec<T> = (0..len.div_ceil(ENERGY_CHUNK))
+        .into_par_iter()
+        .map(|index| chunk(index * ENERGY_CHUNK..len.min((index + 1) * ENERGY_CHUNK)))
+        .collect();
+    chunks.into_iter().fold(zero, add)
+}
+
+/// Conserved quantities summed over every particle at the start of a step
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Energy {
+    pub kinetic: f32,
+    /// Half the sum of mass * potential, so every pair is counted once
+    pub potential: f32,
+    pub momentum: Vec3,
+}
+
+impl Energy {
+    /// CPU reduction of `particles` with the potentials from the same force pass.
+    pub fn from_particles(particles: &ParticleSet, potential: &[f32]) -> Energy {
+        // f64 so the reference stays well below the GPU's f32 accumulation error
+        let zero = (0.0, 0.0, DVec3::ZERO);
+        let add = |a: (f64, f64, DVec3), b: (f64, f64, DVec3)| (a.0 + b.0, a.1 + b.1, a.2 + b.2);
+        let len = particles.mass.len().min(potential.len());
+        let (kinetic, potential, momentum) = chunked_sum(
+            len,
+            zero,
+            |range| {
+                particles.mass[range.clone()]
+                    .iter()
+                    .zip(&particles.vel[range.clone()])
+                    .zip(&potential[range])
+                    .map(|((&mass, vel), &potential)| {
+                        let mass = mass as f64;
+                        let vel = vel.as_dvec3();
+                        (
+                            particle::kinetic_energy(mass, vel),
+                            0.5 * mass * potential as f64,
+                            particle::momentum(mass, vel),
+                        )
+                    })
+                    .fold(zero, add)
+            },
+            add,
+        );
+        Energy {
+            kinetic: kinetic as f32,
+            potential: potential as f32,
+            momentum: momentum.as_vec3(),
+        }
+    }
+
+    pub fn total(&self) -> f32 {
+        self.kinetic + self.potential
+    }
+}
+
+/// Mirrors `EnergySums` in nbody.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Pod, Zeroable)]
+pub struct GpuEnergy {
+    kinetic: f32,
+    potential: f32,
+    _padding: [f32; 2],
+    momentum: [f32; 3],
+    _padding2: f32,
+}
+
+impl From<GpuEnergy> for Energy {
+    fn from(gpu: GpuEnergy) -> Energy {
+        Energy {
+            kinetic: gpu.kinetic,
+            potential: gpu.potential,
+            momentum: Vec3::from_array(gpu.momentum),
+        }
+    }
+}
+
+/// The columns of `diagnostics.csv` taken from the positions rather than the energy sums
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct DiagnosticsColumns {
+    /// Mass fractions of the Lagrangian radii columns, none for no columns
+    pub lagrangian: Vec<f64>,
+    /// nn_min, nn_p1, nn_p50 and nn_mean, see `NeighborDistances`
+    pub nearest_neighbors: bool,
+}
+
+impl DiagnosticsColumns {
+    pub fn from_settings(settings: &Settings) -> DiagnosticsColumns {
+        DiagnosticsColumns {
+            lagrangian: match settings.lagrangian_radii {
+                true => settings.lagrangian_fractions.clone(),
+                false => Vec::new(),
+            },
+            nearest_neighbors: settings.nearest_neighbors,
+        }
+    }
+}
+
+/// `diagnostics.csv` in the output directory, one row per step or per `logging_every` steps
+pub struct DiagnosticsLog {
+    csv: CsvLog,
+    /// Only steps whose number is a multiple of this are logged
+    pub(crate) every: usize,
+    columns: DiagnosticsColumns,
+    /// The particles' masses, in the order of the positions of the batch being simulated
+    pub(crate) masses: Vec<f32>,
+    /// Lagrangian radii at the start of the steps of the batch, until its rows are logged
+    radii: BTreeMap<usize, Vec<f64>>,
+    /// Nearest neighbour distances at the start of the steps of the batch, the same way
+    neighbors: BTreeMap<usize, NeighborDistances>,
+    /// Warn when nn_min goes under this
+    softening: f32,
+    /// Whether nn_min was under the softening at the last step with one, to warn only as it
+    /// goes under
+    below_softening: bool,
+    /// First step of the batch being simulated
+    pub(crate) batch_start: usize,
+}
+
+impl DiagnosticsLog {
+    pub fn create(out_path: &Path, columns: &DiagnosticsColumns) -> Result<DiagnosticsLog, Error> {
+        let csv = CsvLog::create(
+            out_path,
+            "diagnostics.csv",
+            &DiagnosticsLog::header(columns),
+        )?;
+        Ok(DiagnosticsLog::new(csv, columns))
+    }
+
+    /// Continue the log of a resumed run from `first_step`, dropping the rows of steps it runs
+    /// again, or start one if it didn't keep one
+    pub fn append_to(
+        out_path: &Path,
+        first_step: usize,
+        columns: &DiagnosticsColumns,
+    ) -> Result<DiagnosticsLog, Error> {
+        let header = DiagnosticsLog::header(columns);
+        let csv = CsvLog::append_to(out_path, "diagnostics.csv", &header, first_step)?;
+        Ok(DiagnosticsLog::new(csv, columns))
+    }
+
+    fn header(columns: &DiagnosticsColumns) -> String {
+        let mut header =
+            "step,time,dt,kinetic,potential,total,momentum_x,momentum_y,momentum_z".to_string();
+        for fraction in &columns.lagrangian {
+            header += &format!(",r_{}", fraction);
+        }
+        if columns.nearest_neighbors {
+            header += ",nn_min,nn_p1,nn_p50,nn_mean";
+        }
+        header
+    }
+
+    fn new(csv: CsvLog, columns: &DiagnosticsColumns) -> DiagnosticsLog {
+        DiagnosticsLog {
+            csv,
+            every: 1,
+            columns: columns.clone(),
+            masses: Vec::new(),
+            radii: BTreeMap::new(),
+            neighbors: BTreeMap::new(),
+            softening: 0.0,
+            below_softening: false,
+            batch_start: 0,
+        }
+    }
+
+    /// Log only the steps whose number is a multiple of `every`, see `Settings::diagnostics_every`
+    pub fn logging_every(mut self, every: usize) -> DiagnosticsLog {
+        self.every = every.max(1);
+        self
+    }
+
+    /// Warning when the nearest neighbour distance goes under `softening`, with the
+    /// nearest_neighbors columns
+    pub fn warning_below(mut self, softening: f32) -> DiagnosticsLog {
+        self.softening = softening;
+        self
+    }
+
+    /// Append `energies`, the first of which is for `first_step` at simulated time `first_time`
+    /// and all `dt` apart, and flush.
+    pub fn append(
+        &mut self,
+        first_step: usize,
+        first_time: f64,
+        dt: f32,
+        energies: &[Energy],
+    ) -> Result<(), Error> {
+        for (index, energy) in energies.iter().enumerate() {
+            if !(first_step + index).is_multiple_of(self.every) {
+                continue;
+            }
+            let radii = match self.radii.remove(&(first_step + index)) {
+                Some(radii) => radii.iter().map(|r| format!(",{}", r)).collect(),
+                // eg. the masses weren't known
+                None => ",".repeat(self.columns.lagrangian.len()),
+            };
+            let neighbors = match self.neighbors.remove(&(first_step + index)) {
+                Some(n) => format!(",{},{},{},{}", n.min, n.p1, n.p50, n.mean),
+                None if self.columns.nearest_neighbors => ",,,,".to_string(),
+                None => String::new(),
+            };
+            self.csv.write_line(&format!(
+                "{},{},{},{},{},{},{},{},{}{}{}",
+                first_step + index,
+                first_time + index as f64 * dt as f64,
+                dt,
+                energy.kinetic,
+                energy.potential,
+                energy.total(),
+                energy.momentum.x,
+                energy.momentum.y,
+                energy.momentum.z,
+                radii,
+                neighbors
+            ))?;
+        }
+        self.radii.clear();
+        self.neighbors.clear();
+        self.csv.flush()
+    }
+
+    /// Work out the columns taken from the positions at the start of `step`, in the order of
+    /// `masses`, if it's a step that's logged
+    pub(crate) fn record_step(&mut self, step: usize, positions: &[Vec3]) {
+        if !step.is_multiple_of(self.every) {
+            return;
+        }
+        let lagrangian = &self.columns.lagrangian;
+        if !lagrangian.is_empty() && self.masses.len() == positions.len() {
+            let radii = lagrangian_radii(&self.masses, positions, lagrangian);
+            self.radii.insert(step, radii);
+        }
+        if self.columns.nearest_neighbors
+            && let Some(neighbors) = NeighborDistances::of(positions)
+        {
+            let below = neighbors.min < self.softening as f64;
+            if below && !self.below_softening {
+                warn!(
+                    "Minimum nearest-neighbour distance {} at step {} is below the softening {}: \
+                     softening is dominating close encounters, consider a smaller softening or dt",
+                    neighbors.min, step, self.softening
+                );
+            }
+            self.below_softening = below;
+            self.neighbors.insert(step, neighbors);
+        }
+    }
+
+    /// Whether there are Lagrangian radii columns, which need the masses
+    pub(crate) fn logs_radii(&self) -> bool {
+        !self.columns.lagrangian.is_empty()
+    }
+
+    /// Whether there are any columns `record_step` works out
+    pub(crate) fn records_steps(&self) -> bool {
+        self.logs_radii() || self.columns.nearest_neighbors
+    }
+}
+
+/// Distances from each particle to its closest other one, summed up for diagnostics.csv
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct NeighborDistances {
+    pub min: f64,
+    /// 1st percentile, the closest pairs without the single closest's noise
+    pub p1: f64,
+    pub p50: f64,
+    pub mean: f64,
+}
+
+impl NeighborDistances {
+    /// Found with a `SpatialGrid`, so in about linear time. None without two particles.
+    pub fn of(positions: &[Vec3]) -> Option<NeighborDistances> {
+        let mut distances: Vec<f64> = grid::nearest_neighbor_distances(positions)
+            .into_iter()
+            .filter(|distance| distance.is_finite())
+            .map(f64::from)
+            .collect();
+        if distances.is_empty() {
+            return None;
+        }
+        let sum = chunked_sum(
+            distances.len(),
+            0.0,
+            |range| distances[range].iter().sum(),
+            |a, b| a + b,
+        );
+        let mean = sum / distances.len() as f64;
+        let min = distances.iter().copied().fold(f64::INFINITY, f64::min);
+        let mut percentile = |fraction: f64| {
+            let k = ((fraction * distances.len() as f64).ceil() as usize).clamp(1, distances.len());
+            *distances.select_nth_unstable_by(k - 1, f64::total_cmp).1
+        };
+        Some(NeighborDistances {
+            min,
+            p1: percentile(0.01),
+            p50: percentile(0.5),
+            mean,
+        })
+    }
+}
+
+/// How far the total energy has drifted from the first frame's, |E - E0| / |E0|, for the
+/// manifest
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct EnergyDrift {
+    /// Total energy at the start of the first frame. A resumed run that didn't record one starts
+    /// from the first frame it simulates.
+    pub initial: f64,
+    /// Largest drift so far, and the frame it was at
+    pub max: f64,
+    pub max_frame: usize,
+    /// First frame the drift was over energy_drift_warn at, None while it hasn't been
+    pub exceeded_at_frame: Option<usize>,
+    /// Batches warned about
+    pub warnings: usize,
+}
+
+/// Warns when the energies of a batch drift past `Settings::energy_drift_warn`, at most once per
+/// batch. Keeps the `EnergyDrift` it shares with the run up to date.
+pub struct DriftWatch {
+    threshold: f64,
+    integrator: Integrator,
+    drift: Arc<Mutex<Option<EnergyDrift>>>,
+}
+
+impl DriftWatch {
+    /// Carrying on from `drift`, which a resumed run has from the manifest
+    pub fn new(settings: &Settings, drift: Arc<Mutex<Option<EnergyDrift>>>) -> DriftWatch {
+        DriftWatch {
+            threshold: settings.energy_drift_warn,
+            integrator: settings.integrator,
+            drift,
+        }
+    }
+
+    /// Check the energies at the start of each step from `first_frame`
+    pub fn check(&mut self, first_frame: usize, energies: &[Energy]) {
+        let Some(first) = energies.first() else {
+            return;
+        };
+        let mut drift = self.drift.lock().unwrap();
+        let drift = drift.get_or_insert(EnergyDrift {
+            initial: first.total() as f64,
+            max: 0.0,
+            max_frame: first_frame,
+            exceeded_at_frame: None,
+            warnings: 0,
+        });
+        if drift.initial == 0.0 {
+            return;
+        }
+        let mut worst: Option<(usize, f64)> = None;
+        for (index, energy) in energies.iter().enumerate() {
+            let value = ((energy.total() as f64 - drift.initial) / drift.initial).abs();
+            // a run that's blown up has no finite energy, as bad as drift gets
+            let value = if value.is_finite() { value } else { f64::MAX };
+            if value > drift.max {
+                drift.max = value;
+                drift.max_frame = first_frame + index;
+            }
+            if value > self.threshold {
+                drift.exceeded_at_frame.get_or_insert(first_frame + index);
+                if worst.is_none_or(|(_, worst)| value > worst) {
+                    worst = Some((first_frame + index, value));
+                }
+            }
+        }
+        let Some((frame, value)) = worst else {
+            return;
+        };
+        drift.warnings += 1;
+        let hint = match self.integrator {
+            Integrator::Euler => "a smaller dt or integrator verlet",
+            Integrator::Verlet => "a smaller dt",
+        };
+        warn!("{}", "!".repeat(80));
+        warn!(
+            "Energy drift {:.3}% at frame {}, over energy_drift_warn {}%. Try {}.",
+            value * 100.0,
+            frame,
+            self.threshold * 100.0,
+            hint
+        );
+        warn!("{}", "!".repeat(80));
+    }
+}
+
+/// `speed_histogram.csv` in the output directory, the particles' speeds binned at the end of a
+/// batch, with `speed_histogram.json` next to it saying where the bins' edges are
+pub struct SpeedHistogramLog {
+    csv: CsvLog,
+    bins: usize,
+    /// Upper edge of the last bin, None to scale to the fastest particle
+    max: Option<f64>,
+    /// Only batches with a step whose number is a multiple of this get a row
+    pub(crate) every: usize,
+}
+
+/// `speed_histogram.json`, how to read the rows
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct SpeedHistogramLayout {
+    pub bins: usize,
+    /// The bins' edges when speed_histogram_max fixes them. Otherwise bin i of a row is from
+    /// i * upper / bins to (i + 1) * upper / bins, with upper from the row.
+    pub edges: Option<Vec<f64>>,
+    pub columns: Vec<String>,
+}
+
+impl SpeedHistogramLog {
+    pub fn create(settings: &Settings) -> Result<SpeedHistogramLog, Error> {
+        let header = SpeedHistogramLog::write_layout(settings)?;
+        let csv = CsvLog::create(&settings.out_path, "speed_histogram.csv", &header)?;
+        Ok(SpeedHistogramLog::new(csv, settings))
+    }
+
+    /// Continue the log of a resumed run from `first_frame`, dropping the rows it runs again,
+    /// or start one if it didn't keep one
+    pub fn append_to(settings: &Settings, first_frame: usize) -> Result<SpeedHistogramLog, Error> {
+        let header = SpeedHistogramLog::write_layout(settings)?;
+        let csv = CsvLog::append_to(
+            &settings.out_path,
+            "speed_histogram.csv",
+            &header,
+            first_frame,
+        )?;
+        Ok(SpeedHistogramLog::new(csv, settings))
+    }
+
+    /// Write `speed_histogram.json` for `settings`, returning the CSV header
+    fn write_layout(settings: &Settings) -> Result<String, Error> {
+        let bins = settings.speed_histogram_bins;
+        let mut columns: Vec<String> = ["frame", "time", "upper", "over"]
+            .iter()
+            .map(|column| column.to_string())
+            .collect();
+        columns.extend((0..bins).map(|bin| format!("bin_{}", bin)));
+        let layout = SpeedHistogramLayout {
+            bins,
+            edges: settings.speed_histogram_max.map(|max| {
+                (0..=bins)
+                    .map(|edge| edge as f64 * max / bins as f64)
+                    .collect()
+            }),
+            columns,
+        };
+        let layout_path = settings.out_path.join("speed_histogram.json");
+        let json = serde_json::to_string_pretty(&layout).unwrap_or_default();
+        std::fs::write(&layout_path, json).map_err(Error::io("write", &layout_path))?;
+        Ok(layout.columns.join(","))
+    }
+
+    fn new(csv: CsvLog, settings: &Settings) -> SpeedHistogramLog {
+        SpeedHistogramLog {
+            csv,
+            bins: settings.speed_histogram_bins,
+            max: settings.speed_histogram_max,
+            every: settings.diagnostics_every.max(1),
+        }
+    }
+
+    /// Append the speeds of `velocities`, the particles at `frame`, and flush
+    pub fn append(&mut self, frame: usize, time: f64, velocities: &[Vec3]) -> Result<(), Error> {
+        let upper = self.max.unwrap_or_else(|| {
+            velocities
+                .par_iter()
+                .map(|vel| vel.as_dvec3().length())
+                .reduce(|| 0.0, f64::max)
+        });
+        let counts = speed_histogram(velocities, self.bins, upper);
+        let (over, counts) = counts.split_last().unwrap_or((&0, &[]));
+        let counts: Vec<String> = counts.iter().map(u64::to_string).collect();
+        self.csv.write_line(&format!(
+            "{},{},{},{},{}",
+            frame,
+            time,
+            upper,
+            over,
+            counts.join(",")
+        ))?;
+        self.csv.flush()
+    }
+}
+
+/// Counts of `velocities` by speed in `bins` even bins from 0 to `upper`, followed by the count
+/// of the ones faster than that
+pub fn speed_histogram(velocities: &[Vec3], bins: usize, upper: f64) -> Vec<u64> {
+    let bin_of = |vel: &Vec3| {
+        let speed = vel.as_dvec3().length();
+        // NaN too, a particle that's blown up isn't in range
+        if speed > upper || speed.is_nan() {
+            bins
+        } else if upper > 0.0 {
+            ((speed / upper * bins as f64) as usize).min(bins.saturating_sub(1))
+        } else {
+            0
+        }
+    };
+    // integer counts, so the sum doesn't depend on how rayon splits it
+    velocities
+        .par_iter()
+        .fold(
+            || vec![0u64; bins + 1],
+            |mut counts, vel| {
+                counts[bin_of(vel)] += 1;
+                counts
+            },
+        )
+        .reduce(
+            || vec![0u64; bins + 1],
+            |mut counts, other| {
+                for (count, other) in counts.iter_mut().zip(other) {
+                    *count += other;
+                }
+                counts
+            },
+        )
+}
+
+/// `binding_energy.csv` in the output directory: the particles' specific energies, 1/2 v^2 + the
+/// potential, binned at the end of a batch, and the fraction of the mass with less than 0
+pub struct BindingEnergyLog {
+    csv: CsvLog,
+    bins: usize,
+    /// Edges of the bins, None to scale to each row's least and most bound particles
+    min: Option<f64>,
+    max: Option<f64>,
+    /// Only batches with a step whose number is a multiple of this get a row
+    pub(crate) every: usize,
+}
+
+impl BindingEnergyLog {
+    const FILE: &str = "binding_energy.csv";
+
+    pub fn create(settings: &Settings) -> Result<BindingEnergyLog, Error> {
+        let header = BindingEnergyLog::header(settings);
+        let csv = CsvLog::create(&settings.out_path, BindingEnergyLog::FILE, &header)?;
+        Ok(BindingEnergyLog::new(csv, settings))
+    }
+
+    /// Continue the log of a resumed run from `first_frame`, dropping the rows it runs again,
+    /// or start one if it didn't keep one
+    pub fn append_to(settings: &Settings, first_frame: usize) -> Result<BindingEnergyLog, Error> {
+        let header = BindingEnergyLog::header(settings);
+        let csv = CsvLog::append_to(
+            &settings.out_path,
+            BindingEnergyLog::FILE,
+            &header,
+            first_frame,
+        )?;
+        Ok(BindingEnergyLog::new(csv, settings))
+    }
+
+    /// Frame and bound fraction of the last row in `out_path`'s log, None without one
+    pub fn last_bound_fraction(out_path: &Path) -> Option<(usize, f64)> {
+        let log = std::fs::read_to_string(out_path.join(BindingEnergyLog::FILE)).ok()?;
+        let mut columns = log.lines().skip(1).last()?.split(',');
+        let frame = columns.next()?.parse().ok()?;
+        // past the time
+        let fraction = columns.nth(1)?.parse().ok()?;
+        Some((frame, fraction))
+    }
+
+    fn header(settings: &Settings) -> String {
+        let mut header = "frame,time,bound_fraction,lower,upper,under,over".to_string();
+        for bin in 0..settings.binding_energy_bins {
+            header += &format!(",bin_{}", bin);
+        }
+        header
+    }
+
+    fn new(csv: CsvLog, settings: &Settings) -> BindingEnergyLog {
+        BindingEnergyLog {
+            csv,
+            bins: settings.binding_energy_bins,
+            min: settings.binding_energy_min,
+            max: settings.binding_energy_max,
+            every: settings.diagnostics_every.max(1),
+        }
+    }
+
+    /// Append the energies of `particles` at `frame`, with `potential` at each of them per unit
+    /// mass, and flush
+    pub fn append(
+        &mut self,
+        frame: usize,
+        time: f64,
+        particles: &ParticleSet,
+        potential: &[f32],
+    ) -> Result<(), Error> {
+        let energies = specific_energies(particles, potential);
+        let lower = self.min.unwrap_or_else(|| {
+            energies
+                .par_iter()
+                .copied()
+                .reduce(|| f64::INFINITY, f64::min)
+        });
+        let upper = self.max.unwrap_or_else(|| {
+            energies
+                .par_iter()
+                .copied()
+                .reduce(|| f64::NEG_INFINITY, f64::max)
+        });
+        let histogram =
+            binding_energy_histogram(&energies, &particles.mass, self.bins, lower, upper);
+        let counts: String = histogram
+            .counts
+            .iter()
+            .map(|count| format!(",{}", count))
+            .collect();
+        self.csv.write_line(&format!(
+            "{},{},{},{},{},{},{}{}",
+            frame,
+            time,
+            histogram.bound_fraction,
+            lower,
+            upper,
+            histogram.under,
+            histogram.over,
+            counts
+        ))?;
+        self.csv.flush()
+    }
+}
+
+/// Each particle's energy per unit mass, 1/2 v^2 plus its `potential`
+pub fn specific_energies(particles: &ParticleSet, potential: &[f32]) -> Vec<f64> {
+    particles
+        .vel
+        .par_iter()
+        .zip(potential)
+        .map(|(vel, &potential)| 0.5 * vel.as_dvec3().length_squared() + potential as f64)
+        .collect()
+}
+
+/// Specific energies binned, see `binding_energy_histogram`
+#[derive(Clone, PartialEq, Debug)]
+pub struct BindingEnergyHistogram {
+    /// Fraction of the mass with a specific energy under 0
+    pub bound_fraction: f64,
+    /// Particles below the first bin and above the last, or NaN
+    pub under: u64,
+    pub over: u64,
+    pub counts: Vec<u64>,
+}
+
+/// Counts of the particles by specific energy in `bins` even bins from `lower` to `upper`, and
+/// the fraction of the mass that's bound. With `lower` and `upper` the same, or either of them
+/// not finite, the ones in range all go in the first bin.
+pub fn binding_energy_histogram(
+    energies: &[f64],
+    mass: &[f32],
+    bins: usize,
+    lower: f64,
+    upper: f64,
+) -> BindingEnergyHistogram {
+    let span = upper - lower;
+    // index `bins` for under, `bins + 1` for over
+    let bin_of = |energy: f64| {
+        if energy < lower {
+            bins
+        } else if energy > upper || energy.is_nan() {
+            bins + 1
+        } else if span > 0.0 && span.is_finite() {
+            (((energy - lower) / span * bins as f64) as usize).min(bins.saturating_sub(1))
+        } else {
+            0
+        }
+    };
+    let (mut counts, bound, total) = chunked_sum(
+        energies.len().min(mass.len()),
+        (vec![0u64; bins + 2], 0.0, 0.0),
+        |range| {
+            let mut counts = vec![0u64; bins + 2];
+            let (mut bound, mut total) = (0.0, 0.0);
+            for (&energy, &mass) in energies[range.clone()].iter().zip(&mass[range]) {
+                counts[bin_of(energy)] += 1;
+                total += mass as f64;
+                if energy < 0.0 {
+                    bound += mass as f64;
+                }
+            }
+            (counts, bound, total)
+        },
+        |(mut counts, bound, total), (chunk, chunk_bound, chunk_total)| {
+            for (count, chunk) in counts.iter_mut().zip(chunk) {
+                *count += chunk;
+            }
+            (counts, bound + chunk_bound, total + chunk_total)
+        },
+    );
+    let over = counts.pop().unwrap_or_default();
+    let under = counts.pop().unwrap_or_default();
+    BindingEnergyHistogram {
+        bound_fraction: if total > 0.0 { bound / total } else { f64::NAN },
+        under,
+        over,
+        counts,
+    }
+}
+
+/// `density_profile.csv` in the output directory, the mass density in shells about the center
+/// of mass at the end of a batch, a row per shell
+pub struct DensityProfileLog {
+    csv: CsvLog,
+    bins: usize,
+    min_radius: Option<f64>,
+    max_radius: Option<f64>,
+    /// Only batches with a step whose number is a multiple of this get rows
+    pub(crate) every: usize,
+}
+
+/// One shell of a density profile, from `r_inner` to `r_outer` about the center of mass
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Shell {
+    pub r_inner: f64,
+    pub r_outer: f64,
+    pub count: u64,
+    pub mass: f64,
+}
+
+impl Shell {
+    /// Mass over the shell's volume
+    pub fn density(&self) -> f64 {
+        let volume =
+            4.0 / 3.0 * std::f64::consts::PI * (self.r_outer.powi(3) - self.r_inner.powi(3));
+        self.mass / volume
+    }
+}
+
+impl DensityProfileLog {
+    const FILE: &str = "density_profile.csv";
+    const HEADER: &str = "frame,time,bin,r_inner,r_outer,count,mass,density";
+
+    pub fn create(settings: &Settings) -> Result<DensityProfileLog, Error> {
+        let csv = CsvLog::create(&settings.out_path, Self::FILE, Self::HEADER)?;
+        Ok(DensityProfileLog::new(csv, settings))
+    }
+
+    /// Continue the log of a resumed run from `first_frame`, dropping the rows it runs again,
+    /// or start one if it didn't keep one
+    pub fn append_to(settings: &Settings, first_frame: usize) -> Result<DensityProfileLog, Error> {
+        let csv = CsvLog::append_to(&settings.out_path, Self::FILE, Self::HEADER, first_frame)?;
+        Ok(DensityProfileLog::new(csv, settings))
+    }
+
+    fn new(csv: CsvLog, settings: &Settings) -> DensityProfileLog {
+        DensityProfileLog {
+            csv,
+            bins: settings.density_profile_bins,
+            min_radius: settings.density_profile_min_radius,
+            max_radius: settings.density_profile_max_radius,
+            every: settings.diagnostics_every.max(1),
+        }
+    }
+
+    /// Append the profile of `particles`, as they are at `frame`, and flush
+    pub fn append(
+        &mut self,
+        frame: usize,
+        time: f64,
+        particles: &ParticleSet,
+    ) -> Result<(), Error> {
+        let shells = density_profile(particles, self.bins, self.min_radius, self.max_radius);
+        for (bin, shell) in shells.iter().enumerate() {
+            self.csv.write_line(&format!(
+                "{},{},{},{},{},{},{},{}",
+                frame,
+                time,
+                bin,
+                shell.r_inner,
+                shell.r_outer,
+                shell.count,
+                shell.mass,
+                shell.density()
+            ))?;
+        }
+        self.csv.flush()
+    }
+}
+
+/// The mass in `bins` logarithmically spaced shells about the center of mass of `particles`,
+/// from `min_radius` to `max_radius` or the closest and furthest particles when they're unset.
+/// Particles outside aren't counted. Empty when the particles don't span a range.
+pub fn density_profile(
+    particles: &ParticleSet,
+    bins: usize,
+    min_radius: Option<f64>,
+    max_radius: Option<f64>,
+) -> Vec<Shell> {
+    let Some(center) = mass_weighted_mean(&particles.mass, &particles.pos) else {
+        return Vec::new();
+    };
+    let radii: Vec<f64> = particles
+        .pos
+        .par_iter()
+        .map(|pos| (pos.as_dvec3() - center).length())
+        .collect();
+    // the closest particle that isn't right on the center, which has no place on a log scale
+    let min = min_radius.unwrap_or_else(|| {
+        radii
+            .par_iter()
+            .copied()
+            .filter(|&r| r > 0.0)
+            .reduce(|| f64::INFINITY, f64::min)
+    });
+    let max = max_radius.unwrap_or_else(|| radii.par_iter().copied().reduce(|| 0.0, f64::max));
+    if bins == 0 || !min.is_finite() || !max.is_finite() || min <= 0.0 || min >= max {
+        return Vec::new();
+    }
+    let log_span = (max / min).ln();
+    let bin_of = |r: f64| {
+        if !(min..=max).contains(&r) {
+            return None;
+        }
+        Some((((r / min).ln() / log_span * bins as f64) as usize).min(bins - 1))
+    };
+    let zero = || vec![(0u64, 0.0f64); bins];
+    let sums = chunked_sum(
+        radii.len().min(particles.mass.len()),
+        zero(),
+        |range| {
+            let mut sums = zero();
+            for (&r, &mass) in radii[range.clone()].iter().zip(&particles.mass[range]) {
+                if let Some(bin) = bin_of(r) {
+                    sums[bin].0 += 1;
+                    sums[bin].1 += mass as f64;
+                }
+            }
+            sums
+        },
+        |mut sums, chunk| {
+            for (sum, (count, mass)) in sums.iter_mut().zip(chunk) {
+                sum.0 += count;
+                sum.1 += mass;
+            }
+            sums
+        },
+    );
+    let edge = |index: usize| min * (log_span * index as f64 / bins as f64).exp();
+    sums.into_iter()
+        .enumerate()
+        .map(|(bin, (count, mass))| Shell {
+            r_inner: edge(bin),
+            r_outer: edge(bin + 1),
+            count,
+            mass,
+        })
+        .collect()
+}
+
+/// Pairs drawn from each generator of a correlation estimate, the chunks drawn in parallel
+const PAIR_CHUNK: usize = 1 << 16;
+
+/// `correlation.csv` in the output directory, the two-point correlation function estimated
+/// from randomly drawn particle pairs, a row per separation bin
+pub struct CorrelationLog {
+    csv: CsvLog,
+    pairs: usize,
+    bins: usize,
+    radius: f64,
+    min_separation: f64,
+    seed: u64,
+    /// Only frames whose number is a multiple of this get rows
+    pub(crate) every: usize,
+}
+
+/// One separation bin of the correlation function, from `r_inner` to `r_outer`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SeparationBin {
+    pub r_inner: f64,
+    pub r_outer: f64,
+    /// Drawn pairs this far apart
+    pub pairs: u64,
+    /// How many of the drawn pairs a uniform sphere would have this far apart
+    pub expected: f64,
+}
+
+impl SeparationBin {
+    /// ξ(r): 0 as many pairs as a uniform sphere, above 0 clustered, -1 none at all. NaN when a
+    /// uniform sphere wouldn't have any.
+    pub fn xi(&self) -> f64 {
+        if self.expected > 0.0 {
+            self.pairs as f64 / self.expected - 1.0
+        } else {
+            f64::NAN
+        }
+    }
+}
+
+impl CorrelationLog {
+    const FILE: &str = "correlation.csv";
+    const HEADER: &str = "frame,time,bin,r_inner,r_outer,pairs,expected,xi";
+
+    pub fn create(settings: &Settings) -> Result<CorrelationLog, Error> {
+        let csv = CsvLog::create(&settings.out_path, Self::FILE, Self::HEADER)?;
+        Ok(CorrelationLog::new(csv, settings))
+    }
+
+    /// Continue the log of a resumed run from `first_frame`, dropping the rows it runs again,
+    /// or start one if it didn't keep one
+    pub fn append_to(settings: &Settings, first_frame: usize) -> Result<CorrelationLog, Error> {
+        let csv = CsvLog::append_to(&settings.out_path, Self::FILE, Self::HEADER, first_frame)?;
+        Ok(CorrelationLog::new(csv, settings))
+    }
+
+    fn new(csv: CsvLog, settings: &Settings) -> CorrelationLog {
+        let radius = settings.correlation_radius.unwrap_or(settings.arena as f64);
+        CorrelationLog {
+            csv,
+            pairs: settings.correlation_pairs,
+            bins: settings.correlation_bins,
+            radius,
+            min_separation: settings
+                .correlation_min_separation
+                .unwrap_or(2.0 * radius / 1000.0),
+            seed: settings.seed.unwrap_or(0),
+            every: settings.correlation_every.max(1),
+        }
+    }
+
+    /// Append the estimate for `positions`, as they are at `frame`, and flush
+    pub fn append(&mut self, frame: usize, time: f64, positions: &[Vec3]) -> Result<(), Error> {
+        let separations = pair_correlation(
+            positions,
+            self.pairs,
+            self.bins,
+            self.radius,
+            self.min_separation,
+            // a different draw each frame, the same one each rerun
+            self.seed.wrapping_add(frame as u64),
+        );
+        for (bin, separation) in separations.iter().enumerate() {
+            self.csv.write_line(&format!(
+                "{},{},{},{},{},{},{},{}",
+                frame,
+                time,
+                bin,
+                separation.r_inner,
+                separation.r_outer,
+                separation.pairs,
+                separation.expected,
+                separation.xi()
+            ))?;
+        }
+        self.csv.flush()
+    }
+}
+
+/// The two-point correlation function of `positions` from `pairs` pairs of distinct particles
+/// drawn at random with `seed`, their separations counted in `bins` logarithmically spaced bins
+/// from `min_separation` to the diameter of a sphere of `radius`. Each count is compared with
+/// the count expected of particles spread uniformly through that sphere. Empty without two
+/// particles to pair up.
+pub fn pair_correlation(
+    positions: &[Vec3],
+    pairs: usize,
+    bins: usize,
+    radius: f64,
+    min_separation: f64,
+    seed: u64,
+) -> Vec<SeparationBin> {
+    let count = positions.len();
+    let max_separation = 2.0 * radius;
+    if count < 2 || pairs == 0 || bins == 0 || min_separation >= max_separation {
+        return Vec::new();
+    }
+    let log_span = (max_separation / min_separation).ln();
+    let bin_of = |r: f64| {
+        if !(min_separation..=max_separation).contains(&r) {
+            return None;
+        }
+        Some((((r / min_separation).ln() / log_span * bins as f64) as usize).min(bins - 1))
+    };
+    // a seed per chunk, so the pairs don't depend on the thread count
+    let mut rng = StdRng::seed_from_u64(seed);
+    let chunk_seeds: Vec<u64> = (0..pairs.div_ceil(PAIR_CHUNK))
+        .map(|_| rng.random())
+        .collect();
+    let counts = chunk_seeds
+        .par_iter()
+        .enumerate()
+        .map(|(chunk, &seed)| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut counts = vec![0u64; bins];
+            for _ in 0..PAIR_CHUNK.min(pairs - chunk * PAIR_CHUNK) {
+                let i = rng.random_range(0..count);
+                // any of the others, evenly
+                let j = (i + rng.random_range(1..count)) % count;
+                let r = (positions[i].as_dvec3() - positions[j].as_dvec3()).length();
+                if let Some(bin) = bin_of(r) {
+                    counts[bin] += 1;
+                }
+            }
+            counts
+        })
+        .reduce(
+            || vec![0u64; bins],
+            |mut total, counts| {
+                for (total, count) in total.iter_mut().zip(counts) {
+                    *total += count;
+                }
+                total
+            },
+        );
+    let edge = |index: usize| min_separation * (log_span * index as f64 / bins as f64).exp();
+    counts
+        .into_iter()
+        .enumerate()
+        .map(|(bin, drawn)| {
+            let (r_inner, r_outer) = (edge(bin), edge(bin + 1));
+            SeparationBin {
+                r_inner,
+                r_outer,
+                pairs: drawn,
+                expected: pairs as f64
+                    * (uniform_sphere_separations(r_outer, radius)
+                        - uniform_sphere_separations(r_inner, radius)),
+            }
+        })
+        .collect()
+}
+
+/// The fraction of pairs of points spread uniformly through a sphere of `radius` that are
+/// closer than `r`
+fn uniform_sphere_separations(r: f64, radius: f64) -> f64 {
+    let x = (r / radius).clamp(0.0, 2.0);
+    x.powi(3) - 9.0 / 16.0 * x.powi(4) + x.powi(6) / 32.0
+}
+
+/// Distances from the center of mass enclosing each of `fractions` of the total mass: the
+/// distance of the first particle out at which the mass inside reaches the fraction
+pub fn lagrangian_radii(mass: &[f32], pos: &[Vec3], fractions: &[f64]) -> Vec<f64> {
+    let Some(center) = mass_weighted_mean(mass, pos) else {
+        return vec![f64::NAN; fractions.len()];
+    };
+    let mut radii: Vec<f64> = pos
+        .par_iter()
+        .map(|pos| (pos.as_dvec3() - center).length())
+        .collect();
+    let count = radii.len();
+    if mass.iter().all(|&m| m == mass[0]) {
+        // equal masses, the usual case, enclose a fraction of the count: the k-th closest
+        // particle, found in linear time without sorting
+        return fractions
+            .iter()
+            .map(|&fraction| {
+                let k = ((fraction * count as f64).ceil() as usize).clamp(1, count) - 1;
+                *radii.select_nth_unstable_by(k, f64::total_cmp).1
+            })
+            .collect();
+    }
+    let mut by_radius: Vec<(f64, f32)> = radii.into_iter().zip(mass.iter().copied()).collect();
+    by_radius.par_sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+    let enclosed: Vec<f64> = by_radius
+        .iter()
+        .scan(0.0, |enclosed, &(_, mass)| {
+            *enclosed += mass as f64;
+            Some(*enclosed)
+        })
+        .collect();
+    let total = enclosed.last().copied().unwrap_or_default();
+    fractions
+        .iter()
+        .map(|&fraction| {
+            let index = enclosed.partition_point(|&enclosed| enclosed < fraction * total);
+            by_radius[index.min(count - 1)].0
+        })
+        .collect()
+}
+
+/// The mass weighted mean of `values`, eg. the center of mass of positions. None without any
+/// mass.
+pub fn mass_weighted_mean(mass: &[f32], values: &[Vec3]) -> Option<DVec3> {
+    // in order, so deterministic runs log the same
+    let (total, weighted) =
+        mass.iter()
+            .zip(values)
+            .fold((0.0, DVec3::ZERO), |(total, weighted), (&mass, value)| {
+                (
+                    total + mass as f64,
+                    weighted + mass as f64 * value.as_dvec3(),
+                )
+            });
+    (total > 0.0).then(|| weighted / total)
+}
+
+/// A CSV log in the output directory, a header and then rows keyed by the frame, step or batch
+/// in their first column
+pub(crate) struct CsvLog {
+    writer: BufWriter<File>,
+    path: PathBuf,
+}
+
+impl CsvLog {
+    /// `name` in `out_path`, replacing any log there, starting with `header`
+    pub(crate) fn create(out_path: &Path, name: &str, header: &str) -> Result<CsvLog, Error> {
+        let path = out_path.join(name);
+        let file = File::create(&path).map_err(Error::io("create", &path))?;
+        let mut log = CsvLog {
+            writer: BufWriter::new(file),
+            path,
+        };
+        log.write_line(header)?;
+        Ok(log)
+    }
+
+    /// Continue `name` in `out_path` for a resumed run from `first`, dropping the rows it runs
+    /// again, or start one with `header` if it didn't keep one
+    pub(crate) fn append_to(
+        out_path: &Path,
+        name: &str,
+        header: &str,
+        first: usize,
+    ) -> Result<CsvLog, Error> {
+        let path = out_path.join(name);
+        if !path.exists() {
+            return CsvLog::create(out_path, name, header);
+        }
+        let file = append_from(&path, first)?;
+        Ok(CsvLog {
+            writer: BufWriter::new(file),
+            path,
+        })
+    }
+
+    pub(crate) fn write_line(&mut self, line: &str) -> Result<(), Error> {
+        writeln!(self.writer, "{}", line).map_err(Error::io("write", &self.path))
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush().map_err(Error::io("write", &self.path))
+    }
+}
+
+/// Open the CSV at `path` to append to, first dropping the rows whose first column is `first` or
+/// more, the ones a resumed run is about to write again
+fn append_from(path: &Path, first: usize) -> Result<File, Error> {
+    let content = std::fs::read_to_string(path).map_err(Error::io("read", path))?;
+    let kept: String = content
+        .lines()
+        .filter(|line| {
+            let key = line
+                .split(',')
+                .next()
+                .and_then(|key| key.parse::<usize>().ok());
+            key.is_none_or(|key| key < first)
+        })
+        .map(|line| format!("{}\n", line))
+        .collect();
+    if kept.len() < content.len() {
+        std::fs::write(path, kept).map_err(Error::io("write", path))?;
+    }
+    OpenOptions::new()
+        .append(true)
+        .open(path)
+        .map_err(Error::io("open", path))
+}
@@ -0,0 +1,197 @@
+use std::path::{Path, PathBuf};
+
+use super::memory;
+use super::nonfinite::NonFiniteDump;
+
+/// Everything a run can fail with short of a bug. The binary prints it with its `source` chain
+/// and exits with status 1, or `Stopped`'s own status.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Settings that can't be read or don't validate, one line per problem
+    #[error("{}", .0.join("\n"))]
+    Settings(Vec<String>),
+    /// No usable device for force_backend gpu, or one that can't run the kernels
+    #[error("Could not initialize the GPU: {0}")]
+    Gpu(String),
+    /// The force pass failing in a way a new device wouldn't fix
+    #[error("GPU force pass failed: {0}")]
+    ForcePass(String),
+    #[error("GPU lost {attempts} times during batch {batch}, giving up")]
+    DeviceLost { attempts: usize, batch: usize },
+    #[error("Could not build the initial conditions: {0}")]
+    InitialConditions(String),
+    #[error("Could not resume {}: {reason}", .dir.display())]
+    Resume { dir: PathBuf, reason: String },
+    #[error("Could not start the CPU threads")]
+    Threads(#[source] rayon::ThreadPoolBuildError),
+    /// A file or directory that couldn't be created, read or written
+    #[error("Could not {action} {}", .path.display())]
+    Io {
+        action: &'static str,
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// A new run pointed at the output directory of another one
+    #[error(
+        "{} already holds a run, continue it with `gravity-output resume {}` or start over with --force",
+        .0.display(),
+        .0.display()
+    )]
+    OutputInUse(PathBuf),
+    /// Less free space in the output directory than the run is expected to write
+    #[error(
+        "Not enough space in {}: the output takes up to {}, only {} is free",
+        .dir.display(),
+        memory::format_bytes(*.needed),
+        memory::format_bytes(*.available)
+    )]
+    NoSpace {
+        dir: PathBuf,
+        needed: u64,
+        available: u64,
+    },
+    /// The writer thread failing, which stops the simulation at the next batch
+    #[error("Could not write batch {batch}, stopping the simulation")]
+    Writer {
+        batch: usize,
+        #[source]
+        source: Box<Error>,
+    },
+    /// Positions or velocities that went to NaN or infinity, the run stops rather than write
+    /// them. The dump says which particles and what was near them.
+    #[error(
+        "{} particles went non-finite at frame {}, likely a close encounter too tight for dt",
+        .0.count,
+        .0.frame
+    )]
+    NonFinite(Box<NonFiniteDump>),
+    /// A run stopped early for max_wall_time_minutes, max_output_gb or Ctrl-C, checkpointed to
+    /// be continued with `resume`. The binary exits with `status` rather than 1.
+    #[error("Stopped at frame {frame}, {reason}")]
+    Stopped {
+        frame: usize,
+        reason: &'static str,
+        status: i32,
+    },
+    /// `init`, `inspect`, `convert`, `--bench-kernel`, `--preview`, the status server or the live
+    /// stream failing
+    #[error("{0}")]
+    Command(String),
+}
+
+impl Error {
+    /// For `map_err` on an io result, `action` being what was done to `path`, eg. "write"
+    pub fn io(action: &'static str, path: &Path) -> impl FnOnce(std::io::Error) -> Error {
+        let path = path.to_path_buf();
+        move |source| Error::Io {
+            action,
+            path,
+            source,
+        }
+    }
+
+    /// A single settings problem
+    pub fn settings(problem: impl Into<String>) -> Error {
+        Error::Settings(vec![problem.into()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Cli;
+    use crate::output::{OutputLimit, write_frame_group};
+    use crate::schedule::Phase;
+    use crate::settings::{Settings, load_settings};
+    use clap::Parser;
+    use glam::Vec3;
+
+    #[test]
+    #[cfg(feature = "gpu")]
+    fn missing_gpu_is_a_gpu_error() {
+        use crate::backend::{self, ForceBackendKind};
+
+        let mut settings = Settings {
+            force_backend: ForceBackendKind::Gpu,
+            ..Settings::default()
+        };
+        settings.adapter.name = Some("no such adapter".to_string());
+        assert!(matches!(
+            backend::create_backend(&settings),
+            Err(Error::Gpu(_))
+        ));
+    }
+
+    #[test]
+    fn unwritable_out_path_is_an_io_error() {
+        // a regular file where the output directory should be
+        let blocker = std::env::temp_dir().join("gravity-output-error-blocker");
+        std::fs::write(&blocker, []).unwrap();
+        let settings = Settings {
+            out_path: blocker.join("run"),
+            ..Settings::default()
+        };
+        let result = settings.save_resolved();
+        std::fs::remove_file(&blocker).unwrap();
+        assert!(matches!(
+            result,
+            Err(Error::Io {
+                action: "write",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn bad_settings_file_is_a_settings_error() {
+        let path = std::env::temp_dir().join("gravity-output-error-settings.json");
+        std::fs::write(&path, r#"{"dt": "fast"}"#).unwrap();
+        let cli =
+            Cli::try_parse_from(["gravity-output", "--settings", path.to_str().unwrap()]).unwrap();
+        let result = load_settings(cli.run_args());
+        std::fs::remove_file(&path).unwrap();
+        match result {
+            Err(Error::Settings(problems)) => {
+                assert_eq!(problems.len(), 1);
+                assert!(problems[0].starts_with("Could not load"), "{}", problems[0]);
+            }
+            _ => panic!("expected a settings error"),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn full_disk_is_an_io_error() {
+        let dir = std::env::temp_dir().join("gravity-output-error-full");
+        std::fs::create_dir_all(&dir).unwrap();
+        let batch = dir.join(gravity_output_format::batch_file_name(0, None));
+        let _ = std::fs::remove_file(&batch);
+        std::os::unix::fs::symlink("/dev/full", &batch).unwrap();
+
+        let settings = Settings {
+            num_particles: 1000,
+            frames_per_file: 4,
+            out_path: dir.clone(),
+            ..Settings::default()
+        };
+        let mut frame_list = vec![vec![Vec3::ONE; settings.num_particles]; 4];
+        let output = OutputLimit::new(&settings, 0);
+        let result = write_frame_group(
+            &settings,
+            &settings.hash(),
+            &mut frame_list,
+            None,
+            &0,
+            &output,
+            Phase::at(&settings, 0),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        match result {
+            Err(Error::Io { source, .. }) => {
+                assert_eq!(source.kind(), std::io::ErrorKind::StorageFull)
+            }
+            _ => panic!("expected an io error"),
+        }
+    }
+}
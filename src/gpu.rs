@@ -0,0 +1,2388 @@
+use bytemuck::{Pod, Zeroable};
+use glam::Vec3;
+use rayon::iter::{IndexedParallelIterator, ParallelIterator};
+use rayon::slice::ParallelSliceMut;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, mpsc};
+use std::thread::JoinHandle;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+use std::time::SystemTime;
+use tracing::{error, info, trace_span, warn};
+#[cfg(target_arch = "wasm32")]
+use web_time::Instant;
+use wgpu::util::DeviceExt;
+
+use super::ParticleSet;
+use super::adapter::{self, AdapterSettings};
+use super::autotune;
+use super::backend::Forces;
+use super::diagnostics::{Energy, GpuEnergy};
+use super::error::Error;
+use super::layout;
+use super::memory::{self, MemoryEstimate};
+use super::pipeline_cache::DiskPipelineCache;
+#[cfg(feature = "preview")]
+use super::preview::PreviewTarget;
+use super::settings::{
+    DEFAULT_WORKGROUP_SIZE, ForceAccumulation, ForceKernel, Integrator, Settings,
+};
+use super::streaming::StreamingPass;
+use super::tree::{ForceMethod, TreePass};
+
+/// `Particle` as laid out in the device buffers, mirrored by `Particle` in nbody.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Pod, Zeroable)]
+pub struct GpuParticle {
+    pub(crate) pos: [f32; 3],
+    pub(crate) mass: f32,
+    pub(crate) vel: [f32; 3],
+    pub(crate) _padding: f32,
+    pub(crate) acc: [f32; 3],
+    pub(crate) _padding2: f32,
+}
+
+/// Run constants for the shader, mirrored by `SimParams` in nbody.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Pod, Zeroable)]
+pub(crate) struct SimParams {
+    g_const: f32,
+    softening_sq: f32,
+    num_particles: u32,
+    /// First particle of the chunk the force pass computes forces for
+    target_offset: u32,
+    target_count: u32,
+    chunk_offset: u32,
+    source_offset: u32,
+    source_count: u32,
+    accumulate: u32,
+    /// First of this chunk's slots in the energy partials, and the slot count over every chunk
+    partial_offset: u32,
+    num_partials: u32,
+    /// Always 1, see `accumulate_pair` in nbody.wgsl
+    one: f32,
+}
+
+/// Values that may change every step, mirrored by `FrameConstants` in the shader prelude
+#[repr(C)]
+#[derive(Copy, Clone, Pod, Zeroable)]
+pub(crate) struct FrameConstants {
+    dt: f32,
+    /// Steps integrated on this device before this one
+    frame: u32,
+}
+
+const FRAME_CONSTANTS_STRUCT: &str = "struct FrameConstants { dt: f32, frame: u32 }\n";
+const FRAME_CONSTANTS_PUSH: &str = "var<push_constant> frame_constants: FrameConstants;\n";
+const FRAME_CONSTANTS_UNIFORM: &str =
+    "@group(1) @binding(0) var<uniform> frame_constants: FrameConstants;\n";
+
+/// Fallback for devices without push constants: one uniform slot per step of a submission,
+/// picked with a dynamic offset. Slots are rewritten before each submission, which never
+/// affects work that was already submitted.
+struct FrameUniform {
+    buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    /// Bytes between slots, the device's uniform offset alignment
+    stride: u64,
+}
+
+impl SimParams {
+    /// Params for the forces `source` exerts on the `targets` slice of `chunk`, all global ranges.
+    pub(crate) fn for_chunk(
+        settings: &Settings,
+        chunk: &Range<usize>,
+        targets: &Range<usize>,
+        source: &Range<usize>,
+        accumulate: bool,
+    ) -> SimParams {
+        SimParams {
+            g_const: settings.g_const,
+            softening_sq: settings.softening * settings.softening,
+            num_particles: chunk.len() as u32,
+            target_offset: (targets.start - chunk.start) as u32,
+            target_count: targets.len() as u32,
+            chunk_offset: chunk.start as u32,
+            source_offset: source.start as u32,
+            source_count: source.len() as u32,
+            accumulate: accumulate as u32,
+            partial_offset: 0,
+            num_partials: 0,
+            one: 1.0,
+        }
+    }
+}
+
+/// One slice of the particle set, small enough for the device's buffer limits
+struct GpuChunk {
+    /// Particles stored in this chunk
+    range: Range<usize>,
+    /// Particles in `range` this device computes forces for, possibly none
+    targets: Range<usize>,
+    particle_buffer: wgpu::Buffer,
+    /// Forces on `targets`, summed over every source chunk
+    force_buffer: wgpu::Buffer,
+    /// Packed positions then velocities written by the kick pass, kept separate so recording a
+    /// frame is a small copy
+    readback_buffer: wgpu::Buffer,
+    /// One per source chunk in chunk order, the first overwrites the forces and the rest add on
+    bind_groups: Vec<wgpu::BindGroup>,
+}
+
+/// Staging buffers in the readback ring, enough for one submission to map while the next runs
+pub(crate) const STAGING_RING: usize = 2;
+
+/// Timestamps written per submission: force pass, kick pass, drift pass, and position copy,
+/// begin + end
+pub(crate) const TIMESTAMPS_PER_SUBMIT: u32 = 8;
+
+/// Timestamp queries around the first step of each submission, when the device supports them
+struct GpuTimer {
+    query_set: wgpu::QuerySet,
+    /// Resolved timestamps, copied into the tail of the staging buffer that maps them
+    resolve_buffer: wgpu::Buffer,
+    /// Nanoseconds per timestamp tick
+    period: f64,
+    /// Copies can only be timed with TIMESTAMP_QUERY_INSIDE_ENCODERS
+    time_copies: bool,
+}
+
+/// Average GPU time per timed step since the last `take_timings`, in milliseconds
+#[derive(Default, Clone, Copy)]
+pub(crate) struct GpuTimings {
+    pub(crate) force_ms: f64,
+    pub(crate) integrate_ms: f64,
+    pub(crate) copy_ms: Option<f64>,
+    /// Wall clock spent waiting on and copying out of mapped staging buffers
+    pub(crate) map_ms: f64,
+    /// The part of map_ms spent waiting for the device to finish and the buffer to map
+    pub(crate) wait_ms: f64,
+    pub(crate) samples: usize,
+}
+
+/// Bytes of one step's energy sums, as reduced on the device
+pub(crate) const ENERGY_SIZE: u64 = std::mem::size_of::<GpuEnergy>() as u64;
+
+/// Bytes of a packed position or velocity in the readback, without the shader's padding
+pub(crate) const PACKED_VEC3_SIZE: usize = 12;
+
+/// Steps submitted by `GpuCompute::submit_steps` that haven't been read back yet
+pub(crate) struct PendingSteps {
+    slot: usize,
+    steps: usize,
+    /// Whether each step's positions were copied
+    positions: Vec<bool>,
+    velocities: bool,
+}
+
+/// The pipelines built from nbody.wgsl
+pub(crate) struct Pipelines {
+    pub(crate) compute: wgpu::ComputePipeline,
+    drift: wgpu::ComputePipeline,
+    kick: wgpu::ComputePipeline,
+    reduce_energy: wgpu::ComputePipeline,
+    finish_energy: wgpu::ComputePipeline,
+}
+
+/// Everything besides the shader source that goes into `Pipelines`
+struct PipelineConfig {
+    layout: wgpu::PipelineLayout,
+    integrate_layout: wgpu::PipelineLayout,
+    accumulation: ForceAccumulation,
+    push_constants: bool,
+    workgroup_size: u32,
+    integrator: Integrator,
+    /// Use `main_subgroup` from nbody_subgroup.wgsl as the force kernel
+    subgroups: bool,
+    /// Force kernel otherwise
+    kernel: ForceKernel,
+    /// None where the backend has no pipeline cache
+    cache: Option<DiskPipelineCache>,
+}
+
+impl PipelineConfig {
+    /// Compile `source` (nbody.wgsl without the prelude) into both pipelines. Compile errors are
+    /// returned rather than reported to the uncaptured error handler, which would mark the
+    /// device lost.
+    async fn build(&self, device: &wgpu::Device, source: &str) -> Result<Pipelines, String> {
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("N-Body Compute"),
+            source: wgpu::ShaderSource::Wgsl(
+                with_acc_type(
+                    self.accumulation,
+                    &format!(
+                        "{}{}{}{}",
+                        FRAME_CONSTANTS_STRUCT,
+                        if self.push_constants {
+                            FRAME_CONSTANTS_PUSH
+                        } else {
+                            FRAME_CONSTANTS_UNIFORM
+                        },
+                        source,
+                        if self.subgroups {
+                            include_str!("nbody_subgroup.wgsl")
+                        } else {
+                            ""
+                        }
+                    ),
+                )
+                .into(),
+            ),
+        });
+
+        let compute = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("N-Body Pipeline"),
+            layout: Some(&self.layout),
+            module: &shader,
+            entry_point: Some(match (self.subgroups, self.kernel) {
+                (true, _) => "main_subgroup",
+                (false, ForceKernel::Tiled) => "main",
+                (false, ForceKernel::Naive) => "main_naive",
+            }),
+            cache: self.cache.as_ref().map(DiskPipelineCache::cache),
+            compilation_options: wgpu::PipelineCompilationOptions {
+                constants: &[
+                    ("WORKGROUP_SIZE", self.workgroup_size as f64),
+                    (
+                        "COMPENSATED",
+                        (self.accumulation == ForceAccumulation::Kahan) as u32 as f64,
+                    ),
+                ],
+                ..Default::default()
+            },
+        });
+
+        // the integrator is fixed for the run, so bake it in as an override constant
+        let integrate_pipeline = |label, entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(label),
+                layout: Some(&self.integrate_layout),
+                module: &shader,
+                entry_point: Some(entry_point),
+                cache: self.cache.as_ref().map(DiskPipelineCache::cache),
+                compilation_options: wgpu::PipelineCompilationOptions {
+                    constants: &[
+                        ("INTEGRATOR", self.integrator.shader_id() as f64),
+                        ("WORKGROUP_SIZE", self.workgroup_size as f64),
+                    ],
+                    ..Default::default()
+                },
+            })
+        };
+        let drift = integrate_pipeline("Drift Pipeline", "drift");
+        let kick = integrate_pipeline("Kick Pipeline", "kick");
+
+        let energy_pipeline = |label, entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(label),
+                layout: Some(&self.layout),
+                module: &shader,
+                entry_point: Some(entry_point),
+                cache: self.cache.as_ref().map(DiskPipelineCache::cache),
+                compilation_options: wgpu::PipelineCompilationOptions {
+                    constants: &[("WORKGROUP_SIZE", self.workgroup_size as f64)],
+                    ..Default::default()
+                },
+            })
+        };
+        let reduce_energy = energy_pipeline("Reduce Energy Pipeline", "reduce_energy");
+        let finish_energy = energy_pipeline("Finish Energy Pipeline", "finish_energy");
+
+        match device.pop_error_scope().await {
+            Some(error) => Err(error.to_string()),
+            None => Ok(Pipelines {
+                compute,
+                drift,
+                kick,
+                reduce_energy,
+                finish_energy,
+            }),
+        }
+    }
+}
+
+/// Dev mode: nbody.wgsl read from disk, rebuilt between batches when the file changes
+struct ShaderReload {
+    path: PathBuf,
+    /// Modification time of the last version read
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl ShaderReload {
+    /// The file's source if it changed since the last call, None if unchanged or unreadable.
+    fn changed_source(&self) -> Option<String> {
+        let mut last = self.modified.lock().unwrap();
+        let modified = match std::fs::metadata(&self.path).and_then(|meta| meta.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                // only worth mentioning once, not every batch
+                if last.take().is_some() {
+                    warn!(
+                        "Can't read {}: {}, keeping the current shader",
+                        self.path.display(),
+                        e
+                    );
+                }
+                return None;
+            }
+        };
+        if *last == Some(modified) {
+            return None;
+        }
+        *last = Some(modified);
+        match std::fs::read_to_string(&self.path) {
+            Ok(source) => Some(source),
+            Err(e) => {
+                warn!("Can't read {}: {}", self.path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+/// An open device and its queue. Several `GpuCompute`s can share one, each with its own
+/// buffers and pipelines, so simulations running side by side don't each open the device.
+/// Clones are handles to the same device.
+#[derive(Clone)]
+pub struct GpuDevice {
+    pub(crate) adapter_info: wgpu::AdapterInfo,
+    pub(crate) device: wgpu::Device,
+    pub(crate) queue: wgpu::Queue,
+    /// What the preview window's surface is created with
+    #[cfg(feature = "preview")]
+    pub(crate) instance: wgpu::Instance,
+    #[cfg(feature = "preview")]
+    pub(crate) adapter: wgpu::Adapter,
+    /// Set by the device lost and uncaptured error callbacks. Shared by every GpuCompute on the
+    /// device, one losing it loses it for all of them.
+    lost: Arc<AtomicBool>,
+}
+
+impl GpuDevice {
+    /// Device picked by `adapter_settings`, with the optional features `settings` can use
+    pub async fn open(
+        settings: &Settings,
+        adapter_settings: &AdapterSettings,
+    ) -> Result<GpuDevice, Error> {
+        Self::request(settings, adapter_settings)
+            .await
+            .map_err(Error::Gpu)
+    }
+
+    async fn request(
+        settings: &Settings,
+        adapter_settings: &AdapterSettings,
+    ) -> Result<GpuDevice, String> {
+        let instance = adapter::create_instance(settings.gpu_backend);
+        let adapter =
+            adapter::select_adapter(&instance, adapter_settings, settings.gpu_backend).await?;
+        let adapter_info = adapter.get_info();
+        info!("Using adapter: {}", adapter_info.name);
+        info!(
+            "  backend: {:?}{}",
+            adapter_info.backend,
+            if settings.gpu_backend.is_some() {
+                " (forced by gpu_backend)"
+            } else {
+                ""
+            }
+        );
+        info!("  device type: {:?}", adapter_info.device_type);
+        info!(
+            "  driver: {}",
+            format!("{} {}", adapter_info.driver, adapter_info.driver_info).trim()
+        );
+        info!("  limits: {}", describe_limits(&adapter.limits()));
+
+        let mut required_features = if settings.force_accumulation == ForceAccumulation::F64 {
+            adapter.features() & wgpu::Features::SHADER_F64
+        } else {
+            wgpu::Features::empty()
+        };
+        // profiling is best effort, only ask for what the adapter has
+        required_features |= adapter.features()
+            & (wgpu::Features::TIMESTAMP_QUERY | wgpu::Features::TIMESTAMP_QUERY_INSIDE_ENCODERS);
+        // so is the pipeline cache, which speeds up startup where the backend has one
+        required_features |= adapter.features() & wgpu::Features::PIPELINE_CACHE;
+        // per-step values go in push constants where the backend has room for them
+        if adapter.features().contains(wgpu::Features::PUSH_CONSTANTS)
+            && adapter.limits().max_push_constant_size as usize
+                >= std::mem::size_of::<FrameConstants>()
+        {
+            required_features |= wgpu::Features::PUSH_CONSTANTS;
+        }
+        // whether the workgroup size suits the subgroup kernel is up to each GpuCompute
+        if settings.subgroups {
+            required_features |= adapter.features() & wgpu::Features::SUBGROUP;
+        }
+
+        let (device, queue) = adapter
+            .request_device(&wgpu::DeviceDescriptor {
+                label: None,
+                required_features,
+                // everything the adapter offers, anything beyond that is chunked
+                required_limits: adapter.limits(),
+                memory_hints: wgpu::MemoryHints::Performance,
+                trace: wgpu::Trace::Off,
+            })
+            .await
+            .map_err(|e| {
+                format!(
+                    "could not open {} ({:?}) with features {:?} and limits {}: {}",
+                    adapter_info.name,
+                    adapter_info.backend,
+                    required_features,
+                    describe_limits(&adapter.limits()),
+                    e
+                )
+            })?;
+
+        // a lost or broken device flags itself instead of panicking, so the run can recreate it
+        let lost = Arc::new(AtomicBool::new(false));
+        let lost_flag = lost.clone();
+        device.set_device_lost_callback(move |reason, message| {
+            error!("GPU device lost ({:?}): {}", reason, message);
+            lost_flag.store(true, Ordering::Release);
+        });
+        let lost_flag = lost.clone();
+        device.on_uncaptured_error(Box::new(move |error| {
+            // everything after the first error is usually fallout from it
+            if !lost_flag.swap(true, Ordering::AcqRel) {
+                error!("GPU error: {}", error);
+            }
+        }));
+
+        // a field added to GpuParticle but not the shader gives plausible but wrong forces
+        layout::check_particle_layout(&device, &queue).await?;
+
+        Ok(GpuDevice {
+            adapter_info,
+            device,
+            queue,
+            #[cfg(feature = "preview")]
+            instance,
+            #[cfg(feature = "preview")]
+            adapter,
+            lost,
+        })
+    }
+
+    pub fn adapter_info(&self) -> &wgpu::AdapterInfo {
+        &self.adapter_info
+    }
+}
+
+/// Polls a device on a thread of its own, so map callbacks fire as soon as the device is done
+/// instead of whenever the simulation thread next waits on it. Woken after every `map_async`,
+/// and stopped when dropped. A browser maps buffers itself once control is back with it, so
+/// there's no thread there.
+struct PollThread {
+    wake: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PollThread {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn spawn(device: wgpu::Device) -> std::io::Result<PollThread> {
+        let (wake, woken) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("gpu-poll".to_string())
+            .spawn(move || {
+                while woken.recv().is_ok() {
+                    // one wait covers every map requested before it
+                    while woken.try_recv().is_ok() {}
+                    let _ = device.poll(wgpu::wgt::PollType::Wait);
+                }
+            })?;
+        Ok(PollThread {
+            wake: Some(wake),
+            thread: Some(thread),
+        })
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn spawn(_device: wgpu::Device) -> std::io::Result<PollThread> {
+        Ok(PollThread {
+            wake: None,
+            thread: None,
+        })
+    }
+
+    /// Poll until every map requested so far has called back
+    fn wake(&self) {
+        if let Some(wake) = &self.wake {
+            let _ = wake.send(());
+        }
+    }
+}
+
+impl Drop for PollThread {
+    fn drop(&mut self) {
+        // closing the channel ends the loop once the current poll returns
+        self.wake = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A device with the particle buffers and pipelines for one run. Computes forces through
+/// `ForceBackend`, and with `gpu_integration` steps the particles on the device too.
+pub struct GpuCompute {
+    pub(crate) adapter_info: wgpu::AdapterInfo,
+    pub(crate) device: wgpu::Device,
+    pub(crate) queue: wgpu::Queue,
+    /// Swapped out when the shader is hot-reloaded
+    pub(crate) pipelines: RwLock<Pipelines>,
+    pipeline_config: PipelineConfig,
+    /// Set in dev mode, when nbody.wgsl comes from `shader_path`
+    shader_reload: Option<ShaderReload>,
+    /// A single chunk unless the particles exceed the buffer limits. Empty with a tree or
+    /// streaming pass.
+    chunks: Vec<GpuChunk>,
+    /// Set when `force_method` is barnes_hut, replacing the direct force pass
+    tree: Option<TreePass>,
+    /// Set with `out_of_core`, replacing the resident direct force pass
+    streaming: Option<StreamingPass>,
+    /// None when the drift and kick passes get their frame constants as push constants
+    frame_uniform: Option<FrameUniform>,
+    /// Frame index handed to the next integrated step
+    frames_integrated: AtomicU32,
+    /// f32 bits of the dt handed to the drift and kick passes, see `set_dt`
+    dt: AtomicU32,
+    /// Ring of buffers forces/positions are read back through, unmapped again after each read
+    staging_buffers: Vec<wgpu::Buffer>,
+    /// Reduce energy and momentum on the device every step, for `Settings::diagnostics`
+    diagnostics: bool,
+    /// Total of the energy reduction, copied out after every step. The per-workgroup partials it
+    /// sums are only referenced by the bind groups.
+    energy_sum: wgpu::Buffer,
+    /// Staging buffer the next `submit_steps` will copy into
+    next_staging: AtomicUsize,
+    /// map_async callbacks report which staging buffer finished mapping
+    mapped_sender: mpsc::Sender<(usize, Result<(), wgpu::BufferAsyncError>)>,
+    mapped_receiver: Mutex<mpsc::Receiver<(usize, Result<(), wgpu::BufferAsyncError>)>>,
+    /// Runs the map callbacks, so nothing on the simulation thread polls the device
+    poller: PollThread,
+    timer: Option<GpuTimer>,
+    /// Running sums, averaged by `take_timings`
+    timings: Mutex<GpuTimings>,
+    num_particles: usize,
+    /// Particles this device computes forces for, the whole set unless split across GPUs
+    pub(crate) targets: Range<usize>,
+    /// Most steps `step` will record into one command encoder
+    pub(crate) steps_per_submit: usize,
+    workgroup_size: u32,
+    /// Device limit on each dimension of a dispatch, bigger ones wrap into y
+    max_workgroups_per_dimension: u32,
+    /// Set once particle state has been uploaded, after which the device copy is authoritative
+    resident: AtomicBool,
+    /// Set by the device lost and uncaptured error callbacks, after which this GpuCompute only
+    /// returns empty results and has to be replaced
+    lost: Arc<AtomicBool>,
+    /// Set by `fail` for an error recreating the device won't fix
+    failure: Mutex<Option<String>>,
+    /// The device this was created on, for the preview window
+    #[cfg(feature = "preview")]
+    pub(crate) gpu_device: GpuDevice,
+    /// Integrated frames are copied here for the preview window, see `preview_into`
+    #[cfg(feature = "preview")]
+    preview: Mutex<Option<Arc<PreviewTarget>>>,
+}
+
+impl GpuCompute {
+    /// Device picked by `settings.adapter`, computing forces for every particle.
+    pub async fn new(settings: &Settings) -> Result<Self, Error> {
+        Self::with_adapter(settings, &settings.adapter, 0..settings.num_particles).await
+    }
+
+    /// Device picked by `adapter_settings`, computing forces only for the `targets` slice.
+    pub async fn with_adapter(
+        settings: &Settings,
+        adapter_settings: &AdapterSettings,
+        targets: Range<usize>,
+    ) -> Result<Self, Error> {
+        let device = GpuDevice::open(settings, adapter_settings).await?;
+        Self::on_device(settings, &device, targets).await
+    }
+
+    /// Buffers and pipelines for `settings` on an already open `device`, computing forces only
+    /// for the `targets` slice. Simulations sharing a device each get their own.
+    pub async fn on_device(
+        settings: &Settings,
+        device: &GpuDevice,
+        targets: Range<usize>,
+    ) -> Result<Self, Error> {
+        Self::create(settings, device, targets)
+            .await
+            .map_err(Error::Gpu)
+    }
+
+    async fn create(
+        settings: &Settings,
+        gpu_device: &GpuDevice,
+        targets: Range<usize>,
+    ) -> Result<Self, String> {
+        let num_particles = settings.num_particles;
+        let GpuDevice {
+            adapter_info,
+            device,
+            queue,
+            lost,
+            ..
+        } = gpu_device.clone();
+
+        let workgroup_size = match settings.workgroup_size {
+            Some(size) => size,
+            None => match autotune::cached_workgroup_size(&adapter_info, num_particles) {
+                Some(size) => {
+                    info!("Using workgroup_size {} from --bench-kernel", size);
+                    size
+                }
+                None => DEFAULT_WORKGROUP_SIZE,
+            },
+        };
+
+        let mut accumulation = settings.force_accumulation;
+        if accumulation == ForceAccumulation::F64
+            && !device.features().contains(wgpu::Features::SHADER_F64)
+        {
+            warn!("adapter has no SHADER_F64 support, using kahan force accumulation");
+            accumulation = ForceAccumulation::Kahan;
+        }
+        let push_constants = device.features().contains(wgpu::Features::PUSH_CONSTANTS);
+        // the subgroup kernel needs every subgroup full, whatever size the driver picks
+        let subgroups = settings.subgroups
+            && device.features().contains(wgpu::Features::SUBGROUP)
+            && workgroup_size.is_multiple_of(device.limits().max_subgroup_size.max(1));
+        info!(
+            "Force kernel: {}",
+            match (subgroups, settings.force_kernel) {
+                (true, _) => "subgroup shuffle",
+                (false, ForceKernel::Tiled) => "shared memory tiles",
+                (false, ForceKernel::Naive) => "naive direct sum",
+            }
+        );
+
+        let limits = device.limits();
+        let max_size = limits
+            .max_compute_workgroup_size_x
+            .min(limits.max_compute_invocations_per_workgroup)
+            // one vec4 of shared memory per invocation for the source tile
+            .min(limits.max_compute_workgroup_storage_size / 16);
+        if workgroup_size == 0 || workgroup_size > max_size {
+            return Err(format!(
+                "workgroup_size {} is not supported by this device (1..={})",
+                workgroup_size, max_size
+            ));
+        }
+
+        // Buffers larger than the device allows get split into chunks. Storage bindings are usually
+        // more limited than buffers themselves, and the staging ring is never bound.
+        let max_buffer_size = settings
+            .max_buffer_size
+            .unwrap_or(u64::MAX)
+            .min(limits.max_buffer_size);
+        let max_binding_size = max_buffer_size.min(limits.max_storage_buffer_binding_size as u64);
+        let chunk_len = (max_binding_size / std::mem::size_of::<GpuParticle>() as u64) as usize;
+        if chunk_len == 0 {
+            return Err(format!(
+                "buffer limit of {} bytes can't hold a single particle",
+                max_binding_size
+            ));
+        }
+
+        // out of core, no more than a tile of particles is ever on the device
+        let stream_len = if settings.out_of_core {
+            if settings.force_method != ForceMethod::Direct {
+                return Err("out_of_core only works with direct summation".to_string());
+            }
+            let by_budget = settings.gpu_memory_budget.map_or(usize::MAX, |budget| {
+                MemoryEstimate::max_tile_len(settings, num_particles, 1, budget)
+            });
+            Some(chunk_len.min(by_budget).min(num_particles).max(1))
+        } else {
+            None
+        };
+
+        // every frame is read back whole, so one frame of positions and velocities has to fit a
+        // staging buffer. Streaming only reads back a tile of forces at a time.
+        let frame_size = match stream_len {
+            Some(tile_len) => tile_len * 16,
+            None => num_particles * 2 * PACKED_VEC3_SIZE,
+        } as u64;
+        let step_size = frame_size + ENERGY_SIZE;
+        let timestamp_size = TIMESTAMPS_PER_SUBMIT as u64 * 8;
+        if step_size + timestamp_size > max_buffer_size {
+            return Err(format!(
+                "{} particles need {} bytes of readback per frame, more than the {} byte buffer \
+                 limit, at most {} particles fit",
+                num_particles,
+                step_size + timestamp_size,
+                max_buffer_size,
+                max_buffer_size.saturating_sub(ENERGY_SIZE + timestamp_size)
+                    / (2 * PACKED_VEC3_SIZE) as u64
+            ));
+        }
+        // one slot of positions and energies per step recorded in a single submission
+        let fitting_steps = ((max_buffer_size - timestamp_size) / step_size) as usize;
+        let steps_per_submit = if stream_len.is_some() {
+            // nothing is integrated on the device
+            1
+        } else {
+            settings.steps_per_submit.max(1).min(fitting_steps)
+        };
+        if stream_len.is_none() && steps_per_submit < settings.steps_per_submit {
+            info!(
+                "steps_per_submit lowered to {} to fit the staging buffers in the buffer limit",
+                steps_per_submit
+            );
+        }
+
+        // wgpu can't tell how much memory the device has, so the budget comes from the settings
+        let estimate = MemoryEstimate::new(
+            settings,
+            num_particles,
+            steps_per_submit,
+            stream_len.unwrap_or(chunk_len),
+        );
+        info!(
+            "Estimated GPU memory: {}",
+            memory::format_bytes(estimate.total())
+        );
+        if let Some(budget) = settings.gpu_memory_budget
+            && estimate.total() > budget
+        {
+            return Err(match stream_len {
+                Some(_) => format!(
+                    "{}\nmore than the gpu_memory_budget of {}, even streaming one particle at a time",
+                    estimate.describe(),
+                    memory::format_bytes(budget)
+                ),
+                None => format!(
+                    "{}\nmore than the gpu_memory_budget of {}, at most {} particles fit (or set \
+                     out_of_core to stream them)",
+                    estimate.describe(),
+                    memory::format_bytes(budget),
+                    MemoryEstimate::max_particles(settings, steps_per_submit, chunk_len, budget)
+                ),
+            });
+        }
+
+        let timer = device
+            .features()
+            .contains(wgpu::Features::TIMESTAMP_QUERY)
+            .then(|| GpuTimer {
+                query_set: device.create_query_set(&wgpu::QuerySetDescriptor {
+                    label: Some("Step Timestamps"),
+                    ty: wgpu::QueryType::Timestamp,
+                    count: TIMESTAMPS_PER_SUBMIT * STAGING_RING as u32,
+                }),
+                resolve_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Timestamp Resolve"),
+                    // each ring slot resolves into its own aligned block
+                    size: wgpu::QUERY_RESOLVE_BUFFER_ALIGNMENT * STAGING_RING as u64,
+                    usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+                    mapped_at_creation: false,
+                }),
+                period: queue.get_timestamp_period() as f64,
+                time_copies: device
+                    .features()
+                    .contains(wgpu::Features::TIMESTAMP_QUERY_INSIDE_ENCODERS),
+            });
+
+        let staging_buffers = (0..STAGING_RING)
+            .map(|_| {
+                device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Staging"),
+                    // energies and timestamps ride along after the positions
+                    size: step_size * steps_per_submit as u64 + timestamp_size,
+                    usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                })
+            })
+            .collect();
+        let (mapped_sender, mapped_receiver) = mpsc::channel();
+        let poller = PollThread::spawn(device.clone())
+            .map_err(|e| format!("could not start the GPU poll thread: {}", e))?;
+
+        // Bind group layout and pipeline
+        let storage_entry = |binding: u32| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: false },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Compute Bind Group Layout"),
+            entries: &[
+                storage_entry(0),
+                storage_entry(1),
+                storage_entry(2),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                storage_entry(4),
+                storage_entry(5),
+                storage_entry(6),
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Compute Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let frame_uniform = (!push_constants).then(|| {
+            let stride = (std::mem::size_of::<FrameConstants>() as u64)
+                .next_multiple_of(limits.min_uniform_buffer_offset_alignment as u64);
+            let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Frame Constants"),
+                size: stride * steps_per_submit as u64,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Frame Constants Layout"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: wgpu::BufferSize::new(
+                            std::mem::size_of::<FrameConstants>() as u64,
+                        ),
+                    },
+                    count: None,
+                }],
+            });
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Frame Constants"),
+                layout: &layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &buffer,
+                        offset: 0,
+                        size: wgpu::BufferSize::new(std::mem::size_of::<FrameConstants>() as u64),
+                    }),
+                }],
+            });
+            (
+                FrameUniform {
+                    buffer,
+                    bind_group,
+                    stride,
+                },
+                layout,
+            )
+        });
+
+        // the force pass never reads the frame constants, so only the drift and kick passes get them
+        let integrate_layout = match &frame_uniform {
+            None => device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Integrate Pipeline Layout"),
+                bind_group_layouts: &[&bind_group_layout],
+                push_constant_ranges: &[wgpu::PushConstantRange {
+                    stages: wgpu::ShaderStages::COMPUTE,
+                    range: 0..std::mem::size_of::<FrameConstants>() as u32,
+                }],
+            }),
+            Some((_, frame_layout)) => {
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("Integrate Pipeline Layout"),
+                    bind_group_layouts: &[&bind_group_layout, frame_layout],
+                    push_constant_ranges: &[],
+                })
+            }
+        };
+        let frame_uniform = frame_uniform.map(|(uniform, _)| uniform);
+
+        let pipeline_config = PipelineConfig {
+            layout: pipeline_layout,
+            integrate_layout,
+            accumulation,
+            push_constants,
+            workgroup_size,
+            integrator: settings.integrator,
+            subgroups,
+            kernel: settings.force_kernel,
+            cache: DiskPipelineCache::load(&device, &adapter_info),
+        };
+        let pipelines_start = Instant::now();
+        let embedded = include_str!("nbody.wgsl");
+        let shader_reload = settings.shader_path.clone().map(|path| ShaderReload {
+            path,
+            modified: Mutex::new(None),
+        });
+        let pipelines = match &shader_reload {
+            None => pipeline_config.build(&device, embedded).await?,
+            Some(reload) => match reload.changed_source() {
+                None => {
+                    warn!(
+                        "shader {} not found, using the embedded shader",
+                        reload.path.display()
+                    );
+                    pipeline_config.build(&device, embedded).await?
+                }
+                Some(source) => match pipeline_config.build(&device, &source).await {
+                    Ok(pipelines) => {
+                        info!("Loaded shader from {}", reload.path.display());
+                        pipelines
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Could not compile {}, using the embedded shader:\n{}",
+                            reload.path.display(),
+                            e
+                        );
+                        pipeline_config.build(&device, embedded).await?
+                    }
+                },
+            },
+        };
+
+        let tree = match &settings.force_method {
+            ForceMethod::Direct => None,
+            ForceMethod::BarnesHut(barnes_hut) => Some(TreePass::new(
+                &device,
+                settings,
+                barnes_hut,
+                accumulation,
+                workgroup_size,
+                max_binding_size,
+                pipeline_config.cache.as_ref().map(DiskPipelineCache::cache),
+            )?),
+        };
+
+        let cache_state = match &pipeline_config.cache {
+            None => "no pipeline cache on this backend",
+            Some(cache) if cache.was_loaded() => "from the pipeline cache",
+            Some(_) => "pipeline cache was empty",
+        };
+        info!(
+            "Built pipelines in {:.0} ms ({})",
+            pipelines_start.elapsed().as_secs_f64() * 1000.0,
+            cache_state
+        );
+        if let Some(cache) = &pipeline_config.cache {
+            cache.save();
+        }
+
+        if tree.is_some() && settings.gpu_integration {
+            info!("barnes_hut rebuilds its tree on the CPU every step, integrating on the CPU");
+        }
+        if stream_len.is_some() && settings.gpu_integration {
+            info!("out_of_core keeps the particles on the CPU, integrating on the CPU");
+        }
+
+        // the tree and streaming passes keep their own buffers, so only the resident direct pass
+        // needs chunks
+        let chunked_particles = if tree.is_some() || stream_len.is_some() {
+            0
+        } else {
+            num_particles
+        };
+        let ranges: Vec<Range<usize>> = (0..chunked_particles)
+            .step_by(chunk_len)
+            .map(|start| start..(start + chunk_len).min(num_particles))
+            .collect();
+        if ranges.len() > 1 {
+            info!(
+                "Splitting {} particles into {} chunks of up to {} to fit the buffer limit",
+                num_particles,
+                ranges.len(),
+                chunk_len
+            );
+        }
+
+        // each chunk's energy reduction writes one partial sum per workgroup
+        let partial_counts: Vec<usize> = ranges
+            .iter()
+            .map(|range| range.len().div_ceil(workgroup_size as usize))
+            .collect();
+        let num_partials: usize = partial_counts.iter().sum();
+        let energy_partials = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Energy Partials"),
+            size: num_partials.max(1) as u64 * ENERGY_SIZE,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let energy_sum = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Energy Sum"),
+            size: ENERGY_SIZE,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        let streaming = stream_len.map(|tile_len| {
+            info!(
+                "Streaming {} particles through the device in tiles of {}",
+                num_particles, tile_len
+            );
+            StreamingPass::new(
+                &device,
+                settings,
+                &bind_group_layout,
+                tile_len,
+                &energy_partials,
+                &energy_sum,
+            )
+        });
+
+        let mut chunks: Vec<GpuChunk> = ranges
+            .iter()
+            .map(|range| {
+                let start = targets.start.clamp(range.start, range.end);
+                let chunk_targets = start..targets.end.clamp(start, range.end);
+                GpuChunk {
+                    range: range.clone(),
+                    particle_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                        label: Some("Particles"),
+                        size: (range.len() * std::mem::size_of::<GpuParticle>()) as u64,
+                        usage: wgpu::BufferUsages::STORAGE
+                            | wgpu::BufferUsages::COPY_DST
+                            | wgpu::BufferUsages::COPY_SRC,
+                        mapped_at_creation: false,
+                    }),
+                    force_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                        label: Some("Forces"),
+                        size: (chunk_targets.len().max(1) * 16) as u64, // vec3 + padding
+                        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                        mapped_at_creation: false,
+                    }),
+                    readback_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                        label: Some("Readback"),
+                        size: (range.len() * 2 * PACKED_VEC3_SIZE) as u64,
+                        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                        mapped_at_creation: false,
+                    }),
+                    targets: chunk_targets,
+                    bind_groups: Vec::new(),
+                }
+            })
+            .collect();
+
+        // one bind group per (chunk, source chunk) pair, each with its own params
+        for index in 0..chunks.len() {
+            let bind_groups = chunks
+                .iter()
+                .enumerate()
+                .map(|(source_index, source)| {
+                    let chunk = &chunks[index];
+                    let params = SimParams {
+                        partial_offset: partial_counts[..index].iter().sum::<usize>() as u32,
+                        num_partials: num_partials as u32,
+                        ..SimParams::for_chunk(
+                            settings,
+                            &chunk.range,
+                            &chunk.targets,
+                            &source.range,
+                            source_index > 0,
+                        )
+                    };
+                    // written once per run, the bind group keeps it alive
+                    let params_buffer =
+                        device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                            label: Some("Sim Params"),
+                            contents: bytemuck::bytes_of(&params),
+                            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                        });
+
+                    device.create_bind_group(&wgpu::BindGroupDescriptor {
+                        label: Some("Compute Bind Group"),
+                        layout: &bind_group_layout,
+                        entries: &[
+                            wgpu::BindGroupEntry {
+                                binding: 0,
+                                resource: chunk.particle_buffer.as_entire_binding(),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 1,
+                                resource: chunk.force_buffer.as_entire_binding(),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 2,
+                                resource: chunk.readback_buffer.as_entire_binding(),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 3,
+                                resource: params_buffer.as_entire_binding(),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 4,
+                                resource: source.particle_buffer.as_entire_binding(),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 5,
+                                resource: energy_partials.as_entire_binding(),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 6,
+                                resource: energy_sum.as_entire_binding(),
+                            },
+                        ],
+                    })
+                })
+                .collect();
+            chunks[index].bind_groups = bind_groups;
+        }
+
+        Ok(Self {
+            adapter_info,
+            device,
+            queue,
+            pipelines: RwLock::new(pipelines),
+            pipeline_config,
+            shader_reload,
+            chunks,
+            tree,
+            streaming,
+            frame_uniform,
+            frames_integrated: AtomicU32::new(0),
+            dt: AtomicU32::new(settings.dt.to_bits()),
+            staging_buffers,
+            diagnostics: settings.diagnostics,
+            energy_sum,
+            next_staging: AtomicUsize::new(0),
+            mapped_sender,
+            mapped_receiver: Mutex::new(mapped_receiver),
+            poller,
+            timer,
+            timings: Mutex::new(GpuTimings::default()),
+            num_particles,
+            targets,
+            steps_per_submit,
+            workgroup_size,
+            max_workgroups_per_dimension: limits.max_compute_workgroups_per_dimension,
+            resident: AtomicBool::new(false),
+            lost,
+            failure: Mutex::new(None),
+            #[cfg(feature = "preview")]
+            gpu_device: gpu_device.clone(),
+            #[cfg(feature = "preview")]
+            preview: Mutex::new(None),
+        })
+    }
+
+    /// Replace the particle state on the device.
+    pub fn upload(&self, particles: &ParticleSet) {
+        assert_eq!(
+            particles.len(),
+            self.num_particles,
+            "GpuCompute buffers are sized for a fixed particle count"
+        );
+
+        for chunk in &self.chunks {
+            self.write_particles(&chunk.particle_buffer, particles, chunk.range.clone());
+        }
+        self.resident.store(true, Ordering::Release);
+    }
+
+    /// Interleave `particles[range]` straight into the queue's staging memory for the start of
+    /// `buffer`, without building a Vec<GpuParticle> to copy from first. Lands with the next
+    /// submit.
+    pub(crate) fn write_particles(
+        &self,
+        buffer: &wgpu::Buffer,
+        particles: &ParticleSet,
+        range: Range<usize>,
+    ) {
+        let size = (range.len() * std::mem::size_of::<GpuParticle>()) as u64;
+        let Some(size) = wgpu::BufferSize::new(size) else {
+            return;
+        };
+        // None means a validation error, which the error handler has already reported
+        if let Some(mut staging) = self.queue.write_buffer_with(buffer, 0, size) {
+            let fields = (
+                &particles.pos[range.clone()],
+                &particles.mass[range.clone()],
+                &particles.vel[range.clone()],
+                &particles.acc[range],
+            );
+            staging
+                .par_chunks_exact_mut(std::mem::size_of::<GpuParticle>())
+                .zip(fields)
+                .for_each(|(bytes, (pos, &mass, vel, acc))| {
+                    let particle = GpuParticle {
+                        pos: pos.to_array(),
+                        mass,
+                        vel: vel.to_array(),
+                        _padding: 0.0,
+                        acc: acc.to_array(),
+                        _padding2: 0.0,
+                    };
+                    bytes.copy_from_slice(bytemuck::bytes_of(&particle))
+                });
+        }
+    }
+
+    /// Whether `submit_steps` can be used. The tree is rebuilt on the CPU every step, and
+    /// streaming never holds the whole particle set, so with either the particles have to be
+    /// integrated on the CPU.
+    pub(crate) fn integrates_on_device(&self) -> bool {
+        self.tree.is_none() && self.streaming.is_none()
+    }
+
+    /// dt for the steps submitted from now on, for the settings' schedule
+    pub(crate) fn set_dt(&self, dt: f32) {
+        self.dt.store(dt.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Rebuild the pipelines if the `shader_path` file changed. A shader that doesn't compile is
+    /// reported and the previous pipelines stay in use.
+    pub(crate) fn reload_shader(&self) {
+        let Some(reload) = &self.shader_reload else {
+            return;
+        };
+        let Some(source) = reload.changed_source() else {
+            return;
+        };
+        match pollster::block_on(self.pipeline_config.build(&self.device, &source)) {
+            Ok(pipelines) => {
+                *self.pipelines.write().unwrap() = pipelines;
+                info!("Reloaded shader from {}", reload.path.display());
+            }
+            Err(e) => warn!(
+                "Could not compile {}, keeping the previous shader:\n{}",
+                reload.path.display(),
+                e
+            ),
+        }
+    }
+
+    /// Whether the device was lost or hit an error, in which case results can't be trusted.
+    pub fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::Acquire) || self.failure().is_some()
+    }
+
+    /// Stop trusting this GpuCompute's results for a reason that would only come back on a
+    /// new device, eg. a tree too big to bind. The run ends with it instead of retrying.
+    pub(crate) fn fail(&self, message: String) {
+        error!("{}", message);
+        self.failure.lock().unwrap().get_or_insert(message);
+    }
+
+    /// What `fail` was called with first
+    pub fn failure(&self) -> Option<String> {
+        self.failure.lock().unwrap().clone()
+    }
+
+    /// Whether the device already holds the particle state.
+    pub(crate) fn is_resident(&self) -> bool {
+        self.resident.load(Ordering::Acquire)
+    }
+
+    /// Upload `particles` and return the force on and potential at each particle in `targets`.
+    pub(crate) async fn compute_forces_async(&self, particles: &ParticleSet) -> Forces {
+        if let Some(tree) = &self.tree {
+            // multi-GPU isn't supported with the tree, so targets are always everything
+            return tree.compute_forces(self, particles).await;
+        }
+        if let Some(streaming) = &self.streaming {
+            return streaming.compute_forces(self, particles).await;
+        }
+        self.upload(particles);
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Compute Encoder"),
+            });
+        self.encode_pass(
+            &mut encoder,
+            &self.pipelines.read().unwrap().compute,
+            "N-Body Pass",
+            &self.force_dispatches(),
+            None,
+            None,
+        );
+
+        // chunk targets are contiguous and in order, so reading them back to back gives `targets`
+        let sources: Vec<(&wgpu::Buffer, usize)> = self
+            .chunks
+            .iter()
+            .map(|chunk| (&chunk.force_buffer, chunk.targets.len()))
+            .collect();
+        Forces::from_vec4s(&self.read_vec4s(encoder, &sources).await)
+    }
+
+    /// Record `steps` steps of the particles resident on the device into one submission and start
+    /// mapping the positions of the steps `keep` says, and the last step's velocities if asked
+    /// for, without waiting for any of it.
+    ///
+    /// Every step's readback is copied into its own slot of the next staging buffer in the ring,
+    /// so at most `STAGING_RING` submissions may be pending at once. Positions copy 12 bytes per
+    /// particle, velocities another 12.
+    pub(crate) fn submit_steps(
+        &self,
+        steps: usize,
+        velocities: bool,
+        keep: impl Fn(usize) -> bool,
+    ) -> PendingSteps {
+        assert!(
+            (1..=self.steps_per_submit).contains(&steps),
+            "step count must be within 1..=steps_per_submit"
+        );
+        let _span = trace_span!("submit_steps", steps).entered();
+        let slot = self.next_staging.fetch_add(1, Ordering::Relaxed) % STAGING_RING;
+        let staging_buffer = &self.staging_buffers[slot];
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Step Encoder"),
+            });
+
+        let first_frame = self
+            .frames_integrated
+            .fetch_add(steps as u32, Ordering::Relaxed);
+        let positions: Vec<bool> = (0..steps).map(keep).collect();
+        let frames: Vec<FrameConstants> = (0..steps)
+            .map(|step| FrameConstants {
+                dt: f32::from_bits(self.dt.load(Ordering::Relaxed)),
+                frame: first_frame + step as u32,
+            })
+            .collect();
+        if let Some(uniform) = &self.frame_uniform {
+            // one write for the whole submission rather than one per step
+            let mut slots = vec![0u8; steps * uniform.stride as usize];
+            for (slot, frame) in slots.chunks_mut(uniform.stride as usize).zip(&frames) {
+                let bytes = bytemuck::bytes_of(frame);
+                slot[..bytes.len()].copy_from_slice(bytes);
+            }
+            self.queue.write_buffer(&uniform.buffer, 0, &slots);
+        }
+
+        #[cfg(feature = "preview")]
+        let preview = self.preview.lock().unwrap().clone();
+        let pipelines = self.pipelines.read().unwrap();
+        let frame_size = (self.num_particles * 2 * PACKED_VEC3_SIZE) as u64;
+        let velocities_offset = (self.num_particles * PACKED_VEC3_SIZE) as u64;
+        let energies_offset = self.steps_per_submit as u64 * frame_size;
+        let query_base = slot as u32 * TIMESTAMPS_PER_SUBMIT;
+        for (step, frame) in frames.iter().enumerate() {
+            // only the first step of a submission is timed
+            let timer = self.timer.as_ref().filter(|_| step == 0);
+            let pass_timestamps = |first: u32| {
+                timer.map(|timer| wgpu::ComputePassTimestampWrites {
+                    query_set: &timer.query_set,
+                    beginning_of_pass_write_index: Some(query_base + first),
+                    end_of_pass_write_index: Some(query_base + first + 1),
+                })
+            };
+
+            // separate passes so the force pass sees every drifted position, and the kick every
+            // force
+            self.encode_pass(
+                &mut encoder,
+                &pipelines.drift,
+                "Drift Pass",
+                &self.integrate_dispatches(),
+                Some((step, frame)),
+                pass_timestamps(4),
+            );
+            self.encode_pass(
+                &mut encoder,
+                &pipelines.compute,
+                "N-Body Pass",
+                &self.force_dispatches(),
+                None,
+                pass_timestamps(0),
+            );
+            self.encode_pass(
+                &mut encoder,
+                &pipelines.kick,
+                "Kick Pass",
+                &self.integrate_dispatches(),
+                Some((step, frame)),
+                pass_timestamps(2),
+            );
+            if self.diagnostics {
+                // the state at the end of the step, velocities finished by the kick
+                self.encode_pass(
+                    &mut encoder,
+                    &pipelines.reduce_energy,
+                    "Reduce Energy Pass",
+                    &self.integrate_dispatches(),
+                    None,
+                    None,
+                );
+                // every bind group shares the partials, so any of them will do
+                self.encode_pass(
+                    &mut encoder,
+                    &pipelines.finish_energy,
+                    "Finish Energy Pass",
+                    &[(&self.chunks[0].bind_groups[0], self.workgroup_size as usize)],
+                    None,
+                    None,
+                );
+                encoder.copy_buffer_to_buffer(
+                    &self.energy_sum,
+                    0,
+                    staging_buffer,
+                    energies_offset + step as u64 * ENERGY_SIZE,
+                    ENERGY_SIZE,
+                );
+            }
+
+            let copy_timer = timer.filter(|timer| timer.time_copies);
+            if let Some(timer) = copy_timer {
+                encoder.write_timestamp(&timer.query_set, query_base + 6);
+            }
+            // each chunk's positions and velocities land in the frame's two packed arrays
+            for chunk in &self.chunks {
+                let start = (chunk.range.start * PACKED_VEC3_SIZE) as u64;
+                let len = (chunk.range.len() * PACKED_VEC3_SIZE) as u64;
+                let slot_offset = step as u64 * frame_size;
+                if positions[step] {
+                    encoder.copy_buffer_to_buffer(
+                        &chunk.readback_buffer,
+                        0,
+                        staging_buffer,
+                        slot_offset + start,
+                        len,
+                    );
+                }
+                if velocities {
+                    encoder.copy_buffer_to_buffer(
+                        &chunk.readback_buffer,
+                        len,
+                        staging_buffer,
+                        slot_offset + velocities_offset + start,
+                        len,
+                    );
+                }
+            }
+            if let Some(timer) = copy_timer {
+                encoder.write_timestamp(&timer.query_set, query_base + 7);
+            }
+            // straight from the device's copy, the window never waits on a readback
+            #[cfg(feature = "preview")]
+            if let Some(preview) = preview.as_ref().filter(|p| p.wants(frame.frame as usize)) {
+                for chunk in &self.chunks {
+                    encoder.copy_buffer_to_buffer(
+                        &chunk.readback_buffer,
+                        0,
+                        &preview.buffer,
+                        (chunk.range.start * PACKED_VEC3_SIZE) as u64,
+                        (chunk.range.len() * PACKED_VEC3_SIZE) as u64,
+                    );
+                }
+            }
+        }
+
+        let timestamps_offset = energies_offset + self.steps_per_submit as u64 * ENERGY_SIZE;
+        if let Some(timer) = &self.timer {
+            let count = if timer.time_copies { 8 } else { 6 };
+            let resolve_offset = slot as u64 * wgpu::QUERY_RESOLVE_BUFFER_ALIGNMENT;
+            encoder.resolve_query_set(
+                &timer.query_set,
+                query_base..query_base + count,
+                &timer.resolve_buffer,
+                resolve_offset,
+            );
+            encoder.copy_buffer_to_buffer(
+                &timer.resolve_buffer,
+                resolve_offset,
+                staging_buffer,
+                timestamps_offset,
+                count as u64 * 8,
+            );
+        }
+        self.queue.submit(Some(encoder.finish()));
+
+        let sender = self.mapped_sender.clone();
+        staging_buffer
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |r| {
+                // the receiver only goes away with GpuCompute itself
+                let _ = sender.send((slot, r));
+            });
+        self.poller.wake();
+
+        PendingSteps {
+            slot,
+            steps,
+            positions,
+            velocities,
+        }
+    }
+
+    /// Wait for submitted steps to map and copy the positions read back after each step into
+    /// `frames`, one per step, straight from the staging buffer. Frames of steps whose positions
+    /// weren't kept are left as they were. The last step's velocities go
+    /// into `velocities` when they were asked for, and with `diagnostics` each step's energy is
+    /// added to `energies`. Returns whether `velocities` were filled in.
+    ///
+    /// Pending submissions have to be finished in the order they were submitted. A lost device
+    /// leaves everything as it was.
+    pub(crate) fn finish_steps(
+        &self,
+        pending: PendingSteps,
+        frames: &mut [Vec<Vec3>],
+        velocities: &mut [Vec3],
+        energies: &mut Vec<Energy>,
+    ) -> bool {
+        let _span = trace_span!("readback").entered();
+        let map_start = Instant::now();
+        // a submit that failed because the device is gone may never map, and its error has
+        // already flagged the device
+        if self.is_lost() {
+            return false;
+        }
+
+        // the poll thread maps buffers in submission order, so the next callback is always ours
+        let (slot, result) = self.mapped_receiver.lock().unwrap().recv().unwrap();
+        let wait_ms = map_start.elapsed().as_secs_f64() * 1000.0;
+        assert_eq!(slot, pending.slot, "staging buffers finished out of order");
+        if result.is_err() {
+            self.lost.store(true, Ordering::Release);
+            return false;
+        }
+
+        let staging_buffer = &self.staging_buffers[slot];
+        let vec3s_size = self.num_particles * PACKED_VEC3_SIZE;
+        let frame_size = 2 * vec3s_size;
+        let energies_offset = self.steps_per_submit * frame_size;
+        let mut timestamps = [0u64; TIMESTAMPS_PER_SUBMIT as usize];
+        {
+            let data = staging_buffer.slice(..).get_mapped_range();
+            let copy_vec3s = |offset: usize, into: &mut [Vec3]| {
+                bytemuck::cast_slice_mut::<Vec3, u8>(into)
+                    .copy_from_slice(&data[offset..offset + vec3s_size]);
+            };
+            for (step, frame) in frames.iter_mut().enumerate().take(pending.steps) {
+                if pending.positions[step] {
+                    copy_vec3s(step * frame_size, frame);
+                }
+                if self.diagnostics {
+                    let offset = energies_offset + step * ENERGY_SIZE as usize;
+                    energies.push(Energy::from(bytemuck::pod_read_unaligned::<GpuEnergy>(
+                        &data[offset..offset + ENERGY_SIZE as usize],
+                    )));
+                }
+            }
+            if pending.velocities {
+                copy_vec3s((pending.steps - 1) * frame_size + vec3s_size, velocities);
+            }
+            let tail = energies_offset + self.steps_per_submit * ENERGY_SIZE as usize;
+            let timestamp_bytes = bytemuck::cast_slice_mut::<u64, u8>(&mut timestamps);
+            timestamp_bytes.copy_from_slice(&data[tail..tail + timestamp_bytes.len()]);
+        }
+        staging_buffer.unmap();
+
+        let mut timings = self.timings.lock().unwrap();
+        timings.map_ms += map_start.elapsed().as_secs_f64() * 1000.0;
+        timings.wait_ms += wait_ms;
+        timings.samples += 1;
+        if let Some(timer) = &self.timer {
+            let elapsed_ms = |begin: usize| {
+                timestamps[begin + 1].saturating_sub(timestamps[begin]) as f64 * timer.period / 1e6
+            };
+            timings.force_ms += elapsed_ms(0);
+            timings.integrate_ms += elapsed_ms(2) + elapsed_ms(4);
+            if timer.time_copies {
+                *timings.copy_ms.get_or_insert(0.0) += elapsed_ms(6);
+            }
+        }
+
+        pending.velocities
+    }
+
+    /// Average timings per submission since the last call, None if nothing ran.
+    ///
+    /// GPU figures are only filled in when the device supports timestamp queries.
+    pub(crate) fn take_timings(&self) -> Option<GpuTimings> {
+        let mut timings = self.timings.lock().unwrap();
+        let sums = std::mem::take(&mut *timings);
+        if sums.samples == 0 {
+            return None;
+        }
+        let n = sums.samples as f64;
+        Some(GpuTimings {
+            force_ms: sums.force_ms / n,
+            integrate_ms: sums.integrate_ms / n,
+            copy_ms: sums.copy_ms.map(|ms| ms / n),
+            map_ms: sums.map_ms / n,
+            wait_ms: sums.wait_ms / n,
+            samples: sums.samples,
+        })
+    }
+
+    /// Copy one integrated frame in `target`'s every into it from now on
+    #[cfg(feature = "preview")]
+    pub(crate) fn preview_into(&self, target: Arc<PreviewTarget>) {
+        *self.preview.lock().unwrap() = Some(target);
+    }
+
+    pub(crate) fn has_timestamps(&self) -> bool {
+        self.timer.is_some()
+    }
+
+    /// Read the full particle state back off the device.
+    pub(crate) async fn download(&self) -> Vec<GpuParticle> {
+        let mut particles = Vec::with_capacity(self.num_particles);
+        for chunk in &self.chunks {
+            let size = (chunk.range.len() * std::mem::size_of::<GpuParticle>()) as u64;
+
+            // only needed for occasional syncs, so not worth keeping around
+            let staging_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Particle Staging"),
+                size,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+
+            let mut encoder = self
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Download Encoder"),
+                });
+            encoder.copy_buffer_to_buffer(&chunk.particle_buffer, 0, &staging_buffer, 0, size);
+            self.queue.submit(Some(encoder.finish()));
+
+            match map_read::<GpuParticle>(&staging_buffer.slice(..), &self.poller).await {
+                Ok(data) => particles.extend(data),
+                Err(_) => {
+                    self.lost.store(true, Ordering::Release);
+                    return Vec::new();
+                }
+            }
+            staging_buffer.unmap();
+        }
+        particles
+    }
+
+    /// Force pass dispatches: every chunk with targets against every source chunk, in order.
+    fn force_dispatches(&self) -> Vec<(&wgpu::BindGroup, usize)> {
+        self.chunks
+            .iter()
+            .filter(|chunk| !chunk.targets.is_empty())
+            .flat_map(|chunk| {
+                chunk
+                    .bind_groups
+                    .iter()
+                    .map(|bind_group| (bind_group, chunk.targets.len()))
+            })
+            .collect()
+    }
+
+    /// Drift and kick pass dispatches, one per chunk.
+    fn integrate_dispatches(&self) -> Vec<(&wgpu::BindGroup, usize)> {
+        self.chunks
+            .iter()
+            .enumerate()
+            .map(|(index, chunk)| (&chunk.bind_groups[index], chunk.range.len()))
+            .collect()
+    }
+
+    pub(crate) fn encode_pass(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::ComputePipeline,
+        label: &str,
+        dispatches: &[(&wgpu::BindGroup, usize)],
+        frame: Option<(usize, &FrameConstants)>,
+        timestamp_writes: Option<wgpu::ComputePassTimestampWrites>,
+    ) {
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            timestamp_writes,
+            label: Some(label),
+        });
+        compute_pass.set_pipeline(pipeline);
+
+        // `frame` is the step within the submission and its constants
+        if let Some((step, constants)) = frame {
+            match &self.frame_uniform {
+                None => compute_pass.set_push_constants(0, bytemuck::bytes_of(constants)),
+                Some(uniform) => compute_pass.set_bind_group(
+                    1,
+                    &uniform.bind_group,
+                    &[(step as u64 * uniform.stride) as u32],
+                ),
+            }
+        }
+
+        // dispatches in one pass still run in order, so accumulating chunks see earlier writes
+        for (bind_group, invocations) in dispatches {
+            compute_pass.set_bind_group(0, *bind_group, &[]);
+            let workgroups = (*invocations as u32).div_ceil(self.workgroup_size);
+            let (x, y) = dispatch_size(workgroups, self.max_workgroups_per_dimension);
+            compute_pass.dispatch_workgroups(x, y, 1);
+        }
+    }
+
+    /// Finish `encoder` with copies of the first `count` entries of each source buffer, back to
+    /// back in the staging buffer, and read them back.
+    pub(crate) async fn read_vec4s(
+        &self,
+        mut encoder: wgpu::CommandEncoder,
+        sources: &[(&wgpu::Buffer, usize)],
+    ) -> Vec<[f32; 4]> {
+        // only used outside the pipelined step loop, so the first ring buffer is always free
+        let staging_buffer = &self.staging_buffers[0];
+        let mut size = 0;
+        for (source, count) in sources {
+            let bytes = (*count * 16) as u64;
+            if bytes > 0 {
+                encoder.copy_buffer_to_buffer(source, 0, staging_buffer, size, bytes);
+            }
+            size += bytes;
+        }
+        self.queue.submit(Some(encoder.finish()));
+        // a device can end up with no targets, and empty slices can't be mapped
+        if size == 0 {
+            return Vec::new();
+        }
+
+        let Ok(data) = map_read::<[f32; 4]>(&staging_buffer.slice(..size), &self.poller).await
+        else {
+            self.lost.store(true, Ordering::Release);
+            return Vec::new();
+        };
+        // buffer has to be unmapped before it can be reused next frame
+        staging_buffer.unmap();
+        data
+    }
+}
+
+/// The limits that matter for this simulation, for error messages
+fn describe_limits(limits: &wgpu::Limits) -> String {
+    format!(
+        "max_buffer_size={} max_storage_buffer_binding_size={} max_compute_workgroup_size_x={} \
+         max_compute_workgroup_storage_size={}",
+        limits.max_buffer_size,
+        limits.max_storage_buffer_binding_size,
+        limits.max_compute_workgroup_size_x,
+        limits.max_compute_workgroup_storage_size
+    )
+}
+
+/// Split `workgroups` into an x * y grid with neither side over `max_per_dimension`.
+///
+/// The shaders rebuild the flat index as `(y * x_count + x) * WORKGROUP_SIZE + local`, so the
+/// last row may overshoot and those invocations are bounds-checked away.
+fn dispatch_size(workgroups: u32, max_per_dimension: u32) -> (u32, u32) {
+    if workgroups <= max_per_dimension {
+        (workgroups, 1)
+    } else {
+        let rows = workgroups.div_ceil(max_per_dimension);
+        (workgroups.div_ceil(rows), rows)
+    }
+}
+
+/// Shader source with the force accumulator type filled in
+pub(crate) fn with_acc_type(accumulation: ForceAccumulation, source: &str) -> String {
+    let acc_type = match accumulation {
+        ForceAccumulation::F64 => "vec4<f64>",
+        ForceAccumulation::F32 | ForceAccumulation::Kahan => "vec4<f32>",
+    };
+    format!("alias AccVec = {};\n{}", acc_type, source)
+}
+
+/// Map a slice of a `MAP_READ` buffer and copy its contents out. The caller unmaps it.
+///
+/// Doesn't wait on the device itself, `poller` wakes the future once the mapping is done. Fails
+/// when the device is lost before the mapping completes.
+async fn map_read<T: Pod>(
+    buffer_slice: &wgpu::BufferSlice<'_>,
+    poller: &PollThread,
+) -> Result<Vec<T>, wgpu::BufferAsyncError> {
+    let (sender, receiver) = futures::channel::oneshot::channel();
+    buffer_slice.map_async(wgpu::MapMode::Read, move |r| {
+        let _ = sender.send(r);
+    });
+    poller.wake();
+
+    receiver.await.unwrap_or(Err(wgpu::BufferAsyncError))?;
+
+    let data = buffer_slice.get_mapped_range();
+    Ok(bytemuck::cast_slice(&data).to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Particle;
+    use crate::backend::{self, ForceBackend};
+    use crate::simulation::{output_order, sort_particles};
+    use crate::tree;
+
+    /// Defaults, plus whatever adapter the machine has. CI often only has a software one.
+    fn test_settings() -> Settings {
+        let mut settings = Settings::default();
+        settings.adapter.allow_software_adapter = true;
+        // independent of whatever --bench-kernel cached on this machine
+        settings.workgroup_size = Some(DEFAULT_WORKGROUP_SIZE);
+        settings
+    }
+
+    fn test_particles(count: usize) -> ParticleSet {
+        (0..count)
+            .map(|i| {
+                let t = i as f32;
+                let pos = Vec3::new((t * 0.37).sin(), (t * 0.73).cos(), (t * 0.11).sin()) * 10.0;
+                Particle::new(1.0 + (i % 7) as f32, pos, Vec3::ZERO, Vec3::ZERO)
+            })
+            .collect()
+    }
+
+    /// Forces with buffers capped at `max_particles` per chunk, None without a usable adapter
+    fn chunked_forces(
+        particles: &ParticleSet,
+        max_particles: Option<u64>,
+        targets: Range<usize>,
+    ) -> Option<Forces> {
+        let settings = Settings {
+            num_particles: particles.len(),
+            max_buffer_size: max_particles.map(|n| n * std::mem::size_of::<GpuParticle>() as u64),
+            ..test_settings()
+        };
+        gpu_forces(particles, &settings, targets)
+    }
+
+    fn gpu_forces(
+        particles: &ParticleSet,
+        settings: &Settings,
+        targets: Range<usize>,
+    ) -> Option<Forces> {
+        match pollster::block_on(GpuCompute::with_adapter(
+            settings,
+            &settings.adapter,
+            targets,
+        )) {
+            Ok(gpu) => Some(ForceBackend::compute_forces(&gpu, particles)),
+            Err(e) => {
+                println!("skipping, no GPU: {}", e);
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn last_particle_of_partial_workgroup() {
+        // 1001 leaves a workgroup of 64 with only 41 real particles
+        let particles = test_particles(1001);
+        let Some(forces) = chunked_forces(&particles, None, 0..1001) else {
+            return;
+        };
+        let forces = forces.force;
+        assert_eq!(forces.len(), 1001);
+
+        // same sum as Particle::get_influence, with the default settings the GPU ran with
+        let settings = Settings::default();
+        let softening_sq = settings.softening * settings.softening;
+        let last = particles.get(1000);
+        let expected: Vec3 = particles
+            .iter()
+            .take(1000)
+            .map(|other| {
+                let r_vec = other.pos() - last.pos();
+                let r_sq = r_vec.dot(r_vec) + softening_sq;
+                r_vec * (settings.g_const * last.mass() * other.mass() / (r_sq * r_sq.sqrt()))
+            })
+            .sum();
+
+        assert!(
+            (forces[1000] - expected).length() <= 1e-3 * expected.length(),
+            "gpu {:?} cpu {:?}",
+            forces[1000],
+            expected
+        );
+    }
+
+    #[test]
+    fn chunked_forces_match_single_buffer() {
+        let particles = test_particles(300);
+        let Some(whole) = chunked_forces(&particles, None, 0..300) else {
+            return;
+        };
+
+        // 160 particles per chunk leaves a short last chunk, and the target slice straddles both
+        let chunked = chunked_forces(&particles, Some(160), 0..300).unwrap();
+        let sliced = chunked_forces(&particles, Some(160), 50..250).unwrap();
+
+        for (i, (a, b)) in whole.force.iter().zip(&chunked.force).enumerate() {
+            assert!(
+                (*a - *b).length() <= 1e-4 * a.length().max(1e-3),
+                "particle {}",
+                i
+            );
+        }
+        for (i, (a, b)) in whole.force[50..250].iter().zip(&sliced.force).enumerate() {
+            assert!(
+                (*a - *b).length() <= 1e-4 * a.length().max(1e-3),
+                "particle {}",
+                i + 50
+            );
+        }
+        // potentials accumulate across source chunks the same way
+        for (i, (a, b)) in whole.potential.iter().zip(&chunked.potential).enumerate() {
+            assert!((a - b).abs() <= 1e-4 * a.abs(), "particle {}", i);
+        }
+    }
+
+    #[test]
+    fn streamed_forces_match_resident() {
+        let particles = test_particles(300);
+        let Some(resident) = chunked_forces(&particles, None, 0..300) else {
+            return;
+        };
+
+        // tiles of 128 leave a short last tile, and the target slice starts mid tile
+        let settings = Settings {
+            num_particles: particles.len(),
+            max_buffer_size: Some(128 * std::mem::size_of::<GpuParticle>() as u64),
+            out_of_core: true,
+            ..test_settings()
+        };
+        let streamed = gpu_forces(&particles, &settings, 0..300).unwrap();
+        let sliced = gpu_forces(&particles, &settings, 50..250).unwrap();
+
+        for (i, (a, b)) in resident.force.iter().zip(&streamed.force).enumerate() {
+            assert!(
+                (*a - *b).length() <= 1e-4 * a.length().max(1e-3),
+                "particle {}",
+                i
+            );
+        }
+        for (i, (a, b)) in resident
+            .potential
+            .iter()
+            .zip(&streamed.potential)
+            .enumerate()
+        {
+            assert!((a - b).abs() <= 1e-4 * a.abs(), "particle {}", i);
+        }
+        assert_eq!(sliced.force.len(), 200);
+        for (i, (a, b)) in resident.force[50..250]
+            .iter()
+            .zip(&sliced.force)
+            .enumerate()
+        {
+            assert!(
+                (*a - *b).length() <= 1e-4 * a.length().max(1e-3),
+                "particle {}",
+                i + 50
+            );
+        }
+    }
+
+    #[test]
+    fn potential_matches_cpu_sum() {
+        let particles = test_particles(300);
+        let Some(forces) = chunked_forces(&particles, None, 0..300) else {
+            return;
+        };
+
+        // same softening as Particle::get_potential, with the default settings the GPU ran with
+        let settings = Settings::default();
+        let softening_sq = settings.softening * settings.softening;
+        for (i, particle) in particles.iter().enumerate() {
+            let expected: f32 = particles
+                .iter()
+                .enumerate()
+                .filter(|&(j, _)| j != i)
+                .map(|(_, other)| {
+                    let r_vec = other.pos() - particle.pos();
+                    -settings.g_const * other.mass() / (r_vec.dot(r_vec) + softening_sq).sqrt()
+                })
+                .sum();
+            assert!(
+                (forces.potential[i] - expected).abs() <= 1e-4 * expected.abs(),
+                "particle {}: gpu {} cpu {}",
+                i,
+                forces.potential[i],
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn energy_reduction_matches_cpu() {
+        let particles: ParticleSet = test_particles(300)
+            .iter()
+            .enumerate()
+            .map(|(i, particle)| {
+                let t = i as f32;
+                particle.with_vel(Vec3::new((t * 0.19).cos(), (t * 0.53).sin(), 0.5))
+            })
+            .collect();
+
+        // single buffer, chunks with a short last one, and a workgroup that isn't a power of two
+        for (max_particles, workgroup_size) in [(None, 64), (Some(160u64), 64), (None, 48)] {
+            let settings = Settings {
+                num_particles: particles.len(),
+                max_buffer_size: max_particles
+                    .map(|n| n * std::mem::size_of::<GpuParticle>() as u64),
+                workgroup_size: Some(workgroup_size),
+                diagnostics: true,
+                ..test_settings()
+            };
+            let Ok(gpu) = pollster::block_on(GpuCompute::new(&settings)) else {
+                return;
+            };
+            gpu.upload(&particles);
+            let mut frame = vec![vec![Vec3::ZERO; particles.len()]];
+            let mut energies = Vec::new();
+            gpu.finish_steps(
+                gpu.submit_steps(1, false, |_| true),
+                &mut frame,
+                &mut [],
+                &mut energies,
+            );
+            // the energy of the state at the end of the step
+            let mut stepped = particles.clone();
+            for (particle, gpu_particle) in backend::drive(gpu.download()).iter().enumerate() {
+                stepped.pos[particle] = Vec3::from_array(gpu_particle.pos);
+                stepped.vel[particle] = Vec3::from_array(gpu_particle.vel);
+            }
+            let forces = ForceBackend::compute_forces(&gpu, &stepped);
+            let expected = Energy::from_particles(&stepped, &forces.potential);
+
+            let gpu_energy = energies[0];
+            let context = format!(
+                "{:?}: gpu {:?} cpu {:?}",
+                max_particles, gpu_energy, expected
+            );
+            assert!(
+                (gpu_energy.kinetic - expected.kinetic).abs() <= 1e-5 * expected.kinetic,
+                "{}",
+                context
+            );
+            assert!(
+                (gpu_energy.potential - expected.potential).abs()
+                    <= 1e-5 * expected.potential.abs(),
+                "{}",
+                context
+            );
+            assert!(
+                (gpu_energy.momentum - expected.momentum).length()
+                    <= 1e-5 * expected.momentum.length(),
+                "{}",
+                context
+            );
+        }
+    }
+
+    #[test]
+    fn readback_matches_device_state() {
+        let particles: ParticleSet = test_particles(300)
+            .iter()
+            .enumerate()
+            .map(|(i, particle)| particle.with_vel(Vec3::new((i as f32 * 0.19).cos(), 0.5, 0.0)))
+            .collect();
+        // chunked, so each chunk's packed arrays land in the right part of the frame
+        for max_particles in [None, Some(160u64)] {
+            let settings = Settings {
+                num_particles: particles.len(),
+                max_buffer_size: max_particles
+                    .map(|n| n * std::mem::size_of::<GpuParticle>() as u64),
+                ..test_settings()
+            };
+            let Ok(gpu) = pollster::block_on(GpuCompute::new(&settings)) else {
+                return;
+            };
+            gpu.upload(&particles);
+
+            let frame = || vec![vec![Vec3::ZERO; particles.len()]];
+            let mut velocities = vec![Vec3::ZERO; particles.len()];
+            let mut positions_only = frame();
+            let steps = gpu.submit_steps(1, false, |_| true);
+            assert!(!gpu.finish_steps(
+                steps,
+                &mut positions_only,
+                &mut velocities,
+                &mut Vec::new()
+            ));
+            let mut readback = frame();
+            let steps = gpu.submit_steps(1, true, |_| true);
+            assert!(gpu.finish_steps(steps, &mut readback, &mut velocities, &mut Vec::new()));
+            let state = backend::drive(gpu.download());
+
+            for (i, gpu_particle) in state.iter().enumerate() {
+                assert_eq!(readback[0][i], Vec3::from_array(gpu_particle.pos), "{}", i);
+                assert_eq!(velocities[i], Vec3::from_array(gpu_particle.vel), "{}", i);
+            }
+            assert_ne!(positions_only, readback);
+        }
+    }
+
+    #[test]
+    fn sorted_particles_keep_output_order() {
+        let mut particles = test_particles(500);
+        particles.id = (0..500).collect();
+        let mut sorted = particles.clone();
+        sort_particles(&mut sorted);
+        assert_ne!(sorted.id, particles.id);
+
+        // gathering through output_order puts every particle back in its original slot
+        let order = output_order(&sorted);
+        for (id, &index) in order.iter().enumerate() {
+            assert_eq!(sorted.id[index as usize], id as u32);
+            assert_eq!(sorted.pos[index as usize], particles.pos[id]);
+        }
+
+        // and forces computed on the sorted vec land on the same particles
+        let Some(unsorted_forces) = chunked_forces(&particles, None, 0..500) else {
+            return;
+        };
+        let sorted_forces = chunked_forces(&sorted, None, 0..500).unwrap();
+        for (id, &index) in order.iter().enumerate() {
+            let a = unsorted_forces.force[id];
+            let b = sorted_forces.force[index as usize];
+            assert!(
+                (a - b).length() <= 1e-4 * a.length().max(1e-3),
+                "particle {}",
+                id
+            );
+        }
+    }
+
+    #[test]
+    fn subgroup_forces_match_shared_memory() {
+        let particles = test_particles(300);
+        // chunked too, so the subgroup kernel also accumulates across source chunks
+        for max_particles in [None, Some(160u64)] {
+            let settings = Settings {
+                num_particles: particles.len(),
+                max_buffer_size: max_particles
+                    .map(|n| n * std::mem::size_of::<GpuParticle>() as u64),
+                subgroups: true,
+                ..test_settings()
+            };
+            let Ok(gpu) = pollster::block_on(GpuCompute::new(&settings)) else {
+                return;
+            };
+            if !gpu.pipeline_config.subgroups {
+                println!("skipping, no subgroup support");
+                return;
+            }
+            let subgroup = ForceBackend::compute_forces(&gpu, &particles);
+            let shared = gpu_forces(
+                &particles,
+                &Settings {
+                    subgroups: false,
+                    ..settings
+                },
+                0..particles.len(),
+            )
+            .unwrap();
+
+            // same sums in a different order
+            for (i, (a, b)) in shared.force.iter().zip(&subgroup.force).enumerate() {
+                assert!(
+                    (*a - *b).length() <= 1e-4 * a.length().max(1e-3),
+                    "particle {}",
+                    i
+                );
+            }
+            for (i, (a, b)) in shared.potential.iter().zip(&subgroup.potential).enumerate() {
+                assert!((a - b).abs() <= 1e-4 * a.abs(), "particle {}", i);
+            }
+        }
+    }
+
+    #[test]
+    fn tiled_forces_match_naive() {
+        let particles = test_particles(300);
+        // chunked, a target slice, and a workgroup size the source count isn't a multiple of
+        for (max_particles, targets, workgroup_size) in [
+            (None, 0..300, 64),
+            (Some(160u64), 0..300, 64),
+            (None, 70..230, 96),
+        ] {
+            let settings = Settings {
+                num_particles: particles.len(),
+                max_buffer_size: max_particles
+                    .map(|n| n * std::mem::size_of::<GpuParticle>() as u64),
+                workgroup_size: Some(workgroup_size),
+                subgroups: false,
+                ..test_settings()
+            };
+            let forces = |force_kernel| {
+                gpu_forces(
+                    &particles,
+                    &Settings {
+                        force_kernel,
+                        ..settings.clone()
+                    },
+                    targets.clone(),
+                )
+            };
+            let Some(naive) = forces(ForceKernel::Naive) else {
+                return;
+            };
+            let tiled = forces(ForceKernel::Tiled).unwrap();
+
+            assert_eq!(naive.force.len(), targets.len());
+            for (i, (a, b)) in naive.force.iter().zip(&tiled.force).enumerate() {
+                assert!(
+                    (*a - *b).length() <= 1e-4 * a.length().max(1e-3),
+                    "particle {}: naive {} tiled {}",
+                    targets.start + i,
+                    a,
+                    b
+                );
+            }
+            for (i, (a, b)) in naive.potential.iter().zip(&tiled.potential).enumerate() {
+                assert!(
+                    (a - b).abs() <= 1e-4 * a.abs(),
+                    "particle {}",
+                    targets.start + i
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn tree_pass_matches_cpu_tree() {
+        let particles = test_particles(2000);
+        let barnes_hut = tree::BarnesHutSettings {
+            theta: 0.5,
+            leaf_size: 8,
+        };
+        let settings = Settings {
+            num_particles: particles.len(),
+            force_method: ForceMethod::BarnesHut(barnes_hut),
+            ..test_settings()
+        };
+        let Some(gpu) = gpu_forces(&particles, &settings, 0..particles.len()) else {
+            return;
+        };
+
+        let params = tree::TreeParams::new(&settings, &barnes_hut);
+        let cpu = tree::Octree::build(&particles, barnes_hut.leaf_size).forces(&params);
+        for (i, (a, b)) in cpu.force.iter().zip(&gpu.force).enumerate() {
+            assert!(
+                (*a - *b).length() <= 1e-4 * a.length().max(1e-3),
+                "particle {}",
+                i
+            );
+        }
+        for (i, (a, b)) in cpu.potential.iter().zip(&gpu.potential).enumerate() {
+            assert!((a - b).abs() <= 1e-4 * a.abs(), "particle {}", i);
+        }
+    }
+
+    #[test]
+    fn dispatch_size_covers_every_workgroup() {
+        for max in [1, 2, 3, 7, 65535] {
+            for workgroups in [1, 2, max, max + 1, 3 * max - 1, 70_000] {
+                let (x, y) = dispatch_size(workgroups, max);
+                assert!(x <= max);
+                // flat workgroup index as rebuilt in the shader, each one exactly once in order
+                let covered = (0..y).flat_map(|wy| (0..x).map(move |wx| wy * x + wx));
+                let in_range: Vec<u32> = covered.filter(|&i| i < workgroups).collect();
+                let expected: Vec<u32> = (0..workgroups).collect();
+                assert_eq!(in_range, expected, "{} workgroups, max {}", workgroups, max);
+            }
+        }
+    }
+
+    #[test]
+    fn wrapped_dispatch_matches_flat() {
+        // 300 particles is 5 workgroups, wrapped into rows of 2 the last row is half empty
+        let particles = test_particles(300);
+        let settings = Settings {
+            num_particles: particles.len(),
+            ..test_settings()
+        };
+        let Ok(mut gpu) = pollster::block_on(GpuCompute::new(&settings)) else {
+            return;
+        };
+        let flat = ForceBackend::compute_forces(&gpu, &particles);
+        gpu.max_workgroups_per_dimension = 2;
+        let wrapped = ForceBackend::compute_forces(&gpu, &particles);
+        assert_eq!(flat, wrapped);
+    }
+
+    #[test]
+    fn destroyed_device_reports_lost() {
+        let particles = test_particles(100);
+        let settings = Settings {
+            num_particles: particles.len(),
+            ..test_settings()
+        };
+        let Ok(gpu) = pollster::block_on(GpuCompute::new(&settings)) else {
+            return;
+        };
+        assert!(!gpu.is_lost());
+
+        gpu.device.destroy();
+        // no panic, just nothing usable back
+        let forces = ForceBackend::compute_forces(&gpu, &particles);
+        assert!(gpu.is_lost());
+        assert!(forces.force.is_empty());
+    }
+
+    #[test]
+    fn poll_thread_maps_buffers_and_stops_when_dropped() {
+        let settings = test_settings();
+        let Ok(device) = pollster::block_on(GpuDevice::open(&settings, &settings.adapter)) else {
+            return;
+        };
+        let poller = PollThread::spawn(device.device.clone()).unwrap();
+        let buffer = device.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: 16,
+            usage: wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        // nothing on this thread polls the device
+        let data = backend::drive(map_read::<u32>(&buffer.slice(..), &poller)).unwrap();
+        assert_eq!(data, [0; 4]);
+        buffer.unmap();
+
+        // joins the thread, a wake still queued mustn't keep it alive
+        poller.wake();
+        drop(poller);
+    }
+
+    /// Upload timings to compare changes to the upload path against:
+    /// `cargo test --release upload_throughput -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn upload_throughput() {
+        for count in [100_000, 1_000_000] {
+            let particles = test_particles(count);
+            let settings = Settings {
+                num_particles: count,
+                ..test_settings()
+            };
+            let Ok(gpu) = pollster::block_on(GpuCompute::new(&settings)) else {
+                return;
+            };
+            gpu.upload(&particles);
+            let start = Instant::now();
+            for _ in 0..10 {
+                gpu.upload(&particles);
+                gpu.queue.submit([]);
+            }
+            let _ = gpu.device.poll(wgpu::wgt::PollType::Wait);
+            println!(
+                "{} particles: {:.2} ms per upload",
+                count,
+                start.elapsed().as_secs_f64() * 100.0
+            );
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use glam::Vec3;
+use rayon::prelude::*;
+
+/// Positions binned into a uniform grid of cubic cells over their bounding box, about one a
+/// cell, for finding the ones near a point without looking at all of them. Built in O(N) with
+/// a counting sort. A set clumped into a few cells makes the searches slower, not wrong.
+pub struct SpatialGrid {
+    min: Vec3,
+    cell_size: f32,
+    dims: [usize; 3],
+    /// Where each cell's indices start in `indices`, then one past the last cell's
+    starts: Vec<u32>,
+    /// Indices into the positions, a cell at a time
+    indices: Vec<u32>,
+}
+
+impl SpatialGrid {
+    /// Over the finite ones of `positions`, the rest are left out
+    pub fn new(positions: &[Vec3]) -> SpatialGrid {
+        let (min, max) = positions
+            .par_iter()
+            .filter(|pos| pos.is_finite())
+            .fold(
+                || (Vec3::INFINITY, Vec3::NEG_INFINITY),
+                |(min, max), &pos| (min.min(pos), max.max(pos)),
+            )
+            .reduce(
+                || (Vec3::INFINITY, Vec3::NEG_INFINITY),
+                |a, b| (a.0.min(b.0), a.1.max(b.1)),
+            );
+        let count = positions.len().max(1);
+        let extent = (max - min).max(Vec3::ZERO);
+        let largest = extent.max_element();
+        let volume = extent.x * extent.y * extent.z;
+        // one a cell if they fill the box, then coarser until flat or stringy sets don't get
+        // far more cells than particles
+        let mut cell_size = if volume > 0.0 {
+            (volume / count as f32).cbrt()
+        } else {
+            largest / count as f32
+        };
+        if !(cell_size > 0.0 && cell_size.is_finite()) {
+            cell_size = 1.0;
+        }
+        let dims_for = |cell_size: f32| extent.to_array().map(|e| (e / cell_size) as usize + 1);
+        while dims_for(cell_size).iter().product::<usize>() > 2 * count {
+            cell_size *= 1.25;
+        }
+        let mut grid = SpatialGrid {
+            min: if min.is_finite() { min } else { Vec3::ZERO },
+            cell_size,
+            dims: dims_for(cell_size),
+            starts: Vec::new(),
+            indices: Vec::new(),
+        };
+
+        let cells: Vec<Option<u32>> = positions
+            .par_iter()
+            .map(|pos| {
+                pos.is_finite()
+                    .then(|| grid.flat(grid.cell_of(*pos)) as u32)
+            })
+            .collect();
+        let mut starts = vec![0u32; grid.dims.iter().product::<usize>() + 1];
+        for &cell in cells.iter().flatten() {
+            starts[cell as usize + 1] += 1;
+        }
+        for index in 1..starts.len() {
+            starts[index] += starts[index - 1];
+        }
+        let mut next = starts.clone();
+        let mut indices = vec![0u32; *starts.last().unwrap() as usize];
+        for (index, cell) in cells.iter().enumerate() {
+            if let Some(cell) = cell {
+                let slot = &mut next[*cell as usize];
+                indices[*slot as usize] = index as u32;
+                *slot += 1;
+            }
+        }
+        grid.starts = starts;
+        grid.indices = indices;
+        grid
+    }
+
+    fn cell_of(&self, pos: Vec3) -> [usize; 3] {
+        let cell = ((pos - self.min) / self.cell_size).to_array();
+        [0, 1, 2].map(|axis| (cell[axis].max(0.0) as usize).min(self.dims[axis] - 1))
+    }
+
+    fn flat(&self, [x, y, z]: [usize; 3]) -> usize {
+        (z * self.dims[1] + y) * self.dims[0] + x
+    }
+
+    fn cell(&self, cell: [usize; 3]) -> &[u32] {
+        let flat = self.flat(cell);
+        &self.indices[self.starts[flat] as usize..self.starts[flat + 1] as usize]
+    }
+
+    /// Index and distance of the closest of `positions`, the ones the grid was built from, to
+    /// the one at `index`. None when it has no finite neighbour.
+    pub fn nearest(&self, positions: &[Vec3], index: usize) -> Option<(usize, f32)> {
+        let pos = positions[index];
+        if !pos.is_finite() {
+            return None;
+        }
+        let center = self.cell_of(pos).map(|c| c as isize);
+        let mut best: Option<(usize, f32)> = None;
+        let rings = *self.dims.iter().max().unwrap() as isize;
+        for ring in 0..rings {
+            // the cells `ring` away from the center's, the shell only
+            for dz in -ring..=ring {
+                for dy in -ring..=ring {
+                    let on_shell = dz.abs() == ring || dy.abs() == ring;
+                    let step = if on_shell { 1 } else { (2 * ring).max(1) };
+                    for dx in (-ring..=ring).step_by(step as usize) {
+                        let cell = [center[0] + dx, center[1] + dy, center[2] + dz];
+                        let inside =
+                            (0..3).all(|axis| (0..self.dims[axis] as isize).contains(&cell[axis]));
+                        if !inside {
+                            continue;
+                        }
+                        for &other in self.cell(cell.map(|c| c as usize)) {
+                            let other = other as usize;
+                            if other == index {
+                                continue;
+                            }
+                            let distance = positions[other].distance(pos);
+                            if best.is_none_or(|(_, closest)| distance < closest) {
+                                best = Some((other, distance));
+                            }
+                        }
+                    }
+                }
+            }
+            // anything closer than this would be in the cells seen already
+            if best.is_some_and(|(_, closest)| closest <= ring as f32 * self.cell_size) {
+                break;
+            }
+        }
+        best
+    }
+}
+
+/// Each of `positions`' distance to its closest other one, NaN for ones without any
+pub fn nearest_neighbor_distances(positions: &[Vec3]) -> Vec<f32> {
+    let grid = SpatialGrid::new(positions);
+    (0..positions.len())
+        .into_par_iter()
+        .map(|index| {
+            grid.nearest(positions, index)
+                .map_or(f32::NAN, |(_, distance)| distance)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+
+    fn brute_force(positions: &[Vec3]) -> Vec<f32> {
+        positions
+            .iter()
+            .enumerate()
+            .map(|(index, pos)| {
+                positions
+                    .iter()
+                    .enumerate()
+                    .filter(|&(other, _)| other != index)
+                    .map(|(_, other)| other.distance(*pos))
+                    .fold(f32::NAN, f32::min)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nearest_neighbors_match_a_brute_force_search() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut random = |scale: Vec3| {
+            (0..500)
+                .map(|_| Vec3::new(rng.random(), rng.random(), rng.random()) * scale)
+                .collect::<Vec<Vec3>>()
+        };
+        let mut clumped = random(Vec3::ONE);
+        // a tight clump and a far outlier
+        for pos in &mut clumped[..400] {
+            *pos *= 0.001;
+        }
+        clumped[0] = Vec3::splat(1000.0);
+        let sets = [
+            random(Vec3::ONE),
+            // a flat disk, and a line
+            random(Vec3::new(10.0, 10.0, 0.0)),
+            random(Vec3::X),
+            clumped,
+            vec![Vec3::ONE; 3],
+        ];
+        for positions in &sets {
+            assert_eq!(
+                nearest_neighbor_distances(positions),
+                brute_force(positions)
+            );
+        }
+        assert!(nearest_neighbor_distances(&[Vec3::ZERO])[0].is_nan());
+        assert!(nearest_neighbor_distances(&[]).is_empty());
+    }
+}
@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tracing::warn;
+
+use super::manifest::write_atomic;
+use super::observer::{BatchReport, FrameObserver};
+use super::settings::Settings;
+use super::status::unix_seconds;
+
+/// Rewritten in the output directory after every batch
+pub const HEARTBEAT_FILE: &str = "heartbeat.json";
+
+/// Proof a run is still moving, for a watchdog to check the age of. A run wedged on a hung
+/// device stops updating it.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Heartbeat {
+    /// Frames simulated so far, ie. the next one to simulate
+    pub frame: usize,
+    /// The last batch simulated, None before the first
+    pub batch: Option<usize>,
+    /// Seconds since the Unix epoch
+    pub updated_at: u64,
+    /// Wall time per frame of the last batch, simulating only
+    pub seconds_per_frame: Option<f64>,
+}
+
+/// heartbeat.json as an observer
+pub struct HeartbeatFile {
+    path: PathBuf,
+}
+
+impl HeartbeatFile {
+    /// Write the first heartbeat, for a run starting at `first_frame`
+    pub fn start(settings: &Settings, first_frame: usize) -> HeartbeatFile {
+        let heartbeat = HeartbeatFile {
+            path: settings.out_path.join(HEARTBEAT_FILE),
+        };
+        heartbeat.write(&Heartbeat {
+            frame: first_frame,
+            batch: None,
+            updated_at: unix_seconds(),
+            seconds_per_frame: None,
+        });
+        heartbeat
+    }
+
+    fn write(&self, heartbeat: &Heartbeat) {
+        let json = serde_json::to_string_pretty(heartbeat).expect("a heartbeat serializes");
+        if let Err(e) = write_atomic(&self.path, json.as_bytes()) {
+            warn!("Could not write {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Beats after every batch, cut short or not
+impl FrameObserver for HeartbeatFile {
+    fn on_batch_complete(&mut self, batch: &BatchReport) {
+        self.write(&Heartbeat {
+            frame: batch.first_frame + batch.frames,
+            batch: Some(batch.batch),
+            updated_at: unix_seconds(),
+            seconds_per_frame: (batch.frames > 0)
+                .then(|| batch.elapsed.as_secs_f64() / batch.frames as f64),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ParticleSet;
+    use crate::testing::{batch_report, test_dir};
+    use std::time::Duration;
+
+    #[test]
+    fn each_batch_replaces_the_heartbeat() {
+        let dir = test_dir("heartbeat");
+        let settings = Settings {
+            out_path: dir.clone(),
+            ..Settings::default()
+        };
+        let read = || -> Heartbeat {
+            serde_json::from_str(&std::fs::read_to_string(dir.join(HEARTBEAT_FILE)).unwrap())
+                .unwrap()
+        };
+
+        let mut heartbeat = HeartbeatFile::start(&settings, 20);
+        assert_eq!((read().frame, read().batch), (20, None));
+        let particles = ParticleSet::with_capacity(0);
+        heartbeat.on_batch_complete(&BatchReport {
+            elapsed: Duration::from_secs(5),
+            ..batch_report(20, 10, &particles, None)
+        });
+        let beat = read();
+        assert_eq!((beat.frame, beat.batch), (30, Some(2)));
+        assert_eq!(beat.seconds_per_frame, Some(0.5));
+        assert!(beat.updated_at > 0);
+        // nothing left over from the atomic write
+        let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
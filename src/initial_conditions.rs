@@ -0,0 +1,668 @@
+use glam::{Mat3, Vec3};
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use super::Particle;
+
+/// Group tags written onto particles by the composite presets.
+pub const GROUP_BULGE: u32 = 0;
+pub const GROUP_DISK: u32 = 1;
+pub const GROUP_HALO: u32 = 2;
+
+/// Which generator builds the starting particle set.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum InitialConditions {
+    /// Original random sphere around an implied central mass, driven by `arena`/`mass`/`init_vel`.
+    #[default]
+    Sphere,
+    /// Hernquist bulge + exponential disk + Hernquist halo.
+    Galaxy(GalaxySettings),
+    /// Spherical density profile read from a CSV table.
+    Tabulated(TabulatedSettings),
+}
+
+impl InitialConditions {
+    /// Number of particles the generator will produce, if it decides that itself.
+    pub fn particle_count(&self) -> Option<usize> {
+        match self {
+            InitialConditions::Sphere => None,
+            InitialConditions::Galaxy(galaxy) => Some(galaxy.particle_count()),
+            InitialConditions::Tabulated(tabulated) => Some(tabulated.count),
+        }
+    }
+}
+
+/// Components without particles, scales that aren't positive, and galaxy mass fractions that
+/// don't add up to 1
+pub fn problems(initial_conditions: &InitialConditions) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut positive = |name: String, value: f32| {
+        if !(value > 0.0 && value.is_finite()) {
+            problems.push(format!("{} must be positive, not {}", name, value));
+        }
+    };
+    match initial_conditions {
+        InitialConditions::Sphere => {}
+        InitialConditions::Galaxy(galaxy) => {
+            let name = "initial_conditions.galaxy";
+            positive(format!("{}.total_mass", name), galaxy.total_mass);
+            for (component, spheroid) in [("bulge", &galaxy.bulge), ("halo", &galaxy.halo)] {
+                let name = format!("{}.{}", name, component);
+                positive(format!("{}.scale_radius", name), spheroid.scale_radius);
+                positive(
+                    format!("{}.truncation_radius", name),
+                    spheroid.truncation_radius,
+                );
+            }
+            positive(
+                format!("{}.disk.scale_length", name),
+                galaxy.disk.scale_length,
+            );
+            positive(
+                format!("{}.disk.scale_height", name),
+                galaxy.disk.scale_height,
+            );
+
+            let components = [
+                ("bulge", galaxy.bulge.count, galaxy.bulge.mass_fraction),
+                ("disk", galaxy.disk.count, galaxy.disk.mass_fraction),
+                ("halo", galaxy.halo.count, galaxy.halo.mass_fraction),
+            ];
+            for (component, count, _) in components {
+                if count == 0 {
+                    problems.push(format!("{}.{}.count must be at least 1", name, component));
+                }
+            }
+            let total: f32 = components.iter().map(|(_, _, fraction)| fraction).sum();
+            // all() rather than any(< 0) so a NaN fraction fails too
+            if !components.iter().all(|(_, _, fraction)| *fraction >= 0.0)
+                || (total - 1.0).abs() > 1e-3
+            {
+                problems.push(format!(
+                    "{} mass_fraction of the bulge, disk and halo must be at least 0 and add up \
+                     to 1, not {} + {} + {}",
+                    name,
+                    galaxy.bulge.mass_fraction,
+                    galaxy.disk.mass_fraction,
+                    galaxy.halo.mass_fraction
+                ));
+            }
+        }
+        InitialConditions::Tabulated(tabulated) => {
+            if tabulated.count == 0 {
+                problems.push("initial_conditions.tabulated.count must be at least 1".to_string());
+            }
+            if !(tabulated.virial_ratio >= 0.0 && tabulated.virial_ratio.is_finite()) {
+                problems.push(format!(
+                    "initial_conditions.tabulated.virial_ratio can't be negative, not {}",
+                    tabulated.virial_ratio
+                ));
+            }
+        }
+    }
+    problems
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GalaxySettings {
+    pub total_mass: f32,
+    pub bulge: SpheroidSettings,
+    pub disk: DiskSettings,
+    pub halo: SpheroidSettings,
+}
+
+/// Hernquist sphere, sampled out to `truncation_radius`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SpheroidSettings {
+    pub count: usize,
+    pub mass_fraction: f32,
+    pub scale_radius: f32,
+    pub truncation_radius: f32,
+}
+
+/// Exponential disk in the xy plane with a sech² vertical profile.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DiskSettings {
+    pub count: usize,
+    pub mass_fraction: f32,
+    pub scale_length: f32,
+    pub scale_height: f32,
+}
+
+impl Default for GalaxySettings {
+    fn default() -> Self {
+        GalaxySettings {
+            total_mass: 1.2e7,
+            bulge: SpheroidSettings {
+                count: 2000,
+                mass_fraction: 0.1,
+                scale_radius: 2.0,
+                truncation_radius: 30.0,
+            },
+            disk: DiskSettings {
+                count: 6000,
+                mass_fraction: 0.2,
+                scale_length: 10.0,
+                scale_height: 1.0,
+            },
+            halo: SpheroidSettings {
+                count: 4000,
+                mass_fraction: 0.7,
+                scale_radius: 40.0,
+                truncation_radius: 200.0,
+            },
+        }
+    }
+}
+
+impl GalaxySettings {
+    pub fn particle_count(&self) -> usize {
+        self.bulge.count + self.disk.count + self.halo.count
+    }
+
+    /// Mass of all three components enclosed within radius `r`.
+    ///
+    /// The disk is treated as spherically distributed, which is plenty for setting up circular speeds.
+    fn enclosed_mass(&self, r: f32) -> f32 {
+        self.bulge.enclosed_mass(self.bulge_mass(), r)
+            + exponential_disk_enclosed(self.disk_mass(), self.disk.scale_length, r)
+            + self.halo.enclosed_mass(self.halo_mass(), r)
+    }
+
+    fn bulge_mass(&self) -> f32 {
+        self.total_mass * self.bulge.mass_fraction
+    }
+
+    fn disk_mass(&self) -> f32 {
+        self.total_mass * self.disk.mass_fraction
+    }
+
+    fn halo_mass(&self) -> f32 {
+        self.total_mass * self.halo.mass_fraction
+    }
+}
+
+impl SpheroidSettings {
+    /// Mass within radius `r` of a sphere of `mass`, all of it sampled inside the truncation
+    /// radius.
+    fn enclosed_mass(&self, mass: f32, r: f32) -> f32 {
+        let a = self.scale_radius;
+        let r = r.min(self.truncation_radius);
+        mass * hernquist_enclosed(1.0, a, r) / hernquist_enclosed(1.0, a, self.truncation_radius)
+    }
+}
+
+fn hernquist_enclosed(mass: f32, a: f32, r: f32) -> f32 {
+    mass * r * r / ((r + a) * (r + a))
+}
+
+fn exponential_disk_enclosed(mass: f32, scale: f32, r: f32) -> f32 {
+    let x = r / scale;
+    mass * (1.0 - (1.0 + x) * (-x).exp())
+}
+
+/// Builds the bulge + disk + halo composite, tagging each particle with its component group.
+pub fn galaxy(galaxy: &GalaxySettings, g_const: f32, rng: &mut impl Rng) -> Vec<Particle> {
+    let mut particles = Vec::with_capacity(galaxy.particle_count());
+
+    let circular_speed = |r: f32| (g_const * galaxy.enclosed_mass(r) / r.max(1e-3)).sqrt();
+
+    // spheroids get isotropic velocities with an isothermal-like dispersion
+    for (spheroid, mass, group) in [
+        (&galaxy.bulge, galaxy.bulge_mass(), GROUP_BULGE),
+        (&galaxy.halo, galaxy.halo_mass(), GROUP_HALO),
+    ] {
+        let particle_mass = mass / spheroid.count.max(1) as f32;
+        let a = spheroid.scale_radius;
+        let u_max = hernquist_enclosed(1.0, a, spheroid.truncation_radius);
+
+        for _ in 0..spheroid.count {
+            // invert M(<r)/M = r²/(r+a)²
+            let s = (rng.random::<f32>() * u_max).sqrt();
+            let r = a * s / (1.0 - s);
+            let pos = random_direction(rng) * r;
+
+            let sigma = circular_speed(r) / std::f32::consts::SQRT_2;
+            let vel = Vec3::new(gaussian(rng), gaussian(rng), gaussian(rng)) * sigma;
+
+            particles.push(Particle::new(particle_mass, pos, vel, Vec3::ZERO).with_group(group));
+        }
+    }
+
+    // disk is cold and rotates about +z at the local circular speed
+    let disk = &galaxy.disk;
+    let particle_mass = galaxy.disk_mass() / disk.count.max(1) as f32;
+    for _ in 0..disk.count {
+        // R e^(-R/Rd) is a gamma(2) distribution, ie. the sum of two exponentials
+        let radius =
+            -disk.scale_length * (rng.random::<f32>() * rng.random::<f32>()).max(1e-12).ln();
+        let theta = rng.random::<f32>() * 2.0 * std::f32::consts::PI;
+        let z = disk.scale_height
+            * (rng.random::<f32>() * 2.0 - 1.0)
+                .clamp(-0.999, 0.999)
+                .atanh();
+
+        let pos = Vec3::new(radius * theta.cos(), radius * theta.sin(), z);
+        let tangent = Vec3::new(-theta.sin(), theta.cos(), 0.0);
+        let vel = tangent * circular_speed(pos.length());
+
+        particles.push(Particle::new(particle_mass, pos, vel, Vec3::ZERO).with_group(GROUP_DISK));
+    }
+
+    particles
+}
+
+/// Subtracts the mass-weighted mean velocity, returning the velocity that was removed.
+pub fn zero_net_momentum(particles: &mut [Particle]) -> Vec3 {
+    let total_mass: f32 = particles.iter().map(Particle::mass).sum();
+    let momentum: Vec3 = particles.iter().map(Particle::momentum).sum();
+    let mean_vel = momentum / total_mass;
+
+    for particle in particles.iter_mut() {
+        *particle = particle.with_vel(particle.vel() - mean_vel);
+    }
+    mean_vel
+}
+
+/// Removes net angular momentum about the center of mass by applying a rigid counter-rotation.
+///
+/// Solves `I ω = L` with the inertia tensor about the center of mass and subtracts `ω × r` from
+/// every velocity. Returns the angular velocity that was removed.
+pub fn zero_net_angular_momentum(particles: &mut [Particle]) -> Vec3 {
+    let total_mass: f32 = particles.iter().map(Particle::mass).sum();
+    let com: Vec3 = particles.iter().map(|p| p.pos() * p.mass()).sum::<Vec3>() / total_mass;
+
+    let mut angular_momentum = Vec3::ZERO;
+    let mut inertia = Mat3::ZERO;
+    for particle in particles.iter() {
+        let r = particle.pos() - com;
+        angular_momentum += particle.mass() * r.cross(particle.vel());
+        inertia += (Mat3::IDENTITY * r.length_squared()
+            - Mat3::from_cols(r * r.x, r * r.y, r * r.z))
+            * particle.mass();
+    }
+
+    // degenerate (eg. colinear) setups can't be spun rigidly
+    if inertia.determinant().abs() <= f32::EPSILON {
+        return Vec3::ZERO;
+    }
+    let omega = inertia.inverse() * angular_momentum;
+
+    for particle in particles.iter_mut() {
+        *particle = particle.with_vel(particle.vel() - omega.cross(particle.pos() - com));
+    }
+    omega
+}
+
+/// Spherical profile from a `r, rho[, sigma]` CSV table.
+///
+/// Without a sigma column, velocities are isotropic and scaled so that `K / |W| = virial_ratio`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TabulatedSettings {
+    pub path: PathBuf,
+    pub count: usize,
+    #[serde(default = "default_virial_ratio")]
+    pub virial_ratio: f32,
+}
+
+fn default_virial_ratio() -> f32 {
+    0.5
+}
+
+/// Rows of a density table, sorted by radius, with the cumulative mass at each row.
+///
+/// The profile only covers the table's range: the mass starts from zero at the first row, and
+/// radii outside the rows are an error rather than extrapolated.
+struct DensityTable {
+    radius: Vec<f32>,
+    sigma: Option<Vec<f32>>,
+    enclosed_mass: Vec<f32>,
+}
+
+impl DensityTable {
+    fn load(path: &Path) -> Result<DensityTable, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+
+        let mut rows: Vec<Vec<f32>> = Vec::new();
+        for (line_num, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values: Result<Vec<f32>, _> =
+                line.split(',').map(|v| v.trim().parse::<f32>()).collect();
+            match values {
+                Ok(values) => rows.push(values),
+                // tolerate a single header row
+                Err(_) if rows.is_empty() && line_num == 0 => continue,
+                Err(e) => return Err(format!("line {}: {}", line_num + 1, e)),
+            }
+        }
+
+        if rows.len() < 2 {
+            return Err("need at least two rows".to_string());
+        }
+        let columns = rows[0].len();
+        if !(2..=3).contains(&columns) || rows.iter().any(|row| row.len() != columns) {
+            return Err("every row must have 2 (r, rho) or 3 (r, rho, sigma) columns".to_string());
+        }
+        if rows
+            .iter()
+            .any(|row| row.iter().any(|v| *v <= 0.0 || !v.is_finite()))
+        {
+            return Err("all values must be positive for log-log interpolation".to_string());
+        }
+        if rows.windows(2).any(|pair| pair[1][0] <= pair[0][0]) {
+            return Err("radii must be strictly increasing".to_string());
+        }
+
+        let radius: Vec<f32> = rows.iter().map(|row| row[0]).collect();
+        let density: Vec<f32> = rows.iter().map(|row| row[1]).collect();
+        let sigma = (columns == 3).then(|| rows.iter().map(|row| row[2]).collect());
+
+        // dM/dr = 4 pi r^2 rho by the trapezoidal rule, summed in f64
+        let integrand =
+            |i: usize| 4.0 * std::f64::consts::PI * (radius[i] as f64).powi(2) * density[i] as f64;
+        let mut mass = 0.0;
+        let mut enclosed_mass = vec![0.0];
+        for i in 0..radius.len() - 1 {
+            mass += 0.5 * (integrand(i) + integrand(i + 1)) * (radius[i + 1] - radius[i]) as f64;
+            enclosed_mass.push(mass as f32);
+        }
+
+        Ok(DensityTable {
+            radius,
+            sigma,
+            enclosed_mass,
+        })
+    }
+
+    fn total_mass(&self) -> f32 {
+        *self.enclosed_mass.last().unwrap()
+    }
+
+    /// Radius enclosing `mass`, the inverse of `enclosed_mass_at`
+    fn radius_enclosing(&self, mass: f32) -> Result<f32, String> {
+        if !(0.0..=self.total_mass()).contains(&mass) {
+            return Err(format!(
+                "mass {} is outside the table's [0, {}]",
+                mass,
+                self.total_mass()
+            ));
+        }
+        let i = self
+            .enclosed_mass
+            .partition_point(|m| *m < mass)
+            .clamp(1, self.radius.len() - 1);
+        let (m0, m1) = (self.enclosed_mass[i - 1], self.enclosed_mass[i]);
+        let (r0, r1) = (self.radius[i - 1], self.radius[i]);
+        if m1 <= m0 {
+            return Ok(r0);
+        }
+        // no log of the first row's zero mass, that segment is linear
+        if m0 == 0.0 {
+            return Ok(r0 + (mass / m1) * (r1 - r0));
+        }
+        let t = ((mass / m0).ln() / (m1 / m0).ln()).clamp(0.0, 1.0);
+        Ok((r0.ln() + t * (r1 / r0).ln()).exp())
+    }
+
+    /// M(<r) counted from the first row, log-log between rows
+    fn enclosed_mass_at(&self, r: f32) -> Result<f32, String> {
+        let i = self.segment(r)?;
+        if i == 0 {
+            let t = (r - self.radius[0]) / (self.radius[1] - self.radius[0]);
+            return Ok(t * self.enclosed_mass[1]);
+        }
+        self.log_log(&self.enclosed_mass, r)
+    }
+
+    /// The sigma column at `r`
+    fn sigma_at(&self, r: f32) -> Result<Option<f32>, String> {
+        match &self.sigma {
+            Some(sigma) => Ok(Some(self.log_log(sigma, r)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn log_log(&self, values: &[f32], r: f32) -> Result<f32, String> {
+        let i = self.segment(r)?;
+        let t = (r.ln() - self.radius[i].ln()) / (self.radius[i + 1].ln() - self.radius[i].ln());
+        Ok((values[i].ln() + t * (values[i + 1].ln() - values[i].ln())).exp())
+    }
+
+    /// Index of the row starting the segment containing `r`.
+    fn segment(&self, r: f32) -> Result<usize, String> {
+        let (first, last) = (self.radius[0], *self.radius.last().unwrap());
+        if !(first..=last).contains(&r) {
+            return Err(format!(
+                "radius {} is outside the table range [{}, {}]",
+                r, first, last
+            ));
+        }
+        Ok(self
+            .radius
+            .partition_point(|row| *row <= r)
+            .clamp(1, self.radius.len() - 1)
+            - 1)
+    }
+
+    /// Potential energy of the tabulated profile, -G ∫ M(r)/r dM.
+    fn potential_energy(&self, g_const: f32) -> f32 {
+        let integrand = |i: usize| self.enclosed_mass[i] / self.radius[i];
+        let mut energy = 0.0;
+        for i in 1..self.radius.len() {
+            let dm = self.enclosed_mass[i] - self.enclosed_mass[i - 1];
+            energy -= g_const * 0.5 * (integrand(i - 1) + integrand(i)) * dm;
+        }
+        energy
+    }
+}
+
+/// Samples `count` particles from a tabulated density profile by inverse transform of M(<r).
+pub fn tabulated(
+    tabulated: &TabulatedSettings,
+    g_const: f32,
+    rng: &mut impl Rng,
+) -> Result<Vec<Particle>, String> {
+    let table = DensityTable::load(&tabulated.path)
+        .map_err(|e| format!("density table {}: {}", tabulated.path.display(), e))?;
+    let total_mass = table.total_mass();
+    let particle_mass = total_mass / tabulated.count.max(1) as f32;
+
+    let mut particles = Vec::with_capacity(tabulated.count);
+    for _ in 0..tabulated.count {
+        let r = table.radius_enclosing(rng.random::<f32>() * total_mass)?;
+        let pos = random_direction(rng) * r;
+
+        let sigma = match table.sigma_at(r)? {
+            Some(sigma) => sigma,
+            // local isothermal estimate, rescaled to the virial ratio below
+            None => (g_const * table.enclosed_mass_at(r)? / (3.0 * r)).sqrt(),
+        };
+        let vel = Vec3::new(gaussian(rng), gaussian(rng), gaussian(rng)) * sigma;
+
+        particles.push(Particle::new(particle_mass, pos, vel, Vec3::ZERO));
+    }
+
+    if table.sigma.is_none() {
+        let kinetic: f32 = particles.iter().map(Particle::kinetic_energy).sum();
+        let target = tabulated.virial_ratio * table.potential_energy(g_const).abs();
+        if kinetic > 0.0 {
+            let scale = (target / kinetic).sqrt();
+            for particle in particles.iter_mut() {
+                *particle = particle.with_vel(particle.vel() * scale);
+            }
+        }
+    }
+
+    Ok(particles)
+}
+
+fn random_direction(rng: &mut impl Rng) -> Vec3 {
+    let theta = rng.random::<f32>() * 2.0 * std::f32::consts::PI;
+    let phi = (rng.random::<f32>() * 2.0 - 1.0).acos();
+    Vec3::new(phi.sin() * theta.cos(), phi.sin() * theta.sin(), phi.cos())
+}
+
+/// Standard normal sample via Box-Muller.
+fn gaussian(rng: &mut impl Rng) -> f32 {
+    let u1 = rng.random::<f32>().max(f32::MIN_POSITIVE);
+    let u2 = rng.random::<f32>();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    /// `density` tabulated at log-spaced radii from 0.01 to 100, written to `name` in the temp
+    /// directory
+    fn table(name: &str, density: impl Fn(f64) -> f64) -> DensityTable {
+        let rows: String = (0..=80)
+            .map(|i| {
+                let r = 10f64.powf(-2.0 + i as f64 / 20.0);
+                format!("{},{}\n", r, density(r))
+            })
+            .collect();
+        let path = std::env::temp_dir().join(format!("gravity-output-{}.csv", name));
+        std::fs::write(&path, format!("r,rho\n{}", rows)).unwrap();
+        let table = DensityTable::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        table
+    }
+
+    /// Checks `table` against the analytic M(<r), less the mass inside its first row
+    fn assert_matches(table: &DensityTable, enclosed: impl Fn(f64) -> f64) {
+        let inside = enclosed(0.01);
+        for r in [0.02, 0.05, 0.3, 1.0, 4.0, 20.0, 100.0] {
+            let expected = enclosed(r) - inside;
+            let mass = table.enclosed_mass_at(r as f32).unwrap() as f64;
+            assert!(
+                (mass / expected - 1.0).abs() < 0.01,
+                "M({}) = {}, not {}",
+                r,
+                mass,
+                expected
+            );
+            // compared in mass, where M(r) is flat a small error in it is a large one in r
+            let radius = table.radius_enclosing(expected as f32).unwrap() as f64;
+            assert!(
+                ((enclosed(radius) - inside) / expected - 1.0).abs() < 0.01,
+                "r(M = {}) = {}, not {}",
+                expected,
+                radius,
+                r
+            );
+        }
+    }
+
+    fn default_galaxy() -> (GalaxySettings, Vec<Particle>) {
+        let settings = GalaxySettings::default();
+        let particles = galaxy(&settings, 0.01, &mut StdRng::seed_from_u64(5));
+        (settings, particles)
+    }
+
+    /// Mass of the particles within `r` of the origin
+    fn sampled_enclosed_mass(particles: &[Particle], r: f32) -> f32 {
+        particles
+            .iter()
+            .filter(|particle| particle.pos().length() < r)
+            .map(Particle::mass)
+            .sum()
+    }
+
+    #[test]
+    fn galaxy_samples_enclose_the_model_mass() {
+        let (settings, particles) = default_galaxy();
+        assert_eq!(particles.len(), settings.particle_count());
+        let total: f32 = particles.iter().map(Particle::mass).sum();
+        assert!((total / settings.total_mass - 1.0).abs() < 1e-4);
+
+        // from the edge of the bulge out to the halo's truncation radius, each with a few
+        // thousand particles inside. The disk is thin, so its spherical M(<r) is close to the
+        // cylindrical one in the model.
+        for r in [5.0, 10.0, 20.0, 40.0, 100.0, 200.0] {
+            let sampled = sampled_enclosed_mass(&particles, r);
+            let expected = settings.enclosed_mass(r);
+            assert!(
+                (sampled / expected - 1.0).abs() < 0.03,
+                "M({}) = {}, not {}",
+                r,
+                sampled,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn galaxy_disk_rotation_curve_is_flat() {
+        let (_, particles) = default_galaxy();
+        let g_const = 0.01;
+        let disk: Vec<&Particle> = particles
+            .iter()
+            .filter(|particle| particle.group() == GROUP_DISK)
+            .collect();
+
+        // mean rotation speed about +z in 5 unit wide rings, from half a scale length out to three
+        let mut speeds = Vec::new();
+        for inner in [5.0, 10.0, 15.0, 20.0, 25.0] {
+            let ring: Vec<f32> = disk
+                .iter()
+                .filter(|particle| {
+                    (inner..inner + 5.0).contains(&particle.pos().truncate().length())
+                })
+                .map(|particle| {
+                    let radial = particle.pos().truncate().normalize();
+                    radial.perp_dot(particle.vel().truncate())
+                })
+                .collect();
+            assert!(ring.len() > 100, "{} particles at {}", ring.len(), inner);
+            let speed = ring.iter().sum::<f32>() / ring.len() as f32;
+
+            // in orbit around what was actually sampled
+            let r = inner + 2.5;
+            let circular = (g_const * sampled_enclosed_mass(&particles, r) / r).sqrt();
+            assert!(
+                (speed / circular - 1.0).abs() < 0.05,
+                "v({}) = {}, circular speed {}",
+                r,
+                speed,
+                circular
+            );
+            speeds.push(speed);
+        }
+        let slowest = speeds.iter().copied().fold(f32::INFINITY, f32::min);
+        let fastest = speeds.iter().copied().fold(0.0, f32::max);
+        assert!(fastest / slowest < 1.1, "{:?}", speeds);
+    }
+
+    #[test]
+    fn tabulated_plummer_sphere_encloses_the_analytic_mass() {
+        // unit mass and scale radius
+        let table = table("plummer", |r| 3.0 / (4.0 * PI) * (1.0 + r * r).powf(-2.5));
+        assert_matches(&table, |r| r.powi(3) / (r * r + 1.0).powf(1.5));
+    }
+
+    #[test]
+    fn tabulated_hernquist_sphere_encloses_the_analytic_mass() {
+        // a 1 / r cusp, the density still rising steeply at the first row
+        let table = table("hernquist", |r| 1.0 / (2.0 * PI * r * (r + 1.0).powi(3)));
+        assert_matches(&table, |r| r * r / ((r + 1.0) * (r + 1.0)));
+        assert!((table.total_mass() - 0.9803).abs() < 0.005);
+    }
+
+    #[test]
+    fn radii_outside_the_table_are_rejected() {
+        let table = table("range", |r| (1.0 + r * r).powf(-2.5));
+        assert_eq!(table.enclosed_mass_at(0.01).unwrap(), 0.0);
+        assert!(table.enclosed_mass_at(0.005).is_err());
+        assert!(table.enclosed_mass_at(101.0).is_err());
+        assert!(table.radius_enclosing(table.total_mass() * 1.01).is_err());
+        assert!(table.radius_enclosing(-1.0).is_err());
+    }
+}
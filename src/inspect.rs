@@ -0,0 +1,175 @@
+use glam::Vec3;
+use gravity_output_format::{self as format, Batch};
+use std::path::Path;
+
+use super::manifest::Manifest;
+
+/// Summary of one frame's positions. Non-finite positions are counted but left out of the rest.
+struct FrameStats {
+    center: Vec3,
+    rms_radius: f32,
+    min: Vec3,
+    max: Vec3,
+    non_finite: usize,
+}
+
+impl FrameStats {
+    fn new(frame: &[Vec3]) -> FrameStats {
+        let finite: Vec<Vec3> = frame
+            .iter()
+            .copied()
+            .filter(|pos| pos.is_finite())
+            .collect();
+        let count = finite.len().max(1) as f32;
+        let center = finite.iter().sum::<Vec3>() / count;
+        let mean_square = finite
+            .iter()
+            .map(|pos| pos.distance_squared(center))
+            .sum::<f32>()
+            / count;
+        FrameStats {
+            center,
+            rms_radius: mean_square.sqrt(),
+            min: finite
+                .iter()
+                .copied()
+                .reduce(Vec3::min)
+                .unwrap_or(Vec3::ZERO),
+            max: finite
+                .iter()
+                .copied()
+                .reduce(Vec3::max)
+                .unwrap_or(Vec3::ZERO),
+            non_finite: frame.len() - finite.len(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        let mut line = format!(
+            "center {:.3}, rms radius {:.3}, extent {:.3}",
+            self.center,
+            self.rms_radius,
+            self.max - self.min
+        );
+        if self.non_finite > 0 {
+            line += &format!(", {} non-finite positions", self.non_finite);
+        }
+        line
+    }
+}
+
+/// `inspect`: print what's in a batch file, or in every batch file of an output directory
+pub fn inspect(path: &Path) -> Result<(), String> {
+    if path.is_dir() {
+        inspect_dir(path)
+    } else {
+        let batch = format::read_batch(path)?;
+        println!(
+            "{}: {} frames of {} particles",
+            path.display(),
+            batch.frames.len(),
+            batch.num_particles
+        );
+        if let Some(hash) = &batch.comment.settings_hash {
+            println!("  settings hash: {}", hash);
+        }
+        if let Some(units) = batch.comment.units {
+            println!("  units: {}", units);
+        }
+        print_frame_stats(&batch, "  ");
+        Ok(())
+    }
+}
+
+fn print_frame_stats(batch: &Batch, indent: &str) {
+    let last = batch.frames.len().saturating_sub(1);
+    for (index, frame) in batch.frames.iter().enumerate() {
+        if index == 0 || index == last {
+            println!(
+                "{}frame {}: {}",
+                indent,
+                index,
+                FrameStats::new(frame).describe()
+            );
+        }
+    }
+}
+
+fn inspect_dir(dir: &Path) -> Result<(), String> {
+    let manifest = std::fs::read_to_string(dir.join("manifest.json"))
+        .ok()
+        .and_then(|json| serde_json::from_str::<Manifest>(&json).ok());
+    match &manifest {
+        Some(manifest) => println!(
+            "{}: {} particles, {} frames in files of {}, run on {}",
+            dir.display(),
+            manifest.settings.num_particles,
+            manifest.settings.frames_total,
+            manifest.settings.frames_per_file,
+            manifest.backend
+        ),
+        None => println!("{}: no readable manifest.json", dir.display()),
+    }
+    if let Some(manifest) = &manifest {
+        if !manifest.settings_hash.is_empty() {
+            println!("  settings hash: {}", manifest.settings_hash);
+        }
+        if let Some(units) = manifest.units {
+            println!("  units: {}", units);
+        }
+    }
+
+    let batches = format::list_batches(dir)?;
+    let mut frames = 0;
+    let mut expected_batch = 0;
+    for (batch_num, path) in &batches {
+        if *batch_num != expected_batch {
+            println!("  batches {}..{} missing", expected_batch, batch_num);
+        }
+        expected_batch = batch_num + 1;
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        match format::read_batch(path) {
+            Ok(batch) => {
+                println!(
+                    "  {}: {} frames of {} particles",
+                    name,
+                    batch.frames.len(),
+                    batch.num_particles
+                );
+                print_frame_stats(&batch, "    ");
+                frames += batch.frames.len();
+            }
+            Err(e) => println!("  {}: unreadable, {}", name, e),
+        }
+    }
+
+    match &manifest {
+        Some(manifest) => println!(
+            "{} batch files, {} of {} frames",
+            batches.len(),
+            frames,
+            manifest.settings.frames_total
+        ),
+        None => println!("{} batch files, {} frames", batches.len(), frames),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_skip_non_finite_positions() {
+        let frame = [
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(-1.0, 0.0, 0.0),
+            Vec3::new(f32::NAN, 0.0, 0.0),
+        ];
+        let stats = FrameStats::new(&frame);
+        assert_eq!(stats.center, Vec3::ZERO);
+        assert_eq!(stats.rms_radius, 1.0);
+        assert_eq!(stats.max - stats.min, Vec3::new(2.0, 0.0, 0.0));
+        assert_eq!(stats.non_finite, 1);
+    }
+}
@@ -0,0 +1,40 @@
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::atomic::Ordering;
+#[cfg(not(target_arch = "wasm32"))]
+use tracing::warn;
+
+#[cfg(not(target_arch = "wasm32"))]
+use super::logging;
+#[cfg(not(target_arch = "wasm32"))]
+use super::simulation::EXIT_INTERRUPTED;
+
+/// Catch Ctrl-C, returning the flag it sets. The run checks the flag at frame boundaries and
+/// stops cleanly, a second Ctrl-C quits on the spot.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn install() -> Arc<AtomicBool> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+    let installed = ctrlc::set_handler(move || {
+        if flag.swap(true, Ordering::Relaxed) {
+            warn!("Interrupted again, quitting now");
+            logging::finish();
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        warn!("Interrupted, stopping after this frame. Ctrl-C again to quit now");
+    });
+    if let Err(e) = installed {
+        warn!(
+            "Could not catch Ctrl-C, it will stop the run mid-batch: {}",
+            e
+        );
+    }
+    interrupted
+}
+
+/// No Ctrl-C in the browser, the flag is never set
+#[cfg(target_arch = "wasm32")]
+pub fn install() -> Arc<AtomicBool> {
+    Arc::new(AtomicBool::new(false))
+}
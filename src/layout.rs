@@ -0,0 +1,251 @@
+use std::mem::{offset_of, size_of};
+
+use super::gpu::GpuParticle;
+
+/// Every GpuParticle field with the number of f32s in it, in declaration order. Adding a field
+/// to GpuParticle without listing it here fails to compile.
+const PARTICLE_FIELDS: [(&str, usize, usize); 6] = [
+    ("pos", offset_of!(GpuParticle, pos), 3),
+    ("mass", offset_of!(GpuParticle, mass), 1),
+    ("vel", offset_of!(GpuParticle, vel), 3),
+    ("_padding", offset_of!(GpuParticle, _padding), 1),
+    ("acc", offset_of!(GpuParticle, acc), 3),
+    ("_padding2", offset_of!(GpuParticle, _padding2), 1),
+];
+
+const _: () = {
+    let mut covered = 0;
+    let mut i = 0;
+    while i < PARTICLE_FIELDS.len() {
+        covered += PARTICLE_FIELDS[i].2 * 4;
+        i += 1;
+    }
+    assert!(
+        covered == size_of::<GpuParticle>(),
+        "PARTICLE_FIELDS doesn't cover every byte of GpuParticle"
+    );
+};
+
+const MISMATCH: &str =
+    "GpuParticle doesn't match the Particle struct in nbody.wgsl, update both together:";
+
+/// Particles the check shader writes, the second one shows the array stride
+const WRITTEN: usize = 2;
+
+/// Room after the written particles to catch a WGSL struct bigger than GpuParticle
+const BUFFER_PARTICLES: usize = 4;
+
+/// Value the check shader writes to f32 slot `slot` of particle `particle`, as GpuParticle lays
+/// them out. Never zero, so unwritten bytes stand out.
+fn sentinel(particle: usize, slot: usize) -> f32 {
+    (particle * 100 + slot + 1) as f32
+}
+
+/// The `struct Particle { ... }` declaration out of a shader
+fn particle_struct(shader: &str) -> Option<&str> {
+    let start = shader.find("struct Particle {")?;
+    let len = shader[start..].find('}')? + 1;
+    Some(&shader[start..start + len])
+}
+
+/// Check that the Particle struct nbody.wgsl reads and writes has GpuParticle's size and field
+/// offsets, so a field added on one side only fails here instead of producing wrong forces.
+pub async fn check_particle_layout(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+) -> Result<(), String> {
+    let wgsl_struct =
+        particle_struct(include_str!("nbody.wgsl")).ok_or("nbody.wgsl has no Particle struct")?;
+    check_layout(device, queue, wgsl_struct).await
+}
+
+/// Write sentinels into every field of `wgsl_struct` on the device and compare the bytes with
+/// where GpuParticle expects them.
+async fn check_layout(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    wgsl_struct: &str,
+) -> Result<(), String> {
+    let mut body = String::new();
+    for particle in 0..WRITTEN {
+        body += &format!("    var p{}: Particle;\n", particle);
+        for (name, offset, len) in PARTICLE_FIELDS {
+            let values: Vec<String> = (0..len)
+                .map(|i| format!("{:?}", sentinel(particle, offset / 4 + i)))
+                .collect();
+            let value = match len {
+                1 => values[0].clone(),
+                _ => format!("vec{}<f32>({})", len, values.join(", ")),
+            };
+            body += &format!("    p{}.{} = {};\n", particle, name, value);
+        }
+        body += &format!("    out[{0}] = p{0};\n", particle);
+    }
+    let source = format!(
+        "{}\n@group(0) @binding(0) var<storage, read_write> out: array<Particle>;\n\n\
+         @compute @workgroup_size(1)\nfn main() {{\n{}}}\n",
+        wgsl_struct, body
+    );
+
+    let size = (BUFFER_PARTICLES * size_of::<GpuParticle>()) as u64;
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("Particle Layout Check"),
+        source: wgpu::ShaderSource::Wgsl(source.into()),
+    });
+    let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some("Particle Layout Check"),
+        layout: None,
+        module: &shader,
+        entry_point: Some("main"),
+        compilation_options: Default::default(),
+        cache: None,
+    });
+    let output = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Particle Layout Check"),
+        size,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    });
+    let staging = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Particle Layout Staging"),
+        size,
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: None,
+        layout: &pipeline.get_bind_group_layout(0),
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: output.as_entire_binding(),
+        }],
+    });
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Particle Layout Check"),
+    });
+    {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor::default());
+        pass.set_pipeline(&pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.dispatch_workgroups(1, 1, 1);
+    }
+    encoder.copy_buffer_to_buffer(&output, 0, &staging, 0, size);
+    queue.submit(Some(encoder.finish()));
+    // assigning a field the WGSL struct lacks or has as another type doesn't compile
+    if let Some(error) = device.pop_error_scope().await {
+        return Err(format!("{}\n{}", MISMATCH, error));
+    }
+
+    let (sender, mapped) = futures::channel::oneshot::channel();
+    staging
+        .slice(..)
+        .map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+    // a browser maps the buffer on its own once control is back with it, there this returns
+    // straight away
+    device
+        .poll(wgpu::wgt::PollType::Wait)
+        .map_err(|e| format!("particle layout check failed to run: {}", e))?;
+    mapped
+        .await
+        .unwrap_or(Err(wgpu::BufferAsyncError))
+        .map_err(|e| format!("particle layout check failed to read back: {}", e))?;
+    let written: Vec<f32> = bytemuck::cast_slice(&staging.slice(..).get_mapped_range()).to_vec();
+    staging.unmap();
+
+    let expected: Vec<f32> = (0..BUFFER_PARTICLES * size_of::<GpuParticle>() / 4)
+        .map(|slot| {
+            let particle = slot / (size_of::<GpuParticle>() / 4);
+            if particle < WRITTEN {
+                sentinel(particle, slot % (size_of::<GpuParticle>() / 4))
+            } else {
+                0.0
+            }
+        })
+        .collect();
+    if written == expected {
+        return Ok(());
+    }
+    Err(describe_mismatch(&written))
+}
+
+/// Where the shader put each field compared with GpuParticle
+fn describe_mismatch(written: &[f32]) -> String {
+    let byte_offset = |value: f32| written.iter().position(|&v| v == value).map(|i| i * 4);
+    let mut lines = vec![MISMATCH.to_string()];
+    for (name, offset, _) in PARTICLE_FIELDS {
+        match byte_offset(sentinel(0, offset / 4)) {
+            Some(wgsl_offset) if wgsl_offset == offset => {}
+            Some(wgsl_offset) => lines.push(format!(
+                "  {}: byte {} in GpuParticle, byte {} in WGSL",
+                name, offset, wgsl_offset
+            )),
+            None => lines.push(format!(
+                "  {}: byte {} in GpuParticle, missing or a different type in WGSL",
+                name, offset
+            )),
+        }
+    }
+    match byte_offset(sentinel(1, 0)) {
+        Some(stride) if stride == size_of::<GpuParticle>() => {}
+        Some(stride) => lines.push(format!(
+            "  size: {} bytes in GpuParticle, {} bytes in WGSL",
+            size_of::<GpuParticle>(),
+            stride
+        )),
+        None => lines.push(format!(
+            "  size: {} bytes in GpuParticle, more than {} bytes in WGSL",
+            size_of::<GpuParticle>(),
+            size_of::<GpuParticle>() * BUFFER_PARTICLES / WRITTEN
+        )),
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GpuCompute;
+    use crate::settings::Settings;
+
+    fn test_gpu() -> Option<GpuCompute> {
+        let mut settings = Settings::default();
+        settings.adapter.allow_software_adapter = true;
+        settings.num_particles = 16;
+        match pollster::block_on(GpuCompute::new(&settings)) {
+            Ok(gpu) => Some(gpu),
+            Err(e) => {
+                println!("skipping, no GPU: {}", e);
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn shader_particle_matches_gpu_particle() {
+        let Some(gpu) = test_gpu() else { return };
+        pollster::block_on(check_particle_layout(&gpu.device, &gpu.queue)).unwrap();
+    }
+
+    #[test]
+    fn reordered_fields_are_reported() {
+        let Some(gpu) = test_gpu() else { return };
+        let swapped = particle_struct(include_str!("nbody.wgsl"))
+            .unwrap()
+            .replace(
+                "    vel: vec3<f32>,\n    _padding: f32,",
+                "    _padding: f32,\n    vel: vec3<f32>,",
+            );
+        let error =
+            pollster::block_on(check_layout(&gpu.device, &gpu.queue, &swapped)).unwrap_err();
+        println!("{}", error);
+        // vec3 aligns to 16 bytes, so the padding moving in front pushes vel and everything
+        // after it back
+        assert!(error.contains("_padding: byte 28 in GpuParticle, byte 16 in WGSL"));
+        assert!(error.contains("vel: byte 16 in GpuParticle, byte 32 in WGSL"));
+        assert!(error.contains("size: 48 bytes in GpuParticle, 64 bytes in WGSL"));
+    }
+}
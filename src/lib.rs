@@ -0,0 +1,74 @@
+//! N-body gravity on the GPU, writing every frame's particle positions to gzipped batch files.
+//!
+//! The `gravity-output` binary parses its command line and hands it to `simulation::run`. The
+//! pieces it runs on are public for other drivers: `Simulation` to step a run's particles,
+//! `settings` for loading and validating one, `particle` and `gpu` for the state and the force
+//! passes, and `output` for writing the batch files. Their layout is in the
+//! `gravity-output-format` crate, which reads them back without pulling in the simulation.
+//!
+//! `gpu` and everything on top of wgpu is behind the default `gpu` feature. Without it only the
+//! CPU force backend is built. The `preview` feature adds the `run --preview` window,
+//! `status-server` the HTTP status of `status_address` and `live-stream` the WebSocket frames
+//! of `stream_address`.
+
+mod adapter;
+#[cfg(feature = "gpu")]
+mod autotune;
+mod backend;
+mod checkpoint;
+pub mod cli;
+pub mod convert;
+mod diagnostics;
+pub mod error;
+#[cfg(feature = "gpu")]
+pub mod gpu;
+mod grid;
+mod heartbeat;
+pub mod initial_conditions;
+pub mod inspect;
+mod interrupt;
+#[cfg(feature = "gpu")]
+mod layout;
+#[cfg(feature = "live-stream")]
+mod live;
+pub mod logging;
+mod manifest;
+mod memory;
+mod nonfinite;
+pub mod observer;
+pub mod output;
+pub mod particle;
+#[cfg(feature = "gpu")]
+mod pipeline_cache;
+#[cfg(feature = "preview")]
+mod preview;
+pub mod progress;
+mod reload;
+mod resume;
+pub mod schedule;
+pub mod settings;
+pub mod simulation;
+mod status;
+#[cfg(feature = "gpu")]
+mod streaming;
+mod summary;
+#[cfg(test)]
+mod testing;
+mod timings;
+mod tracked;
+mod tree;
+mod units;
+pub mod wizard;
+mod writer;
+
+pub use backend::{CpuBackend, ForceBackend, ForceBackendKind, Forces};
+pub use diagnostics::Energy;
+pub use error::Error;
+#[cfg(feature = "gpu")]
+pub use gpu::{GpuCompute, GpuDevice, GpuParticle};
+pub use memory::{CountingAllocator, HostMemoryPlan};
+pub use observer::{BatchReport, FrameObserver, FrameSink};
+pub use particle::{Particle, ParticleSet};
+pub use settings::Settings;
+pub use simulation::{Simulation, Snapshot};
+pub use timings::BatchTimings;
@@ -0,0 +1,325 @@
+use glam::Vec3;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use tungstenite::{Bytes, Message};
+
+use super::error::Error;
+use super::observer::{BatchReport, FrameObserver};
+use super::settings::Settings;
+
+/// "GRVL", the first bytes of every message
+pub const MESSAGE_MAGIC: [u8; 4] = *b"GRVL";
+/// Bumped when the layout below changes
+pub const MESSAGE_VERSION: u32 = 1;
+/// Bytes before a message's positions
+pub const MESSAGE_HEADER_SIZE: usize = 32;
+
+/// Clients being sent frames, dropped once their thread has gone
+type Clients = Arc<Mutex<Vec<SyncSender<Bytes>>>>;
+
+/// `stream_address`: a WebSocket server pushing every `stream_every`-th frame to whoever's
+/// connected, as one binary message per frame. All little endian:
+///
+/// | bytes | field |
+/// |-------|-------|
+/// | 0..4 | `MESSAGE_MAGIC` |
+/// | 4..8 | `MESSAGE_VERSION`, u32 |
+/// | 8..16 | frame index, u64 |
+/// | 16..24 | simulated time, f64 |
+/// | 24..28 | particles in the message, u32 |
+/// | 28..32 | `stream_stride`, u32 |
+/// | 32.. | f32 x, y, z of each particle |
+///
+/// The particles are the ones with ids 0, stride, 2 * stride... in id order, the same ones in
+/// every message. A client that falls more than a batch behind misses frames, the simulation
+/// never waits for one.
+pub struct LiveStream {
+    every: usize,
+    stride: usize,
+    clients: Clients,
+    /// Frames of the batch to send once its ids are known, only copied while someone's connected
+    pending: Vec<(usize, f64, Vec<Vec3>)>,
+    address: SocketAddr,
+    stop: Arc<AtomicBool>,
+    accept_thread: Option<JoinHandle<()>>,
+}
+
+impl LiveStream {
+    pub fn start(address: &str, settings: &Settings) -> Result<LiveStream, Error> {
+        let could_not = |e: std::io::Error| {
+            Error::Command(format!("Could not stream frames on {}: {}", address, e))
+        };
+        let listener = TcpListener::bind(address).map_err(could_not)?;
+        // polled, so the thread notices the run ending
+        listener.set_nonblocking(true).map_err(could_not)?;
+        let address = listener.local_addr().map_err(could_not)?;
+        let clients = Clients::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        // frames come a batch at a time, a client keeping up gets all of a batch's
+        let queue = settings
+            .frames_per_file
+            .div_ceil(settings.stream_every)
+            .max(1);
+        let accept_thread = {
+            let clients = clients.clone();
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("live stream".to_string())
+                .spawn(move || accept(listener, clients, stop, queue))
+                .map_err(could_not)?
+        };
+        let stream = LiveStream {
+            every: settings.stream_every,
+            stride: settings.stream_stride,
+            clients,
+            pending: Vec::new(),
+            address,
+            stop,
+            accept_thread: Some(accept_thread),
+        };
+        info!("Streaming frames on ws://{}", stream.address());
+        Ok(stream)
+    }
+
+    /// Where it's listening, with the port picked when the address asked for port 0
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Queue `message` for every client, dropping it for the ones whose queue is full
+    fn broadcast(&self, message: Bytes) {
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|client| match client.try_send(message.clone()) {
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+}
+
+impl FrameObserver for LiveStream {
+    fn on_frame(
+        &mut self,
+        frame_index: usize,
+        time: f64,
+        positions: &[Vec3],
+        _velocities: Option<&[Vec3]>,
+    ) {
+        if !frame_index.is_multiple_of(self.every) || self.clients.lock().unwrap().is_empty() {
+            return;
+        }
+        self.pending.push((frame_index, time, positions.to_vec()));
+    }
+
+    fn wants_positions(&self, frame_index: usize) -> bool {
+        frame_index.is_multiple_of(self.every)
+    }
+
+    fn on_batch_complete(&mut self, batch: &BatchReport) {
+        for (frame, time, positions) in std::mem::take(&mut self.pending) {
+            let message = encode(frame, time, &positions, &batch.particles.id, self.stride);
+            self.broadcast(Bytes::from(message));
+        }
+    }
+
+    fn on_settings_changed(&mut self, settings: &Settings) {
+        self.every = settings.stream_every.max(1);
+    }
+}
+
+impl Drop for LiveStream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.accept_thread.take() {
+            let _ = thread.join();
+        }
+        // the client threads close their connections once their queue is gone
+        self.clients.lock().unwrap().clear();
+    }
+}
+
+/// One frame's message, `positions` in simulation order and put back in id order by `ids`
+pub fn encode(frame: usize, time: f64, positions: &[Vec3], ids: &[u32], stride: usize) -> Vec<u8> {
+    let mut streamed = vec![Vec3::ZERO; positions.len().div_ceil(stride)];
+    for (pos, &id) in positions.iter().zip(ids) {
+        let id = id as usize;
+        if id.is_multiple_of(stride) {
+            streamed[id / stride] = *pos;
+        }
+    }
+    let mut message = Vec::with_capacity(MESSAGE_HEADER_SIZE + streamed.len() * 12);
+    message.extend_from_slice(&MESSAGE_MAGIC);
+    message.extend_from_slice(&MESSAGE_VERSION.to_le_bytes());
+    message.extend_from_slice(&(frame as u64).to_le_bytes());
+    message.extend_from_slice(&time.to_le_bytes());
+    message.extend_from_slice(&(streamed.len() as u32).to_le_bytes());
+    message.extend_from_slice(&(stride as u32).to_le_bytes());
+    for pos in &streamed {
+        for value in pos.to_array() {
+            message.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    message
+}
+
+fn accept(listener: TcpListener, clients: Clients, stop: Arc<AtomicBool>, queue: usize) {
+    while !stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                let clients = clients.clone();
+                let spawned = std::thread::Builder::new()
+                    .name("live stream client".to_string())
+                    .spawn(move || serve(stream, peer, clients, queue));
+                if let Err(e) = spawned {
+                    warn!(
+                        "Could not start a thread for live stream client {}: {}",
+                        peer, e
+                    );
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(50))
+            }
+            Err(e) => {
+                warn!("Live stream: {}", e);
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+}
+
+/// Send one client its frames until either side goes away
+fn serve(stream: TcpStream, peer: SocketAddr, clients: Clients, queue: usize) {
+    // a client that never finishes the handshake shouldn't keep the thread forever
+    let handshake = stream
+        .set_nonblocking(false)
+        .and_then(|()| stream.set_read_timeout(Some(Duration::from_secs(5))));
+    if let Err(e) = handshake {
+        debug!("Live stream client {}: {}", peer, e);
+        return;
+    }
+    let mut socket = match tungstenite::accept(stream) {
+        Ok(socket) => socket,
+        Err(e) => {
+            debug!("Live stream client {} failed the handshake: {}", peer, e);
+            return;
+        }
+    };
+    // short, to notice a close between frames
+    if let Err(e) = socket
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_millis(10)))
+    {
+        debug!("Live stream client {}: {}", peer, e);
+        return;
+    }
+    let (sender, frames): (_, Receiver<Bytes>) = std::sync::mpsc::sync_channel(queue);
+    clients.lock().unwrap().push(sender);
+    info!("Live stream client {} connected", peer);
+    loop {
+        match frames.recv_timeout(Duration::from_millis(100)) {
+            Ok(frame) => {
+                if socket.send(Message::Binary(frame)).is_err() {
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                let _ = socket.close(None);
+                let _ = socket.flush();
+                break;
+            }
+        }
+        match socket.read() {
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(_) => break,
+        }
+    }
+    info!("Live stream client {} disconnected", peer);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_in_id_order_and_strided() {
+        let positions = [Vec3::splat(2.0), Vec3::splat(0.0), Vec3::splat(1.0)];
+        let ids = [2, 0, 1];
+        let message = encode(7, 0.5, &positions, &ids, 2);
+        assert_eq!(message.len(), MESSAGE_HEADER_SIZE + 2 * 12);
+        assert_eq!(message[..4], MESSAGE_MAGIC);
+        let u32_at = |at: usize| u32::from_le_bytes(message[at..at + 4].try_into().unwrap());
+        let f32_at = |at: usize| f32::from_le_bytes(message[at..at + 4].try_into().unwrap());
+        assert_eq!(u32_at(4), MESSAGE_VERSION);
+        assert_eq!(u64::from_le_bytes(message[8..16].try_into().unwrap()), 7);
+        assert_eq!(f64::from_le_bytes(message[16..24].try_into().unwrap()), 0.5);
+        assert_eq!((u32_at(24), u32_at(28)), (2, 2));
+        // ids 0 and 2
+        assert_eq!(f32_at(32), 0.0);
+        assert_eq!(f32_at(44), 2.0);
+    }
+
+    #[test]
+    fn clients_get_every_nth_frame_and_slow_ones_drop_frames() {
+        let settings = Settings {
+            frames_per_file: 4,
+            stream_every: 2,
+            ..Settings::default()
+        };
+        let mut stream = LiveStream::start("127.0.0.1:0", &settings).unwrap();
+        let (mut client, _) = tungstenite::connect(format!("ws://{}", stream.address())).unwrap();
+        // until the server thread has it
+        while stream.clients.lock().unwrap().is_empty() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let particles: crate::ParticleSet = (0..3)
+            .map(|id| crate::Particle::new_zero().with_id(id))
+            .collect();
+        let batch = |first_frame: usize, stream: &mut LiveStream| {
+            for frame in first_frame..first_frame + 4 {
+                stream.on_frame(frame, frame as f64, &[Vec3::splat(frame as f32); 3], None);
+            }
+            stream.on_batch_complete(&BatchReport {
+                batch: first_frame / 4,
+                first_frame,
+                frames: 4,
+                complete: true,
+                first_time: 0.0,
+                dt: 1.0,
+                energies: &[],
+                particles: &particles,
+                potential: None,
+                elapsed: Duration::ZERO,
+            });
+        };
+        // a client that never reads, as far as the simulation can tell
+        let (slow, slow_frames) = std::sync::mpsc::sync_channel(2);
+        stream.clients.lock().unwrap().push(slow);
+        let frame_of = |message: &[u8]| u64::from_le_bytes(message[8..16].try_into().unwrap());
+
+        let mut frames = Vec::new();
+        for first_frame in [0, 4] {
+            batch(first_frame, &mut stream);
+            while frames.len() < first_frame / 2 + 2 {
+                if let Message::Binary(message) = client.read().unwrap() {
+                    frames.push(frame_of(&message));
+                }
+            }
+        }
+        assert_eq!(frames, [0, 2, 4, 6]);
+        let slow_frames: Vec<u64> = slow_frames.try_iter().map(|m| frame_of(&m)).collect();
+        assert_eq!(slow_frames, [0, 2]);
+        drop(stream);
+    }
+}
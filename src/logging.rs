@@ -0,0 +1,222 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use tracing::level_filters::LevelFilter;
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::reload;
+
+use super::progress;
+
+/// Where `--log-file` lines go. The file usually lives in the output directory, which isn't
+/// known until the settings load, so lines are held in memory until it's opened.
+enum LogFile {
+    Buffered(Vec<u8>),
+    Open(File),
+    Failed,
+}
+
+static LOG_FILE: Mutex<LogFile> = Mutex::new(LogFile::Buffered(Vec::new()));
+/// `--log-file` as given
+static LOG_FILE_PATH: OnceLock<PathBuf> = OnceLock::new();
+/// Writer thread of the `--trace-file`, which finishes the file when dropped
+static TRACE_FILE: Mutex<Option<FlushGuard>> = Mutex::new(None);
+/// Swaps the level of the stdout and `--log-file` lines, see `set_level`
+static SET_LEVEL: OnceLock<Box<dyn Fn(Option<LogLevel>) + Send + Sync>> = OnceLock::new();
+/// Our warnings and errors so far, see `warnings`
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+/// `Settings::log_level`, the levels `--quiet` and `-v` pick between
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn filter(self) -> LevelFilter {
+        match self {
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// stdout, with the progress bar out of the way of each line
+struct StdoutWriter;
+
+impl Write for StdoutWriter {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        progress::suspend_bar(|| std::io::stdout().write_all(bytes))?;
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stdout().flush()
+    }
+}
+
+/// Counts the events it's let through into WARNINGS
+struct WarningCounter;
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for WarningCounter {
+    fn on_event(&self, _event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+        WARNINGS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+struct LogFileWriter;
+
+impl Write for LogFileWriter {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        match &mut *LOG_FILE.lock().unwrap() {
+            LogFile::Buffered(buffer) => buffer.extend_from_slice(bytes),
+            LogFile::Open(file) => file.write_all(bytes)?,
+            LogFile::Failed => {}
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut *LOG_FILE.lock().unwrap() {
+            LogFile::Open(file) => file.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Level for `--quiet` and each `-v`: warnings and errors only, info by default, then debug
+/// for per-frame details and trace
+pub fn level(quiet: bool, verbose: u8) -> LevelFilter {
+    match (quiet, verbose) {
+        (true, _) => LevelFilter::WARN,
+        (false, 0) => LevelFilter::INFO,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    }
+}
+
+/// Log timestamped lines to stdout at `level`, and JSON lines to `log_file` when given. wgpu
+/// and the other dependencies only get a say at trace level, their warnings about missing
+/// drivers and windowing systems are noise for a headless compute run.
+///
+/// With `trace_file`, every span is also recorded there in the Chrome trace format, for
+/// Perfetto or chrome://tracing, whatever `level` is. The spans are at trace level, so without
+/// one they're never even built below `-vv`. Call `finish` before exiting to complete the file.
+pub fn init(level: LevelFilter, log_file: Option<&Path>, trace_file: Option<&Path>) {
+    let json = log_file.map(|path| {
+        let _ = LOG_FILE_PATH.set(path.to_path_buf());
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_writer(|| LogFileWriter)
+    });
+    let (stdout_filter, stdout_level) = reload::Layer::new(targets(level));
+    let (json_filter, json_level) = reload::Layer::new(targets(level));
+    let _ = SET_LEVEL.set(Box::new(move |new_level: Option<LogLevel>| {
+        let new_level = new_level.map_or(level, LogLevel::filter);
+        let _ = stdout_level.reload(targets(new_level));
+        let _ = json_level.reload(targets(new_level));
+    }));
+    let chrome = trace_file.and_then(|path| match File::create(path) {
+        Ok(file) => {
+            let (layer, guard) = ChromeLayerBuilder::new()
+                .writer(file)
+                .include_args(true)
+                .build();
+            *TRACE_FILE.lock().unwrap() = Some(guard);
+            Some(layer.with_filter(
+                Targets::new().with_target(env!("CARGO_CRATE_NAME"), LevelFilter::TRACE),
+            ))
+        }
+        Err(e) => {
+            eprintln!("Could not create trace file {}: {}", path.display(), e);
+            None
+        }
+    });
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(|| StdoutWriter)
+                .with_target(false)
+                .with_ansi(std::io::stdout().is_terminal())
+                .with_filter(stdout_filter),
+        )
+        .with(json.with_filter(json_filter))
+        .with(chrome)
+        .with(
+            WarningCounter.with_filter(
+                Targets::new().with_target(env!("CARGO_CRATE_NAME"), LevelFilter::WARN),
+            ),
+        )
+        .init();
+}
+
+/// Override the level `init` was given, for `Settings::log_level`, or go back to it with None.
+/// The `--trace-file` keeps recording every span.
+pub fn set_level(level: Option<LogLevel>) {
+    if let Some(set_level) = SET_LEVEL.get() {
+        set_level(level);
+    }
+}
+
+/// Our lines at `level`, the dependencies' only at trace level
+fn targets(level: LevelFilter) -> Targets {
+    let dependencies = match level {
+        LevelFilter::TRACE => LevelFilter::TRACE,
+        _ => LevelFilter::OFF,
+    };
+    Targets::new()
+        .with_target(env!("CARGO_CRATE_NAME"), level)
+        .with_default(dependencies)
+}
+
+/// Warnings and errors we've logged since `init`, whatever the level shown. Always 0 without
+/// it.
+pub fn warnings() -> usize {
+    WARNINGS.load(Ordering::Relaxed)
+}
+
+/// Complete the `--trace-file`, before the process exits
+pub fn finish() {
+    drop(TRACE_FILE.lock().unwrap().take());
+}
+
+/// Open the `--log-file`, relative to `dir` unless it's absolute, and write out what was logged
+/// before it was known
+pub fn open_log_file(dir: &Path) {
+    let Some(path) = LOG_FILE_PATH.get() else {
+        return;
+    };
+    let path = dir.join(path);
+    let error = {
+        let mut log_file = LOG_FILE.lock().unwrap();
+        let LogFile::Buffered(buffer) = &*log_file else {
+            return;
+        };
+        let opened = File::create(&path).and_then(|mut file| {
+            file.write_all(buffer)?;
+            Ok(file)
+        });
+        match opened {
+            Ok(file) => {
+                *log_file = LogFile::Open(file);
+                return;
+            }
+            Err(e) => {
+                *log_file = LogFile::Failed;
+                e
+            }
+        }
+    };
+    tracing::warn!("Could not write log file {}: {}", path.display(), error);
+}
@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use super::adapter::AdapterSummary;
+use super::diagnostics::EnergyDrift;
+use super::settings::Settings;
+use super::units::UnitSystem;
+
+/// Record of how an output directory was produced, written to `manifest.json` next to the batches.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Manifest {
+    pub settings: Settings,
+    /// Where `settings` was loaded from, before command-line overrides
+    #[serde(default)]
+    pub settings_file: Option<PathBuf>,
+    /// Profile from the settings file that was applied, see `--profile`
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// `Settings::hash`, also in each batch file's header
+    #[serde(default)]
+    pub settings_hash: String,
+    /// Force backend that ran, eg. "GPU (...)" or "CPU (16 threads)"
+    pub backend: String,
+    pub adapter: Option<AdapterSummary>,
+    /// Physical units of the positions and times, when the settings give them
+    #[serde(default)]
+    pub units: Option<UnitSystem>,
+    /// "running", "complete", "interrupted at frame N" or "failed ..." saying why
+    #[serde(default)]
+    pub status: String,
+    /// Things that changed how the output was written partway through, eg. reaching
+    /// max_output_gb
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// With diagnostics, how far the total energy has drifted and whether it went past
+    /// energy_drift_warn
+    #[serde(default)]
+    pub energy_drift: Option<EnergyDrift>,
+    /// Ids of the tracked_particles, sample included, which a resumed run keeps tracking
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tracked_particles: Vec<u32>,
+}
+
+impl Manifest {
+    pub fn new(settings: &Settings) -> Manifest {
+        Manifest {
+            settings: settings.clone(),
+            settings_file: settings.settings_file.clone(),
+            profile: settings.profile.clone(),
+            settings_hash: settings.hash(),
+            backend: String::new(),
+            adapter: None,
+            units: settings
+                .units
+                .as_ref()
+                .and_then(|units| units.system().ok()),
+            status: "running".to_string(),
+            events: Vec::new(),
+            energy_drift: None,
+            tracked_particles: Vec::new(),
+        }
+    }
+
+    pub fn load(out_path: &Path) -> Result<Manifest, String> {
+        let path = out_path.join("manifest.json");
+        let json = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        serde_json::from_str(&json)
+            .map_err(|e| format!("Could not parse {}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, out_path: &Path) {
+        let path = out_path.join("manifest.json");
+        match serde_json::to_string_pretty(self) {
+            Ok(json) => {
+                if let Err(e) = write_atomic(&path, json.as_bytes()) {
+                    warn!("Could not write {}: {}", path.display(), e);
+                }
+            }
+            Err(e) => warn!("Could not serialize manifest: {}", e),
+        }
+    }
+}
+
+/// Write `bytes` to `path` through a temporary file next to it, so anything reading `path`
+/// sees either the old contents or the new, never half of them
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    std::fs::write(&temp, bytes).and_then(|_| std::fs::rename(&temp, path))
+}
@@ -0,0 +1,388 @@
+use glam::Vec3;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::checkpoint;
+#[cfg(feature = "gpu")]
+use super::gpu::{
+    ENERGY_SIZE, GpuParticle, PACKED_VEC3_SIZE, STAGING_RING, SimParams, TIMESTAMPS_PER_SUBMIT,
+};
+#[cfg(feature = "gpu")]
+use super::settings::DEFAULT_WORKGROUP_SIZE;
+use super::settings::Settings;
+#[cfg(feature = "gpu")]
+use super::tree::{ForceMethod, TreeNode};
+use super::writer;
+
+/// GPU memory `GpuCompute` allocates for a run, by buffer. Worked out from the settings alone so
+/// it can be checked before any buffer exists, or without a device at all.
+#[cfg(feature = "gpu")]
+pub struct MemoryEstimate {
+    pub num_particles: usize,
+    buffers: Vec<(&'static str, u64)>,
+}
+
+#[cfg(feature = "gpu")]
+impl MemoryEstimate {
+    /// `steps_per_submit` and `chunk_len` as GpuCompute ends up using them after the device
+    /// limits are applied. With `out_of_core`, `chunk_len` is the streaming tile length.
+    pub fn new(
+        settings: &Settings,
+        num_particles: usize,
+        steps_per_submit: usize,
+        chunk_len: usize,
+    ) -> MemoryEstimate {
+        let n = num_particles as u64;
+        let frame_size = n * 16;
+        let packed_frame_size = n * 2 * PACKED_VEC3_SIZE as u64;
+        // streaming only ever reads back one tile of forces
+        let readback_size = if settings.out_of_core {
+            chunk_len.min(num_particles) as u64 * 16
+        } else {
+            packed_frame_size
+        };
+        let staging = STAGING_RING as u64
+            * ((readback_size + ENERGY_SIZE) * steps_per_submit as u64
+                + TIMESTAMPS_PER_SUBMIT as u64 * 8);
+
+        let mut buffers = Vec::new();
+        match &settings.force_method {
+            ForceMethod::Direct if settings.out_of_core => {
+                let tile = chunk_len.min(num_particles) as u64;
+                let particles = tile * std::mem::size_of::<GpuParticle>() as u64;
+                buffers.push(("streamed targets", particles));
+                buffers.push(("streamed sources", particles));
+                buffers.push(("streamed forces", tile * 16));
+                buffers.push(("uniforms", std::mem::size_of::<SimParams>() as u64));
+            }
+            ForceMethod::Direct => {
+                // a tuned size isn't known yet, the partials are tiny either way
+                let workgroup_size = settings
+                    .workgroup_size
+                    .unwrap_or(DEFAULT_WORKGROUP_SIZE)
+                    .max(1);
+                let chunks = num_particles.div_ceil(chunk_len.max(1)).max(1) as u64;
+                buffers.push(("particles", n * std::mem::size_of::<GpuParticle>() as u64));
+                buffers.push(("forces", frame_size));
+                buffers.push(("readback", packed_frame_size));
+                buffers.push((
+                    "energy partials",
+                    (num_particles.div_ceil(workgroup_size as usize) as u64 + 1) * ENERGY_SIZE,
+                ));
+                // one params uniform per pair of chunks
+                buffers.push((
+                    "uniforms",
+                    chunks * chunks * std::mem::size_of::<SimParams>() as u64,
+                ));
+            }
+            ForceMethod::BarnesHut(barnes_hut) => {
+                buffers.push(("tree bodies", frame_size));
+                buffers.push(("tree forces", frame_size));
+                // the real count depends on how the particles cluster, two nodes per full leaf
+                // plus the headroom TreePass allocates is typical
+                let nodes = num_particles.div_ceil(barnes_hut.leaf_size.max(1)) as u64 * 5 / 2;
+                buffers.push((
+                    "tree nodes (approx.)",
+                    nodes * std::mem::size_of::<TreeNode>() as u64,
+                ));
+            }
+        }
+        buffers.push(("staging", staging));
+
+        MemoryEstimate {
+            num_particles,
+            buffers,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.buffers.iter().map(|(_, size)| size).sum()
+    }
+
+    /// Most particles whose estimate stays within `budget`, everything else unchanged
+    pub fn max_particles(
+        settings: &Settings,
+        steps_per_submit: usize,
+        chunk_len: usize,
+        budget: u64,
+    ) -> usize {
+        largest_fit(|n| {
+            MemoryEstimate::new(settings, n, steps_per_submit, chunk_len).total() <= budget
+        })
+    }
+
+    /// Longest streaming tile whose estimate stays within `budget`, for `out_of_core`
+    pub fn max_tile_len(
+        settings: &Settings,
+        num_particles: usize,
+        steps_per_submit: usize,
+        budget: u64,
+    ) -> usize {
+        largest_fit(|tile| {
+            MemoryEstimate::new(settings, num_particles, steps_per_submit, tile).total() <= budget
+        })
+    }
+
+    /// One line per buffer and the total
+    pub fn describe(&self) -> String {
+        let mut lines = vec![format!("GPU memory for {} particles:", self.num_particles)];
+        for (name, size) in &self.buffers {
+            lines.push(format!("  {:<22}{:>12}", name, format_bytes(*size)));
+        }
+        lines.push(format!(
+            "  {:<22}{:>12}",
+            "total",
+            format_bytes(self.total())
+        ));
+        lines.join("\n")
+    }
+}
+
+/// Largest count `fits`, which has to hold up to some point and never after. Particle ids are
+/// u32, so that's as far as it looks.
+#[cfg(feature = "gpu")]
+fn largest_fit(fits: impl Fn(usize) -> bool) -> usize {
+    const MAX: usize = u32::MAX as usize;
+    if !fits(1) {
+        return 0;
+    }
+    // double past the budget, then bisect between the last fit and the first miss
+    let mut low = 1;
+    let mut high = 2;
+    while fits(high) {
+        if high >= MAX {
+            return MAX;
+        }
+        low = high;
+        high = (high * 2).min(MAX);
+    }
+    while high - low > 1 {
+        let mid = low + (high - low) / 2;
+        if fits(mid) {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+/// Host memory a run's large buffers take, the ones that grow with the particle count. Printed
+/// at startup, before most of it is allocated.
+pub struct HostMemoryPlan {
+    pub num_particles: usize,
+    buffers: Vec<(&'static str, u64)>,
+}
+
+impl HostMemoryPlan {
+    /// The buffers every run has. `on_device` when the particles are integrated on the GPU, so
+    /// there are no forces coming back each frame.
+    pub fn new(settings: &Settings, on_device: bool) -> HostMemoryPlan {
+        let n = settings.num_particles as u64;
+        let frame = n * std::mem::size_of::<Vec3>() as u64;
+        // mass, position, velocity, acceleration, group and id
+        let particles = n * 48;
+        let mut buffers = vec![
+            ("particles", particles),
+            ("batch start copy", particles),
+            (
+                "frame buffers",
+                writer::batch_buffers(settings) as u64 * settings.frames_per_file as u64 * frame,
+            ),
+        ];
+        if !on_device {
+            // the ones being integrated and the next frame's being computed
+            buffers.push(("forces", 2 * n * 16));
+        }
+        if settings.reorder_interval > 0 {
+            buffers.push(("output order", n * 4));
+            buffers.push(("sorted frame gather", frame));
+        }
+        if settings.checkpoint_every > 0 {
+            buffers.push((
+                "checkpoint",
+                checkpoint::encoded_size(settings.num_particles) as u64,
+            ));
+        }
+        HostMemoryPlan {
+            num_particles: settings.num_particles,
+            buffers,
+        }
+    }
+
+    /// With another buffer, eg. one only some backends need
+    pub fn with(mut self, name: &'static str, bytes: u64) -> HostMemoryPlan {
+        if bytes > 0 {
+            self.buffers.push((name, bytes));
+        }
+        self
+    }
+
+    pub fn total(&self) -> u64 {
+        self.buffers.iter().map(|(_, size)| size).sum()
+    }
+
+    /// One line per buffer and the total
+    pub fn describe(&self) -> String {
+        let mut lines = vec![format!("Host memory for {} particles:", self.num_particles)];
+        for (name, size) in &self.buffers {
+            lines.push(format!("  {:<22}{:>12}", name, format_bytes(*size)));
+        }
+        lines.push(format!(
+            "  {:<22}{:>12}",
+            "total",
+            format_bytes(self.total())
+        ));
+        lines.join("\n")
+    }
+}
+
+/// Memory the system could still hand out, None where that can't be told
+pub fn available_ram() -> Option<u64> {
+    meminfo_kib("/proc/meminfo", "MemAvailable:")
+}
+
+/// Most host memory this process has had resident so far, None where that can't be told
+pub fn peak_rss() -> Option<u64> {
+    meminfo_kib("/proc/self/status", "VmHWM:")
+}
+
+/// A "name: 123 kB" line of a /proc file, in bytes
+fn meminfo_kib(path: &str, name: &str) -> Option<u64> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    let line = contents.lines().find(|line| line.starts_with(name))?;
+    let kib: u64 = line[name.len()..]
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+/// Allocations of at least `CountingAllocator::count_from` bytes since the start
+static LARGE_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static COUNT_FROM: AtomicUsize = AtomicUsize::new(usize::MAX);
+/// Of `LARGE_ALLOCATIONS`, the ones made inside `CountingAllocator::uncounted`
+static UNCOUNTED: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, counting allocations of particle-sized buffers so a batch can check it
+/// made none per frame. The binary uses it in debug builds.
+pub struct CountingAllocator;
+
+impl CountingAllocator {
+    /// Count allocations of `bytes` or more from now on
+    pub fn count_from(bytes: usize) {
+        COUNT_FROM.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Allocations counted so far, on every thread
+    pub fn large_allocations() -> usize {
+        LARGE_ALLOCATIONS.load(Ordering::Relaxed) - UNCOUNTED.load(Ordering::Relaxed)
+    }
+
+    /// Run `f` without counting what it allocates, for work that isn't the simulation's, like
+    /// the observers'. Other threads' allocations meanwhile go uncounted as well.
+    pub fn uncounted<T>(f: impl FnOnce() -> T) -> T {
+        let before = LARGE_ALLOCATIONS.load(Ordering::Relaxed);
+        let result = f();
+        let made = LARGE_ALLOCATIONS.load(Ordering::Relaxed) - before;
+        UNCOUNTED.fetch_add(made, Ordering::Relaxed);
+        result
+    }
+
+    fn count(size: usize) {
+        if size >= COUNT_FROM.load(Ordering::Relaxed) {
+            LARGE_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        CountingAllocator::count(layout.size());
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        CountingAllocator::count(layout.size());
+        unsafe { System.alloc_zeroed(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if new_size > layout.size() {
+            CountingAllocator::count(new_size);
+        }
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "gpu")]
+    fn max_particles_is_the_largest_fit() {
+        let settings = Settings::default();
+        let budget = MemoryEstimate::new(&settings, 12000, 1, usize::MAX).total();
+        assert_eq!(
+            MemoryEstimate::max_particles(&settings, 1, usize::MAX, budget),
+            12000
+        );
+
+        let budget = 10 * 1024 * 1024;
+        let max = MemoryEstimate::max_particles(&settings, 4, 1000, budget);
+        assert!(MemoryEstimate::new(&settings, max, 4, 1000).total() <= budget);
+        assert!(MemoryEstimate::new(&settings, max + 1, 4, 1000).total() > budget);
+    }
+
+    #[test]
+    fn host_memory_plan_counts_what_the_settings_turn_on() {
+        let settings = Settings {
+            num_particles: 1000,
+            frames_per_file: 10,
+            ..Settings::default()
+        };
+        let frame_buffers = writer::batch_buffers(&settings) as u64 * 10 * 12_000;
+        let base = 2 * 48_000 + frame_buffers;
+        assert_eq!(HostMemoryPlan::new(&settings, true).total(), base);
+        assert_eq!(
+            HostMemoryPlan::new(&settings, false).total(),
+            base + 2 * 16_000
+        );
+
+        let sorted = Settings {
+            reorder_interval: 100,
+            ..settings.clone()
+        };
+        let plan = HostMemoryPlan::new(&sorted, true).with("scratch", 5);
+        assert_eq!(plan.total(), base + 4_000 + 12_000 + 5);
+        assert!(plan.describe().contains("sorted frame gather"));
+    }
+
+    #[test]
+    fn format_bytes_picks_a_unit() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
@@ -3,60 +3,320 @@ struct Particle {
     mass: f32,
     vel: vec3<f32>,
     _padding: f32,
+    acc: vec3<f32>,
+    _padding2: f32,
 }
 
-@group(0) @binding(0) var<storage, read> particles: array<Particle>;
+// Particles that don't fit in one buffer are split into chunks. Each bind group pairs the chunk
+// being integrated / receiving forces with one source chunk, which is the same buffer when
+// everything fits.
+@group(0) @binding(0) var<storage, read_write> particles: array<Particle>;
+// xyz = force, w = potential (-sum of G * m_j / r_ij, per unit mass)
 @group(0) @binding(1) var<storage, read_write> forces: array<vec4<f32>>;
+// Written by `kick` for the readback: every position in the chunk packed as 3 floats, then every
+// velocity the same way, so positions alone are one contiguous copy
+@group(0) @binding(2) var<storage, read_write> readback: array<f32>;
 
-const WORKGROUP_SIZE: u32 = 64u;
-const G_CONST: f32 = 0.01;
+// Mirrors SimParams in gpu.rs
+struct SimParams {
+    g_const: f32,
+    softening_sq: f32,
+    // Particles in this chunk
+    num_particles: u32,
+    // Slice of the chunk the force pass computes forces for (all of it unless split across GPUs)
+    target_offset: u32,
+    target_count: u32,
+    // Global index of the first particle in this chunk and in the source chunk
+    chunk_offset: u32,
+    source_offset: u32,
+    source_count: u32,
+    // Non-zero adds onto the forces from earlier source chunks instead of overwriting them
+    accumulate: u32,
+    // Where this chunk's workgroups write their energy partial sums, and how many there are in
+    // total across every chunk
+    partial_offset: u32,
+    num_partials: u32,
+    // Always 1, only the compiler doesn't know it, see accumulate_pair
+    one: f32,
+}
+
+@group(0) @binding(3) var<uniform> params: SimParams;
+@group(0) @binding(4) var<storage, read_write> sources: array<Particle>;
+
+// Mirrors GpuEnergy in diagnostics.rs
+struct EnergySums {
+    kinetic: f32,
+    potential: f32,
+    momentum: vec3<f32>,
+}
+
+// One entry per workgroup of `reduce_energy` across all chunks, summed by `finish_energy`
+@group(0) @binding(5) var<storage, read_write> energy_partials: array<EnergySums>;
+@group(0) @binding(6) var<storage, read_write> energy_sum: EnergySums;
+
+// Set at pipeline creation from Settings::workgroup_size
+override WORKGROUP_SIZE: u32 = 64u;
+
+// Compensated (Kahan) summation of the force contributions
+override COMPENSATED: bool = false;
+
+// `AccVec` (the force and potential accumulator type) is prepended by GpuCompute: vec4<f32>, or
+// vec4<f64> when the device supports SHADER_F64 and double accumulation was asked for.
+
+// So is `frame_constants` (dt and frame index of the current step), as push constants where the
+// device has them and a dynamic offset uniform in group 1 otherwise. See FRAME_CONSTANTS_* in
+// gpu.rs.
+
+// 0 = semi-implicit Euler, 1 = velocity Verlet (matches Integrator::shader_id)
+override INTEGRATOR: u32 = 0u;
+
+// Dispatches too big for one dimension wrap into y, see dispatch_size in gpu.rs
+fn flat_index(workgroup_id: vec3<u32>, num_workgroups: vec3<u32>, local_id: vec3<u32>) -> u32 {
+    return (workgroup_id.y * num_workgroups.x + workgroup_id.x) * WORKGROUP_SIZE + local_id.x;
+}
+
+// Source particles for the current tile, shared by the whole workgroup (xyz = pos, w = mass)
+var<workgroup> tile_particles: array<vec4<f32>, WORKGROUP_SIZE>;
+
+// Adds the force and potential from `source` (xyz = pos, w = mass) on a particle at `pos_i`
+fn accumulate_pair(
+    force: ptr<function, AccVec>,
+    compensation: ptr<function, AccVec>,
+    source: vec4<f32>,
+    pos_i: vec3<f32>,
+    mass_i: f32
+) {
+    let diff = source.xyz - pos_i;
+    let dist_sq = dot(diff, diff) + params.softening_sq;
+    let dist = sqrt(dist_sq);
+    let force_mag = params.g_const * mass_i * source.w / dist_sq;
+
+    let potential = -params.g_const * source.w / dist;
+
+    let contribution = AccVec(vec4<f32>((diff / dist) * force_mag, potential));
+    if (COMPENSATED) {
+        let y = contribution - *compensation;
+        // WGSL has no `precise`, and drivers that reassociate floats fold (force + y) - force
+        // into y, the compensation into 0. Scaling by a uniform they can't see is 1 stops that.
+        let t = (*force + y) * params.one;
+        *compensation = (t - *force) - y;
+        *force = t;
+    } else {
+        *force += contribution;
+    }
+}
+
+// Writes the summed force of one target, onto the earlier source chunks' if accumulating
+fn store_force(local_target: u32, force: AccVec) {
+    var total = vec4<f32>(force);
+    if (params.accumulate != 0u) {
+        total += forces[local_target];
+    }
+    forces[local_target] = total;
+}
 
 @compute @workgroup_size(WORKGROUP_SIZE)
 fn main(
-    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
     @builtin(local_invocation_id) local_id: vec3<u32>
 ) {
-    let idx = global_id.x;
-    let num_particles = arrayLength(&particles);
-    
-    if (idx < num_particles) {
-        var force = vec3<f32>(0.0);
-        let pos_i = particles[idx].pos;
-        let mass_i = particles[idx].mass;
-        
-        var shared_particles: array<vec4<f32>, WORKGROUP_SIZE>;
-        
-        // Process in tiles
-        for (var tile = 0u; tile < (num_particles + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE; tile++) {
-            let tile_idx = tile * WORKGROUP_SIZE + local_id.x;
-            
-            // Load tile to shared memory
-            if (tile_idx < num_particles) {
-                shared_particles[local_id.x] = vec4<f32>(
-                    particles[tile_idx].pos, 
-                    particles[tile_idx].mass
-                );
-            }
-            workgroupBarrier();
-            
-            // Compute forces from this tile
-            for (var j = 0u; j < WORKGROUP_SIZE; j++) {
-                let global_j = tile * WORKGROUP_SIZE + j;
-                if (global_j < num_particles && global_j != idx) {
-                    let pos_j = shared_particles[j].xyz;
-                    let mass_j = shared_particles[j].w;
-                    
-                    let diff = pos_j - pos_i;
-                    let dist_sq = dot(diff, diff) + 0.001; // softening
-                    let dist = sqrt(dist_sq);
-                    let force_mag = G_CONST * mass_i * mass_j / dist_sq;
-                    
-                    force += (diff / dist) * force_mag;
-                }
+    // forces are written slice-local, sources always span every particle
+    let local_target = flat_index(workgroup_id, num_workgroups, local_id);
+    let idx = params.target_offset + local_target;
+    let num_particles = params.num_particles;
+    let in_range = local_target < params.target_count && idx < num_particles;
+    // compared against global source indices to skip the self interaction
+    let global_idx = params.chunk_offset + idx;
+    let source_count = params.source_count;
+
+    // Out of range invocations still help load tiles, they just don't write a result. They can't
+    // return early since every invocation in the workgroup has to reach the barriers.
+    var pos_i = vec3<f32>(0.0);
+    var mass_i = 0.0;
+    if (in_range) {
+        pos_i = particles[idx].pos;
+        mass_i = particles[idx].mass;
+    }
+
+    var force = AccVec(0.0);
+    var compensation = AccVec(0.0);
+    let num_tiles = (source_count + WORKGROUP_SIZE - 1u) / WORKGROUP_SIZE;
+
+    for (var tile = 0u; tile < num_tiles; tile++) {
+        // Each invocation loads one source particle of the tile
+        let tile_idx = tile * WORKGROUP_SIZE + local_id.x;
+        if (tile_idx < source_count) {
+            tile_particles[local_id.x] = vec4<f32>(
+                sources[tile_idx].pos,
+                sources[tile_idx].mass
+            );
+        } else {
+            // zero mass contributes nothing
+            tile_particles[local_id.x] = vec4<f32>(0.0);
+        }
+        workgroupBarrier();
+
+        // Accumulate forces from this tile out of shared memory
+        for (var j = 0u; j < WORKGROUP_SIZE; j++) {
+            let global_j = params.source_offset + tile * WORKGROUP_SIZE + j;
+            if (global_j != global_idx) {
+                accumulate_pair(&force, &compensation, tile_particles[j], pos_i, mass_i);
             }
-            workgroupBarrier();
         }
-        
-        forces[idx] = vec4<f32>(force, 0.0);
+
+        // Don't overwrite the tile until everyone is done with it
+        workgroupBarrier();
+    }
+
+    if (in_range) {
+        store_force(local_target, force);
+    }
+}
+
+// Same sum as `main` without the tiles, every invocation reads every source itself
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn main_naive(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>
+) {
+    let local_target = flat_index(workgroup_id, num_workgroups, local_id);
+    let idx = params.target_offset + local_target;
+    if (local_target >= params.target_count || idx >= params.num_particles) {
+        return;
+    }
+    let global_idx = params.chunk_offset + idx;
+    let pos_i = particles[idx].pos;
+    let mass_i = particles[idx].mass;
+
+    var force = AccVec(0.0);
+    var compensation = AccVec(0.0);
+    for (var j = 0u; j < params.source_count; j++) {
+        if (params.source_offset + j != global_idx) {
+            let source = vec4<f32>(sources[j].pos, sources[j].mass);
+            accumulate_pair(&force, &compensation, source, pos_i, mass_i);
+        }
+    }
+    store_force(local_target, force);
+}
+
+// First half of a step, before the force pass: moves the particles on with the acceleration
+// stored by the last `kick`, mirroring `drift` in particle.rs
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn drift(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>
+) {
+    let idx = flat_index(workgroup_id, num_workgroups, local_id);
+    if (idx >= params.num_particles) {
+        return;
+    }
+    let dt = frame_constants.dt;
+
+    var particle = particles[idx];
+    if (INTEGRATOR == 1u) {
+        particle.pos += particle.vel * dt + 0.5 * particle.acc * dt * dt;
+    } else {
+        particle.vel += particle.acc * dt;
+        particle.pos += particle.vel * dt;
+    }
+    particles[idx] = particle;
+}
+
+// Second half, after the force pass: finishes the velocities with the forces from `main` and
+// stores the acceleration, mirroring `kick` in particle.rs. The state is then the step's end, and
+// goes into the readback.
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn kick(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>
+) {
+    let idx = flat_index(workgroup_id, num_workgroups, local_id);
+    if (idx >= params.num_particles) {
+        return;
+    }
+    let dt = frame_constants.dt;
+
+    var particle = particles[idx];
+    let acc = forces[idx].xyz / particle.mass;
+    if (INTEGRATOR == 1u) {
+        particle.vel += 0.5 * (particle.acc + acc) * dt;
+    }
+    particle.acc = acc;
+
+    particles[idx] = particle;
+    let pos_base = idx * 3u;
+    readback[pos_base] = particle.pos.x;
+    readback[pos_base + 1u] = particle.pos.y;
+    readback[pos_base + 2u] = particle.pos.z;
+    let vel_base = (params.num_particles + idx) * 3u;
+    readback[vel_base] = particle.vel.x;
+    readback[vel_base + 1u] = particle.vel.y;
+    readback[vel_base + 2u] = particle.vel.z;
+}
+
+var<workgroup> energy_scratch: array<EnergySums, WORKGROUP_SIZE>;
+
+fn add_energy(a: EnergySums, b: EnergySums) -> EnergySums {
+    return EnergySums(a.kinetic + b.kinetic, a.potential + b.potential, a.momentum + b.momentum);
+}
+
+// Sum energy_scratch into its first element. Halving the remaining range each round (rounding up)
+// also works for workgroup sizes that aren't powers of two.
+fn reduce_energy_scratch(local: u32) {
+    workgroupBarrier();
+    var remaining = WORKGROUP_SIZE;
+    while (remaining > 1u) {
+        let half = (remaining + 1u) / 2u;
+        if (local + half < remaining) {
+            energy_scratch[local] = add_energy(energy_scratch[local], energy_scratch[local + half]);
+        }
+        workgroupBarrier();
+        remaining = half;
+    }
+}
+
+// Per-workgroup kinetic energy, potential energy and momentum of the state the force pass just
+// ran on, so it has to run after `kick` has finished the velocities
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn reduce_energy(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>
+) {
+    let idx = flat_index(workgroup_id, num_workgroups, local_id);
+    var sums = EnergySums(0.0, 0.0, vec3<f32>(0.0));
+    if (idx < params.num_particles) {
+        let particle = particles[idx];
+        sums.kinetic = 0.5 * particle.mass * dot(particle.vel, particle.vel);
+        // half, since every pair shows up in both particles' potentials
+        sums.potential = 0.5 * particle.mass * forces[idx].w;
+        sums.momentum = particle.mass * particle.vel;
+    }
+    energy_scratch[local_id.x] = sums;
+    reduce_energy_scratch(local_id.x);
+
+    // wrapped dispatches can have workgroups past the end, which would spill into the next chunk
+    let workgroup = workgroup_id.y * num_workgroups.x + workgroup_id.x;
+    if (local_id.x == 0u && workgroup * WORKGROUP_SIZE < params.num_particles) {
+        energy_partials[params.partial_offset + workgroup] = energy_scratch[0];
+    }
+}
+
+// Sums every chunk's partials into `energy_sum`, dispatched as a single workgroup
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn finish_energy(@builtin(local_invocation_id) local_id: vec3<u32>) {
+    var sums = EnergySums(0.0, 0.0, vec3<f32>(0.0));
+    for (var i = local_id.x; i < params.num_partials; i += WORKGROUP_SIZE) {
+        sums = add_energy(sums, energy_partials[i]);
     }
-}
\ No newline at end of file
+    energy_scratch[local_id.x] = sums;
+    reduce_energy_scratch(local_id.x);
+
+    if (local_id.x == 0u) {
+        energy_sum = energy_scratch[0];
+    }
+}
@@ -0,0 +1,56 @@
+// Alternate force kernel for devices with SUBGROUP, appended to nbody.wgsl by GpuCompute. It
+// can't live in nbody.wgsl itself since the subgroup built-ins fail validation without the
+// feature even when the entry point isn't used.
+//
+// Each subgroup walks the sources on its own: every lane loads one source particle into a
+// register and the others read it straight out of that lane, so there is no shared memory and
+// no workgroup barrier. Only used when WORKGROUP_SIZE is a multiple of the subgroup size, so
+// every subgroup is full and every lane it reads from is active.
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn main_subgroup(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>,
+    @builtin(subgroup_size) subgroup_size: u32,
+    @builtin(subgroup_invocation_id) lane: u32
+) {
+    let local_target = flat_index(workgroup_id, num_workgroups, local_id);
+    let idx = params.target_offset + local_target;
+    let in_range = local_target < params.target_count && idx < params.num_particles;
+    let global_idx = params.chunk_offset + idx;
+    let source_count = params.source_count;
+
+    // Out of range lanes still load sources for the rest of their subgroup
+    var pos_i = vec3<f32>(0.0);
+    var mass_i = 0.0;
+    if (in_range) {
+        pos_i = particles[idx].pos;
+        mass_i = particles[idx].mass;
+    }
+
+    var force = AccVec(0.0);
+    var compensation = AccVec(0.0);
+    let num_tiles = (source_count + subgroup_size - 1u) / subgroup_size;
+
+    for (var tile = 0u; tile < num_tiles; tile++) {
+        let tile_idx = tile * subgroup_size + lane;
+        // zero mass contributes nothing
+        var own_source = vec4<f32>(0.0);
+        if (tile_idx < source_count) {
+            own_source = vec4<f32>(sources[tile_idx].pos, sources[tile_idx].mass);
+        }
+
+        for (var j = 0u; j < subgroup_size; j++) {
+            // a broadcast from lane j, but subgroupBroadcast only takes a constant lane
+            let source = subgroupShuffle(own_source, j);
+            let global_j = params.source_offset + tile * subgroup_size + j;
+            if (global_j != global_idx) {
+                accumulate_pair(&force, &compensation, source, pos_i, mass_i);
+            }
+        }
+    }
+
+    if (in_range) {
+        store_force(local_target, force);
+    }
+}
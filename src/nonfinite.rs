@@ -0,0 +1,220 @@
+use glam::Vec3;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+use super::ParticleSet;
+use super::error::Error;
+
+/// Written to the output directory when a run stops on a non-finite state
+pub const DUMP_FILE: &str = "nonfinite_dump.json";
+/// Batch dts kept for a dump, the current batch's included
+pub const DT_HISTORY: usize = 8;
+/// Offending particles a dump describes, `count` says how many there were in all
+const MAX_DUMPED: usize = 32;
+/// Closest other particles listed for each of them
+const NEIGHBORS: usize = 8;
+
+/// Which particles went to NaN or infinity, when, and what was around them just before. Almost
+/// always a close encounter too tight for dt and the softening.
+#[derive(Serialize, Clone, Debug)]
+pub struct NonFiniteDump {
+    /// The first frame with a non-finite position, or the batch's last when only a velocity at
+    /// its end was
+    pub frame: usize,
+    /// Simulated time at the end of `frame`
+    pub time: f64,
+    /// Particles with a non-finite position or velocity at `frame`
+    pub count: usize,
+    /// First frame and dt of the last few batches, oldest first, this one last
+    pub recent_dt: Vec<(usize, f32)>,
+    /// The first of the `count`, in simulation order
+    pub particles: Vec<DumpedParticle>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DumpedParticle {
+    pub id: u32,
+    /// Place in the simulation's particle order
+    pub index: usize,
+    pub mass: f32,
+    pub group: u32,
+    /// At `frame`
+    pub position: Vec3,
+    /// At the frame before, the last one all finite
+    pub last_finite_position: Vec3,
+    /// At the start of the batch
+    pub batch_start: ParticleState,
+    /// At the end of the batch, the only other time the velocities are known
+    pub batch_end_velocity: Vec3,
+    /// The closest others at the last finite frame, closest first
+    pub neighbors: Vec<Neighbor>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct ParticleState {
+    pub pos: Vec3,
+    pub vel: Vec3,
+    pub acc: Vec3,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct Neighbor {
+    pub id: u32,
+    pub mass: f32,
+    /// From the offending particle at the last finite frame
+    pub distance: f32,
+    pub last_finite_position: Vec3,
+    pub batch_start_velocity: Vec3,
+}
+
+/// The first of a batch's `frames` with a non-finite position, or the last when only the
+/// `velocities` at its end are. A rayon any() a frame, cheap next to the frame's forces.
+pub fn first_nonfinite(frames: &[Vec<Vec3>], velocities: &[Vec3]) -> Option<usize> {
+    let nonfinite = |values: &[Vec3]| values.par_iter().any(|value| !value.is_finite());
+    frames
+        .iter()
+        .position(|positions| nonfinite(positions))
+        .or_else(|| (!frames.is_empty() && nonfinite(velocities)).then(|| frames.len() - 1))
+}
+
+impl NonFiniteDump {
+    /// For `frames[offset]`, the batch's `frames` having been simulated from `start` to `end`.
+    /// Particle order doesn't change within a batch, so `end`'s ids go for every frame.
+    pub fn new(
+        first_frame: usize,
+        offset: usize,
+        time: f64,
+        frames: &[Vec<Vec3>],
+        start: &ParticleSet,
+        end: &ParticleSet,
+        recent_dt: Vec<(usize, f32)>,
+    ) -> NonFiniteDump {
+        let positions = &frames[offset];
+        let offending: Vec<usize> = (0..positions.len())
+            .into_par_iter()
+            .filter(|&index| !positions[index].is_finite() || !end.vel[index].is_finite())
+            .collect();
+        // with only velocities gone, the frame itself is the last finite one
+        let last_finite = match offset {
+            _ if positions.iter().all(|pos| pos.is_finite()) => positions,
+            0 => &start.pos,
+            _ => &frames[offset - 1],
+        };
+        let particles = offending
+            .iter()
+            .take(MAX_DUMPED)
+            .map(|&index| DumpedParticle {
+                id: end.id[index],
+                index,
+                mass: end.mass[index],
+                group: end.group[index],
+                position: positions[index],
+                last_finite_position: last_finite[index],
+                batch_start: ParticleState {
+                    pos: start.pos[index],
+                    vel: start.vel[index],
+                    acc: start.acc[index],
+                },
+                batch_end_velocity: end.vel[index],
+                neighbors: neighbors(index, last_finite, start, end),
+            })
+            .collect();
+        NonFiniteDump {
+            frame: first_frame + offset,
+            time,
+            count: offending.len(),
+            recent_dt,
+            particles,
+        }
+    }
+
+    /// Write it to DUMP_FILE in `dir`
+    pub fn save(&self, dir: &Path) -> Result<PathBuf, Error> {
+        let path = dir.join(DUMP_FILE);
+        let json = serde_json::to_string_pretty(self).expect("a dump serializes");
+        std::fs::write(&path, json).map_err(Error::io("write", &path))?;
+        Ok(path)
+    }
+}
+
+/// The NEIGHBORS particles closest to the one at `index`, at `positions`
+fn neighbors(
+    index: usize,
+    positions: &[Vec3],
+    start: &ParticleSet,
+    end: &ParticleSet,
+) -> Vec<Neighbor> {
+    let center = positions[index];
+    let mut by_distance: Vec<(f32, usize)> = positions
+        .par_iter()
+        .enumerate()
+        .filter(|&(other, pos)| other != index && pos.is_finite())
+        .map(|(other, pos)| (pos.distance(center), other))
+        .collect();
+    // ties go to the lower index, so a dump doesn't depend on the thread count
+    let order = |a: &(f32, usize), b: &(f32, usize)| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1));
+    if NEIGHBORS < by_distance.len() {
+        by_distance.select_nth_unstable_by(NEIGHBORS, order);
+        by_distance.truncate(NEIGHBORS);
+    }
+    by_distance.sort_unstable_by(order);
+    by_distance
+        .into_iter()
+        .map(|(distance, other)| Neighbor {
+            id: end.id[other],
+            mass: end.mass[other],
+            distance,
+            last_finite_position: positions[other],
+            batch_start_velocity: start.vel[other],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Particle;
+
+    #[test]
+    fn dumps_the_first_nonfinite_frame_with_what_was_nearby() {
+        let start: ParticleSet = (0..12)
+            .map(|i| {
+                Particle::new_zero()
+                    .with_mass(1.0 + i as f32)
+                    .with_pos(Vec3::X * i as f32)
+                    .with_id(11 - i)
+            })
+            .collect();
+        let mut frames = vec![start.pos.clone(); 4];
+        // particle 3 blows up in the third frame, and has gone further in the fourth
+        frames[2][3] = Vec3::NAN;
+        frames[3][3] = Vec3::INFINITY;
+        frames[3][4] = Vec3::NAN;
+        let mut end = start.clone();
+        end.vel[3] = Vec3::NAN;
+
+        assert_eq!(first_nonfinite(&frames[..2], &start.vel), None);
+        assert_eq!(first_nonfinite(&frames[..2], &end.vel), Some(1));
+        let offset = first_nonfinite(&frames, &end.vel).unwrap();
+        assert_eq!(offset, 2);
+
+        let dump = NonFiniteDump::new(40, offset, 4.3, &frames, &start, &end, vec![(40, 0.1)]);
+        assert_eq!((dump.frame, dump.count), (42, 1));
+        let particle = &dump.particles[0];
+        assert_eq!((particle.id, particle.index, particle.mass), (8, 3, 4.0));
+        assert!(particle.position.is_nan());
+        assert_eq!(particle.last_finite_position, Vec3::X * 3.0);
+        let neighbors: Vec<(u32, f32)> = particle
+            .neighbors
+            .iter()
+            .map(|neighbor| (neighbor.id, neighbor.distance))
+            .collect();
+        assert_eq!(neighbors.len(), NEIGHBORS);
+        assert_eq!(neighbors[..3], [(9, 1.0), (7, 1.0), (10, 2.0)]);
+
+        let json = serde_json::to_value(&dump).unwrap();
+        assert_eq!(json["particles"][0]["position"][0], serde_json::Value::Null);
+        assert_eq!(json["recent_dt"][0][1], 0.1f32 as f64);
+    }
+}
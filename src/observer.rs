@@ -0,0 +1,619 @@
+use glam::Vec3;
+use std::time::Duration;
+use tracing::warn;
+
+use super::ParticleSet;
+use super::diagnostics::{
+    BindingEnergyLog, CorrelationLog, DensityProfileLog, DiagnosticsLog, DriftWatch, Energy,
+    SpeedHistogramLog,
+};
+use super::error::Error;
+use super::settings::Settings;
+use super::status::{self, StatusBoard};
+
+/// Per-frame analysis hooked into a `Simulation`, see `Simulation::observed_by`.
+///
+/// Observers are called on the thread running the simulation once `run_batch` has finished
+/// simulating, after every GPU submission of the batch has been read back and before the next
+/// one is recorded. The device is never waiting on an observer: a slow one only delays the start
+/// of the next batch, the way a slow disk does. Frames a batch stops short of aren't reported,
+/// and a batch rerun after the device is lost is only reported once.
+pub trait FrameObserver: Send {
+    /// One simulated frame. `time` is the simulated time the positions are at, `positions` are
+    /// in the order of `Simulation::particles`, whose ids map them back to output order.
+    /// `velocities` are only read back for the last frame of a batch, None for the others.
+    fn on_frame(
+        &mut self,
+        _frame_index: usize,
+        _time: f64,
+        _positions: &[Vec3],
+        _velocities: Option<&[Vec3]>,
+    ) {
+    }
+
+    /// Whether `on_frame` reads the positions of `frame_index`. Integrating on the GPU, frames
+    /// no observer wants and output_every drops aren't read back, `on_frame` gets stale
+    /// positions for them.
+    fn wants_positions(&self, _frame_index: usize) -> bool {
+        false
+    }
+
+    /// Before `run_batch` starts on batch number `batch`, at `first_frame`
+    fn on_batch_start(&mut self, _batch: usize, _first_frame: usize) {}
+
+    /// Right after `on_batch_start`, the particles as the batch starts from them. Their order,
+    /// masses and ids stay the same until the batch's frames have been reported, so they say
+    /// which particle each of the positions `on_frame` gets is.
+    fn on_batch_particles(&mut self, _particles: &ParticleSet) {}
+
+    /// Whether `on_batch_complete` wants `BatchReport::potential` for `batch`, which doesn't
+    /// have it yet. It takes another force pass, so only the batches someone asks for get one.
+    fn needs_potential(&self, _batch: &BatchReport) -> bool {
+        false
+    }
+
+    /// After `on_frame` for every frame of the batch
+    fn on_batch_complete(&mut self, _batch: &BatchReport) {}
+
+    /// Between batches, when `Settings::hot_reload` has changed some of the settings
+    fn on_settings_changed(&mut self, _settings: &Settings) {}
+}
+
+/// Where `Simulation::run_frames` records frames. The sink hands out the buffers they're
+/// simulated into, so frames aren't copied on their way to it.
+pub trait FrameSink {
+    /// Buffers for up to `frames` frames of `num_particles` positions each. Extra ones are
+    /// ignored, fewer leave the rest of the frames to the next call.
+    fn buffers(&mut self, frames: usize, num_particles: usize) -> Result<Vec<Vec<Vec3>>, Error>;
+
+    /// Frames simulated from `first_frame` into buffers from `buffers`, cut down to the ones
+    /// simulated. Positions are in the order of `Simulation::particles`.
+    fn record(&mut self, first_frame: usize, frames: Vec<Vec<Vec3>>) -> Result<(), Error>;
+}
+
+/// Every frame kept in memory, in order
+impl FrameSink for Vec<Vec<Vec3>> {
+    fn buffers(&mut self, frames: usize, num_particles: usize) -> Result<Vec<Vec<Vec3>>, Error> {
+        Ok(vec![vec![Vec3::ZERO; num_particles]; frames])
+    }
+
+    fn record(&mut self, _first_frame: usize, frames: Vec<Vec<Vec3>>) -> Result<(), Error> {
+        self.extend(frames);
+        Ok(())
+    }
+}
+
+/// What a `run_batch` call simulated, for `FrameObserver::on_batch_complete`. `step` is a batch
+/// of one frame.
+pub struct BatchReport<'a> {
+    /// Batch number, the output file the frames go to
+    pub batch: usize,
+    pub first_frame: usize,
+    /// Frames simulated, fewer than asked when interrupted
+    pub frames: usize,
+    /// Whether every frame asked for was simulated
+    pub complete: bool,
+    /// Simulated time at the start of `first_frame`
+    pub first_time: f64,
+    pub dt: f32,
+    /// Energies at the end of each frame, with `diagnostics`
+    pub energies: &'a [Energy],
+    /// The particles after the last frame
+    pub particles: &'a ParticleSet,
+    /// The softened potential per unit mass at each of `particles`, when an observer asked for
+    /// it with `FrameObserver::needs_potential`
+    pub potential: Option<&'a [f32]>,
+    /// Wall time spent simulating the batch
+    pub elapsed: Duration,
+}
+
+impl BatchReport<'_> {
+    /// Whether one of the batch's steps is a multiple of `every`, for the diagnostics that are
+    /// only taken at the end of a batch
+    pub fn has_step_multiple_of(&self, every: usize) -> bool {
+        self.first_frame.next_multiple_of(every.max(1)) < self.first_frame + self.frames
+    }
+
+    /// Frame and simulated time of `particles`
+    pub fn last_frame(&self) -> (usize, f64) {
+        (
+            self.first_frame + self.frames - 1,
+            self.first_time + self.frames as f64 * self.dt as f64,
+        )
+    }
+}
+
+/// diagnostics.csv as an observer
+impl FrameObserver for DiagnosticsLog {
+    fn on_batch_start(&mut self, _batch: usize, first_frame: usize) {
+        self.batch_start = first_frame;
+    }
+
+    fn on_batch_particles(&mut self, particles: &ParticleSet) {
+        if self.logs_radii() {
+            self.masses.clone_from(&particles.mass);
+        }
+        if self.records_steps() {
+            self.record_step(self.batch_start, &particles.pos);
+        }
+    }
+
+    /// Frame `frame_index` is where step `frame_index + 1` starts
+    fn on_frame(
+        &mut self,
+        frame_index: usize,
+        _time: f64,
+        positions: &[Vec3],
+        _velocities: Option<&[Vec3]>,
+    ) {
+        self.record_step(frame_index + 1, positions);
+    }
+
+    fn wants_positions(&self, frame_index: usize) -> bool {
+        self.records_steps() && (frame_index + 1).is_multiple_of(self.every)
+    }
+
+    fn on_batch_complete(&mut self, batch: &BatchReport) {
+        // a batch cut short runs again on resume, its rows would be logged twice
+        if !batch.complete {
+            return;
+        }
+        // the end of a frame is the start of the next step
+        if let Err(e) = self.append(
+            batch.first_frame + 1,
+            batch.first_time + batch.dt as f64,
+            batch.dt,
+            batch.energies,
+        ) {
+            warn!("{}", e);
+        }
+    }
+
+    fn on_settings_changed(&mut self, settings: &Settings) {
+        self.every = settings.diagnostics_every.max(1);
+    }
+}
+
+/// speed_histogram.csv as an observer. Velocities are only current at the end of a batch, so
+/// that's the frame binned.
+impl FrameObserver for SpeedHistogramLog {
+    fn on_batch_complete(&mut self, batch: &BatchReport) {
+        if !batch.complete || !batch.has_step_multiple_of(self.every) {
+            return;
+        }
+        let (frame, time) = batch.last_frame();
+        if let Err(e) = self.append(frame, time, &batch.particles.vel) {
+            warn!("{}", e);
+        }
+    }
+
+    fn on_settings_changed(&mut self, settings: &Settings) {
+        self.every = settings.diagnostics_every.max(1);
+    }
+}
+
+/// density_profile.csv as an observer, the particles at the end of a batch
+impl FrameObserver for DensityProfileLog {
+    fn on_batch_complete(&mut self, batch: &BatchReport) {
+        if !batch.complete || !batch.has_step_multiple_of(self.every) {
+            return;
+        }
+        let (frame, time) = batch.last_frame();
+        if let Err(e) = self.append(frame, time, batch.particles) {
+            warn!("{}", e);
+        }
+    }
+
+    fn on_settings_changed(&mut self, settings: &Settings) {
+        self.every = settings.diagnostics_every.max(1);
+    }
+}
+
+/// binding_energy.csv as an observer, with the potential asked for at the batches it logs
+impl FrameObserver for BindingEnergyLog {
+    fn needs_potential(&self, batch: &BatchReport) -> bool {
+        batch.complete && batch.has_step_multiple_of(self.every)
+    }
+
+    fn on_batch_complete(&mut self, batch: &BatchReport) {
+        let Some(potential) = batch.potential else {
+            return;
+        };
+        if !self.needs_potential(batch) {
+            return;
+        }
+        let (frame, time) = batch.last_frame();
+        if let Err(e) = self.append(frame, time, batch.particles, potential) {
+            warn!("{}", e);
+        }
+    }
+
+    fn on_settings_changed(&mut self, settings: &Settings) {
+        self.every = settings.diagnostics_every.max(1);
+    }
+}
+
+/// correlation.csv as an observer, only positions needed so any frame will do
+impl FrameObserver for CorrelationLog {
+    fn on_frame(
+        &mut self,
+        frame_index: usize,
+        time: f64,
+        positions: &[Vec3],
+        _velocities: Option<&[Vec3]>,
+    ) {
+        if !frame_index.is_multiple_of(self.every) {
+            return;
+        }
+        if let Err(e) = self.append(frame_index, time, positions) {
+            warn!("{}", e);
+        }
+    }
+
+    fn wants_positions(&self, frame_index: usize) -> bool {
+        frame_index.is_multiple_of(self.every)
+    }
+
+    fn on_settings_changed(&mut self, settings: &Settings) {
+        self.every = settings.correlation_every.max(1);
+    }
+}
+
+/// energy_drift_warn as an observer
+impl FrameObserver for DriftWatch {
+    fn on_batch_complete(&mut self, batch: &BatchReport) {
+        self.check(batch.first_frame + 1, batch.energies);
+    }
+}
+
+/// The escaped count for the status server
+impl FrameObserver for StatusBoard {
+    fn on_batch_complete(&mut self, batch: &BatchReport) {
+        let escaped = status::escaped_count(batch.particles, self.g_const);
+        self.update(|status| status.escaped = Some(escaped));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ForceBackendKind;
+    use crate::diagnostics::{self, DriftWatch};
+    use crate::testing::{batch_report, test_dir};
+    use crate::{Particle, Simulation};
+    use std::sync::{Arc, Mutex};
+
+    /// Everything an observer was called with, in order
+    #[derive(Default)]
+    struct Calls {
+        frames: Vec<(usize, f64, Vec<Vec3>, bool)>,
+        batches: Vec<(usize, usize, usize)>,
+    }
+
+    struct Recorder {
+        calls: Arc<Mutex<Calls>>,
+        /// Time to take over each frame
+        delay: Duration,
+    }
+
+    impl FrameObserver for Recorder {
+        fn on_frame(&mut self, frame: usize, time: f64, pos: &[Vec3], vel: Option<&[Vec3]>) {
+            std::thread::sleep(self.delay);
+            let mut calls = self.calls.lock().unwrap();
+            calls
+                .frames
+                .push((frame, time, pos.to_vec(), vel.is_some()));
+        }
+
+        fn wants_positions(&self, _frame: usize) -> bool {
+            true
+        }
+
+        fn on_batch_complete(&mut self, batch: &BatchReport) {
+            let mut calls = self.calls.lock().unwrap();
+            calls
+                .batches
+                .push((batch.batch, batch.first_frame, batch.frames));
+        }
+    }
+
+    fn simulation(delay: Duration) -> (Simulation, Arc<Mutex<Calls>>) {
+        let settings = Settings {
+            num_particles: 4,
+            frames_per_file: 3,
+            force_backend: ForceBackendKind::Cpu,
+            ..Settings::default()
+        };
+        let particles: ParticleSet = (0..4)
+            .map(|i| {
+                let t = i as f32;
+                Particle::new(1.0, Vec3::new(t, t * t, -t), Vec3::Y, Vec3::ZERO)
+            })
+            .collect();
+        let calls = Arc::new(Mutex::new(Calls::default()));
+        let recorder = Recorder {
+            calls: calls.clone(),
+            delay,
+        };
+        let simulation = Simulation::new(settings, particles)
+            .unwrap()
+            .observed_by(Box::new(recorder));
+        (simulation, calls)
+    }
+
+    #[test]
+    fn observers_see_each_batch_once_it_is_simulated() {
+        let (mut simulation, calls) = simulation(Duration::ZERO);
+        let dt = simulation.settings().dt as f64;
+        let mut frame_list = vec![vec![Vec3::ZERO; 4]; 3];
+        simulation.run_batch(&mut frame_list).unwrap();
+        simulation.step().unwrap();
+
+        let calls = calls.lock().unwrap();
+        let frames: Vec<usize> = calls.frames.iter().map(|call| call.0).collect();
+        assert_eq!(frames, [0, 1, 2, 3]);
+        for (call, positions) in calls.frames.iter().zip(&frame_list) {
+            assert_eq!(call.2, *positions);
+            assert!((call.1 - (call.0 + 1) as f64 * dt).abs() < 1e-9);
+        }
+        // velocities come with the last frame of each batch
+        let velocities: Vec<bool> = calls.frames.iter().map(|call| call.3).collect();
+        assert_eq!(velocities, [false, false, true, true]);
+        assert_eq!(calls.batches, [(0, 0, 3), (1, 3, 1)]);
+    }
+
+    #[test]
+    fn slow_observers_do_not_change_the_run() {
+        let (mut slow, _) = simulation(Duration::from_millis(20));
+        let (mut fast, _) = simulation(Duration::ZERO);
+        for simulation in [&mut slow, &mut fast] {
+            let mut frame_list = vec![vec![Vec3::ZERO; 4]; 3];
+            simulation.run_batch(&mut frame_list).unwrap();
+        }
+        assert_eq!(slow.particles().pos, fast.particles().pos);
+    }
+
+    #[test]
+    fn drift_is_warned_about_once_a_batch_and_kept_in_the_shared_record() {
+        let drift = Arc::new(Mutex::new(None));
+        let mut watch = DriftWatch::new(&Settings::default(), drift.clone());
+        let energy = |total: f32| Energy {
+            kinetic: total,
+            ..Energy::default()
+        };
+        let particles = ParticleSet::default();
+        let mut batch = |first_frame: usize, totals: &[f32]| {
+            let energies: Vec<Energy> = totals.iter().map(|&total| energy(total)).collect();
+            watch.on_batch_complete(&BatchReport {
+                batch: first_frame / 3,
+                first_frame,
+                frames: energies.len(),
+                complete: true,
+                first_time: 0.0,
+                dt: 0.1,
+                energies: &energies,
+                particles: &particles,
+                potential: None,
+                elapsed: Duration::ZERO,
+            });
+            drift.lock().unwrap().unwrap()
+        };
+
+        let record = batch(0, &[-100.0, -100.5, -99.8]);
+        assert_eq!(record.initial, -100.0);
+        assert_eq!((record.max_frame, record.exceeded_at_frame), (2, None));
+
+        // two frames over 1%, one warning for the batch at the worse one
+        let record = batch(3, &[-102.0, -103.0, -100.0]);
+        assert_eq!(record.warnings, 1);
+        assert_eq!(record.exceeded_at_frame, Some(4));
+        assert_eq!(record.max_frame, 5);
+        assert!((record.max - 0.03).abs() < 1e-6);
+
+        let record = batch(6, &[f32::NAN, -100.0, -100.0]);
+        assert_eq!(record.warnings, 2);
+        assert_eq!((record.exceeded_at_frame, record.max_frame), (Some(4), 7));
+    }
+
+    #[test]
+    fn speed_histograms_bin_the_batch_end_and_resume_drops_rerun_rows() {
+        let dir = test_dir("speed-histogram");
+        let settings = Settings {
+            out_path: dir.clone(),
+            diagnostics_every: 5,
+            speed_histogram_bins: 4,
+            ..Settings::default()
+        };
+        let particles: ParticleSet = [0.0, 1.0, 2.9, 3.0, 4.0]
+            .into_iter()
+            .map(|speed| Particle::new_zero().with_vel(Vec3::X * speed))
+            .collect();
+        let batch = |first_frame| batch_report(first_frame, 3, &particles, None);
+        let rows = || {
+            std::fs::read_to_string(dir.join("speed_histogram.csv"))
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        // frames 0-2 have step 0 and 3-5 step 5, 6-8 none
+        let mut log = SpeedHistogramLog::create(&settings).unwrap();
+        for first_frame in [0, 3, 6] {
+            log.on_batch_complete(&batch(first_frame));
+        }
+        assert_eq!(
+            rows(),
+            [
+                "frame,time,upper,over,bin_0,bin_1,bin_2,bin_3",
+                "2,1.5,4,0,1,1,1,2",
+                "5,3,4,0,1,1,1,2",
+            ]
+        );
+
+        let fixed = Settings {
+            speed_histogram_max: Some(3.0),
+            ..settings
+        };
+        let mut log = SpeedHistogramLog::append_to(&fixed, 3).unwrap();
+        log.on_batch_complete(&batch(3));
+        assert_eq!(rows()[1..], ["2,1.5,4,0,1,1,1,2", "5,3,3,1,1,1,0,2"]);
+        let layout: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(dir.join("speed_histogram.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(layout["bins"], 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn density_profiles_are_about_the_moving_center_of_mass() {
+        let dir = test_dir("density-profile");
+        let settings = Settings {
+            out_path: dir.clone(),
+            density_profile_bins: 2,
+            ..Settings::default()
+        };
+        // pairs 1, 2 and 4 from a center well away from the origin
+        let center = Vec3::splat(10.0);
+        let particles: ParticleSet = [Vec3::X, Vec3::Y * 2.0, Vec3::Z * 4.0]
+            .into_iter()
+            .flat_map(|offset| [center + offset, center - offset])
+            .map(|pos| Particle::new_zero().with_mass(1.0).with_pos(pos))
+            .collect();
+
+        let mut log = DensityProfileLog::create(&settings).unwrap();
+        log.on_batch_complete(&batch_report(0, 3, &particles, None));
+        let content = std::fs::read_to_string(dir.join("density_profile.csv")).unwrap();
+        let rows: Vec<Vec<f64>> = content
+            .lines()
+            .skip(1)
+            .map(|line| {
+                line.split(',')
+                    .map(|value| value.parse().unwrap())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(rows.len(), 2);
+        // frame, time, bin, edges and the counts; the ones on the outer edge are in the last shell
+        assert_eq!(rows[0][..6], [2.0, 1.5, 0.0, 1.0, 2.0, 2.0]);
+        assert_eq!(rows[1][..6], [2.0, 1.5, 1.0, 2.0, 4.0, 4.0]);
+        let volume = 4.0 / 3.0 * std::f64::consts::PI * (8.0 - 1.0);
+        assert!((rows[0][7] - 2.0 / volume).abs() < 1e-9);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn binding_energies_are_only_logged_with_the_potential_asked_for() {
+        let dir = test_dir("binding-energy");
+        let settings = Settings {
+            out_path: dir.clone(),
+            binding_energy_bins: 2,
+            binding_energy_min: Some(-2.0),
+            binding_energy_max: Some(2.0),
+            diagnostics_every: 4,
+            ..Settings::default()
+        };
+        // energies -3, -1, 0.5 and 4, the heaviest bound
+        let particles: ParticleSet = [(3.0, 0.0), (2.0, 1.0), (1.0, 1.0), (1.0, 3.0)]
+            .into_iter()
+            .map(|(mass, speed)| {
+                Particle::new_zero()
+                    .with_mass(mass)
+                    .with_vel(Vec3::X * speed)
+            })
+            .collect();
+        let potential = [-3.0, -1.5, 0.0, -0.5];
+
+        let mut log = BindingEnergyLog::create(&settings).unwrap();
+        let batch = |first_frame, potential| batch_report(first_frame, 2, &particles, potential);
+        // no multiple of 4 among frames 1 and 2
+        assert!(!log.needs_potential(&batch(1, None)));
+        assert!(log.needs_potential(&batch(3, None)));
+        log.on_batch_complete(&batch(1, Some(&potential)));
+        log.on_batch_complete(&batch(3, None));
+        log.on_batch_complete(&batch(3, Some(&potential)));
+        let content = std::fs::read_to_string(dir.join("binding_energy.csv")).unwrap();
+        assert_eq!(
+            content.lines().collect::<Vec<_>>(),
+            [
+                "frame,time,bound_fraction,lower,upper,under,over,bin_0,bin_1",
+                "4,2.5,0.7142857142857143,-2,2,1,1,1,1",
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn correlation_of_a_uniform_sphere_is_flat_and_reruns_draw_the_same_pairs() {
+        use rand::prelude::*;
+
+        let dir = test_dir("correlation");
+        let settings = Settings {
+            out_path: dir.clone(),
+            arena: 1.0,
+            correlation_pairs: 100_000,
+            correlation_every: 2,
+            correlation_bins: 6,
+            correlation_min_separation: Some(0.1),
+            ..Settings::default()
+        };
+        let mut rng = StdRng::seed_from_u64(1);
+        let positions: Vec<Vec3> = std::iter::repeat_with(|| {
+            Vec3::new(rng.random(), rng.random(), rng.random()) * 2.0 - 1.0
+        })
+        .filter(|pos| pos.length() <= 1.0)
+        .take(4000)
+        .collect();
+
+        let run = || {
+            let mut log = CorrelationLog::create(&settings).unwrap();
+            for frame in 0..3 {
+                log.on_frame(frame, frame as f64, &positions, None);
+            }
+            std::fs::read_to_string(dir.join("correlation.csv")).unwrap()
+        };
+        let content = run();
+        assert_eq!(content, run());
+        let rows: Vec<Vec<f64>> = content
+            .lines()
+            .skip(1)
+            .map(|line| {
+                line.split(',')
+                    .map(|value| value.parse().unwrap())
+                    .collect()
+            })
+            .collect();
+        // frames 0 and 2, a row per bin
+        assert_eq!(rows.len(), 12);
+        assert_eq!((rows[0][0], rows[6][0]), (0.0, 2.0));
+        assert_eq!(rows[0][3], 0.1);
+        assert!((rows[5][4] - 2.0).abs() < 1e-9);
+        // a different draw each frame
+        assert_ne!(rows[0][5], rows[6][5]);
+        for row in &rows {
+            // the outermost bin only gets the few pairs on opposite sides
+            if row[6] > 1000.0 {
+                assert!(row[7].abs() < 0.1, "{:?}", row);
+            }
+        }
+
+        // squashed into clumps, close pairs are far more common than in the sphere
+        let clumped: Vec<Vec3> = positions
+            .iter()
+            .map(|&pos| pos * 0.05 + if pos.x > 0.0 { Vec3::X } else { -Vec3::X } * 0.5)
+            .collect();
+        let separations = diagnostics::pair_correlation(&clumped, 100_000, 6, 1.0, 0.01, 0);
+        assert!(separations[0].xi() > 10.0, "{:?}", separations[0]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn simulations_with_observers_can_move_between_threads() {
+        let (mut simulation, calls) = simulation(Duration::ZERO);
+        std::thread::spawn(move || simulation.step().unwrap())
+            .join()
+            .unwrap();
+        assert_eq!(calls.lock().unwrap().frames.len(), 1);
+    }
+}
@@ -0,0 +1,424 @@
+use glam::Vec3;
+use gravity_output_format::{self as format, BatchComment, BatchEncoder, Compression, Header};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+use tracing::{trace_span, warn};
+#[cfg(target_arch = "wasm32")]
+use web_time::Instant;
+
+use super::error::Error;
+use super::manifest::Manifest;
+use super::memory;
+use super::schedule::Phase;
+use super::settings::{OutputLimitPolicy, Settings};
+use super::timings::BatchTimings;
+
+/// Batch file bytes written against max_output_gb, and how the output is cut down once that's
+/// reached
+#[derive(Clone)]
+pub struct OutputLimit {
+    bytes_written: u64,
+    compression: Compression,
+    /// Only frames whose number is a multiple of this are written
+    keep_every: usize,
+    reached: bool,
+}
+
+impl OutputLimit {
+    /// Counting the batches a resumed run already wrote
+    pub fn new(settings: &Settings, first_batch: usize) -> OutputLimit {
+        let bytes_written = format::list_batches(&settings.out_path)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(batch, _)| *batch < first_batch)
+            .filter_map(|(_, path)| std::fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        OutputLimit {
+            bytes_written,
+            compression: Compression::new(settings.compression_level),
+            keep_every: 1,
+            reached: false,
+        }
+    }
+
+    /// Apply output_limit_policy the first time the output is at max_output_gb, before
+    /// `next_batch` is simulated. True when the policy is to stop there.
+    pub fn check(
+        &mut self,
+        settings: &Settings,
+        manifest: &mut Manifest,
+        next_batch: usize,
+    ) -> bool {
+        let Some(limit_gb) = settings.max_output_gb else {
+            return false;
+        };
+        if self.reached || (self.bytes_written as f64) < limit_gb * 1e9 {
+            return false;
+        }
+        self.reached = true;
+        let next_frame = next_batch * settings.frames_per_file;
+        let action = match settings.output_limit_policy {
+            OutputLimitPolicy::Stop => "stopping".to_string(),
+            OutputLimitPolicy::Compress => {
+                self.compression = Compression::best();
+                "compressing at gzip level 9 from now on".to_string()
+            }
+            OutputLimitPolicy::Decimate { keep_every } => {
+                self.keep_every = keep_every;
+                format!("writing one frame in {} from now on", keep_every)
+            }
+        };
+        let event = format!(
+            "output reached {} at frame {}, over max_output_gb {}: {}",
+            memory::format_bytes(self.bytes_written),
+            next_frame,
+            limit_gb,
+            action
+        );
+        warn!("{}", "!".repeat(80));
+        warn!("{}", event);
+        warn!("{}", "!".repeat(80));
+        manifest.events.push(event);
+        manifest.save(&settings.out_path);
+        settings.output_limit_policy == OutputLimitPolicy::Stop
+    }
+
+    /// gzip level of the batches sent from now on, for `Settings::hot_reload`. Once
+    /// output_limit_policy compress has switched to level 9 it stays there.
+    pub fn set_compression_level(&mut self, level: u32) {
+        if !(self.reached && self.compression == Compression::best()) {
+            self.compression = Compression::new(level);
+        }
+    }
+
+    /// Count a batch file of `bytes` against max_output_gb
+    pub fn record(&mut self, bytes: u64) {
+        self.bytes_written += bytes;
+    }
+}
+
+/// Write batch of frames, gathered into id order by `order` when the particles have been sorted.
+/// Returns the size of the file, for `OutputLimit::record`, and how long the serialize and
+/// compress_write parts took.
+pub fn write_frame_group(
+    settings: &Settings,
+    settings_hash: &str,
+    frame_list: &mut [Vec<Vec3>],
+    order: Option<&[u32]>,
+    batch_num: &usize,
+    output: &OutputLimit,
+    phase: Phase,
+) -> Result<(u64, BatchTimings), Error> {
+    let _span = trace_span!("write_frame_group", batch = batch_num).entered();
+    let hash = settings.hash_in_file_names.then_some(settings_hash);
+    let filename = settings
+        .out_path
+        .join(format::batch_file_name(*batch_num, hash));
+    let comment = BatchComment {
+        settings_hash: Some(settings_hash.to_string()),
+        units: settings
+            .units
+            .as_ref()
+            .and_then(|units| units.system().ok()),
+    };
+
+    // frame numbers count from the start of the run, so decimation doesn't restart each batch
+    let first_frame = batch_num * settings.frames_per_file;
+    let keep = |frame: usize| {
+        frame.is_multiple_of(output.keep_every) && frame.is_multiple_of(phase.output_every)
+    };
+    let kept = (first_frame..first_frame + frame_list.len())
+        .filter(|&frame| keep(frame))
+        .count();
+
+    let header = Header {
+        num_frames: kept,
+        num_particles: settings.num_particles,
+    };
+    let mut gathered = Vec::new();
+    let mut serialize = 0.0;
+    let start = Instant::now();
+    settings.write_retry.write(
+        &filename,
+        || std::fs::File::create(&filename),
+        |file| {
+            let mut encoder = BatchEncoder::new(file, header, &comment, output.compression)?;
+            let frames = frame_list
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| keep(first_frame + index))
+                .map(|(_, frame)| frame);
+            for frame in frames {
+                let gather_start = Instant::now();
+                let positions = match order {
+                    Some(order) => {
+                        gathered.clear();
+                        gathered.extend(order.iter().map(|&index| frame[index as usize]));
+                        &gathered
+                    }
+                    None => frame,
+                };
+                serialize += gather_start.elapsed().as_secs_f64();
+                encoder.write_frame(positions)?;
+            }
+            encoder.finish()?;
+            Ok(())
+        },
+    )?;
+    // everything but the gathering is gzip and the file
+    let timings = BatchTimings {
+        serialize,
+        compress_write: start.elapsed().as_secs_f64() - serialize,
+        ..BatchTimings::default()
+    };
+    let bytes = std::fs::metadata(&filename).map_or(0, |metadata| metadata.len());
+    Ok((bytes, timings))
+}
+
+/// Bytes the batch files from `first_batch` on take at most. Positions hardly compress, so this
+/// is their raw size, up to where max_output_gb stops the run when it does.
+pub fn expected_bytes(settings: &Settings, first_batch: usize) -> u64 {
+    let batches = (settings.frames_total / settings.frames_per_file).saturating_sub(first_batch);
+    let batch_bytes = Header {
+        num_frames: settings.frames_per_file,
+        num_particles: settings.num_particles,
+    }
+    .file_size() as u64;
+    let bytes = batches as u64 * batch_bytes;
+    match settings.max_output_gb {
+        // the batch that crosses the limit is still written
+        Some(limit_gb) if settings.output_limit_policy == OutputLimitPolicy::Stop => {
+            bytes.min((limit_gb * 1e9) as u64 + batch_bytes)
+        }
+        _ => bytes,
+    }
+}
+
+/// Refuse to start a run whose output won't fit in out_path. Where the free space can't be told
+/// the run goes ahead.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn check_free_space(settings: &Settings, first_batch: usize) -> Result<(), Error> {
+    let available = match fs4::available_space(&settings.out_path) {
+        Ok(available) => available,
+        Err(e) => {
+            warn!(
+                "Could not check the free space in {}: {}",
+                settings.out_path.display(),
+                e
+            );
+            return Ok(());
+        }
+    };
+    let needed = expected_bytes(settings, first_batch);
+    if needed > available {
+        return Err(Error::NoSpace {
+            dir: settings.out_path.clone(),
+            needed,
+            available,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn check_free_space(_settings: &Settings, _first_batch: usize) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Retries of a batch file write that failed in a way that tends to clear up by itself, eg. on a
+/// network filesystem. Once they run out the write fails, and the run with it.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct WriteRetry {
+    /// Tries after the first one, 0 to fail on the first error
+    pub attempts: u32,
+    /// Wait before the first retry, doubling for each one after
+    pub backoff_seconds: f64,
+}
+
+impl Default for WriteRetry {
+    fn default() -> Self {
+        WriteRetry {
+            attempts: 5,
+            backoff_seconds: 1.0,
+        }
+    }
+}
+
+impl WriteRetry {
+    /// Write the file at `path` from scratch: `open` it, then `contents` into it. Each retry
+    /// starts over with a fresh `open`, whichever of the two failed.
+    pub fn write<W: Write>(
+        &self,
+        path: &Path,
+        mut open: impl FnMut() -> io::Result<W>,
+        mut contents: impl FnMut(&mut W) -> io::Result<()>,
+    ) -> Result<(), Error> {
+        let mut backoff = Duration::from_secs_f64(self.backoff_seconds);
+        let mut retries = 0;
+        loop {
+            let (action, result) = match open() {
+                Ok(mut writer) => ("write", contents(&mut writer).and_then(|()| writer.flush())),
+                Err(e) => ("create", Err(e)),
+            };
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if retries < self.attempts && is_transient(&e) => {
+                    retries += 1;
+                    warn!(
+                        "Could not {} {}: {}, retrying in {:.1}s ({}/{})",
+                        action,
+                        path.display(),
+                        e,
+                        backoff.as_secs_f64(),
+                        retries,
+                        self.attempts
+                    );
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                Err(source) => {
+                    return Err(Error::Io {
+                        action,
+                        path: path.to_path_buf(),
+                        source,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Errors a write can hit one moment and not the next. Anything else, a full disk or a missing
+/// directory, fails the same way however often it's retried.
+fn is_transient(error: &io::Error) -> bool {
+    // EIO has no ErrorKind of its own
+    #[cfg(unix)]
+    if error.raw_os_error() == Some(5) {
+        return true;
+    }
+    matches!(
+        error.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::ResourceBusy
+            | io::ErrorKind::StaleNetworkFileHandle
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::path::PathBuf;
+    use std::rc::Rc;
+
+    /// Fails every write with `error` until `failures` have happened
+    struct FlakyWriter {
+        written: Rc<RefCell<Vec<u8>>>,
+        failures: Rc<Cell<usize>>,
+        error: io::ErrorKind,
+    }
+
+    impl Write for FlakyWriter {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            if self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return Err(io::Error::from(self.error));
+            }
+            self.written.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Writes "batch" with `retry`, the first `failures` writes failing with `error`. Returns
+    /// the result, what ended up written and how often the file was opened.
+    fn write(
+        retry: WriteRetry,
+        failures: usize,
+        error: io::ErrorKind,
+    ) -> (Result<(), Error>, Vec<u8>, usize) {
+        let path = PathBuf::from("batch_0000.bin.gz");
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let failures = Rc::new(Cell::new(failures));
+        let mut opened = 0;
+        let result = retry.write(
+            &path,
+            || {
+                opened += 1;
+                // a retry starts the file over
+                written.borrow_mut().clear();
+                Ok(FlakyWriter {
+                    written: written.clone(),
+                    failures: failures.clone(),
+                    error,
+                })
+            },
+            |writer| writer.write_all(b"batch"),
+        );
+        (result, written.take(), opened)
+    }
+
+    const NO_WAIT: WriteRetry = WriteRetry {
+        attempts: 3,
+        backoff_seconds: 0.0,
+    };
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let (result, written, opened) = write(NO_WAIT, 2, io::ErrorKind::TimedOut);
+        result.unwrap();
+        assert_eq!(written, b"batch");
+        assert_eq!(opened, 3);
+    }
+
+    #[test]
+    fn retries_run_out() {
+        let (result, _, opened) = write(NO_WAIT, 10, io::ErrorKind::StaleNetworkFileHandle);
+        assert!(matches!(
+            result,
+            Err(Error::Io {
+                action: "write",
+                ..
+            })
+        ));
+        assert_eq!(opened, 4);
+    }
+
+    #[test]
+    fn other_errors_fail_straight_away() {
+        let (result, _, opened) = write(NO_WAIT, 1, io::ErrorKind::StorageFull);
+        assert!(result.is_err());
+        assert_eq!(opened, 1);
+    }
+
+    #[test]
+    fn expected_bytes_stop_at_max_output_gb() {
+        let mut settings = Settings {
+            num_particles: 1000,
+            frames_total: 100,
+            frames_per_file: 10,
+            ..Settings::default()
+        };
+        let batch_bytes = 8 + 1000 * 12 * 10;
+        assert_eq!(expected_bytes(&settings, 0), 10 * batch_bytes);
+        assert_eq!(expected_bytes(&settings, 4), 6 * batch_bytes);
+
+        settings.max_output_gb = Some(1e-6);
+        assert_eq!(expected_bytes(&settings, 0), 1000 + batch_bytes);
+        settings.output_limit_policy = OutputLimitPolicy::Compress;
+        assert_eq!(expected_bytes(&settings, 0), 10 * batch_bytes);
+    }
+}
@@ -0,0 +1,404 @@
+use glam::{DVec3, Vec3};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use tracing::trace_span;
+
+use super::settings::{Integrator, Settings};
+
+/// Constants of the force law `Particle::get_influence` and `get_potential` evaluate
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Gravity {
+    pub g_const: f32,
+    pub softening_sq: f32,
+}
+
+impl Gravity {
+    pub fn new(settings: &Settings) -> Gravity {
+        Gravity {
+            g_const: settings.g_const,
+            softening_sq: settings.softening * settings.softening,
+        }
+    }
+
+    /// Force a body of `mass` at `pos` experiences from one of `other_mass` at `other_pos`
+    pub fn force(&self, pos: Vec3, mass: f32, other_pos: Vec3, other_mass: f32) -> Vec3 {
+        // same Plummer softening as nbody.wgsl so the backends agree
+        let r_vec = other_pos - pos;
+        let r_sq = (r_vec).dot(r_vec) + self.softening_sq;
+
+        // Combined magnitude and direction calculation
+        let force_over_r3 = self.g_const * mass * other_mass / (r_sq * r_sq.sqrt());
+
+        r_vec * force_over_r3
+    }
+
+    /// Potential at `pos` per unit mass from a body of `other_mass` at `other_pos`
+    pub fn potential(&self, pos: Vec3, other_pos: Vec3, other_mass: f32) -> f32 {
+        let r_vec = other_pos - pos;
+        let r_sq = r_vec.dot(r_vec) + self.softening_sq;
+        -self.g_const * other_mass / r_sq.sqrt()
+    }
+}
+
+/// One body of the simulation, the CPU side of `GpuParticle`. A run keeps them in a
+/// `ParticleSet`, this is a copy of one.
+///
+/// Built with `new` and the `with_` setters, eg.
+/// `Particle::new_zero().with_mass(2.0).with_vel(Vec3::X)`. Serialized, acc, group and id can
+/// be left out.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Particle {
+    mass: f32,
+    pos: Vec3,
+    vel: Vec3,
+    /// Acceleration at `pos`, from the last force pass, which the next step starts with
+    #[serde(default)]
+    acc: Vec3,
+    /// Component tag assigned by the initial conditions (bulge/disk/halo...)
+    #[serde(default)]
+    group: u32,
+    /// Index in the initial particle set, and so in every output frame, however the particle
+    /// vec gets reordered
+    #[serde(default)]
+    id: u32,
+}
+
+impl Particle {
+    /// New in group 0 with id 0, the initial conditions number them
+    pub fn new(mass: f32, pos: Vec3, vel: Vec3, acc: Vec3) -> Particle {
+        Particle {
+            mass,
+            pos,
+            vel,
+            acc,
+            group: 0,
+            id: 0,
+        }
+    }
+
+    /// New with default values at zero
+    pub fn new_zero() -> Particle {
+        Particle {
+            mass: 1.0,
+            pos: Vec3::ZERO,
+            vel: Vec3::ZERO,
+            acc: Vec3::ZERO,
+            group: 0,
+            id: 0,
+        }
+    }
+
+    pub fn mass(&self) -> f32 {
+        self.mass
+    }
+
+    pub fn pos(&self) -> Vec3 {
+        self.pos
+    }
+
+    pub fn vel(&self) -> Vec3 {
+        self.vel
+    }
+
+    pub fn acc(&self) -> Vec3 {
+        self.acc
+    }
+
+    pub fn group(&self) -> u32 {
+        self.group
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn with_mass(self, mass: f32) -> Particle {
+        Particle { mass, ..self }
+    }
+
+    pub fn with_pos(self, pos: Vec3) -> Particle {
+        Particle { pos, ..self }
+    }
+
+    pub fn with_vel(self, vel: Vec3) -> Particle {
+        Particle { vel, ..self }
+    }
+
+    pub fn with_acc(self, acc: Vec3) -> Particle {
+        Particle { acc, ..self }
+    }
+
+    pub fn with_group(self, group: u32) -> Particle {
+        Particle { group, ..self }
+    }
+
+    pub fn with_id(self, id: u32) -> Particle {
+        Particle { id, ..self }
+    }
+
+    /// 1/2 m v^2
+    pub fn kinetic_energy(&self) -> f32 {
+        kinetic_energy(self.mass as f64, self.vel.as_dvec3()) as f32
+    }
+
+    /// m v
+    pub fn momentum(&self) -> Vec3 {
+        momentum(self.mass as f64, self.vel.as_dvec3()).as_vec3()
+    }
+
+    /// Returns force that `self` experiences from other.
+    ///
+    /// Returns the force vector of influence
+    pub fn get_influence(&self, other: &Particle, gravity: &Gravity) -> Vec3 {
+        gravity.force(self.pos, self.mass, other.pos, other.mass)
+    }
+
+    /// Gravitational potential `other` creates at `self`, per unit mass.
+    ///
+    /// Softened the same way as `get_influence`, and stored in the w slot of the GPU forces.
+    pub fn get_potential(&self, other: &Particle, gravity: &Gravity) -> f32 {
+        gravity.potential(self.pos, other.pos, other.mass)
+    }
+
+    /// First half of a step: move on with the acceleration stored by the last `kick`.
+    ///
+    /// Mirrors the `drift` entry point in nbody.wgsl.
+    pub fn drift(&mut self, integrator: Integrator, dt: f32) {
+        drift(&mut self.pos, &mut self.vel, self.acc, integrator, dt);
+    }
+
+    /// Second half of a step: finish the velocity with `force`, computed at the position `drift`
+    /// moved to, and store the acceleration for the next step.
+    ///
+    /// Mirrors the `kick` entry point in nbody.wgsl.
+    pub fn kick(&mut self, force: &Vec3, integrator: Integrator, dt: f32) {
+        kick(
+            &mut self.vel,
+            &mut self.acc,
+            self.mass,
+            *force,
+            integrator,
+            dt,
+        );
+    }
+}
+
+/// `Particle::kinetic_energy` on the fields of one particle, in f64 for the diagnostics' sums
+pub(crate) fn kinetic_energy(mass: f64, vel: DVec3) -> f64 {
+    0.5 * mass * vel.length_squared()
+}
+
+/// `Particle::momentum` on the fields of one particle
+pub(crate) fn momentum(mass: f64, vel: DVec3) -> DVec3 {
+    mass * vel
+}
+
+/// `Particle::drift` on the fields of one particle
+fn drift(pos: &mut Vec3, vel: &mut Vec3, acc: Vec3, integrator: Integrator, dt: f32) {
+    match integrator {
+        // Simple Euler integration (more stable for this system)
+        Integrator::Euler => {
+            *vel += acc * dt;
+            *pos += *vel * dt;
+        }
+        // Velocity Verlet, the velocity is finished by `kick` once the new acceleration is known
+        Integrator::Verlet => {
+            *pos += *vel * dt + 0.5 * acc * dt * dt;
+        }
+    }
+}
+
+/// `Particle::kick` on the fields of one particle
+fn kick(
+    vel: &mut Vec3,
+    last_acc: &mut Vec3,
+    mass: f32,
+    force: Vec3,
+    integrator: Integrator,
+    dt: f32,
+) {
+    let acc = force / mass;
+    if integrator == Integrator::Verlet {
+        *vel += 0.5 * (*last_acc + acc) * dt;
+    }
+    *last_acc = acc;
+}
+
+/// Every particle of a run, a vec per field so that a pass over the positions only reads
+/// positions. `get` and `iter` give `Particle`s back for everything else.
+#[derive(Default)]
+pub struct ParticleSet {
+    pub mass: Vec<f32>,
+    pub pos: Vec<Vec3>,
+    pub vel: Vec<Vec3>,
+    /// Acceleration at `pos`, from the last force pass, which the next step starts with
+    pub acc: Vec<Vec3>,
+    /// Component tag assigned by the initial conditions (bulge/disk/halo...)
+    pub group: Vec<u32>,
+    /// Index in the initial particle set, and so in every output frame, however the set gets
+    /// reordered
+    pub id: Vec<u32>,
+}
+
+impl ParticleSet {
+    pub fn with_capacity(capacity: usize) -> ParticleSet {
+        ParticleSet {
+            mass: Vec::with_capacity(capacity),
+            pos: Vec::with_capacity(capacity),
+            vel: Vec::with_capacity(capacity),
+            acc: Vec::with_capacity(capacity),
+            group: Vec::with_capacity(capacity),
+            id: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos.is_empty()
+    }
+
+    pub fn push(&mut self, particle: Particle) {
+        self.mass.push(particle.mass);
+        self.pos.push(particle.pos);
+        self.vel.push(particle.vel);
+        self.acc.push(particle.acc);
+        self.group.push(particle.group);
+        self.id.push(particle.id);
+    }
+
+    /// Copy of the `index`th particle
+    pub fn get(&self, index: usize) -> Particle {
+        Particle {
+            mass: self.mass[index],
+            pos: self.pos[index],
+            vel: self.vel[index],
+            acc: self.acc[index],
+            group: self.group[index],
+            id: self.id[index],
+        }
+    }
+
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = Particle> + '_ {
+        (0..self.len()).map(|index| self.get(index))
+    }
+
+    /// The particles at `indices`, in that order
+    pub fn select(&self, indices: &[u32]) -> ParticleSet {
+        fn gather<T: Copy + Send + Sync>(field: &[T], indices: &[u32]) -> Vec<T> {
+            indices
+                .par_iter()
+                .map(|&index| field[index as usize])
+                .collect()
+        }
+        ParticleSet {
+            mass: gather(&self.mass, indices),
+            pos: gather(&self.pos, indices),
+            vel: gather(&self.vel, indices),
+            acc: gather(&self.acc, indices),
+            group: gather(&self.group, indices),
+            id: gather(&self.id, indices),
+        }
+    }
+
+    /// `Particle::drift` every particle
+    pub fn drift(&mut self, integrator: Integrator, dt: f32) {
+        let _span = trace_span!("integrate").entered();
+        (&mut self.pos, &mut self.vel, &self.acc)
+            .into_par_iter()
+            .for_each(|(pos, vel, &acc)| drift(pos, vel, acc, integrator, dt));
+    }
+
+    /// `Particle::kick` every particle with the force on it
+    pub fn kick(&mut self, forces: &[Vec3], integrator: Integrator, dt: f32) {
+        let _span = trace_span!("integrate").entered();
+        (&mut self.vel, &mut self.acc, &self.mass, forces)
+            .into_par_iter()
+            .for_each(|(vel, acc, &mass, &force)| kick(vel, acc, mass, force, integrator, dt));
+    }
+
+    /// Store the acceleration from `forces` without moving anything, for a state nothing has
+    /// computed forces for yet. The first `drift` needs it.
+    pub fn set_accelerations(&mut self, forces: &[Vec3]) {
+        (&mut self.acc, &self.mass, forces)
+            .into_par_iter()
+            .for_each(|(acc, &mass, &force)| *acc = force / mass);
+    }
+}
+
+/// Field by field, so `clone_from` reuses the vecs already there
+impl Clone for ParticleSet {
+    fn clone(&self) -> ParticleSet {
+        ParticleSet {
+            mass: self.mass.clone(),
+            pos: self.pos.clone(),
+            vel: self.vel.clone(),
+            acc: self.acc.clone(),
+            group: self.group.clone(),
+            id: self.id.clone(),
+        }
+    }
+
+    fn clone_from(&mut self, source: &ParticleSet) {
+        self.mass.clone_from(&source.mass);
+        self.pos.clone_from(&source.pos);
+        self.vel.clone_from(&source.vel);
+        self.acc.clone_from(&source.acc);
+        self.group.clone_from(&source.group);
+        self.id.clone_from(&source.id);
+    }
+}
+
+impl FromIterator<Particle> for ParticleSet {
+    fn from_iter<I: IntoIterator<Item = Particle>>(particles: I) -> ParticleSet {
+        let particles = particles.into_iter();
+        let mut set = ParticleSet::with_capacity(particles.size_hint().0);
+        for particle in particles {
+            set.push(particle);
+        }
+        set
+    }
+}
+
+impl From<Vec<Particle>> for ParticleSet {
+    fn from(particles: Vec<Particle>) -> ParticleSet {
+        particles.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builders_set_one_field_each() {
+        let particle = Particle::new_zero()
+            .with_mass(2.0)
+            .with_pos(Vec3::X)
+            .with_vel(Vec3::new(0.0, 3.0, 4.0))
+            .with_group(1)
+            .with_id(7);
+        assert_eq!(particle.mass(), 2.0);
+        assert_eq!(particle.pos(), Vec3::X);
+        assert_eq!(particle.acc(), Vec3::ZERO);
+        assert_eq!((particle.group(), particle.id()), (1, 7));
+        assert_eq!(particle.kinetic_energy(), 25.0);
+        assert_eq!(particle.momentum(), Vec3::new(0.0, 6.0, 8.0));
+    }
+
+    #[test]
+    fn serializes_with_optional_fields() {
+        let particle = Particle::new(2.0, Vec3::X, Vec3::Y, Vec3::Z).with_id(3);
+        let json = serde_json::to_string(&particle).unwrap();
+        assert_eq!(serde_json::from_str::<Particle>(&json).unwrap(), particle);
+
+        let minimal: Particle =
+            serde_json::from_str(r#"{"mass": 1.0, "pos": [1, 2, 3], "vel": [0, 0, 0]}"#).unwrap();
+        assert_eq!(
+            minimal,
+            Particle::new(1.0, Vec3::new(1.0, 2.0, 3.0), Vec3::ZERO, Vec3::ZERO)
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use std::path::PathBuf;
+
+/// wgpu pipeline cache persisted between runs, so repeat runs skip most of the driver's shader
+/// compilation. Only Vulkan supports it, everywhere else `load` returns None.
+pub struct DiskPipelineCache {
+    cache: wgpu::PipelineCache,
+    path: PathBuf,
+    /// Whether there was a file to start from
+    loaded: bool,
+}
+
+impl DiskPipelineCache {
+    /// Open the cache for this adapter, empty if there's no file yet or it doesn't apply anymore.
+    pub fn load(device: &wgpu::Device, adapter_info: &wgpu::AdapterInfo) -> Option<Self> {
+        if !device.features().contains(wgpu::Features::PIPELINE_CACHE) {
+            return None;
+        }
+        // the key covers vendor and device, the cache data itself checks the driver version
+        let key = wgpu::util::pipeline_cache_key(adapter_info)?;
+        let path = cache_dir()?.join(key);
+        let data = std::fs::read(&path).ok();
+
+        // SAFETY: the file is only ever written from `PipelineCache::get_data` below. Data from
+        // another wgpu or driver version is detected and, with `fallback`, replaced by an empty
+        // cache.
+        let cache = unsafe {
+            device.create_pipeline_cache(&wgpu::PipelineCacheDescriptor {
+                label: Some("Pipeline Cache"),
+                data: data.as_deref(),
+                fallback: true,
+            })
+        };
+        Some(DiskPipelineCache {
+            cache,
+            path,
+            loaded: data.is_some(),
+        })
+    }
+
+    pub fn cache(&self) -> &wgpu::PipelineCache {
+        &self.cache
+    }
+
+    pub fn was_loaded(&self) -> bool {
+        self.loaded
+    }
+
+    /// Write the cache back to disk. Best effort: a cache that can't be written is just missing
+    /// next run.
+    pub fn save(&self) {
+        let Some(data) = self.cache.get_data() else {
+            return;
+        };
+        if let Some(dir) = self.path.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        // written aside and renamed, so a concurrent run never reads half a cache. The name is
+        // this process's own, two runs saving at once would otherwise write the same file.
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(format!(".{}.tmp", std::process::id()));
+        let temp = PathBuf::from(temp);
+        if std::fs::write(&temp, data).is_err() || std::fs::rename(&temp, &self.path).is_err() {
+            let _ = std::fs::remove_file(&temp);
+        }
+    }
+}
+
+/// Per-user cache directory for this program
+pub fn cache_dir() -> Option<PathBuf> {
+    let home = || std::env::var_os("HOME").map(PathBuf::from);
+    let base = if cfg!(windows) {
+        std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        home().map(|home| home.join("Library").join("Caches"))
+    } else {
+        std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+            .or_else(|| home().map(|home| home.join(".cache")))
+    };
+    Some(base?.join("gravity-output"))
+}
@@ -0,0 +1,598 @@
+use glam::{Mat4, Vec3};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use winit::application::ApplicationHandler;
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+use winit::event::{ElementState, MouseButton, MouseScrollDelta, WindowEvent};
+use winit::event_loop::{ActiveEventLoop, ControlFlow, EventLoop};
+use winit::window::{Window, WindowId};
+
+use super::ParticleSet;
+use super::error::Error;
+use super::gpu::{GpuDevice, PACKED_VEC3_SIZE};
+use super::observer::FrameObserver;
+use super::simulation::Simulation;
+
+/// How often the window looks for a new frame when nothing else wakes it
+const POLL_INTERVAL: Duration = Duration::from_millis(16);
+
+/// Where frames are copied for the preview window to draw. Frames are skipped while the last
+/// one hasn't been drawn yet, so a window that falls behind never holds the run back.
+pub(crate) struct PreviewTarget {
+    /// Packed positions in particle vec order, the window's vertex buffer
+    pub(crate) buffer: wgpu::Buffer,
+    every: usize,
+    /// Set once a frame has been copied in, cleared once the window has drawn it
+    fresh: AtomicBool,
+    /// Set when the window is closed, nothing's copied after that
+    closed: AtomicBool,
+}
+
+impl PreviewTarget {
+    fn new(device: &wgpu::Device, num_particles: usize, every: usize) -> PreviewTarget {
+        PreviewTarget {
+            buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Preview Positions"),
+                size: (num_particles * PACKED_VEC3_SIZE) as u64,
+                usage: wgpu::BufferUsages::VERTEX
+                    | wgpu::BufferUsages::COPY_DST
+                    | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            }),
+            every: every.max(1),
+            fresh: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether frame number `frame` should be copied in. Claims the buffer for it when it is.
+    pub(crate) fn wants(&self, frame: usize) -> bool {
+        frame.is_multiple_of(self.every)
+            && !self.closed.load(Ordering::Relaxed)
+            && !self.fresh.swap(true, Ordering::AcqRel)
+    }
+}
+
+/// Open the preview window on `simulation`'s GPU, drawing one frame in `every` from now on.
+///
+/// With gpu_integration the device copies the positions into the window's buffer itself,
+/// otherwise they're uploaded from the frames read back for the batch files. A device lost
+/// partway through leaves the window on the last frame it got.
+pub(crate) fn open(simulation: Simulation, every: usize) -> Result<Simulation, Error> {
+    let Some(gpu) = simulation.backend().as_gpu() else {
+        return Err(Error::Command(
+            "--preview draws on the run's GPU, it needs force_backend gpu on a single device"
+                .to_string(),
+        ));
+    };
+    let target = Arc::new(PreviewTarget::new(
+        &gpu.device,
+        simulation.particles().len(),
+        every,
+    ));
+    let camera = OrbitCamera::framing(simulation.particles());
+    spawn_window(gpu.gpu_device.clone(), target.clone(), camera)?;
+    info!("Preview: drawing one frame in {}", target.every);
+    if simulation.integrates_on_device() {
+        gpu.preview_into(target);
+        return Ok(simulation);
+    }
+    let upload = PreviewUpload {
+        queue: gpu.queue.clone(),
+        target,
+    };
+    Ok(simulation.observed_by(Box::new(upload)))
+}
+
+/// Uploads frames to the window when the particles are integrated on the CPU
+struct PreviewUpload {
+    queue: wgpu::Queue,
+    target: Arc<PreviewTarget>,
+}
+
+impl FrameObserver for PreviewUpload {
+    fn on_frame(
+        &mut self,
+        frame_index: usize,
+        _time: f64,
+        positions: &[Vec3],
+        _velocities: Option<&[Vec3]>,
+    ) {
+        if self.target.wants(frame_index) {
+            self.queue
+                .write_buffer(&self.target.buffer, 0, bytemuck::cast_slice(positions));
+        }
+    }
+
+    fn wants_positions(&self, frame_index: usize) -> bool {
+        frame_index.is_multiple_of(self.target.every)
+    }
+}
+
+/// Start the window's event loop on a thread of its own, returning once it's running
+fn spawn_window(
+    gpu_device: GpuDevice,
+    target: Arc<PreviewTarget>,
+    camera: OrbitCamera,
+) -> Result<(), Error> {
+    let (started, start_result) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("preview".to_string())
+        .spawn(move || {
+            let event_loop = match event_loop() {
+                Ok(event_loop) => event_loop,
+                Err(e) => {
+                    let _ = started.send(Err(e));
+                    return;
+                }
+            };
+            let _ = started.send(Ok(()));
+            let num_particles = (target.buffer.size() / PACKED_VEC3_SIZE as u64) as u32;
+            let mut app = PreviewWindow {
+                gpu_device,
+                target,
+                num_particles,
+                camera,
+                state: None,
+                cursor: None,
+                dragging: false,
+            };
+            if let Err(e) = event_loop.run_app(&mut app) {
+                warn!("Preview window: {}", e);
+            }
+            app.target.closed.store(true, Ordering::Relaxed);
+        })
+        .map_err(|e| Error::Command(format!("Could not start the preview thread: {}", e)))?;
+    match start_result.recv() {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(Error::Command(format!(
+            "Could not open the preview window: {}",
+            e
+        ))),
+        Err(_) => Err(Error::Command(
+            "The preview thread stopped before opening its window".to_string(),
+        )),
+    }
+}
+
+/// An event loop the preview thread can run, which winit only allows off the main thread on
+/// some platforms
+#[cfg(target_os = "linux")]
+fn event_loop() -> Result<EventLoop<()>, String> {
+    use winit::platform::x11::EventLoopBuilderExtX11;
+    let mut builder = EventLoop::builder();
+    // sets it for wayland too
+    EventLoopBuilderExtX11::with_any_thread(&mut builder, true);
+    builder.build().map_err(|e| e.to_string())
+}
+
+#[cfg(target_os = "windows")]
+fn event_loop() -> Result<EventLoop<()>, String> {
+    use winit::platform::windows::EventLoopBuilderExtWindows;
+    EventLoop::builder()
+        .with_any_thread(true)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn event_loop() -> Result<EventLoop<()>, String> {
+    Err("only Linux and Windows can run it beside the simulation".to_string())
+}
+
+/// Looking at `target` from `distance` away, `yaw` around the y axis and `pitch` above the
+/// xz plane
+#[derive(Clone, Copy, Debug)]
+struct OrbitCamera {
+    target: Vec3,
+    distance: f32,
+    yaw: f32,
+    pitch: f32,
+    aspect: f32,
+}
+
+impl OrbitCamera {
+    /// Far enough back from the centre of `particles` to see all of them
+    fn framing(particles: &ParticleSet) -> OrbitCamera {
+        let count = particles.pos.len().max(1) as f32;
+        let target = particles.pos.iter().copied().sum::<Vec3>() / count;
+        let radius = particles
+            .pos
+            .iter()
+            .map(|pos| pos.distance(target))
+            .filter(|distance| distance.is_finite())
+            .fold(0.0f32, f32::max);
+        OrbitCamera {
+            target,
+            distance: 2.5 * radius.max(1e-3),
+            yaw: 0.0,
+            pitch: 0.3,
+            aspect: 1.0,
+        }
+    }
+
+    fn eye(&self) -> Vec3 {
+        let direction = Vec3::new(
+            self.pitch.cos() * self.yaw.sin(),
+            self.pitch.sin(),
+            self.pitch.cos() * self.yaw.cos(),
+        );
+        self.target + self.distance * direction
+    }
+
+    fn view_proj(&self) -> Mat4 {
+        let proj = Mat4::perspective_rh(
+            45f32.to_radians(),
+            self.aspect,
+            self.distance * 1e-3,
+            self.distance * 1e3,
+        );
+        proj * Mat4::look_at_rh(self.eye(), self.target, Vec3::Y)
+    }
+
+    /// Dragging by `dx`, `dy` pixels
+    fn rotate(&mut self, dx: f32, dy: f32) {
+        self.yaw -= dx * 0.01;
+        // short of straight up or down, where look_at flips over
+        self.pitch = (self.pitch + dy * 0.01).clamp(-1.5, 1.5);
+    }
+
+    /// Scrolling by `lines`, in towards the target for positive ones
+    fn zoom(&mut self, lines: f32) {
+        self.distance *= 0.9f32.powf(lines);
+    }
+}
+
+/// What's only there while the window is open
+struct WindowState {
+    window: Arc<Window>,
+    surface: wgpu::Surface<'static>,
+    config: wgpu::SurfaceConfiguration,
+    pipeline: wgpu::RenderPipeline,
+    camera_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    /// Camera moved or window resized since the last draw
+    stale: bool,
+}
+
+struct PreviewWindow {
+    gpu_device: GpuDevice,
+    target: Arc<PreviewTarget>,
+    num_particles: u32,
+    camera: OrbitCamera,
+    state: Option<WindowState>,
+    cursor: Option<PhysicalPosition<f64>>,
+    dragging: bool,
+}
+
+impl PreviewWindow {
+    fn create_state(&self, event_loop: &ActiveEventLoop) -> Result<WindowState, String> {
+        let attributes = Window::default_attributes()
+            .with_title("gravity-output preview")
+            .with_inner_size(PhysicalSize::new(1024, 768));
+        let window = Arc::new(
+            event_loop
+                .create_window(attributes)
+                .map_err(|e| e.to_string())?,
+        );
+        let GpuDevice {
+            instance,
+            adapter,
+            device,
+            ..
+        } = &self.gpu_device;
+        let surface = instance
+            .create_surface(window.clone())
+            .map_err(|e| e.to_string())?;
+        let size = window.inner_size();
+        let config = surface
+            .get_default_config(adapter, size.width.max(1), size.height.max(1))
+            .ok_or("the run's adapter can't draw to this window")?;
+        surface.configure(device, &config);
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Preview Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("preview.wgsl").into()),
+        });
+        let camera_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Preview Camera"),
+            size: std::mem::size_of::<Mat4>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Preview Bind Group Layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Preview Bind Group"),
+            layout: &bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: camera_buffer.as_entire_binding(),
+            }],
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Preview Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Preview Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vs_main"),
+                compilation_options: Default::default(),
+                buffers: &[wgpu::VertexBufferLayout {
+                    array_stride: PACKED_VEC3_SIZE as u64,
+                    step_mode: wgpu::VertexStepMode::Vertex,
+                    attributes: &wgpu::vertex_attr_array![0 => Float32x3],
+                }],
+            },
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::PointList,
+                ..Default::default()
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: Some("fs_main"),
+                compilation_options: Default::default(),
+                targets: &[Some(config.format.into())],
+            }),
+            multiview: None,
+            cache: None,
+        });
+        Ok(WindowState {
+            window,
+            surface,
+            config,
+            pipeline,
+            camera_buffer,
+            bind_group,
+            stale: true,
+        })
+    }
+
+    /// Draw the latest frame. Errors are the window's own and only close it, they don't
+    /// reach the device's error handler and so can't stop the run.
+    fn draw(&mut self) -> Result<(), String> {
+        let Some(state) = &mut self.state else {
+            return Ok(());
+        };
+        let GpuDevice { device, queue, .. } = &self.gpu_device;
+        let frame = match state.surface.get_current_texture() {
+            Ok(frame) => frame,
+            Err(wgpu::SurfaceError::Outdated | wgpu::SurfaceError::Lost) => {
+                state.surface.configure(device, &state.config);
+                return Ok(());
+            }
+            // try again on the next wake up
+            Err(wgpu::SurfaceError::Timeout) => return Ok(()),
+            Err(e) => return Err(e.to_string()),
+        };
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        self.camera.aspect = state.config.width as f32 / state.config.height.max(1) as f32;
+        queue.write_buffer(
+            &state.camera_buffer,
+            0,
+            bytemuck::bytes_of(&self.camera.view_proj()),
+        );
+        let view = frame
+            .texture
+            .create_view(&wgpu::TextureViewDescriptor::default());
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Preview Encoder"),
+        });
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Preview Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    depth_slice: None,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            pass.set_pipeline(&state.pipeline);
+            pass.set_bind_group(0, &state.bind_group, &[]);
+            pass.set_vertex_buffer(0, self.target.buffer.slice(..));
+            pass.draw(0..self.num_particles, 0..1);
+        }
+        queue.submit(Some(encoder.finish()));
+        state.window.pre_present_notify();
+        frame.present();
+        state.stale = false;
+        // the next frame can be copied in once this one's submitted
+        self.target.fresh.store(false, Ordering::Release);
+        match pollster::block_on(device.pop_error_scope()) {
+            Some(error) => Err(error.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    fn close(&mut self, event_loop: &ActiveEventLoop) {
+        self.target.closed.store(true, Ordering::Relaxed);
+        self.state = None;
+        event_loop.exit();
+    }
+}
+
+impl ApplicationHandler for PreviewWindow {
+    fn resumed(&mut self, event_loop: &ActiveEventLoop) {
+        if self.state.is_some() {
+            return;
+        }
+        match self.create_state(event_loop) {
+            Ok(state) => self.state = Some(state),
+            Err(e) => {
+                warn!("Could not open the preview window: {}", e);
+                self.close(event_loop);
+            }
+        }
+    }
+
+    fn window_event(&mut self, event_loop: &ActiveEventLoop, _id: WindowId, event: WindowEvent) {
+        let Some(state) = &mut self.state else {
+            return;
+        };
+        match event {
+            // the run carries on without it
+            WindowEvent::CloseRequested => self.close(event_loop),
+            WindowEvent::Resized(size) => {
+                state.config.width = size.width.max(1);
+                state.config.height = size.height.max(1);
+                state
+                    .surface
+                    .configure(&self.gpu_device.device, &state.config);
+                state.stale = true;
+            }
+            WindowEvent::MouseInput {
+                state: pressed,
+                button: MouseButton::Left,
+                ..
+            } => self.dragging = pressed == ElementState::Pressed,
+            WindowEvent::CursorMoved { position, .. } => {
+                if let Some(last) = self.cursor
+                    && self.dragging
+                {
+                    self.camera
+                        .rotate((position.x - last.x) as f32, (position.y - last.y) as f32);
+                    state.stale = true;
+                }
+                self.cursor = Some(position);
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => y,
+                    MouseScrollDelta::PixelDelta(position) => position.y as f32 / 40.0,
+                };
+                self.camera.zoom(lines);
+                state.stale = true;
+            }
+            WindowEvent::RedrawRequested => {
+                if let Err(e) = self.draw() {
+                    warn!("Preview window: {}, closing it", e);
+                    self.close(event_loop);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+        if let Some(state) = &self.state
+            && (state.stale || self.target.fresh.load(Ordering::Acquire))
+        {
+            state.window.request_redraw();
+        }
+        event_loop.set_control_flow(ControlFlow::WaitUntil(Instant::now() + POLL_INTERVAL));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu::GpuCompute;
+    use crate::settings::Settings;
+    use crate::{Particle, backend};
+
+    /// `buffer`'s positions, copied back through a staging buffer
+    fn read_positions(gpu: &GpuCompute, buffer: &wgpu::Buffer) -> Vec<Vec3> {
+        let staging = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: buffer.size(),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = gpu
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, buffer.size());
+        gpu.queue.submit(Some(encoder.finish()));
+        staging.slice(..).map_async(wgpu::MapMode::Read, |_| ());
+        gpu.device.poll(wgpu::wgt::PollType::Wait).unwrap();
+        bytemuck::cast_slice(&staging.slice(..).get_mapped_range()).to_vec()
+    }
+
+    #[test]
+    fn the_device_copies_every_nth_frame_unless_the_last_is_undrawn() {
+        let particles: ParticleSet = (0..300)
+            .map(|i| {
+                let t = i as f32;
+                let pos = Vec3::new(t.sin(), t.cos(), t * 0.01) * 10.0;
+                Particle::new(1.0, pos, Vec3::X, Vec3::ZERO)
+            })
+            .collect();
+        let mut settings = Settings {
+            num_particles: particles.len(),
+            // chunked, each chunk's positions go to their own part of the buffer
+            max_buffer_size: Some(160 * std::mem::size_of::<crate::GpuParticle>() as u64),
+            ..Settings::default()
+        };
+        settings.adapter.allow_software_adapter = true;
+        let Ok(gpu) = backend::drive(GpuCompute::new(&settings)) else {
+            return;
+        };
+        gpu.upload(&particles);
+        let target = Arc::new(PreviewTarget::new(&gpu.device, particles.len(), 2));
+        gpu.preview_into(target.clone());
+
+        let mut frames = vec![vec![Vec3::ZERO; particles.len()]; 5];
+        let step = |frame: usize, frames: &mut [Vec<Vec3>]| {
+            let steps = gpu.submit_steps(1, false, |_| true);
+            gpu.finish_steps(steps, &mut frames[frame..], &mut [], &mut Vec::new());
+        };
+        // frame 2 is skipped, frame 0 hasn't been drawn yet
+        for frame in 0..3 {
+            step(frame, &mut frames);
+        }
+        assert_eq!(read_positions(&gpu, &target.buffer), frames[0]);
+
+        target.fresh.store(false, Ordering::Release);
+        for frame in 3..5 {
+            step(frame, &mut frames);
+        }
+        assert_eq!(read_positions(&gpu, &target.buffer), frames[4]);
+        assert_ne!(frames[4], frames[0]);
+    }
+
+    #[test]
+    fn the_camera_starts_out_seeing_every_particle() {
+        let particles: ParticleSet = [Vec3::new(10.0, 0.0, 0.0), Vec3::new(-10.0, 4.0, 2.0)]
+            .into_iter()
+            .map(|pos| Particle::new(1.0, pos, Vec3::ZERO, Vec3::ZERO))
+            .collect();
+        let mut camera = OrbitCamera::framing(&particles);
+        camera.rotate(120.0, -40.0);
+        camera.zoom(1.0);
+        let view_proj = camera.view_proj();
+        for pos in &particles.pos {
+            let clip = view_proj * pos.extend(1.0);
+            let ndc = clip.truncate() / clip.w;
+            assert!(clip.w > 0.0);
+            assert!(ndc.x.abs() <= 1.0 && ndc.y.abs() <= 1.0, "{:?}", ndc);
+            assert!((0.0..=1.0).contains(&ndc.z));
+        }
+    }
+}
@@ -0,0 +1,19 @@
+// Particles as points for the --preview window, drawn by preview.rs.
+
+// Mirrors the uniform written by OrbitCamera in preview.rs
+struct Camera {
+    view_proj: mat4x4<f32>,
+}
+
+@group(0) @binding(0) var<uniform> camera: Camera;
+
+// Packed positions, as the kick pass writes them for readback
+@vertex
+fn vs_main(@location(0) pos: vec3<f32>) -> @builtin(position) vec4<f32> {
+    return camera.view_proj * vec4<f32>(pos, 1.0);
+}
+
+@fragment
+fn fs_main() -> @location(0) vec4<f32> {
+    return vec4<f32>(0.85, 0.9, 1.0, 1.0);
+}
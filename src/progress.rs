@@ -0,0 +1,413 @@
+#[cfg(not(target_arch = "wasm32"))]
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde::Serialize;
+use std::collections::VecDeque;
+#[cfg(not(target_arch = "wasm32"))]
+use std::io::IsTerminal;
+use std::io::Write;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::{debug, info};
+
+use super::observer::{BatchReport, FrameObserver};
+use super::settings::Settings;
+
+/// How `run` reports its progress, see `--progress-format`
+#[derive(Clone, Copy, Default, PartialEq, Debug, clap::ValueEnum)]
+pub enum ProgressFormat {
+    /// A progress bar on a terminal, otherwise a log line per batch
+    #[default]
+    Console,
+    /// A JSON object per event on stdout, for dashboards to follow
+    Json,
+}
+
+impl ProgressFormat {
+    /// With `bar` the console format draws a progress bar, as long as stdout is a terminal. Log
+    /// lines are for files and nohup.
+    pub fn sink(self, bar: bool) -> Box<dyn ProgressSink> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            ProgressFormat::Console if bar && std::io::stdout().is_terminal() => {
+                Box::new(BarProgress::new())
+            }
+            ProgressFormat::Console => Box::new(ConsoleProgress),
+            ProgressFormat::Json => Box::new(JsonProgress::new(Box::new(std::io::stdout()))),
+        }
+    }
+}
+
+/// Something that happened to the run
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    BatchStarted {
+        batch: usize,
+        first_frame: usize,
+    },
+    BatchCompleted {
+        batch: usize,
+        first_frame: usize,
+        frames: usize,
+        /// Wall time simulating the batch
+        seconds: f64,
+        seconds_per_frame: f64,
+    },
+}
+
+/// Where the run is at when an event happens. The counters are cumulative, a resumed run counts
+/// the frames and batches done before it stopped.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct Progress {
+    /// Seconds since the run started, from a monotonic clock
+    pub elapsed: f64,
+    pub frames_done: usize,
+    pub frames_total: usize,
+    pub batches_done: usize,
+    pub batches_total: usize,
+    /// Seconds left at the speed of the last `ETA_WINDOW` batches, once a batch is done
+    pub eta_seconds: Option<f64>,
+}
+
+/// Where progress reports go
+pub trait ProgressSink: Send {
+    fn report(&mut self, event: &ProgressEvent, progress: &Progress);
+}
+
+/// The human readable log lines
+pub struct ConsoleProgress;
+
+impl ProgressSink for ConsoleProgress {
+    fn report(&mut self, event: &ProgressEvent, progress: &Progress) {
+        match event {
+            ProgressEvent::BatchStarted { batch, first_frame } => {
+                debug!("Starting batch {} at frame {}", batch, first_frame)
+            }
+            ProgressEvent::BatchCompleted { .. } => info!("{}", completed_line(event, progress)),
+        }
+    }
+}
+
+/// eg. "Done with batch: 3, frames: 300-399, Seconds: 12.5 per frame: 0.125, ETA 6h 12m"
+fn completed_line(event: &ProgressEvent, progress: &Progress) -> String {
+    let ProgressEvent::BatchCompleted {
+        batch,
+        first_frame,
+        frames,
+        seconds,
+        seconds_per_frame,
+    } = event
+    else {
+        return String::new();
+    };
+    format!(
+        "Done with batch: {}, frames: {}-{}, Seconds: {} per frame: {}, ETA {}",
+        batch,
+        first_frame,
+        first_frame + frames - 1,
+        seconds,
+        seconds_per_frame,
+        format_eta(progress)
+    )
+}
+
+fn format_eta(progress: &Progress) -> String {
+    progress
+        .eta_seconds
+        .map_or("unknown".to_string(), format_duration)
+}
+
+/// The bar being drawn, which log lines on stdout have to get out of the way of
+#[cfg(not(target_arch = "wasm32"))]
+static ACTIVE_BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// Run `write` with the progress bar cleared off the terminal, and draw it again after
+pub(crate) fn suspend_bar<R>(write: impl FnOnce() -> R) -> R {
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(bar) = ACTIVE_BAR.lock().unwrap().clone() {
+        return bar.suspend(write);
+    }
+    write()
+}
+
+/// A bar over the run's frames with the ETA, and a line under it for what the current batch is
+/// doing. The per-batch lines are still logged, at debug level.
+#[cfg(not(target_arch = "wasm32"))]
+struct BarProgress {
+    bar: ProgressBar,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl BarProgress {
+    fn new() -> BarProgress {
+        let bar = ProgressBar::with_draw_target(None, ProgressDrawTarget::stdout());
+        let style = ProgressStyle::with_template(
+            "[{elapsed_precise}] {wide_bar} {pos}/{len} frames, ETA {prefix}\n{msg}",
+        )
+        .expect("the progress bar template is valid");
+        bar.set_style(style);
+        bar.set_prefix("unknown");
+        // elapsed keeps counting during batches that take minutes
+        bar.enable_steady_tick(std::time::Duration::from_millis(500));
+        *ACTIVE_BAR.lock().unwrap() = Some(bar.clone());
+        BarProgress { bar }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ProgressSink for BarProgress {
+    fn report(&mut self, event: &ProgressEvent, progress: &Progress) {
+        self.bar.set_length(progress.frames_total as u64);
+        self.bar.set_position(progress.frames_done as u64);
+        self.bar.set_prefix(format_eta(progress));
+        match event {
+            ProgressEvent::BatchStarted { batch, first_frame } => {
+                debug!("Starting batch {} at frame {}", batch, first_frame);
+                self.bar.set_message(format!("batch {}: computing", batch));
+            }
+            ProgressEvent::BatchCompleted { batch, .. } => {
+                debug!("{}", completed_line(event, progress));
+                // until the next batch starts, which waits on the writer
+                self.bar.set_message(format!("batch {}: writing", batch));
+            }
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for BarProgress {
+    fn drop(&mut self) {
+        ACTIVE_BAR.lock().unwrap().take();
+        self.bar.finish_and_clear();
+    }
+}
+
+/// One JSON object per line, the event's fields alongside the progress counters
+pub struct JsonProgress {
+    writer: Box<dyn Write + Send>,
+}
+
+impl JsonProgress {
+    pub fn new(writer: Box<dyn Write + Send>) -> JsonProgress {
+        JsonProgress { writer }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    #[serde(flatten)]
+    event: &'a ProgressEvent,
+    #[serde(flatten)]
+    progress: &'a Progress,
+}
+
+impl ProgressSink for JsonProgress {
+    fn report(&mut self, event: &ProgressEvent, progress: &Progress) {
+        let Ok(line) = serde_json::to_string(&JsonLine { event, progress }) else {
+            return;
+        };
+        // a reader that went away shouldn't stop the run
+        let _ = writeln!(self.writer, "{}", line).and_then(|()| self.writer.flush());
+    }
+}
+
+/// Counts the frames simulated and reports each batch to a `ProgressSink`
+pub struct ProgressTracker {
+    sink: Box<dyn ProgressSink>,
+    start: Instant,
+    frames_total: usize,
+    frames_per_file: usize,
+    frames_done: usize,
+    /// Frames and seconds of the last `ETA_WINDOW` batches this run simulated, for the ETA
+    recent: VecDeque<(usize, f64)>,
+}
+
+/// Batches the ETA is averaged over, so it follows a run that speeds up or slows down
+pub const ETA_WINDOW: usize = 10;
+
+impl ProgressTracker {
+    /// For a run of `settings` starting at `first_frame`
+    pub fn new(sink: Box<dyn ProgressSink>, settings: &Settings, first_frame: usize) -> Self {
+        ProgressTracker {
+            sink,
+            start: Instant::now(),
+            frames_total: settings.frames_total,
+            frames_per_file: settings.frames_per_file,
+            frames_done: first_frame,
+            recent: VecDeque::with_capacity(ETA_WINDOW),
+        }
+    }
+
+    fn progress(&self) -> Progress {
+        let frames: usize = self.recent.iter().map(|(frames, _)| frames).sum();
+        let seconds: f64 = self.recent.iter().map(|(_, seconds)| seconds).sum();
+        let eta_seconds = (frames > 0).then(|| {
+            let remaining = self.frames_total.saturating_sub(self.frames_done);
+            remaining as f64 * seconds / frames as f64
+        });
+        Progress {
+            elapsed: self.start.elapsed().as_secs_f64(),
+            frames_done: self.frames_done,
+            frames_total: self.frames_total,
+            batches_done: self.frames_done / self.frames_per_file,
+            batches_total: self.frames_total / self.frames_per_file,
+            eta_seconds,
+        }
+    }
+}
+
+impl FrameObserver for ProgressTracker {
+    fn on_batch_start(&mut self, batch: usize, first_frame: usize) {
+        let progress = self.progress();
+        self.sink.report(
+            &ProgressEvent::BatchStarted { batch, first_frame },
+            &progress,
+        );
+    }
+
+    fn on_batch_complete(&mut self, batch: &BatchReport) {
+        // the run stops after a batch cut short, which is run again whole on resume
+        if !batch.complete {
+            return;
+        }
+        let seconds = batch.elapsed.as_secs_f64();
+        self.frames_done = batch.first_frame + batch.frames;
+        if self.recent.len() == ETA_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((batch.frames, seconds));
+        let progress = self.progress();
+        self.sink.report(
+            &ProgressEvent::BatchCompleted {
+                batch: batch.batch,
+                first_frame: batch.first_frame,
+                frames: batch.frames,
+                seconds,
+                seconds_per_frame: seconds / batch.frames as f64,
+            },
+            &progress,
+        );
+    }
+}
+
+/// eg. "6h 12m" or "42.0s"
+pub(crate) fn format_duration(seconds: f64) -> String {
+    let whole = seconds as u64;
+    match whole {
+        0..60 => format!("{:.1}s", seconds),
+        60..3600 => format!("{}m {}s", whole / 60, whole % 60),
+        _ => format!("{}h {}m", whole / 3600, whole % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ParticleSet;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// Writes into a buffer the test keeps a handle to
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn report(batch: usize, complete: bool, particles: &ParticleSet) -> BatchReport<'_> {
+        BatchReport {
+            batch,
+            first_frame: batch * 10,
+            frames: 10,
+            complete,
+            first_time: 0.0,
+            dt: 0.1,
+            energies: &[],
+            particles,
+            potential: None,
+            elapsed: Duration::from_secs(2),
+        }
+    }
+
+    #[test]
+    fn json_lines_carry_cumulative_counters() {
+        let settings = Settings {
+            frames_total: 50,
+            frames_per_file: 10,
+            ..Settings::default()
+        };
+        let buffer = Shared::default();
+        let sink = JsonProgress::new(Box::new(buffer.clone()));
+        // resumed at batch 2
+        let mut tracker = ProgressTracker::new(Box::new(sink), &settings, 20);
+        let particles = ParticleSet::default();
+        tracker.on_batch_start(2, 20);
+        tracker.on_batch_complete(&report(2, true, &particles));
+        tracker.on_batch_start(3, 30);
+        tracker.on_batch_complete(&report(3, false, &particles));
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        // nothing for the batch cut short
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["event"], "batch_started");
+        assert_eq!(lines[0]["frames_done"], 20);
+        assert!(lines[0]["eta_seconds"].is_null());
+
+        assert_eq!(lines[1]["event"], "batch_completed");
+        assert_eq!(lines[1]["batch"], 2);
+        assert_eq!(lines[1]["frames_done"], 30);
+        assert_eq!(lines[1]["batches_done"], 3);
+        assert_eq!(lines[1]["batches_total"], 5);
+        assert_eq!(lines[1]["seconds_per_frame"], 0.2);
+        // 20 frames left at 0.2s each
+        assert_eq!(lines[1]["eta_seconds"], 4.0);
+
+        let times: Vec<f64> = lines
+            .iter()
+            .map(|line| line["elapsed"].as_f64().unwrap())
+            .collect();
+        assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn eta_follows_the_recent_batches() {
+        let settings = Settings {
+            frames_total: 1000,
+            frames_per_file: 10,
+            ..Settings::default()
+        };
+        let mut tracker = ProgressTracker::new(Box::new(ConsoleProgress), &settings, 0);
+        let particles = ParticleSet::default();
+        // a slow start that's out of the window by the end
+        for batch in 0..2 + ETA_WINDOW {
+            let elapsed = Duration::from_secs(if batch < 2 { 100 } else { 2 });
+            tracker.on_batch_complete(&BatchReport {
+                elapsed,
+                ..report(batch, true, &particles)
+            });
+        }
+        // 880 frames left at 0.2s each
+        let eta = tracker.progress().eta_seconds.unwrap();
+        assert!((eta - 176.0).abs() < 1e-9, "{}", eta);
+    }
+
+    #[test]
+    fn format_duration_picks_units() {
+        assert_eq!(format_duration(42.04), "42.0s");
+        assert_eq!(format_duration(125.0), "2m 5s");
+        assert_eq!(format_duration(6.0 * 3600.0 + 12.0 * 60.0 + 30.0), "6h 12m");
+    }
+}
@@ -0,0 +1,297 @@
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use super::logging::LogLevel;
+use super::settings::{Settings, SettingsFormat};
+
+/// Settings `hot_reload` applies between batches. The others would change what's being
+/// simulated or are fixed once the device is set up, dt changes go in the schedule.
+pub const RELOADABLE_FIELDS: [&str; 4] = [
+    "output_every",
+    "diagnostics_every",
+    "compression_level",
+    "log_level",
+];
+
+/// Files in out_path read after the settings file, so they win when both change a field at
+/// once. Only for the fields in `RELOADABLE_FIELDS`, and read again by a resumed run.
+pub const OVERRIDES_FILES: [&str; 2] = ["overrides.toml", "overrides.json"];
+
+/// A new value for one of `RELOADABLE_FIELDS`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Reload {
+    OutputEvery(usize),
+    DiagnosticsEvery(usize),
+    CompressionLevel(u32),
+    LogLevel(Option<LogLevel>),
+}
+
+impl Reload {
+    /// The reloadable fields as `settings` has them
+    pub fn all(settings: &Settings) -> [Reload; 4] {
+        [
+            Reload::OutputEvery(settings.output_every),
+            Reload::DiagnosticsEvery(settings.diagnostics_every),
+            Reload::CompressionLevel(settings.compression_level),
+            Reload::LogLevel(settings.log_level),
+        ]
+    }
+
+    /// `field` set to `value`, if it's reloadable and the value is good
+    fn parse(field: &str, value: Value) -> Result<Reload, String> {
+        let reload = match field {
+            "output_every" => serde_json::from_value(value).map(Reload::OutputEvery),
+            "diagnostics_every" => serde_json::from_value(value).map(Reload::DiagnosticsEvery),
+            "compression_level" => serde_json::from_value(value).map(Reload::CompressionLevel),
+            "log_level" => serde_json::from_value(value).map(Reload::LogLevel),
+            _ => {
+                return Err(format!(
+                    "{} can't change during a run, only {} can",
+                    field,
+                    RELOADABLE_FIELDS.join(", ")
+                ));
+            }
+        }
+        .map_err(|e| format!("{}: {}", field, e))?;
+        match reload.problem() {
+            Some(problem) => Err(problem),
+            None => Ok(reload),
+        }
+    }
+
+    /// What's wrong with the value, for `Settings::validate` too
+    pub fn problem(&self) -> Option<String> {
+        match *self {
+            Reload::OutputEvery(0) => Some("output_every must be at least 1".to_string()),
+            Reload::DiagnosticsEvery(0) => Some("diagnostics_every must be at least 1".to_string()),
+            Reload::CompressionLevel(level) if level > 9 => Some(format!(
+                "compression_level must be from 0 to 9, not {}",
+                level
+            )),
+            _ => None,
+        }
+    }
+
+    fn field(&self) -> &'static str {
+        match self {
+            Reload::OutputEvery(_) => "output_every",
+            Reload::DiagnosticsEvery(_) => "diagnostics_every",
+            Reload::CompressionLevel(_) => "compression_level",
+            Reload::LogLevel(_) => "log_level",
+        }
+    }
+}
+
+/// eg. "output_every 10"
+impl std::fmt::Display for Reload {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Reload::OutputEvery(every) | Reload::DiagnosticsEvery(every) => {
+                write!(f, "{} {}", self.field(), every)
+            }
+            Reload::CompressionLevel(level) => write!(f, "{} {}", self.field(), level),
+            Reload::LogLevel(Some(level)) => write!(f, "{} {:?}", self.field(), level),
+            Reload::LogLevel(None) => write!(f, "{} from the command line", self.field()),
+        }
+    }
+}
+
+/// One file `SettingsReloader` reads, and what it had the last time
+struct Source {
+    path: PathBuf,
+    /// The settings file has everything else in it too, which only warns when it's edited
+    is_settings_file: bool,
+    /// Profile the run was started with, whose fields win over the file's top level ones
+    profile: Option<String>,
+    content: Option<String>,
+    fields: serde_json::Map<String, Value>,
+}
+
+/// Watches the settings file and the overrides file for `Settings::hot_reload`
+pub struct SettingsReloader {
+    sources: Vec<Source>,
+    /// The reloadable fields as the run has them now
+    current: [Reload; 4],
+}
+
+impl SettingsReloader {
+    /// The settings file is taken as it is now, so only later edits to it count. An overrides
+    /// file that's there already is applied by the first `check`.
+    pub fn new(settings: &Settings) -> SettingsReloader {
+        let settings_file = settings
+            .settings_file
+            .as_ref()
+            .filter(|path| path.is_file())
+            .map(|path| Source {
+                path: path.clone(),
+                is_settings_file: true,
+                profile: settings.profile.clone(),
+                content: None,
+                fields: serde_json::Map::new(),
+            });
+        let overrides = OVERRIDES_FILES.iter().map(|name| Source {
+            path: settings.out_path.join(name),
+            is_settings_file: false,
+            profile: None,
+            content: None,
+            fields: serde_json::Map::new(),
+        });
+        let mut sources: Vec<Source> = settings_file.into_iter().chain(overrides).collect();
+        if let Some(settings_file) = sources.first_mut().filter(|s| s.is_settings_file) {
+            let _ = settings_file.read();
+        }
+        SettingsReloader {
+            sources,
+            current: Reload::all(settings),
+        }
+    }
+
+    /// Values changed since the last check, in the order to apply them. Fields that can't
+    /// change, aren't settings or have bad values are warned about once per edit and ignored.
+    pub fn check(&mut self) -> Vec<(Reload, &Path)> {
+        let mut changes: Vec<(Reload, usize)> = Vec::new();
+        for (index, source) in self.sources.iter_mut().enumerate() {
+            let previous = match source.read() {
+                Ok(Some(previous)) => previous,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Could not reload {}: {}", source.path.display(), e);
+                    continue;
+                }
+            };
+            for (field, value) in &source.fields {
+                if source.is_settings_file && previous.get(field) == Some(value) {
+                    continue;
+                }
+                if !is_setting(field) {
+                    warn!(
+                        "{} in {} isn't a setting, ignored",
+                        field,
+                        source.path.display()
+                    );
+                    continue;
+                }
+                let reload = match Reload::parse(field, value.clone()) {
+                    Ok(reload) => reload,
+                    Err(e) => {
+                        warn!("{} in {}, ignored", e, source.path.display());
+                        continue;
+                    }
+                };
+                let current = self
+                    .current
+                    .iter_mut()
+                    .find(|current| current.field() == reload.field())
+                    .expect("every reloadable field is in Reload::all");
+                if *current != reload {
+                    *current = reload;
+                    changes.retain(|(change, _)| change.field() != reload.field());
+                    changes.push((reload, index));
+                }
+            }
+        }
+        changes
+            .into_iter()
+            .map(|(reload, index)| (reload, self.sources[index].path.as_path()))
+            .collect()
+    }
+}
+
+impl Source {
+    /// Read the file again, returning the fields it had before when it's changed since, and
+    /// None when it hasn't or isn't there
+    fn read(&mut self) -> Result<Option<serde_json::Map<String, Value>>, String> {
+        let Ok(content) = std::fs::read_to_string(&self.path) else {
+            return Ok(None);
+        };
+        if self.content.as_ref() == Some(&content) {
+            return Ok(None);
+        }
+        // a bad edit is only warned about once
+        self.content = Some(content);
+        let content = self.content.as_deref().unwrap_or_default();
+        let mut fields = SettingsFormat::from_path(&self.path).fields(content)?;
+        if self.is_settings_file {
+            let profile = self
+                .profile
+                .as_ref()
+                .and_then(|name| fields.get("profiles")?.get(name)?.as_object().cloned());
+            fields.extend(profile.unwrap_or_default());
+        }
+        Ok(Some(std::mem::replace(&mut self.fields, fields)))
+    }
+}
+
+/// Whether `field` is one of the top level settings
+fn is_setting(field: &str) -> bool {
+    match serde_json::to_value(Settings::default()) {
+        Ok(Value::Object(fields)) => fields.contains_key(field),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn overrides_apply_once_and_only_reloadable_fields() {
+        let dir = test_dir("gravity-output-reload-overrides");
+        let settings = Settings {
+            out_path: dir.clone(),
+            ..Settings::default()
+        };
+        let mut reloader = SettingsReloader::new(&settings);
+        assert!(reloader.check().is_empty());
+
+        let overrides = dir.join("overrides.toml");
+        std::fs::write(
+            &overrides,
+            "output_every = 5\ncompression_level = 1\ndt = 0.5\nno_such_field = 1\n",
+        )
+        .unwrap();
+        // compression_level is what the run has already, dt and the unknown field only warn
+        let changes = reloader.check();
+        assert_eq!(changes, [(Reload::OutputEvery(5), overrides.as_path())]);
+        assert!(reloader.check().is_empty());
+
+        std::fs::write(&overrides, "output_every = 0\nlog_level = \"debug\"\n").unwrap();
+        let changes = reloader.check();
+        assert_eq!(
+            changes,
+            [(Reload::LogLevel(Some(LogLevel::Debug)), overrides.as_path())]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn settings_file_edits_count_and_overrides_win() {
+        let dir = test_dir("gravity-output-reload-settings-file");
+        let settings_file = dir.join("settings.toml");
+        std::fs::write(&settings_file, "diagnostics_every = 1\noutput_every = 1\n").unwrap();
+        let settings = Settings {
+            out_path: dir.clone(),
+            settings_file: Some(settings_file.clone()),
+            ..Settings::default()
+        };
+        let mut reloader = SettingsReloader::new(&settings);
+        assert!(reloader.check().is_empty());
+
+        std::fs::write(dir.join("overrides.json"), r#"{"output_every": 4}"#).unwrap();
+        std::fs::write(&settings_file, "diagnostics_every = 10\noutput_every = 2\n").unwrap();
+        let changes: Vec<Reload> = reloader.check().into_iter().map(|(r, _)| r).collect();
+        assert_eq!(
+            changes,
+            [Reload::DiagnosticsEvery(10), Reload::OutputEvery(4)]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+use glam::Vec3;
+use gravity_output_format as format;
+use std::path::Path;
+use tracing::{info, warn};
+
+use super::Particle;
+use super::ParticleSet;
+use super::checkpoint::Checkpoint;
+use super::initial_conditions::InitialConditions;
+use super::manifest::Manifest;
+use super::schedule::Phase;
+use super::settings::{RESOLVED_SETTINGS_FILE, Settings, display_value};
+
+/// Where `resume` picks a run back up: its settings, the first batch still to simulate and the
+/// particle state after the last batch written.
+pub struct ResumePoint {
+    pub settings: Settings,
+    pub next_batch: usize,
+    pub particles: ParticleSet,
+}
+
+impl ResumePoint {
+    /// Read back the run in `dir` with the settings it was started with, or with `changed`
+    /// settings loaded the usual way for `--allow-changed`. The particles come from the newest
+    /// checkpoint, written every checkpoint_every batches and when a run stops early, and the
+    /// batches after it are run again. Without one they're rebuilt from the last two frames, as
+    /// the batch files only hold positions: exact for euler, which moves each particle by its
+    /// new velocity times dt, and off by half a step of acceleration for verlet.
+    pub fn load(
+        dir: &Path,
+        frames_total: Option<usize>,
+        changed: Option<Settings>,
+    ) -> Result<ResumePoint, String> {
+        let original = load_run_settings(dir)?;
+        let run_hash = original.hash();
+        let mut settings = match changed {
+            Some(mut changed) => {
+                changed.out_path = original.out_path.clone();
+                let differences = differences(&original, &changed);
+                if differences.is_empty() {
+                    info!("Settings unchanged from the original run");
+                } else {
+                    info!("Settings changed from the original run:");
+                    for difference in differences {
+                        info!("  {}", difference);
+                    }
+                }
+                changed
+            }
+            None => original,
+        };
+        if let Some(frames_total) = frames_total {
+            settings.frames_total = frames_total;
+        }
+        settings
+            .validate()
+            .map_err(|problems| problems.join(", "))?;
+
+        // everything up to the first missing or unreadable batch counts as done
+        let mut last_frames: Vec<Vec<Vec3>> = Vec::new();
+        let mut next_batch = 0;
+        let mut decimated = false;
+        for (batch_num, path) in format::list_batches(dir)? {
+            if batch_num != next_batch {
+                break;
+            }
+            let Ok(batch) = format::read_batch(&path) else {
+                warn!("{} is unreadable, resuming from it", path.display());
+                break;
+            };
+            if batch.num_particles != settings.num_particles {
+                return Err(format!(
+                    "{} has {} particles, the manifest {}",
+                    path.display(),
+                    batch.num_particles,
+                    settings.num_particles
+                ));
+            }
+            decimated = batch.frames.len() < settings.frames_per_file;
+            last_frames.extend(batch.frames);
+            let keep = last_frames.len().saturating_sub(2);
+            last_frames.drain(..keep);
+            next_batch += 1;
+        }
+
+        // the newest checkpoint of this run that isn't past the batches written. Batches after
+        // it, like one cut short by Ctrl-C or a kill, are run again from it, which gives the
+        // same frames where rebuilding from the last two doesn't.
+        let checkpoint = Checkpoint::load_all(dir).into_iter().find(|checkpoint| {
+            let problem = if checkpoint.settings_hash != run_hash {
+                format!("it's from settings {}", checkpoint.settings_hash)
+            } else if checkpoint.next_batch > next_batch {
+                "it's past the batches written".to_string()
+            } else if checkpoint.next_frame != checkpoint.next_batch * settings.frames_per_file
+                || checkpoint.particles.len() != settings.num_particles
+            {
+                "it doesn't match the changed settings".to_string()
+            } else {
+                return true;
+            };
+            warn!(
+                "Ignoring the checkpoint for batch {}, {}",
+                checkpoint.next_batch, problem
+            );
+            false
+        });
+        // a short last batch with a checkpoint before it was cut short, so isn't done yet
+        let cut_short = decimated
+            && checkpoint
+                .as_ref()
+                .is_some_and(|checkpoint| checkpoint.next_batch + 1 == next_batch);
+        let num_batches = settings.frames_total / settings.frames_per_file;
+        if next_batch >= num_batches && !cut_short {
+            return Err(format!(
+                "all {} batches are already written, pass --frames to extend the run",
+                num_batches
+            ));
+        }
+        if let Some(checkpoint) = &checkpoint
+            && checkpoint.next_batch < next_batch
+        {
+            let batches = match next_batch - checkpoint.next_batch {
+                1 => format!("batch {}", checkpoint.next_batch),
+                _ => format!("batches {}-{}", checkpoint.next_batch, next_batch - 1),
+            };
+            info!("Running {} again from the checkpoint before", batches);
+            next_batch = checkpoint.next_batch;
+        }
+
+        let particles = match checkpoint {
+            Some(checkpoint) => {
+                info!("Using the checkpoint for batch {}", checkpoint.next_batch);
+                checkpoint.particles
+            }
+            None => rebuild_particles(&settings, &last_frames, decimated, next_batch, dir)?,
+        };
+        info!(
+            "Resuming {} at batch {} of {}",
+            settings.out_path.display(),
+            next_batch,
+            num_batches
+        );
+        Ok(ResumePoint {
+            settings,
+            next_batch,
+            particles,
+        })
+    }
+}
+
+/// Particles from the last two frames written, for runs stopped without a checkpoint
+fn rebuild_particles(
+    settings: &Settings,
+    last_frames: &[Vec<Vec3>],
+    decimated: bool,
+    next_batch: usize,
+    dir: &Path,
+) -> Result<ParticleSet, String> {
+    if decimated {
+        return Err(
+            "the last batch was decimated for max_output_gb or by the schedule's output_every, \
+             its frames are too far apart to rebuild velocities from and there's no checkpoint"
+                .to_string(),
+        );
+    }
+    if !matches!(settings.initial_conditions, InitialConditions::Sphere) {
+        // galaxy and tabulated particles have their own masses, which aren't written out
+        return Err(
+            "only runs with sphere initial conditions can be resumed, the batch files \
+         don't record the other generators' per-particle masses"
+                .to_string(),
+        );
+    }
+    let [previous, last] = last_frames else {
+        return Err(format!(
+            "{} needs at least two frames written to resume from",
+            dir.display()
+        ));
+    };
+    // the step between the last two frames, which the schedule may have changed
+    let dt = Phase::at(settings, next_batch * settings.frames_per_file - 1).dt;
+    Ok(previous
+        .iter()
+        .zip(last)
+        .enumerate()
+        .map(|(id, (previous, pos))| {
+            let vel = (pos - previous) / dt;
+            Particle::new(settings.mass, *pos, vel, Vec3::ZERO).with_id(id as u32)
+        })
+        .collect())
+}
+
+/// Settings `dir` was produced with: its settings.resolved.json, or for runs from before that
+/// was written the copy in manifest.json. Where they came from is only in the manifest.
+fn load_run_settings(dir: &Path) -> Result<Settings, String> {
+    let read = |name: &str| {
+        let path = dir.join(name);
+        let json = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        Ok::<_, String>((path, json))
+    };
+    let manifest = read("manifest.json").and_then(|(path, json)| {
+        serde_json::from_str::<Manifest>(&json)
+            .map_err(|e| format!("Could not parse {}: {}", path.display(), e))
+    });
+    let mut settings = match (read(RESOLVED_SETTINGS_FILE), &manifest) {
+        (Ok((path, json)), _) => serde_json::from_str::<Settings>(&json)
+            .map_err(|e| format!("Could not parse {}: {}", path.display(), e))?,
+        (Err(_), Ok(manifest)) => {
+            info!(
+                "No {}, using the settings in manifest.json",
+                RESOLVED_SETTINGS_FILE
+            );
+            manifest.settings.clone()
+        }
+        (Err(_), Err(e)) => return Err(e.clone()),
+    };
+    if let Ok(manifest) = manifest {
+        settings.settings_file = manifest.settings_file;
+        settings.profile = manifest.profile;
+    }
+    settings.out_path = std::fs::canonicalize(dir).unwrap_or(dir.to_path_buf());
+    Ok(settings)
+}
+
+/// `field: old -> new` for every setting that differs
+fn differences(original: &Settings, changed: &Settings) -> Vec<String> {
+    let (Ok(serde_json::Value::Object(original)), Ok(serde_json::Value::Object(changed))) = (
+        serde_json::to_value(original),
+        serde_json::to_value(changed),
+    ) else {
+        return Vec::new();
+    };
+    original
+        .iter()
+        .filter(|(key, value)| changed.get(*key) != Some(*value))
+        .map(|(key, value)| {
+            let new = changed.get(key).cloned().unwrap_or_default();
+            format!(
+                "{}: {} -> {}",
+                key,
+                display_value(value),
+                display_value(&new)
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{OutputLimit, write_frame_group};
+
+    #[test]
+    fn differences_list_each_changed_field() {
+        let original = Settings::default();
+        let changed = Settings {
+            dt: 0.5,
+            num_particles: 10,
+            ..Settings::default()
+        };
+        assert_eq!(
+            differences(&original, &changed),
+            ["dt: 0.0055555557 -> 0.5", "num_particles: 12000 -> 10"]
+        );
+        assert!(differences(&original, &original).is_empty());
+    }
+
+    #[test]
+    fn batch_cut_short_runs_again_from_the_checkpoint() {
+        let dir = std::env::temp_dir().join("gravity-output-resume-cut-short");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let settings = Settings {
+            num_particles: 4,
+            frames_per_file: 5,
+            frames_total: 15,
+            out_path: dir.clone(),
+            ..Settings::default()
+        };
+        settings.save_resolved().unwrap();
+        let output = OutputLimit::new(&settings, 0);
+        for (batch, frames) in [(0, 5), (1, 2)] {
+            let mut frame_list = vec![vec![Vec3::ONE; 4]; frames];
+            write_frame_group(
+                &settings,
+                &settings.hash(),
+                &mut frame_list,
+                None,
+                &batch,
+                &output,
+                Phase::at(&settings, 0),
+            )
+            .unwrap();
+        }
+        let particles: ParticleSet = (0..4)
+            .map(|i| Particle::new(1.0, Vec3::splat(i as f32), Vec3::X, Vec3::ZERO))
+            .collect();
+        Checkpoint::save(&dir, &settings.hash(), 1, 5, &particles).unwrap();
+
+        let resume = ResumePoint::load(&dir, None, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let resume = resume.unwrap();
+        assert_eq!(resume.next_batch, 1);
+        assert_eq!(resume.particles.pos[3], particles.pos[3]);
+    }
+}
@@ -0,0 +1,201 @@
+use serde::{Deserialize, Serialize};
+
+use super::settings::Settings;
+
+/// A change to dt or to how often frames are written, from `at_frame` on. Nothing else can
+/// change mid-run, so any other field in an entry is an error.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleEntry {
+    /// Has to be at a batch boundary, a multiple of frames_per_file
+    pub at_frame: usize,
+    #[serde(default)]
+    pub dt: Option<f32>,
+    /// Write only the frames whose number is a multiple of this
+    #[serde(default)]
+    pub output_every: Option<usize>,
+}
+
+impl ScheduleEntry {
+    /// eg. "dt 0.002, output every 10"
+    pub fn describe(&self) -> String {
+        let mut changes = Vec::new();
+        if let Some(dt) = self.dt {
+            changes.push(format!("dt {}", dt));
+        }
+        if let Some(output_every) = self.output_every {
+            changes.push(format!("output every {}", output_every));
+        }
+        changes.join(", ")
+    }
+}
+
+/// dt and output cadence in effect for a stretch of the run
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Phase {
+    pub dt: f32,
+    pub output_every: usize,
+}
+
+impl Phase {
+    /// In effect at `frame`: the settings' dt and output_every with every entry up to the frame
+    /// applied
+    pub fn at(settings: &Settings, frame: usize) -> Phase {
+        let start = Phase {
+            dt: settings.dt,
+            output_every: settings.output_every,
+        };
+        settings
+            .schedule
+            .iter()
+            .take_while(|entry| entry.at_frame <= frame)
+            .fold(start, |phase, entry| Phase {
+                dt: entry.dt.unwrap_or(phase.dt),
+                output_every: entry.output_every.unwrap_or(phase.output_every),
+            })
+    }
+}
+
+/// Simulated time at the start of `frame`, which is no longer frame * dt once dt changes
+pub fn time_at(settings: &Settings, frame: usize) -> f64 {
+    let (mut time, mut start, mut dt) = (0.0, 0, settings.dt as f64);
+    for entry in settings
+        .schedule
+        .iter()
+        .take_while(|entry| entry.at_frame < frame)
+    {
+        time += (entry.at_frame - start) as f64 * dt;
+        start = entry.at_frame;
+        dt = entry.dt.map_or(dt, f64::from);
+    }
+    time + (frame - start) as f64 * dt
+}
+
+/// Entries out of order, off a batch boundary, past the end of the run or changing nothing,
+/// and bad values
+pub fn problems(settings: &Settings) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut previous = None;
+    for (index, entry) in settings.schedule.iter().enumerate() {
+        let name = format!("schedule[{}]", index);
+        if previous.is_some_and(|previous| entry.at_frame <= previous) {
+            problems.push(format!(
+                "{} at_frame {} isn't after the entry before it",
+                name, entry.at_frame
+            ));
+        }
+        previous = Some(entry.at_frame);
+        if settings.frames_per_file > 0 && !entry.at_frame.is_multiple_of(settings.frames_per_file)
+        {
+            problems.push(format!(
+                "{} at_frame {} isn't at a batch boundary, a multiple of frames_per_file {}",
+                name, entry.at_frame, settings.frames_per_file
+            ));
+        }
+        if entry.at_frame >= settings.frames_total {
+            problems.push(format!(
+                "{} at_frame {} is past the last frame, frames_total is {}",
+                name, entry.at_frame, settings.frames_total
+            ));
+        }
+        if let Some(dt) = entry.dt
+            && !(dt > 0.0 && dt.is_finite())
+        {
+            problems.push(format!("{} dt must be positive, not {}", name, dt));
+        }
+        if entry.output_every == Some(0) {
+            problems.push(format!("{} output_every must be at least 1", name));
+        }
+        if entry.dt.is_none() && entry.output_every.is_none() {
+            problems.push(format!("{} changes neither dt nor output_every", name));
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduled(schedule: serde_json::Value) -> Settings {
+        Settings {
+            dt: 0.01,
+            frames_total: 1000,
+            frames_per_file: 100,
+            schedule: serde_json::from_value(schedule).unwrap(),
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn phases_and_time_follow_the_schedule() {
+        let settings = scheduled(serde_json::json!([
+            { "at_frame": 200, "dt": 0.002, "output_every": 5 },
+            { "at_frame": 500, "output_every": 1 },
+        ]));
+        assert!(problems(&settings).is_empty());
+
+        let phase = |frame| Phase::at(&settings, frame);
+        assert_eq!(
+            phase(199),
+            Phase {
+                dt: 0.01,
+                output_every: 1
+            }
+        );
+        assert_eq!(
+            phase(200),
+            Phase {
+                dt: 0.002,
+                output_every: 5
+            }
+        );
+        assert_eq!(
+            phase(900),
+            Phase {
+                dt: 0.002,
+                output_every: 1
+            }
+        );
+
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-6;
+        assert!(close(time_at(&settings, 100), 1.0));
+        assert!(close(time_at(&settings, 200), 2.0));
+        assert!(close(time_at(&settings, 700), 3.0));
+    }
+
+    #[test]
+    fn bad_schedules_are_reported() {
+        let settings = scheduled(serde_json::json!([
+            { "at_frame": 500, "dt": 0.002 },
+            { "at_frame": 300, "dt": -1.0 },
+            { "at_frame": 450, "output_every": 0 },
+            { "at_frame": 2000 },
+        ]));
+        let problems = problems(&settings);
+        for expected in [
+            "schedule[1] at_frame 300 isn't after",
+            "schedule[1] dt must be positive",
+            "schedule[2] at_frame 450 isn't at a batch boundary",
+            "schedule[2] output_every must be at least 1",
+            "schedule[3] at_frame 2000 is past the last frame",
+            "schedule[3] changes neither",
+        ] {
+            assert!(
+                problems.iter().any(|problem| problem.starts_with(expected)),
+                "{} in {:?}",
+                expected,
+                problems
+            );
+        }
+    }
+
+    #[test]
+    fn only_dt_and_output_every_can_change() {
+        let error = serde_json::from_value::<ScheduleEntry>(serde_json::json!(
+            { "at_frame": 200, "num_particles": 10 }
+        ))
+        .unwrap_err();
+        assert!(error.to_string().contains("unknown field `num_particles`"));
+    }
+}
@@ -0,0 +1,2048 @@
+use glam::Vec3;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use super::Particle;
+use super::ParticleSet;
+use super::adapter::{AdapterSettings, GpuBackend};
+use super::backend::{DeviceSettings, ForceBackendKind};
+use super::cli::RunArgs;
+use super::error::Error;
+use super::initial_conditions::{self, InitialConditions};
+use super::logging::LogLevel;
+use super::output::WriteRetry;
+use super::reload::Reload;
+use super::schedule::{self, ScheduleEntry};
+use super::tracked::TrackedParticles;
+use super::tree::ForceMethod;
+use super::units::Units;
+use super::wizard;
+use rand::prelude::*;
+use sha2::{Digest, Sha256};
+
+/// Missing fields take their value from `Settings::default()`, unknown ones are errors so a
+/// misspelt setting isn't quietly ignored
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Version of the settings layout the file was written for, see `SCHEMA_VERSION`. Files
+    /// without one predate versioning.
+    #[serde(default)]
+    pub schema_version: u32,
+    pub num_particles: usize,
+    pub frames_total: usize,
+    pub frames_per_file: usize,
+    pub dt: f32,
+    /// Changes to dt and the output cadence partway through, eg. a coarse dt while the system
+    /// relaxes, applied at batch boundaries
+    #[serde(default)]
+    pub schedule: Vec<ScheduleEntry>,
+    /// Write only the frames whose number is a multiple of this, until the schedule changes it
+    #[serde(default = "default_every")]
+    pub output_every: usize,
+    pub arena: f32,
+    pub g_const: f32,
+    /// Physical units of the simulation, eg. `{ preset = "galactic" }` for kpc, Msun and Myr.
+    /// With them set g_const is derived from the real G.
+    #[serde(default)]
+    pub units: Option<Units>,
+    /// Plummer softening length used by the force kernel
+    #[serde(default = "default_softening")]
+    pub softening: f32,
+    pub mass: f32,
+    pub init_vel: f32,
+    pub out_path: PathBuf,
+    #[serde(default)]
+    pub initial_conditions: InitialConditions,
+    /// Seed for the initial conditions' random numbers. Unset draws a new one every run, which
+    /// is logged, or uses 0 with `deterministic`.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Subtract the mass-weighted mean velocity after generation
+    #[serde(default)]
+    pub zero_net_momentum: bool,
+    /// Cancel net angular momentum with a rigid counter-rotation after generation
+    #[serde(default)]
+    pub zero_net_angular_momentum: bool,
+    #[serde(default)]
+    pub integrator: Integrator,
+    /// Integrate on the GPU so particle state stays on the device between frames
+    #[serde(default = "default_true")]
+    pub gpu_integration: bool,
+    /// Threads per compute workgroup, also the shared-memory tile width. Unset uses what
+    /// `--bench-kernel` found fastest for the adapter and particle count, or 64 if it hasn't
+    /// been run.
+    #[serde(default)]
+    pub workgroup_size: Option<u32>,
+    /// Create the wgpu instance with only this backend, eg. vulkan where the GL driver
+    /// miscompiles the kernel. Applies to every device.
+    #[serde(default)]
+    pub gpu_backend: Option<GpuBackend>,
+    #[serde(default)]
+    pub adapter: AdapterSettings,
+    #[serde(default)]
+    pub force_backend: ForceBackendKind,
+    /// Two or more entries split the force pass across several GPUs
+    #[serde(default)]
+    pub devices: Vec<DeviceSettings>,
+    /// Precision of the per-particle force sum in the GPU kernel
+    #[serde(default)]
+    pub force_accumulation: ForceAccumulation,
+    /// Direct-sum force kernel when the subgroup kernel isn't in use
+    #[serde(default)]
+    pub force_kernel: ForceKernel,
+    /// Share source particles between the lanes of a subgroup instead of running force_kernel,
+    /// where the device supports subgroups and workgroup_size is a multiple of its subgroup
+    /// size. Off unless asked for, it hasn't been timed against the other kernels.
+    #[serde(default)]
+    pub subgroups: bool,
+    /// Steps recorded into each GPU submission when integrating on the GPU
+    #[serde(default = "default_steps_per_submit")]
+    pub steps_per_submit: usize,
+    /// Cap on any single GPU buffer in bytes, below the device's own limit. Particle sets that
+    /// don't fit are split into chunks, so this is mostly useful for exercising that path.
+    #[serde(default)]
+    pub max_buffer_size: Option<u64>,
+    /// GPU memory in bytes the run may use. wgpu can't query how much the device has, so with
+    /// this set a run that wouldn't fit fails before allocating anything. See `--dry-run`.
+    #[serde(default)]
+    pub gpu_memory_budget: Option<u64>,
+    /// Stream the particles through the device a tile at a time instead of keeping them all
+    /// resident, for sets that don't fit. Tiles are sized by max_buffer_size and
+    /// gpu_memory_budget. Much slower, and integrates on the CPU.
+    #[serde(default)]
+    pub out_of_core: bool,
+    /// Direct summation or a Barnes-Hut tree
+    #[serde(default)]
+    pub force_method: ForceMethod,
+    /// Development: load nbody.wgsl from this file instead of the embedded copy, and rebuild the
+    /// pipelines between batches whenever it changes. Also set by `--shader-path`.
+    #[serde(default)]
+    pub shader_path: Option<PathBuf>,
+    /// Write total kinetic and potential energy and momentum of every step to diagnostics.csv
+    #[serde(default)]
+    pub diagnostics: bool,
+    /// Log only the steps whose number is a multiple of this to diagnostics.csv. The energies
+    /// are still summed every step.
+    #[serde(default = "default_every")]
+    pub diagnostics_every: usize,
+    /// With `diagnostics`, warn when the total energy has drifted from the first frame's by more
+    /// than this fraction of it, at most once a batch
+    #[serde(default = "default_energy_drift_warn")]
+    pub energy_drift_warn: f64,
+    /// With `diagnostics`, add the distances from the center of mass enclosing each of
+    /// lagrangian_fractions of the mass to diagnostics.csv, as r_<fraction> columns
+    #[serde(default)]
+    pub lagrangian_radii: bool,
+    /// Mass fractions of the Lagrangian radii, each more than 0 and at most 1
+    #[serde(default = "default_lagrangian_fractions")]
+    pub lagrangian_fractions: Vec<f64>,
+    /// With `diagnostics`, add the minimum, 1st percentile, median and mean of the distance from
+    /// each particle to its closest other one to diagnostics.csv, as nn_min, nn_p1, nn_p50 and
+    /// nn_mean columns, and warn when nn_min goes under the softening
+    #[serde(default)]
+    pub nearest_neighbors: bool,
+    /// With `diagnostics`, bin the particles' speeds into this many bins at the end of every
+    /// batch a diagnostics_every step falls in, appending the counts to speed_histogram.csv. 0
+    /// for none.
+    #[serde(default)]
+    pub speed_histogram_bins: usize,
+    /// Upper edge of the last speed bin, faster particles are counted apart. Unset scales the
+    /// bins to each batch's fastest particle.
+    #[serde(default)]
+    pub speed_histogram_max: Option<f64>,
+    /// With `diagnostics`, bin the particles' specific energies, 1/2 v^2 plus the potential, into
+    /// this many bins at the end of every batch a diagnostics_every step falls in. Appended to
+    /// binding_energy.csv with the fraction of the mass that's bound, 0 for none. The potential
+    /// takes a force pass of its own each time.
+    #[serde(default)]
+    pub binding_energy_bins: usize,
+    /// Lower edge of the first energy bin, less bound particles are counted apart. Unset is
+    /// each batch's most bound particle.
+    #[serde(default)]
+    pub binding_energy_min: Option<f64>,
+    /// Upper edge of the last energy bin. Unset is each batch's least bound particle.
+    #[serde(default)]
+    pub binding_energy_max: Option<f64>,
+    /// With `diagnostics`, the mass density in this many logarithmically spaced shells about
+    /// the center of mass, at the end of every batch a diagnostics_every step falls in. Appended
+    /// to density_profile.csv, 0 for none.
+    #[serde(default)]
+    pub density_profile_bins: usize,
+    /// Inner edge of the first shell. Unset is the distance of the particle closest to the
+    /// center.
+    #[serde(default)]
+    pub density_profile_min_radius: Option<f64>,
+    /// Outer edge of the last shell. Unset is the distance of the furthest particle.
+    #[serde(default)]
+    pub density_profile_max_radius: Option<f64>,
+    /// With `diagnostics`, estimate the two-point correlation function from this many randomly
+    /// drawn particle pairs every correlation_every frames, appending it to correlation.csv. 0
+    /// for none. The pairs are drawn from `seed`, or 0 without one, so reruns pick the same.
+    #[serde(default)]
+    pub correlation_pairs: usize,
+    /// Frames between correlation estimates, each costs about as much as a frame's forces
+    #[serde(default = "default_correlation_every")]
+    pub correlation_every: usize,
+    /// Logarithmically spaced separation bins of the correlation function
+    #[serde(default = "default_correlation_bins")]
+    pub correlation_bins: usize,
+    /// Radius of the uniform sphere the pair counts are compared against. Unset is `arena`,
+    /// the sphere the default initial conditions fill.
+    #[serde(default)]
+    pub correlation_radius: Option<f64>,
+    /// Inner edge of the first separation bin, the last ends at the sphere's diameter. Unset
+    /// is a thousandth of the diameter.
+    #[serde(default)]
+    pub correlation_min_separation: Option<f64>,
+    /// Particles whose position and velocity after every step go to tracked.csv, whatever
+    /// output_every is. The ids picked are kept in the manifest.
+    #[serde(default)]
+    pub tracked_particles: TrackedParticles,
+    /// Sort the particles into Morton order every this many frames, 0 to never sort. Applied at
+    /// the first batch boundary once due; output frames keep the original particle order.
+    #[serde(default)]
+    pub reorder_interval: usize,
+    /// Threads for the CPU work: integration, diagnostics, the CPU force backend and the tree.
+    /// Unset or 0 uses every core.
+    #[serde(default)]
+    pub cpu_threads: Option<usize>,
+    /// Bit-reproducible runs: the initial conditions seeded and every CPU sum done in the same
+    /// order whatever cpu_threads is. Exact with force_backend cpu, GPU force sums can still
+    /// differ between drivers and devices.
+    #[serde(default)]
+    pub deterministic: bool,
+    /// Write a checkpoint every this many batches, for `resume` to carry on from exactly after
+    /// a crash or kill. 0 to only write one when stopping early.
+    #[serde(default)]
+    pub checkpoint_every: usize,
+    /// Stop before this much wall time has passed, with a checkpoint `resume` carries on from,
+    /// and exit with status 75 so a job script knows to resubmit
+    #[serde(default)]
+    pub max_wall_time_minutes: Option<f64>,
+    /// Time kept in hand before max_wall_time_minutes, in multiples of the slowest batch so
+    /// far. A batch isn't started unless this many more would fit.
+    #[serde(default = "default_wall_time_margin")]
+    pub wall_time_margin: f64,
+    /// Compressed size in gigabytes the batch files may reach before output_limit_policy kicks
+    /// in
+    #[serde(default)]
+    pub max_output_gb: Option<f64>,
+    #[serde(default)]
+    pub output_limit_policy: OutputLimitPolicy,
+    /// Name the batch files batch_<settings hash>_0001.bin.gz, so files from runs with
+    /// different settings can't be mixed up
+    #[serde(default)]
+    pub hash_in_file_names: bool,
+    /// Retries of batch file writes that fail with errors that tend to clear up on their own,
+    /// eg. EIO or a stale handle on NFS
+    #[serde(default)]
+    pub write_retry: WriteRetry,
+    /// gzip level of the batch files, 0 to 9
+    #[serde(default = "default_compression_level")]
+    pub compression_level: u32,
+    /// Simulate each batch while the one before it is written, which takes a second batch of
+    /// frame buffers in host memory. Unset, only when that fits in the memory available.
+    #[serde(default)]
+    pub write_overlap: Option<bool>,
+    /// Log level, overriding --quiet and -v
+    #[serde(default)]
+    pub log_level: Option<LogLevel>,
+    /// Check the settings file and <out_path>/overrides.toml before every batch, and apply
+    /// changes to the fields in `reload::RELOADABLE_FIELDS` without restarting
+    #[serde(default)]
+    pub hot_reload: bool,
+    /// Serve the run's status as JSON on this address and port, eg. "127.0.0.1:9000", for
+    /// monitoring. Needs a build with the status-server feature.
+    #[serde(default)]
+    pub status_address: Option<String>,
+    /// Push every stream_every-th frame over a WebSocket on this address, eg. "127.0.0.1:9001",
+    /// for live dashboards. Needs a build with the live-stream feature. The messages are laid
+    /// out in src/live.rs, examples/live.html draws them.
+    #[serde(default)]
+    pub stream_address: Option<String>,
+    /// Frames between the ones streamed
+    #[serde(default = "default_stream_every")]
+    pub stream_every: usize,
+    /// Stream only the particles whose id is a multiple of this, 1 for all of them
+    #[serde(default = "default_every")]
+    pub stream_stride: usize,
+    /// Named sets of overrides on the settings above, picked with `--profile`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, serde_json::Map<String, serde_json::Value>>,
+    /// File these settings were loaded from, filled in by load_settings
+    #[serde(skip)]
+    pub settings_file: Option<PathBuf>,
+    /// Profile applied by load_settings
+    #[serde(skip)]
+    pub profile: Option<String>,
+}
+
+/// Current settings layout. Bump it when fields are added, renamed or removed so older files
+/// get rewritten.
+///
+/// 2 added the schedule, output cadence, units, seed, force_kernel, the analysis logs, the
+/// run limits, threads and checkpoints, write_overlap, the status and stream servers, and
+/// profiles.
+pub const SCHEMA_VERSION: u32 = 2;
+
+fn default_steps_per_submit() -> usize {
+    1
+}
+
+fn default_every() -> usize {
+    1
+}
+
+fn default_stream_every() -> usize {
+    10
+}
+
+fn default_correlation_every() -> usize {
+    500
+}
+
+fn default_correlation_bins() -> usize {
+    20
+}
+
+fn default_lagrangian_fractions() -> Vec<f64> {
+    vec![0.1, 0.25, 0.5, 0.75, 0.9]
+}
+
+fn default_energy_drift_warn() -> f64 {
+    0.01
+}
+
+fn default_compression_level() -> u32 {
+    // gzip's fast level, a third of the time of the default for a little less compression
+    1
+}
+
+fn default_wall_time_margin() -> f64 {
+    1.5
+}
+
+fn default_softening() -> f32 {
+    // sqrt of the 0.001 that used to be hardcoded in nbody.wgsl
+    0.031622775
+}
+
+/// Workgroup size without a setting or a tuned value
+pub const DEFAULT_WORKGROUP_SIZE: u32 = 64;
+
+fn default_true() -> bool {
+    true
+}
+
+/// How the force kernel sums contributions from every source particle
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ForceAccumulation {
+    /// Plain f32 sum
+    #[default]
+    F32,
+    /// f32 with Kahan compensation, works everywhere at roughly 4x the adds
+    Kahan,
+    /// Native f64 accumulator, needs SHADER_F64 (falls back to kahan without it)
+    F64,
+}
+
+/// How the direct-sum force kernel reads the source particles
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ForceKernel {
+    /// Every invocation reads every source straight from the storage buffer
+    #[default]
+    Naive,
+    /// Sources staged through workgroup memory a tile at a time. Opt-in until it's been timed
+    /// faster on real hardware.
+    Tiled,
+}
+
+/// What happens once the output reaches max_output_gb
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputLimitPolicy {
+    /// Checkpoint and exit, to resume once there's room
+    #[default]
+    Stop,
+    /// Carry on at the slowest, smallest gzip level
+    Compress,
+    /// Carry on writing only one frame in `keep_every`
+    Decimate {
+        #[serde(default = "default_keep_every")]
+        keep_every: usize,
+    },
+}
+
+fn default_keep_every() -> usize {
+    10
+}
+
+/// Time integration scheme, shared by the CPU tick and the `integrate` shader entry point
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Integrator {
+    /// Semi-implicit Euler: kick then drift
+    #[default]
+    Euler,
+    /// Velocity Verlet, using the acceleration stored from the previous step
+    Verlet,
+}
+
+impl Integrator {
+    /// Value of the `INTEGRATOR` override constant in nbody.wgsl
+    pub fn shader_id(self) -> u32 {
+        match self {
+            Integrator::Euler => 0,
+            Integrator::Verlet => 1,
+        }
+    }
+}
+
+impl Settings {
+    /// Every out of range or inconsistent setting, so they can all be fixed in one go. Also
+    /// creates out_path to check it can be written to.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, problem: String| {
+            if !ok {
+                problems.push(problem);
+            }
+        };
+
+        check(
+            self.schema_version <= SCHEMA_VERSION,
+            format!(
+                "schema_version {} is newer than this build's {}",
+                self.schema_version, SCHEMA_VERSION
+            ),
+        );
+        check(
+            self.num_particles > 0,
+            "num_particles must be at least 1".to_string(),
+        );
+        check(
+            self.frames_total > 0,
+            "frames_total must be at least 1".to_string(),
+        );
+        check(
+            self.frames_per_file > 0,
+            "frames_per_file must be at least 1".to_string(),
+        );
+        check(
+            self.frames_total == 0 || self.frames_per_file <= self.frames_total,
+            format!(
+                "frames_per_file ({}) is more than frames_total ({}), so no file would be written",
+                self.frames_per_file, self.frames_total
+            ),
+        );
+        for (name, value) in [("dt", self.dt), ("arena", self.arena), ("mass", self.mass)] {
+            check(
+                value.is_finite() && value > 0.0,
+                format!("{} must be a positive number, not {}", name, value),
+            );
+        }
+        for (name, value) in [("g_const", self.g_const), ("init_vel", self.init_vel)] {
+            check(
+                value.is_finite(),
+                format!("{} must be a number, not {}", name, value),
+            );
+        }
+        check(
+            self.softening.is_finite() && self.softening >= 0.0,
+            format!("softening can't be negative, not {}", self.softening),
+        );
+        check(
+            self.workgroup_size != Some(0),
+            "workgroup_size must be at least 1".to_string(),
+        );
+        check(
+            self.max_buffer_size != Some(0),
+            "max_buffer_size must be at least 1 byte".to_string(),
+        );
+        check(
+            self.gpu_memory_budget != Some(0),
+            "gpu_memory_budget must be at least 1 byte".to_string(),
+        );
+        for (index, device) in self.devices.iter().enumerate() {
+            check(
+                device.weight.is_finite() && device.weight > 0.0,
+                format!(
+                    "devices[{}].weight must be a positive number, not {}",
+                    index, device.weight
+                ),
+            );
+        }
+        if let ForceMethod::BarnesHut(barnes_hut) = &self.force_method {
+            check(
+                barnes_hut.theta.is_finite() && barnes_hut.theta >= 0.0,
+                format!(
+                    "barnes_hut theta can't be negative, not {}",
+                    barnes_hut.theta
+                ),
+            );
+            check(
+                barnes_hut.leaf_size > 0,
+                "barnes_hut leaf_size must be at least 1".to_string(),
+            );
+        }
+        let writable = check_writable(&self.out_path);
+        check(
+            writable.is_ok(),
+            format!(
+                "out_path {} can't be written to: {}",
+                self.out_path.display(),
+                writable.err().unwrap_or_default()
+            ),
+        );
+
+        if let Some(Err(e)) = self.units.as_ref().map(Units::system) {
+            check(false, e);
+        }
+        for problem in schedule::problems(self) {
+            check(false, problem);
+        }
+        for problem in initial_conditions::problems(&self.initial_conditions) {
+            check(false, problem);
+        }
+        for problem in Reload::all(self).iter().filter_map(Reload::problem) {
+            check(false, problem);
+        }
+        if let Some(minutes) = self.max_wall_time_minutes {
+            check(
+                minutes > 0.0 && minutes.is_finite(),
+                format!("max_wall_time_minutes must be positive, not {}", minutes),
+            );
+        }
+        if let Some(gb) = self.max_output_gb {
+            check(
+                gb > 0.0 && gb.is_finite(),
+                format!("max_output_gb must be positive, not {}", gb),
+            );
+        }
+        if let OutputLimitPolicy::Decimate { keep_every } = self.output_limit_policy {
+            check(
+                keep_every > 0,
+                "output_limit_policy keep_every must be at least 1".to_string(),
+            );
+        }
+        for (field, address) in [
+            ("status_address", &self.status_address),
+            ("stream_address", &self.stream_address),
+        ] {
+            if let Some(address) = address {
+                check(
+                    address.parse::<std::net::SocketAddr>().is_ok(),
+                    format!(
+                        "{} must be an address and port like 127.0.0.1:9000, not {}",
+                        field, address
+                    ),
+                );
+            }
+        }
+        check(
+            self.stream_every > 0,
+            "stream_every must be at least 1".to_string(),
+        );
+        check(
+            self.stream_stride > 0,
+            "stream_stride must be at least 1".to_string(),
+        );
+        for &fraction in &self.lagrangian_fractions {
+            check(
+                fraction > 0.0 && fraction <= 1.0,
+                format!(
+                    "lagrangian_fractions must be more than 0 and at most 1, not {}",
+                    fraction
+                ),
+            );
+        }
+        if let Some(max) = self.speed_histogram_max {
+            check(
+                max > 0.0 && max.is_finite(),
+                format!("speed_histogram_max must be positive, not {}", max),
+            );
+        }
+        for (field, energy) in [
+            ("binding_energy_min", self.binding_energy_min),
+            ("binding_energy_max", self.binding_energy_max),
+        ] {
+            if let Some(energy) = energy {
+                check(
+                    energy.is_finite(),
+                    format!("{} must be a number, not {}", field, energy),
+                );
+            }
+        }
+        if let (Some(min), Some(max)) = (self.binding_energy_min, self.binding_energy_max) {
+            check(
+                min < max,
+                format!(
+                    "binding_energy_min {} must be less than binding_energy_max {}",
+                    min, max
+                ),
+            );
+        }
+        for (field, radius) in [
+            (
+                "density_profile_min_radius",
+                self.density_profile_min_radius,
+            ),
+            (
+                "density_profile_max_radius",
+                self.density_profile_max_radius,
+            ),
+        ] {
+            if let Some(radius) = radius {
+                check(
+                    radius > 0.0 && radius.is_finite(),
+                    format!("{} must be positive, not {}", field, radius),
+                );
+            }
+        }
+        if let (Some(min), Some(max)) = (
+            self.density_profile_min_radius,
+            self.density_profile_max_radius,
+        ) {
+            check(
+                min < max,
+                format!(
+                    "density_profile_min_radius {} must be less than density_profile_max_radius {}",
+                    min, max
+                ),
+            );
+        }
+        check(
+            self.correlation_every > 0,
+            "correlation_every must be at least 1".to_string(),
+        );
+        check(
+            self.correlation_bins > 0,
+            "correlation_bins must be at least 1".to_string(),
+        );
+        for (field, value) in [
+            ("correlation_radius", self.correlation_radius),
+            (
+                "correlation_min_separation",
+                self.correlation_min_separation,
+            ),
+        ] {
+            if let Some(value) = value {
+                check(
+                    value > 0.0 && value.is_finite(),
+                    format!("{} must be positive, not {}", field, value),
+                );
+            }
+        }
+        if let Some(min) = self.correlation_min_separation {
+            let diameter = 2.0 * self.correlation_radius.unwrap_or(self.arena as f64);
+            check(
+                min < diameter,
+                format!(
+                    "correlation_min_separation {} must be less than the sphere's diameter {}",
+                    min, diameter
+                ),
+            );
+        }
+        check(
+            self.energy_drift_warn > 0.0,
+            format!(
+                "energy_drift_warn must be more than zero, not {}",
+                self.energy_drift_warn
+            ),
+        );
+        check(
+            self.wall_time_margin >= 0.0 && self.wall_time_margin.is_finite(),
+            format!(
+                "wall_time_margin must be zero or more, not {}",
+                self.wall_time_margin
+            ),
+        );
+        check(
+            self.write_retry.backoff_seconds >= 0.0 && self.write_retry.backoff_seconds.is_finite(),
+            format!(
+                "write_retry backoff_seconds must be zero or more, not {}",
+                self.write_retry.backoff_seconds
+            ),
+        );
+
+        for name in self.profiles.keys() {
+            if let Err(e) = self.clone().apply_profile(name) {
+                problems.push(format!("profiles.{}: {}", name, e));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// Override these settings with the profile `name`, returning the fields it sets
+    pub fn apply_profile(&mut self, name: &str) -> Result<Vec<String>, String> {
+        let Some(profile) = self.profiles.get(name) else {
+            let available: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            return Err(match available.as_slice() {
+                [] => format!("unknown profile {}, the settings file defines none", name),
+                _ => format!(
+                    "unknown profile {}, available: {}",
+                    name,
+                    available.join(", ")
+                ),
+            });
+        };
+        if profile.contains_key("profiles") {
+            return Err("profiles can't define profiles".to_string());
+        }
+        let mut fields = match serde_json::to_value(&*self) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            _ => return Err("could not serialize the settings".to_string()),
+        };
+        fields.extend(profile.clone());
+        let keys = profile.keys().cloned().collect();
+        let settings: Settings =
+            serde_json::from_value(fields.into()).map_err(|e| e.to_string())?;
+        *self = Settings {
+            settings_file: self.settings_file.take(),
+            profile: Some(name.to_string()),
+            ..settings
+        };
+        Ok(keys)
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            schema_version: SCHEMA_VERSION,
+            num_particles: 12000,
+            frames_total: 10000,
+            frames_per_file: 100,
+            dt: 1.0 / 180.0,
+            schedule: Vec::new(),
+            output_every: default_every(),
+            arena: 100.0,
+            g_const: 0.01,
+            units: None,
+            softening: default_softening(),
+            mass: 1000.,
+            init_vel: 4.5,
+            out_path: PathBuf::from(""), // initialized properly in load_settings
+            initial_conditions: InitialConditions::default(),
+            seed: None,
+            zero_net_momentum: false,
+            zero_net_angular_momentum: false,
+            integrator: Integrator::default(),
+            gpu_integration: true,
+            workgroup_size: None,
+            gpu_backend: None,
+            adapter: AdapterSettings::default(),
+            force_backend: ForceBackendKind::default(),
+            devices: Vec::new(),
+            force_accumulation: ForceAccumulation::default(),
+            force_kernel: ForceKernel::default(),
+            subgroups: false,
+            steps_per_submit: default_steps_per_submit(),
+            max_buffer_size: None,
+            gpu_memory_budget: None,
+            out_of_core: false,
+            force_method: ForceMethod::default(),
+            shader_path: None,
+            diagnostics: false,
+            diagnostics_every: default_every(),
+            energy_drift_warn: default_energy_drift_warn(),
+            lagrangian_radii: false,
+            lagrangian_fractions: default_lagrangian_fractions(),
+            nearest_neighbors: false,
+            tracked_particles: TrackedParticles::default(),
+            speed_histogram_bins: 0,
+            speed_histogram_max: None,
+            binding_energy_bins: 0,
+            binding_energy_min: None,
+            binding_energy_max: None,
+            density_profile_bins: 0,
+            density_profile_min_radius: None,
+            density_profile_max_radius: None,
+            correlation_pairs: 0,
+            correlation_every: default_correlation_every(),
+            correlation_bins: default_correlation_bins(),
+            correlation_radius: None,
+            correlation_min_separation: None,
+            reorder_interval: 0,
+            cpu_threads: None,
+            deterministic: false,
+            checkpoint_every: 0,
+            max_wall_time_minutes: None,
+            wall_time_margin: default_wall_time_margin(),
+            max_output_gb: None,
+            output_limit_policy: OutputLimitPolicy::default(),
+            hash_in_file_names: false,
+            write_retry: WriteRetry::default(),
+            compression_level: default_compression_level(),
+            write_overlap: None,
+            log_level: None,
+            hot_reload: false,
+            status_address: None,
+            stream_address: None,
+            stream_every: default_stream_every(),
+            stream_stride: default_every(),
+            profiles: BTreeMap::new(),
+            settings_file: None,
+            profile: None,
+        }
+    }
+}
+
+/// handles initial distribution and velocity, numbering the particles in order
+pub fn init_particles(settings: &Settings) -> Result<ParticleSet, Error> {
+    let seed = match settings.seed {
+        Some(seed) => seed,
+        None if settings.deterministic => 0,
+        None => rand::random(),
+    };
+    info!("Initial conditions seed: {}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let mut particles = match &settings.initial_conditions {
+        InitialConditions::Sphere => init_sphere(settings, &mut rng),
+        InitialConditions::Galaxy(galaxy) => {
+            initial_conditions::galaxy(galaxy, settings.g_const, &mut rng)
+        }
+        InitialConditions::Tabulated(tabulated) => {
+            initial_conditions::tabulated(tabulated, settings.g_const, &mut rng)
+                .map_err(Error::InitialConditions)?
+        }
+    };
+
+    for (id, particle) in particles.iter_mut().enumerate() {
+        *particle = particle.with_id(id as u32);
+    }
+
+    if settings.zero_net_momentum {
+        let removed = initial_conditions::zero_net_momentum(&mut particles);
+        info!("Removed net velocity: {:?}", removed);
+    }
+    if settings.zero_net_angular_momentum {
+        let removed = initial_conditions::zero_net_angular_momentum(&mut particles);
+        info!("Removed net angular velocity: {:?}", removed);
+    }
+
+    Ok(particles.into())
+}
+
+/// Random sphere orbiting an implied central mass
+fn init_sphere(settings: &Settings, rng: &mut impl Rng) -> Vec<Particle> {
+    (0..settings.num_particles)
+        .map(|_| {
+            // Random spherical distribution
+            let r = settings.arena * (rng.random::<f32>().powf(1.0 / 3.0)); // Avoid center
+            let theta = rng.random::<f32>() * 2.0 * std::f32::consts::PI;
+            let phi = (rng.random::<f32>() * 2.0 - 1.0).acos();
+
+            let pos = Vec3::new(
+                r * phi.sin() * theta.cos(),
+                r * phi.sin() * theta.sin(),
+                r * phi.cos(),
+            );
+
+            // Calculate orbital velocity for a central mass system
+            let central_mass = settings.num_particles as f32 * 5.0; // random numbers go brrr
+            let orbital_speed = (settings.g_const * central_mass / r).sqrt() * settings.init_vel;
+            let tangent = Vec3::new(-pos.y, pos.x, 0.0).normalize_or_zero();
+            let vel = tangent * orbital_speed;
+
+            Particle::new(settings.mass, pos, vel, Vec3::ZERO)
+        })
+        .collect()
+}
+
+/// Format of a settings file
+#[derive(Clone, Copy, PartialEq, Debug, clap::ValueEnum)]
+pub enum SettingsFormat {
+    Json,
+    Toml,
+}
+
+impl SettingsFormat {
+    /// Settings files in the order they're looked for
+    const SEARCH_ORDER: [SettingsFormat; 2] = [SettingsFormat::Toml, SettingsFormat::Json];
+
+    /// settings.toml is TOML, anything else is taken to be JSON
+    pub(crate) fn from_path(path: &Path) -> SettingsFormat {
+        match path.extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("toml") => SettingsFormat::Toml,
+            _ => SettingsFormat::Json,
+        }
+    }
+
+    pub fn file_name(self) -> &'static str {
+        match self {
+            SettingsFormat::Json => "settings.json",
+            SettingsFormat::Toml => "settings.toml",
+        }
+    }
+
+    /// Settings from a file's contents, and the top level keys it sets
+    fn parse(self, content: &str) -> Result<(Settings, Vec<String>), String> {
+        match self {
+            SettingsFormat::Json => {
+                let json: serde_json::Map<String, serde_json::Value> =
+                    serde_json::from_str(content).map_err(|e| e.to_string())?;
+                let keys = json.keys().cloned().collect();
+                let settings = serde_json::from_value(json.into()).map_err(|e| e.to_string())?;
+                Ok((settings, keys))
+            }
+            SettingsFormat::Toml => {
+                let error = |e: toml::de::Error| e.to_string().trim_end().to_string();
+                let table: toml::Table = toml::from_str(content).map_err(error)?;
+                let keys = table.keys().cloned().collect();
+                let settings = table.try_into().map_err(error)?;
+                Ok((settings, keys))
+            }
+        }
+    }
+
+    /// The top level fields of a file's contents, without checking they're settings
+    pub(crate) fn fields(
+        self,
+        content: &str,
+    ) -> Result<serde_json::Map<String, serde_json::Value>, String> {
+        match self {
+            SettingsFormat::Json => serde_json::from_str(content).map_err(|e| e.to_string()),
+            SettingsFormat::Toml => {
+                let table: toml::Table =
+                    toml::from_str(content).map_err(|e| e.to_string().trim_end().to_string())?;
+                match serde_json::to_value(table) {
+                    Ok(serde_json::Value::Object(fields)) => Ok(fields),
+                    _ => Err("not a table".to_string()),
+                }
+            }
+        }
+    }
+
+    pub fn serialize(self, settings: &Settings) -> Result<String, String> {
+        match self {
+            SettingsFormat::Json => {
+                serde_json::to_string_pretty(settings).map_err(|e| e.to_string())
+            }
+            SettingsFormat::Toml => toml::to_string_pretty(settings).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// `--settings` path that reads the settings from stdin, in --settings-format
+const STDIN_PATH: &str = "-";
+
+/// Settings for `cli`: its settings file, profile, environment overrides and flags, in rising
+/// precedence. Every problem they have is in the error.
+pub fn load_settings(cli: &RunArgs) -> Result<Settings, Error> {
+    // a file asked for by --settings has to exist, without one the working directory's settings
+    // file is optional
+    let from_stdin = cli.settings_path.as_deref() == Some(Path::new(STDIN_PATH));
+    let file = match &cli.settings_path {
+        Some(path) if from_stdin => Some((path.clone(), cli.settings_format)),
+        Some(path) if !path.is_file() => {
+            return Err(Error::settings(format!(
+                "Settings file {} not found",
+                path.display()
+            )));
+        }
+        Some(path) => Some((path.clone(), SettingsFormat::from_path(path))),
+        None => {
+            let found: Vec<SettingsFormat> = SettingsFormat::SEARCH_ORDER
+                .into_iter()
+                .filter(|format| Path::new(format.file_name()).exists())
+                .collect();
+            if let [used, ignored @ ..] = found.as_slice() {
+                for format in ignored {
+                    warn!(
+                        "Ignoring {}, {} takes precedence",
+                        format.file_name(),
+                        used.file_name()
+                    );
+                }
+            }
+            found
+                .first()
+                .map(|&format| (PathBuf::from(format.file_name()), format))
+                .or_else(|| {
+                    wizard::first_run(cli.settings_format).map(|path| (path, cli.settings_format))
+                })
+        }
+    }
+    .map(|(path, format)| match from_stdin {
+        true => (path, format),
+        false => (std::fs::canonicalize(&path).unwrap_or(path), format),
+    });
+
+    // which settings the file sets, to report where each one came from. The user's file is only
+    // rewritten to migrate it from an older schema, otherwise its comments and layout survive.
+    // Settings piped in are never written anywhere but the output directory.
+    let mut file_keys = Vec::new();
+    let mut settings = match &file {
+        Some((path, format)) => match read_settings_source(path, from_stdin)
+            .and_then(|content| format.parse(&content))
+        {
+            Ok((mut settings, keys)) => {
+                info!("Loaded settings from {}", source_name(path, from_stdin));
+                if settings.schema_version < SCHEMA_VERSION && !from_stdin {
+                    migrate_settings_file(path, *format, &mut settings, &keys);
+                }
+                file_keys = keys;
+                settings
+            }
+            Err(e) => {
+                // falling back to defaults would quietly run something else than asked for.
+                // toml errors can run over several lines, so the error goes last.
+                return Err(Error::settings(format!(
+                    "Could not load {}: {}",
+                    source_name(path, from_stdin),
+                    e
+                )));
+            }
+        },
+        None => {
+            info!("No settings file found, creating one with default values");
+            create_default_settings(cli.settings_format)
+        }
+    };
+    let from_profile = match &cli.profile {
+        Some(name) => settings.apply_profile(name).map_err(Error::settings)?,
+        None => Vec::new(),
+    };
+    let from_env = match apply_env_overrides(&mut settings, env::vars()) {
+        Ok(from_env) => from_env,
+        Err(problems) => {
+            return Err(Error::Settings(
+                problems
+                    .iter()
+                    .map(|problem| format!("Invalid environment override {}", problem))
+                    .collect(),
+            ));
+        }
+    };
+    settings.settings_file = Some(match file {
+        Some((path, _)) => path,
+        None => {
+            let created = PathBuf::from(cli.settings_format.file_name());
+            std::fs::canonicalize(&created).unwrap_or(created)
+        }
+    });
+
+    let mut from_command_line: Vec<String> = cli
+        .apply(&mut settings)
+        .into_iter()
+        .map(String::from)
+        .collect();
+    match apply_set_overrides(&mut settings, &cli.set) {
+        Ok(set) => from_command_line.extend(set),
+        Err(problems) => {
+            return Err(Error::Settings(
+                problems
+                    .iter()
+                    .map(|problem| format!("Invalid --set {}", problem))
+                    .collect(),
+            ));
+        }
+    }
+    let output_path = resolve_out_path(
+        &settings.out_path,
+        &env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
+    );
+
+    // presets that pick their own particle counts win over num_particles
+    if let Some(count) = settings.initial_conditions.particle_count() {
+        if count != settings.num_particles {
+            info!(
+                "num_particles set to {} by initial conditions (was {})",
+                count, settings.num_particles
+            );
+        }
+        settings.num_particles = count;
+    }
+
+    // after every source, so a g_const from any of them is reported as replaced
+    if let Some(Ok(system)) = settings.units.as_ref().map(Units::system) {
+        let g_const = system.gravitational_constant() as f32;
+        let g_given = [&file_keys, &from_profile, &from_env, &from_command_line]
+            .iter()
+            .any(|keys| keys.iter().any(|key| key == "g_const"));
+        if g_given && settings.g_const != g_const {
+            warn!(
+                "Replacing g_const {} with {}, G in {}",
+                settings.g_const, g_const, system
+            );
+        } else {
+            info!("g_const {}, G in {}", g_const, system);
+        }
+        settings.g_const = g_const;
+    }
+
+    settings.out_path = output_path;
+    print_sources(
+        &settings,
+        &file_keys,
+        &from_profile,
+        &from_env,
+        &from_command_line,
+    );
+    if let Err(problems) = settings.validate() {
+        return Err(Error::Settings(
+            problems
+                .iter()
+                .map(|problem| format!("Invalid setting: {}", problem))
+                .collect(),
+        ));
+    }
+    // applied, so they don't need to follow the settings into the output directory
+    settings.profiles.clear();
+    info!("Output directory: {}", settings.out_path.display());
+    Ok(settings)
+}
+
+/// Contents of the settings file at `path`, or everything on stdin
+fn read_settings_source(path: &Path, from_stdin: bool) -> Result<String, String> {
+    if !from_stdin {
+        return std::fs::read_to_string(path).map_err(|e| e.to_string());
+    }
+    let mut content = String::new();
+    std::io::stdin()
+        .read_to_string(&mut content)
+        .map_err(|e| e.to_string())?;
+    Ok(content)
+}
+
+fn source_name(path: &Path, from_stdin: bool) -> String {
+    match from_stdin {
+        true => "stdin".to_string(),
+        false => path.display().to_string(),
+    }
+}
+
+/// Full path of `out_path`, relative ones against the directory the program was started in and
+/// an empty one as `output` there
+fn resolve_out_path(out_path: &Path, cwd: &Path) -> PathBuf {
+    if out_path.as_os_str().is_empty() {
+        cwd.join("output")
+    } else if out_path.is_absolute() {
+        out_path.to_path_buf()
+    } else {
+        cwd.join(out_path)
+    }
+}
+
+/// Copy of the effective settings written to the output directory when a run starts, which
+/// `resume` reads back
+pub const RESOLVED_SETTINGS_FILE: &str = "settings.resolved.json";
+
+/// Fields left out of `Settings::hash`, which covers what's simulated: the initial conditions,
+/// the physics, how the forces are summed and integrated, and which frames are written. Where
+/// the output goes and how it's stored, how long the run is and its limits, what's logged,
+/// analysed or served alongside, which hardware runs it and how it's tuned, and the profiles
+/// not applied are all left out. So is shader_path, a development option whose path differs
+/// between machines. max_output_gb stays in, its output_limit_policy can decimate the frames.
+const UNHASHED_FIELDS: [&str; 46] = [
+    "schema_version",
+    "out_path",
+    "frames_total",
+    "hash_in_file_names",
+    "compression_level",
+    "write_overlap",
+    "write_retry",
+    "checkpoint_every",
+    "max_wall_time_minutes",
+    "wall_time_margin",
+    // hardware and tuning
+    "workgroup_size",
+    "gpu_backend",
+    "adapter",
+    "devices",
+    "steps_per_submit",
+    "max_buffer_size",
+    "gpu_memory_budget",
+    "cpu_threads",
+    "shader_path",
+    // logs and analysis
+    "log_level",
+    "diagnostics",
+    "diagnostics_every",
+    "energy_drift_warn",
+    "lagrangian_radii",
+    "lagrangian_fractions",
+    "nearest_neighbors",
+    "speed_histogram_bins",
+    "speed_histogram_max",
+    "binding_energy_bins",
+    "binding_energy_min",
+    "binding_energy_max",
+    "density_profile_bins",
+    "density_profile_min_radius",
+    "density_profile_max_radius",
+    "correlation_pairs",
+    "correlation_every",
+    "correlation_bins",
+    "correlation_radius",
+    "correlation_min_separation",
+    "tracked_particles",
+    // served alongside
+    "hot_reload",
+    "status_address",
+    "stream_address",
+    "stream_every",
+    "stream_stride",
+    "profiles",
+];
+
+impl Settings {
+    /// First 8 hex digits of the SHA-256 of these settings as JSON, keys sorted, without
+    /// `UNHASHED_FIELDS`. Stable across runs and machines for the same configuration.
+    pub fn hash(&self) -> String {
+        let mut fields = match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            _ => serde_json::Map::new(),
+        };
+        for field in UNHASHED_FIELDS {
+            fields.remove(field);
+        }
+        // serde_json's map is a BTreeMap, so the keys serialize in order at every level
+        let digest = Sha256::digest(serde_json::Value::Object(fields).to_string());
+        digest[..4]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Write the settings as resolved from file, environment and command line to
+    /// `<out_path>/settings.resolved.json`
+    pub fn save_resolved(&self) -> Result<(), Error> {
+        let path = self.out_path.join(RESOLVED_SETTINGS_FILE);
+        let json =
+            serde_json::to_string_pretty(self).map_err(|e| Error::settings(e.to_string()))?;
+        std::fs::write(&path, json).map_err(Error::io("write", &path))
+    }
+}
+
+/// Create `dir` if needed and check a file can be written in it and deleted again
+fn check_writable(dir: &Path) -> Result<(), String> {
+    std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    let probe = dir.join(".write_test");
+    // synced, as some network filesystems only report a failed write once it's flushed
+    let written = std::fs::File::create(&probe)
+        .and_then(|mut file| {
+            file.write_all(b"gravity-output")?;
+            file.sync_all()
+        })
+        .map_err(|e| e.to_string());
+    let removed = std::fs::remove_file(&probe).map_err(|e| format!("can't delete files: {}", e));
+    written.and(removed)
+}
+
+/// Prefix of the environment variables that override settings, eg. GRAVITY_NUM_PARTICLES
+const ENV_PREFIX: &str = "GRAVITY_";
+
+/// Override top level settings from `GRAVITY_<FIELD>` variables, returning the fields set or
+/// every variable that didn't parse. Values are read as JSON, or as a plain string when they
+/// aren't JSON, so `GRAVITY_DT=0.002` and `GRAVITY_INTEGRATOR=verlet` both work.
+fn apply_env_overrides(
+    settings: &mut Settings,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<Vec<String>, Vec<String>> {
+    let Ok(serde_json::Value::Object(mut fields)) = serde_json::to_value(&*settings) else {
+        return Ok(Vec::new());
+    };
+    let mut set = Vec::new();
+    let mut problems = Vec::new();
+    for (name, raw) in vars {
+        let Some(field) = name
+            .strip_prefix(ENV_PREFIX)
+            .map(str::to_lowercase)
+            .filter(|field| fields.contains_key(field))
+        else {
+            continue;
+        };
+        let result = parse_override(&raw, |value| {
+            let mut candidate = fields.clone();
+            candidate.insert(field.clone(), value);
+            serde_json::from_value::<Settings>(candidate.clone().into()).map(|_| candidate)
+        });
+        match result {
+            Ok(candidate) => {
+                fields = candidate;
+                set.push(field);
+            }
+            Err(e) => problems.push(format!("{}={}: {}", name, raw, e)),
+        }
+    }
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+    if !set.is_empty() {
+        *settings = serde_json::from_value(fields.into()).map_err(|e| vec![e.to_string()])?;
+    }
+    Ok(set)
+}
+
+/// Apply `raw` as JSON, or as a plain string when that doesn't parse or fit, reporting the
+/// JSON attempt's error if neither does
+fn parse_override<T>(
+    raw: &str,
+    apply: impl Fn(serde_json::Value) -> Result<T, serde_json::Error>,
+) -> Result<T, serde_json::Error> {
+    let as_string = serde_json::Value::String(raw.to_string());
+    match serde_json::from_str(raw) {
+        // a bare number is still a valid string, eg. an out_path of 2024
+        Ok(parsed) if parsed != as_string => {
+            apply(parsed).or_else(|e| apply(as_string).map_err(|_| e))
+        }
+        _ => apply(as_string),
+    }
+}
+
+/// Apply `--set path.to.field=value` overrides in order, returning the top level fields they
+/// change or every one that didn't apply. Values are read like the environment overrides, and
+/// numeric path segments index into lists, eg. `devices.1.weight=2`.
+fn apply_set_overrides(
+    settings: &mut Settings,
+    overrides: &[String],
+) -> Result<Vec<String>, Vec<String>> {
+    let Ok(serde_json::Value::Object(fields)) = serde_json::to_value(&*settings) else {
+        return Ok(Vec::new());
+    };
+    let mut fields = serde_json::Value::Object(fields);
+    let mut set = Vec::new();
+    let mut problems = Vec::new();
+    for assignment in overrides {
+        let Some((path, raw)) = assignment.split_once('=') else {
+            problems.push(format!("{}: expected path.to.field=value", assignment));
+            continue;
+        };
+        let result = field_at(&mut fields.clone(), path).and_then(|_| {
+            parse_override(raw, |value| {
+                let mut candidate = fields.clone();
+                *field_at(&mut candidate, path).expect("path checked above") = value;
+                serde_json::from_value::<Settings>(candidate.clone()).map(|_| candidate)
+            })
+            .map_err(|e| e.to_string())
+        });
+        match result {
+            Ok(candidate) => {
+                fields = candidate;
+                let top = path.split('.').next().unwrap_or_default().to_string();
+                if !set.contains(&top) {
+                    set.push(top);
+                }
+            }
+            Err(e) => problems.push(format!("{}: {}", assignment, e)),
+        }
+    }
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+    if !set.is_empty() {
+        *settings = serde_json::from_value(fields).map_err(|e| vec![e.to_string()])?;
+    }
+    Ok(set)
+}
+
+/// The value at the dotted `path` in the serialized settings. Unset optional sections are
+/// filled in on the way, so `units.preset` works while `units` is null; anything else has to
+/// exist already.
+fn field_at<'a>(
+    fields: &'a mut serde_json::Value,
+    path: &str,
+) -> Result<&'a mut serde_json::Value, String> {
+    let mut value = fields;
+    let mut walked = Vec::new();
+    for segment in path.split('.') {
+        let parent = match walked.is_empty() {
+            true => "the settings".to_string(),
+            false => walked.join("."),
+        };
+        if value.is_null() {
+            *value = serde_json::Value::Object(serde_json::Map::new());
+            value = value
+                .as_object_mut()
+                .expect("just replaced")
+                .entry(segment)
+                .or_insert(serde_json::Value::Null);
+        } else if let serde_json::Value::Object(object) = value {
+            if !object.contains_key(segment) {
+                let keys: Vec<&str> = object.keys().map(String::as_str).collect();
+                return Err(format!(
+                    "{} has no field {}, expected one of: {}",
+                    parent,
+                    segment,
+                    keys.join(", ")
+                ));
+            }
+            value = object.get_mut(segment).expect("checked above");
+        } else if let serde_json::Value::Array(list) = value {
+            let len = list.len();
+            value = segment
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| list.get_mut(index))
+                .ok_or_else(|| {
+                    format!(
+                        "{} has {} entries, {} isn't one of them",
+                        parent, len, segment
+                    )
+                })?;
+        } else {
+            return Err(format!(
+                "{} is {}, it has no field {}; set it whole instead",
+                parent, value, segment
+            ));
+        }
+        walked.push(segment);
+    }
+    Ok(value)
+}
+
+/// Print the settings the command line, environment or settings file set, in order of
+/// precedence
+fn print_sources(
+    settings: &Settings,
+    file_keys: &[String],
+    profile_keys: &[String],
+    env_keys: &[String],
+    command_line: &[String],
+) {
+    let Ok(serde_json::Value::Object(resolved)) = serde_json::to_value(settings) else {
+        return;
+    };
+    let profile = format!(
+        "profile {}",
+        settings.profile.as_deref().unwrap_or_default()
+    );
+    match &settings.profile {
+        Some(profile) => info!(
+            "Settings (command line > environment > profile {} > settings file > defaults):",
+            profile
+        ),
+        None => info!("Settings (command line > environment > settings file > defaults):"),
+    }
+    let mut defaulted = 0;
+    for (key, value) in resolved.iter().filter(|(key, _)| *key != "profiles") {
+        let source = if command_line.contains(key) {
+            "command line"
+        } else if env_keys.contains(key) {
+            "environment"
+        } else if profile_keys.contains(key) {
+            profile.as_str()
+        } else if file_keys.contains(key) {
+            "settings file"
+        } else {
+            defaulted += 1;
+            continue;
+        };
+        info!("  {} = {} ({})", key, display_value(value), source);
+    }
+    info!("  {} more at their defaults", defaulted);
+}
+
+/// Rewrite a settings file from an older schema in the current one, after copying it to
+/// `<name>.bak`, and report the fields it didn't set and now gets defaults for
+fn migrate_settings_file(
+    path: &Path,
+    format: SettingsFormat,
+    settings: &mut Settings,
+    file_keys: &[String],
+) {
+    let from = settings.schema_version;
+    settings.schema_version = SCHEMA_VERSION;
+    let defaulted = defaulted_fields(file_keys);
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    let backup = PathBuf::from(backup);
+
+    let result = std::fs::copy(path, &backup)
+        .map_err(|e| format!("Could not back up to {}: {}", backup.display(), e))
+        .and_then(|_| format.serialize(settings))
+        .and_then(|contents| {
+            std::fs::write(path, contents)
+                .map_err(|e| format!("Could not rewrite {}: {}", path.display(), e))
+        });
+    match result {
+        Ok(()) => info!(
+            "Migrated {} from schema version {} to {}, the original is in {}",
+            path.display(),
+            from,
+            SCHEMA_VERSION,
+            backup.display()
+        ),
+        Err(e) => warn!("{}, leaving the file at schema version {}", e, from),
+    }
+    if !defaulted.is_empty() {
+        info!(
+            "  fields it didn't set, now at their defaults: {}",
+            defaulted.join(", ")
+        );
+    }
+}
+
+/// Settings fields missing from `file_keys`
+fn defaulted_fields(file_keys: &[String]) -> Vec<String> {
+    let Ok(serde_json::Value::Object(fields)) = serde_json::to_value(Settings::default()) else {
+        return Vec::new();
+    };
+    fields
+        .into_iter()
+        .map(|(key, _)| key)
+        .filter(|key| key != "schema_version" && !file_keys.contains(key))
+        .collect()
+}
+
+/// A serialized setting as it would be written. Settings floats are f32, widened to f64 they
+/// print their rounding error.
+pub fn display_value(value: &serde_json::Value) -> String {
+    match value.as_f64() {
+        Some(float) if value.is_f64() => (float as f32).to_string(),
+        _ => value.to_string(),
+    }
+}
+
+fn create_default_settings(format: SettingsFormat) -> Settings {
+    let settings = Settings::default();
+    match format.serialize(&settings) {
+        Ok(contents) => {
+            if let Err(e) = std::fs::write(format.file_name(), contents) {
+                warn!("Could not create {}: {}", format.file_name(), e);
+            } else {
+                info!("Created {} with default values", format.file_name());
+            }
+        }
+        Err(e) => warn!("Could not serialize settings: {}", e),
+    }
+    settings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initial_conditions::{GalaxySettings, TabulatedSettings};
+    use crate::tree::BarnesHutSettings;
+    use crate::units::UnitPreset;
+
+    fn valid_settings() -> Settings {
+        Settings {
+            out_path: std::env::temp_dir().join("gravity-output-validate"),
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(valid_settings().validate(), Ok(()));
+    }
+
+    #[test]
+    fn each_bad_setting_is_reported() {
+        // a regular file where a directory is needed
+        let blocker = std::env::temp_dir().join("gravity-output-validate-file");
+        std::fs::write(&blocker, []).unwrap();
+
+        let with = |change: &dyn Fn(&mut Settings)| {
+            let mut settings = valid_settings();
+            change(&mut settings);
+            settings
+        };
+        let barnes_hut =
+            |theta, leaf_size| ForceMethod::BarnesHut(BarnesHutSettings { theta, leaf_size });
+        let galaxy = |change: &dyn Fn(&mut GalaxySettings)| {
+            with(&|s| {
+                let mut galaxy = GalaxySettings::default();
+                change(&mut galaxy);
+                s.initial_conditions = InitialConditions::Galaxy(galaxy);
+            })
+        };
+        let cases = [
+            ("num_particles", with(&|s| s.num_particles = 0)),
+            ("frames_total", with(&|s| s.frames_total = 0)),
+            ("frames_per_file must", with(&|s| s.frames_per_file = 0)),
+            (
+                "more than frames_total",
+                with(&|s| s.frames_per_file = s.frames_total + 1),
+            ),
+            ("dt", with(&|s| s.dt = 0.0)),
+            ("arena", with(&|s| s.arena = -1.0)),
+            ("mass", with(&|s| s.mass = f32::NAN)),
+            ("g_const", with(&|s| s.g_const = f32::INFINITY)),
+            ("init_vel", with(&|s| s.init_vel = f32::NAN)),
+            ("softening", with(&|s| s.softening = -0.1)),
+            ("workgroup_size", with(&|s| s.workgroup_size = Some(0))),
+            ("max_buffer_size", with(&|s| s.max_buffer_size = Some(0))),
+            (
+                "gpu_memory_budget",
+                with(&|s| s.gpu_memory_budget = Some(0)),
+            ),
+            (
+                "devices[0].weight",
+                with(&|s| {
+                    s.devices = vec![DeviceSettings {
+                        adapter: AdapterSettings::default(),
+                        weight: 0.0,
+                    }]
+                }),
+            ),
+            (
+                "max_wall_time_minutes",
+                with(&|s| s.max_wall_time_minutes = Some(-5.0)),
+            ),
+            ("max_output_gb", with(&|s| s.max_output_gb = Some(0.0))),
+            (
+                "backoff_seconds",
+                with(&|s| s.write_retry.backoff_seconds = f64::NAN),
+            ),
+            ("units", with(&|s| s.units = Some(Units::default()))),
+            (
+                "keep_every",
+                with(&|s| s.output_limit_policy = OutputLimitPolicy::Decimate { keep_every: 0 }),
+            ),
+            (
+                "correlation_min_separation",
+                with(&|s| s.correlation_min_separation = Some(500.0)),
+            ),
+            (
+                "binding_energy_min",
+                with(&|s| {
+                    s.binding_energy_min = Some(1.0);
+                    s.binding_energy_max = Some(-1.0);
+                }),
+            ),
+            ("theta", with(&|s| s.force_method = barnes_hut(-1.0, 8))),
+            ("leaf_size", with(&|s| s.force_method = barnes_hut(0.5, 0))),
+            ("out_path", with(&|s| s.out_path = blocker.join("output"))),
+            ("add up to 1", galaxy(&|g| g.bulge.mass_fraction = 0.3)),
+            (
+                "bulge.scale_radius",
+                galaxy(&|g| g.bulge.scale_radius = -2.0),
+            ),
+            (
+                "halo.truncation_radius",
+                galaxy(&|g| g.halo.truncation_radius = -1.0),
+            ),
+            (
+                "disk.scale_length",
+                galaxy(&|g| g.disk.scale_length = -10.0),
+            ),
+            ("disk.count", galaxy(&|g| g.disk.count = 0)),
+            (
+                "tabulated.count",
+                with(&|s| {
+                    s.initial_conditions = InitialConditions::Tabulated(TabulatedSettings {
+                        path: PathBuf::from("profile.csv"),
+                        count: 0,
+                        virial_ratio: 0.5,
+                    })
+                }),
+            ),
+        ];
+        for (expected, settings) in cases {
+            let problems = settings.validate().unwrap_err();
+            assert_eq!(problems.len(), 1, "{:?}", problems);
+            assert!(
+                problems[0].contains(expected),
+                "{:?} for {}",
+                problems,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn env_vars_override_fields() {
+        let vars = [
+            ("GRAVITY_NUM_PARTICLES", "500000"),
+            ("GRAVITY_DT", "0.002"),
+            ("GRAVITY_INTEGRATOR", "verlet"),
+            ("GRAVITY_OUT_PATH", "2024"),
+            ("GRAVITY_WORKGROUP_SIZE", "128"),
+            // not settings
+            ("GRAVITY_ALLOW_SOFTWARE_ADAPTER", "1"),
+            ("PATH", "/usr/bin"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let mut settings = Settings::default();
+        let set = apply_env_overrides(&mut settings, vars.into_iter()).unwrap();
+
+        assert_eq!(
+            set,
+            [
+                "num_particles",
+                "dt",
+                "integrator",
+                "out_path",
+                "workgroup_size"
+            ]
+        );
+        assert_eq!(settings.num_particles, 500000);
+        assert_eq!(settings.dt, 0.002);
+        assert_eq!(settings.integrator, Integrator::Verlet);
+        assert_eq!(settings.out_path, PathBuf::from("2024"));
+        assert_eq!(settings.workgroup_size, Some(128));
+    }
+
+    #[test]
+    fn bad_env_values_name_the_variable() {
+        let vars = [("GRAVITY_DT", "fast"), ("GRAVITY_NUM_PARTICLES", "-3")]
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        let mut settings = Settings::default();
+        let problems = apply_env_overrides(&mut settings, vars.into_iter()).unwrap_err();
+
+        assert_eq!(problems.len(), 2);
+        assert!(
+            problems[0].starts_with("GRAVITY_DT=fast: "),
+            "{}",
+            problems[0]
+        );
+        assert!(
+            problems[1].starts_with("GRAVITY_NUM_PARTICLES=-3: "),
+            "{}",
+            problems[1]
+        );
+        assert_eq!(settings.dt, Settings::default().dt);
+    }
+
+    #[test]
+    fn relative_out_paths_are_against_the_working_directory() {
+        let cwd = Path::new("/home/user/sims");
+        assert_eq!(
+            resolve_out_path(Path::new("runs/a"), cwd),
+            PathBuf::from("/home/user/sims/runs/a")
+        );
+        assert_eq!(
+            resolve_out_path(Path::new("/scratch/a"), cwd),
+            PathBuf::from("/scratch/a")
+        );
+        assert_eq!(
+            resolve_out_path(Path::new(""), cwd),
+            PathBuf::from("/home/user/sims/output")
+        );
+    }
+
+    #[test]
+    fn set_overrides_reach_nested_fields() {
+        let overrides = [
+            "adapter.name=radeon",
+            "units.preset=galactic",
+            "devices.0.weight=2",
+            "dt=0.5",
+        ]
+        .map(String::from);
+        let mut settings = Settings {
+            devices: vec![DeviceSettings {
+                adapter: AdapterSettings::default(),
+                weight: 1.0,
+            }],
+            ..Settings::default()
+        };
+        let set = apply_set_overrides(&mut settings, &overrides).unwrap();
+
+        assert_eq!(set, ["adapter", "units", "devices", "dt"]);
+        assert_eq!(settings.adapter.name.as_deref(), Some("radeon"));
+        assert_eq!(
+            settings.units.map(|units| units.preset),
+            Some(Some(UnitPreset::Galactic))
+        );
+        assert_eq!(settings.devices[0].weight, 2.0);
+        assert_eq!(settings.dt, 0.5);
+    }
+
+    #[test]
+    fn bad_set_overrides_say_what_is_wrong() {
+        let overrides = [
+            "adapter.nmae=radeon",
+            "dt=fast",
+            "initial_conditions.galaxy.total_mass=5",
+            "devices.3.weight=2",
+            "units.colour=red",
+            "dt",
+        ]
+        .map(String::from);
+        let mut settings = Settings::default();
+        let problems = apply_set_overrides(&mut settings, &overrides).unwrap_err();
+
+        let expected = [
+            "adapter has no field nmae, expected one of: ",
+            "dt=fast: invalid type",
+            "initial_conditions is \"sphere\", it has no field galaxy",
+            "devices has 0 entries, 3 isn't one of them",
+            "unknown field `colour`",
+            "expected path.to.field=value",
+        ];
+        assert_eq!(problems.len(), expected.len(), "{:?}", problems);
+        for (problem, expected) in problems.iter().zip(expected) {
+            assert!(problem.contains(expected), "{}", problem);
+        }
+        assert_eq!(settings.dt, Settings::default().dt);
+    }
+
+    #[test]
+    fn hashes_follow_the_simulated_configuration() {
+        let settings = Settings::default();
+        let hash = settings.hash();
+        assert_eq!(hash.len(), 8);
+        assert!(hash.chars().all(|c| c.is_ascii_hexdigit()), "{}", hash);
+        let elsewhere = Settings {
+            out_path: PathBuf::from("elsewhere"),
+            frames_total: 20,
+            hash_in_file_names: true,
+            ..settings.clone()
+        };
+        assert_eq!(elsewhere.hash(), hash);
+        let changed = Settings {
+            softening: settings.softening * 2.0,
+            ..settings.clone()
+        };
+        assert_ne!(changed.hash(), hash);
+    }
+
+    #[test]
+    fn every_field_is_hashed_or_not_on_purpose() {
+        // what's simulated, see UNHASHED_FIELDS
+        const HASHED_FIELDS: [&str; 27] = [
+            "num_particles",
+            "frames_per_file",
+            "dt",
+            "schedule",
+            "output_every",
+            "arena",
+            "g_const",
+            "units",
+            "softening",
+            "mass",
+            "init_vel",
+            "initial_conditions",
+            "seed",
+            "zero_net_momentum",
+            "zero_net_angular_momentum",
+            "integrator",
+            "gpu_integration",
+            "force_backend",
+            "force_accumulation",
+            "force_kernel",
+            "subgroups",
+            "out_of_core",
+            "force_method",
+            "reorder_interval",
+            "deterministic",
+            "max_output_gb",
+            "output_limit_policy",
+        ];
+        let settings = Settings {
+            profiles: BTreeMap::from([(String::new(), serde_json::Map::new())]),
+            ..Settings::default()
+        };
+        let Ok(serde_json::Value::Object(fields)) = serde_json::to_value(&settings) else {
+            panic!("settings serialize to an object");
+        };
+        for field in fields.keys() {
+            let hashed = HASHED_FIELDS.contains(&field.as_str());
+            let unhashed = UNHASHED_FIELDS.contains(&field.as_str());
+            assert!(
+                hashed != unhashed,
+                "{} is hashed: {}, unhashed: {}",
+                field,
+                hashed,
+                unhashed
+            );
+        }
+
+        // adding output alongside the run keeps its configuration
+        let analysed = Settings {
+            diagnostics: true,
+            speed_histogram_bins: 20,
+            tracked_particles: TrackedParticles {
+                ids: vec![1, 2],
+                sample: 0,
+            },
+            max_wall_time_minutes: Some(60.0),
+            shader_path: Some(PathBuf::from("/home/someone/nbody.wgsl")),
+            ..Settings::default()
+        };
+        assert_eq!(analysed.hash(), Settings::default().hash());
+    }
+
+    #[test]
+    fn all_problems_are_collected() {
+        let settings = Settings {
+            num_particles: 0,
+            dt: 0.0,
+            frames_per_file: 0,
+            ..valid_settings()
+        };
+        assert_eq!(settings.validate().unwrap_err().len(), 3);
+    }
+
+    #[test]
+    fn default_settings_round_trip() {
+        for format in SettingsFormat::SEARCH_ORDER {
+            let written = format.serialize(&Settings::default()).unwrap();
+            let (settings, keys) = format.parse(&written).unwrap();
+            assert_eq!(
+                format.serialize(&settings).unwrap(),
+                written,
+                "{:?}",
+                format
+            );
+            assert!(keys.iter().any(|key| key == "num_particles"));
+        }
+    }
+
+    #[test]
+    fn toml_comments_and_partial_files() {
+        let toml = "# a short test run\nnum_particles = 500 # small\nframes_total = 20\n\
+                    frames_per_file = 10\ndt = 0.01\narena = 5.0\ng_const = 0.1\nmass = 1.0\n\
+                    init_vel = 1.0\nout_path = \"runs\"\nintegrator = \"verlet\"\n";
+        let (settings, keys) = SettingsFormat::Toml.parse(toml).unwrap();
+        assert_eq!(settings.num_particles, 500);
+        assert_eq!(settings.integrator, Integrator::Verlet);
+        assert_eq!(settings.softening, default_softening());
+        assert!(!keys.contains(&"softening".to_string()));
+    }
+
+    #[test]
+    fn misspelt_fields_are_errors() {
+        let json = r#"{"num_particls": 500, "dt": 0.01}"#;
+        let e = SettingsFormat::Json.parse(json).err().unwrap();
+        assert!(e.contains("num_particls"), "{}", e);
+        let e = SettingsFormat::Toml
+            .parse("num_particls = 500\n")
+            .err()
+            .unwrap();
+        assert!(e.contains("num_particls"), "{}", e);
+    }
+
+    #[test]
+    fn old_files_are_migrated_with_a_backup() {
+        let dir = std::env::temp_dir().join("gravity-output-migrate");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("settings.json");
+        let old = r#"{"num_particles": 500, "dt": 0.01, "out_path": "runs"}"#;
+        std::fs::write(&path, old).unwrap();
+
+        let (mut settings, keys) = SettingsFormat::Json.parse(old).unwrap();
+        assert_eq!(settings.schema_version, 0);
+        // missing fields come from Settings::default(), not the type's default
+        assert_eq!(settings.frames_total, Settings::default().frames_total);
+        assert!(defaulted_fields(&keys).contains(&"frames_total".to_string()));
+        assert!(!defaulted_fields(&keys).contains(&"dt".to_string()));
+
+        migrate_settings_file(&path, SettingsFormat::Json, &mut settings, &keys);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("settings.json.bak")).unwrap(),
+            old
+        );
+        let (migrated, keys) = SettingsFormat::Json
+            .parse(&std::fs::read_to_string(&path).unwrap())
+            .unwrap();
+        assert_eq!(migrated.schema_version, SCHEMA_VERSION);
+        assert_eq!(migrated.num_particles, 500);
+        assert!(defaulted_fields(&keys).is_empty());
+    }
+
+    #[test]
+    fn version_1_files_report_the_fields_added_since() {
+        let dir = std::env::temp_dir().join("gravity-output-migrate-v1");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("settings.json");
+        let old = r#"{"schema_version": 1, "num_particles": 500, "out_path": "runs"}"#;
+        std::fs::write(&path, old).unwrap();
+
+        let (mut settings, keys) = SettingsFormat::Json.parse(old).unwrap();
+        assert!(settings.schema_version < SCHEMA_VERSION);
+        assert!(defaulted_fields(&keys).contains(&"force_kernel".to_string()));
+
+        migrate_settings_file(&path, SettingsFormat::Json, &mut settings, &keys);
+        let (migrated, _) = SettingsFormat::Json
+            .parse(&std::fs::read_to_string(&path).unwrap())
+            .unwrap();
+        assert_eq!(migrated.schema_version, SCHEMA_VERSION);
+        assert_eq!(migrated.num_particles, 500);
+    }
+
+    #[test]
+    #[cfg(not(feature = "gpu"))]
+    fn force_backend_gpu_needs_the_gpu_feature() {
+        let error = SettingsFormat::Json
+            .parse(r#"{"force_backend": "gpu"}"#)
+            .err()
+            .unwrap();
+        assert!(error.contains("unknown variant `gpu`"), "{}", error);
+    }
+
+    #[test]
+    fn profiles_override_the_base_settings() {
+        let toml = "num_particles = 500000\nframes_total = 100000\ndt = 0.01\n\n\
+                    [profiles.quick-test]\nnum_particles = 2000\nframes_total = 500\n\n\
+                    [profiles.production]\ndt = 0.002\n";
+        let (mut settings, _) = SettingsFormat::Toml.parse(toml).unwrap();
+        let keys = settings.apply_profile("quick-test").unwrap();
+
+        assert_eq!(keys, ["frames_total", "num_particles"]);
+        assert_eq!(settings.num_particles, 2000);
+        assert_eq!(settings.frames_total, 500);
+        assert_eq!(settings.dt, 0.01);
+        assert_eq!(settings.profile.as_deref(), Some("quick-test"));
+
+        let e = settings.apply_profile("prod").unwrap_err();
+        assert!(e.ends_with("available: production, quick-test"), "{}", e);
+    }
+
+    #[test]
+    fn unused_profiles_leave_the_hash_alone() {
+        let hash = |production: &str| {
+            let toml = format!(
+                "[profiles.quick-test]\nnum_particles = 2000\n\n[profiles.production]\n{}\n",
+                production
+            );
+            let (mut settings, _) = SettingsFormat::Toml.parse(&toml).unwrap();
+            settings.apply_profile("quick-test").unwrap();
+            settings.hash()
+        };
+        assert_eq!(hash("dt = 0.002"), hash("dt = 0.001\nnum_particles = 10"));
+    }
+
+    #[test]
+    fn misspelt_profile_fields_are_reported() {
+        let json = r#"{"profiles": {"quick-test": {"num_particls": 2000}}}"#;
+        let (settings, _) = SettingsFormat::Json.parse(json).unwrap();
+        let settings = Settings {
+            out_path: valid_settings().out_path,
+            ..settings
+        };
+        let problems = settings.validate().unwrap_err();
+        assert_eq!(problems.len(), 1);
+        assert!(
+            problems[0].starts_with("profiles.quick-test: unknown field"),
+            "{:?}",
+            problems
+        );
+    }
+
+    #[test]
+    fn output_limit_policies_parse() {
+        let toml = "max_output_gb = 2.5\noutput_limit_policy = { decimate = { keep_every = 5 } }\n";
+        let (settings, _) = SettingsFormat::Toml.parse(toml).unwrap();
+        assert_eq!(settings.max_output_gb, Some(2.5));
+        assert_eq!(
+            settings.output_limit_policy,
+            OutputLimitPolicy::Decimate { keep_every: 5 }
+        );
+        let (settings, _) = SettingsFormat::Json
+            .parse(r#"{"output_limit_policy": {"decimate": {}}}"#)
+            .unwrap();
+        assert_eq!(
+            settings.output_limit_policy,
+            OutputLimitPolicy::Decimate { keep_every: 10 }
+        );
+    }
+}
@@ -0,0 +1,1812 @@
+use glam::Vec3;
+use gravity_output_format::{self as format, BatchComment, BatchEncoder, Compression, Header};
+#[cfg(feature = "gpu")]
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
+};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+#[cfg(feature = "gpu")]
+use tracing::debug;
+use tracing::{info, trace_span, warn};
+#[cfg(target_arch = "wasm32")]
+use web_time::Instant;
+
+use super::ParticleSet;
+#[cfg(feature = "gpu")]
+use super::adapter::AdapterSummary;
+#[cfg(feature = "gpu")]
+use super::autotune;
+use super::backend::{self, ForceBackend, MAX_DEVICE_RESETS};
+use super::checkpoint::{CHECKPOINT_FILE, Checkpoint, PREVIOUS_CHECKPOINT_FILE};
+use super::cli::{Cli, Command, RunArgs};
+use super::diagnostics::{
+    BindingEnergyLog, CorrelationLog, DensityProfileLog, DiagnosticsColumns, DiagnosticsLog,
+    DriftWatch, Energy, SpeedHistogramLog,
+};
+use super::error::Error;
+#[cfg(feature = "gpu")]
+use super::gpu::{GpuCompute, GpuParticle, GpuTimings, PendingSteps, STAGING_RING};
+use super::heartbeat::HeartbeatFile;
+use super::interrupt;
+#[cfg(feature = "live-stream")]
+use super::live::LiveStream;
+use super::logging;
+use super::manifest::Manifest;
+#[cfg(feature = "gpu")]
+use super::memory::MemoryEstimate;
+use super::memory::{self, CountingAllocator, HostMemoryPlan};
+use super::nonfinite::{self, DT_HISTORY, NonFiniteDump};
+use super::observer::{BatchReport, FrameObserver, FrameSink};
+use super::output::{self, OutputLimit};
+#[cfg(feature = "preview")]
+use super::preview;
+use super::progress::{self, ProgressTracker};
+use super::reload::{Reload, SettingsReloader};
+use super::resume::ResumePoint;
+use super::schedule::{self, Phase, ScheduleEntry};
+use super::settings::{Settings, init_particles, load_settings};
+#[cfg(feature = "status-server")]
+use super::status::StatusServer;
+use super::status::{self, StatusBoard};
+use super::summary::{self, RunSummary, Segment};
+use super::timings::{BatchTimings, TimingsLog};
+use super::tracked::TrackedLog;
+use super::tree;
+use super::writer::{self, BatchWriter};
+
+/// One run's settings, particles and the force backend they're stepped with. Frames are
+/// simulated in order from `frame`, each batch with the dt of the schedule at its first frame.
+pub struct Simulation {
+    settings: Settings,
+    /// `Settings::hash`, for the manifest and batch files
+    settings_hash: String,
+    /// CPU copy of the particle state. With `gpu_integration` the device buffer is the source of
+    /// truth and this is only brought up to date at the end of each batch.
+    particles: ParticleSet,
+    backend: Box<dyn ForceBackend>,
+    /// Next frame to simulate
+    frame: usize,
+    /// Frame and particles the last `run_batch` started from, for `rewind`
+    batch_start: (usize, ParticleSet),
+    /// Set to stop `run_batch` at the next frame boundary
+    interrupted: Arc<AtomicBool>,
+    /// Called once each batch is simulated
+    observers: Vec<Box<dyn FrameObserver>>,
+    /// The frame buffer `step` simulates into
+    step_frame: Vec<Vec<Vec3>>,
+    /// Where the time has gone since the last `take_timings`
+    timings: BatchTimings,
+    /// First frame and dt of the last few batches, for a non-finite dump
+    recent_dt: VecDeque<(usize, f32)>,
+}
+
+/// A run's state at the start of one frame, owned, with particles in id order
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub frame_index: usize,
+    /// Simulated time
+    pub time: f64,
+    pub positions: Vec<Vec3>,
+    pub velocities: Vec<Vec3>,
+    pub masses: Vec<f32>,
+}
+
+impl Simulation {
+    /// Starting from `particles` at frame 0, on the backend `settings` ask for. Fails when
+    /// force_backend is gpu and there's no usable device.
+    pub fn new(settings: Settings, particles: impl Into<ParticleSet>) -> Result<Simulation, Error> {
+        let backend = backend::create_backend(&settings)?;
+        Ok(Simulation::with_backend(settings, particles, backend))
+    }
+
+    /// `new` for callers that can't block while the device opens, eg. in the browser
+    pub async fn new_async(
+        settings: Settings,
+        particles: impl Into<ParticleSet>,
+    ) -> Result<Simulation, Error> {
+        let backend = backend::create_backend_async(&settings).await?;
+        let mut particles = particles.into();
+        if !particles.is_empty() {
+            let forces = backend.compute_forces_async(&particles).await;
+            particles.set_accelerations(&forces.force);
+        }
+        Ok(Simulation::primed(settings, particles, backend))
+    }
+
+    /// On `backend` instead, eg. a `GpuCompute` on a device shared with other simulations. A
+    /// backend that loses its device is replaced by the one `settings` ask for.
+    ///
+    /// The particles' accelerations are computed here with one force pass, whatever they came
+    /// with, so the first step starts from the forces at the initial positions.
+    pub fn with_backend(
+        settings: Settings,
+        particles: impl Into<ParticleSet>,
+        backend: Box<dyn ForceBackend>,
+    ) -> Simulation {
+        let mut particles = particles.into();
+        if !particles.is_empty() {
+            let forces = backend.compute_forces(&particles);
+            particles.set_accelerations(&forces.force);
+        }
+        Simulation::primed(settings, particles, backend)
+    }
+
+    /// `with_backend` once the particles' accelerations are the forces at their positions
+    fn primed(
+        settings: Settings,
+        particles: ParticleSet,
+        backend: Box<dyn ForceBackend>,
+    ) -> Simulation {
+        let simulation = Simulation {
+            settings_hash: settings.hash(),
+            settings,
+            particles,
+            backend,
+            frame: 0,
+            batch_start: (0, ParticleSet::default()),
+            interrupted: Arc::new(AtomicBool::new(false)),
+            observers: Vec::new(),
+            step_frame: Vec::new(),
+            timings: BatchTimings::default(),
+            recent_dt: VecDeque::with_capacity(DT_HISTORY),
+        };
+        // the force pass left a resident device holding the particles without their accelerations
+        #[cfg(feature = "gpu")]
+        if let Some(gpu) = simulation.backend.as_gpu()
+            && gpu.is_resident()
+        {
+            gpu.upload(&simulation.particles);
+        }
+        simulation
+    }
+
+    /// Continuing from `frame` instead, the particles being the state at its start
+    pub fn starting_at(self, frame: usize) -> Simulation {
+        Simulation { frame, ..self }
+    }
+
+    /// Stopping batches early once `interrupted` is set, see `run_batch`
+    pub fn interrupted_by(self, interrupted: Arc<AtomicBool>) -> Simulation {
+        Simulation {
+            interrupted,
+            ..self
+        }
+    }
+
+    /// Calling `observer` with every frame simulated from now on, after the ones registered
+    /// before it. See `FrameObserver` for when.
+    pub fn observed_by(mut self, observer: Box<dyn FrameObserver>) -> Simulation {
+        self.observers.push(observer);
+        self
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn settings_hash(&self) -> &str {
+        &self.settings_hash
+    }
+
+    pub fn backend(&self) -> &dyn ForceBackend {
+        &*self.backend
+    }
+
+    /// Next frame `step`, `run_frames` or `run_batch` simulates
+    pub fn frame_index(&self) -> usize {
+        self.frame
+    }
+
+    /// Simulated time at the start of `frame_index`
+    pub fn time(&self) -> f64 {
+        schedule::time_at(&self.settings, self.frame)
+    }
+
+    /// The particles at the start of `frame_index`, in their current order. A run integrating on
+    /// the GPU leaves their accelerations stale, the device keeps its own.
+    pub fn particles(&self) -> &ParticleSet {
+        &self.particles
+    }
+
+    /// A copy of the state at the start of `frame_index`, in particle id order. Particles built
+    /// by hand need ids from `Particle::with_id` for that.
+    pub fn snapshot(&self) -> Snapshot {
+        let order = output_order(&self.particles);
+        let gather = |values: &[Vec3]| order.iter().map(|&index| values[index as usize]).collect();
+        Snapshot {
+            frame_index: self.frame,
+            time: self.time(),
+            positions: gather(&self.particles.pos),
+            velocities: gather(&self.particles.vel),
+            masses: order
+                .iter()
+                .map(|&index| self.particles.mass[index as usize])
+                .collect(),
+        }
+    }
+
+    /// Simulate one frame.
+    ///
+    /// Every step is a batch of one: with gpu_integration the state stays on the device, but
+    /// each step waits for its readback before the next is submitted. `run_frames` keeps
+    /// submissions in flight instead.
+    pub fn step(&mut self) -> Result<(), Error> {
+        let mut frame = std::mem::take(&mut self.step_frame);
+        frame.resize(1, Vec::new());
+        frame[0].resize(self.particles.len(), Vec3::ZERO);
+        let result = self.run_batch(&mut frame).map(|_| ());
+        self.step_frame = frame;
+        result
+    }
+
+    /// `step` for callers that can't block while the forces are read back, eg. in the browser.
+    ///
+    /// The particles are integrated on the CPU whatever gpu_integration says, and the observers
+    /// aren't called. A lost device fails the step rather than being recreated.
+    pub async fn step_async(&mut self) -> Result<(), Error> {
+        let dt = Phase::at(&self.settings, self.frame).dt;
+        self.batch_start.0 = self.frame;
+        self.batch_start.1.clone_from(&self.particles);
+        self.particles.drift(self.settings.integrator, dt);
+        let forces = self.backend.compute_forces_async(&self.particles).await;
+        if let Some(e) = self.backend.failure() {
+            return Err(e);
+        }
+        if self.backend.is_lost() {
+            return Err(Error::DeviceLost {
+                attempts: 1,
+                batch: self.frame / self.settings.frames_per_file,
+            });
+        }
+        self.particles
+            .kick(&forces.force, self.settings.integrator, dt);
+        if self.recent_dt.len() == DT_HISTORY {
+            self.recent_dt.pop_front();
+        }
+        self.recent_dt.push_back((self.frame, dt));
+
+        let frame = std::slice::from_ref(&self.particles.pos);
+        if nonfinite::first_nonfinite(frame, &self.particles.vel).is_some() {
+            return Err(Error::NonFinite(Box::new(NonFiniteDump::new(
+                self.frame,
+                0,
+                schedule::time_at(&self.settings, self.frame) + dt as f64,
+                frame,
+                &self.batch_start.1,
+                &self.particles,
+                self.recent_dt.iter().copied().collect(),
+            ))));
+        }
+        // a resident device integrates from its own copy, which the kick just overtook
+        #[cfg(feature = "gpu")]
+        if let Some(gpu) = self.backend.as_gpu()
+            && gpu.is_resident()
+        {
+            gpu.upload(&self.particles);
+        }
+        self.frame += 1;
+        Ok(())
+    }
+
+    /// Simulate `frames` frames into `sink`, a batch at a time so each has its schedule's dt and
+    /// the GPU keeps working while the last batch's frames are read back. Returns how many were
+    /// simulated, fewer once interrupted.
+    pub fn run_frames(&mut self, frames: usize, sink: &mut dyn FrameSink) -> Result<usize, Error> {
+        let first_frame = self.frame;
+        while self.frame < first_frame + frames {
+            let batch_end =
+                (self.frame / self.settings.frames_per_file + 1) * self.settings.frames_per_file;
+            let count = batch_end.min(first_frame + frames) - self.frame;
+            let mut frame_list = sink.buffers(count, self.particles.len())?;
+            frame_list.truncate(count);
+            let start = self.frame;
+            self.run_batch(&mut frame_list)?;
+            frame_list.truncate(self.frame - start);
+            sink.record(start, frame_list)?;
+            if self.frame - start < count {
+                break;
+            }
+        }
+        Ok(self.frame - first_frame)
+    }
+
+    /// Simulate a frame per entry of `frame_list` and record the positions into it, in particle
+    /// vec order. Returns the energies recorded on the way, with `diagnostics`. Integrating on
+    /// the GPU, frames output_every drops are only recorded when an observer wants them, see
+    /// `FrameObserver::wants_positions`.
+    ///
+    /// If the GPU is lost partway through, the backend is recreated and the frames rerun from the
+    /// particle state they started with, up to MAX_DEVICE_RESETS times. Once interrupted it
+    /// stops at the next frame boundary, `frame` then says how far it got and the frames after
+    /// it are left as they were. The observers see the frames simulated once the batch is done.
+    ///
+    /// A batch that leaves any position or velocity non-finite fails with `Error::NonFinite`
+    /// instead, without the observers seeing it or `frame` moving on.
+    pub fn run_batch(&mut self, frame_list: &mut [Vec<Vec3>]) -> Result<Vec<Energy>, Error> {
+        let start = Instant::now();
+        let phase = Phase::at(&self.settings, self.frame);
+        let batch_num = self.frame / self.settings.frames_per_file;
+        CountingAllocator::uncounted(|| {
+            for observer in &mut self.observers {
+                observer.on_batch_start(batch_num, self.frame);
+                observer.on_batch_particles(&self.particles);
+            }
+        });
+        // copied into the last batch's buffer rather than a fresh one, at a million particles
+        // that's a quarter of the time
+        self.batch_start.0 = self.frame;
+        self.batch_start.1.clone_from(&self.particles);
+        let mut energies = Vec::new();
+        let mut simulated = 0;
+        for attempt in 1.. {
+            energies.clear();
+            simulated = match self.integrate_on_device(frame_list, &mut energies, phase.dt) {
+                Some(simulated) => simulated,
+                None => {
+                    let (simulated, timings) = integrate_on_cpu(
+                        &*self.backend,
+                        &mut self.particles,
+                        frame_list,
+                        &mut energies,
+                        &self.settings,
+                        phase.dt,
+                        &self.interrupted,
+                    );
+                    self.timings += timings;
+                    simulated
+                }
+            };
+
+            if let Some(e) = self.backend.failure() {
+                return Err(e);
+            }
+            if !self.backend.is_lost() {
+                break;
+            }
+            if attempt > MAX_DEVICE_RESETS {
+                return Err(Error::DeviceLost {
+                    attempts: attempt,
+                    batch: batch_num,
+                });
+            }
+            warn!(
+                "Recreating the force backend and retrying batch {} (attempt {}/{})",
+                batch_num, attempt, MAX_DEVICE_RESETS
+            );
+            self.particles.clone_from(&self.batch_start.1);
+            self.backend = backend::create_backend(&self.settings)?;
+        }
+        if self.recent_dt.len() == DT_HISTORY {
+            self.recent_dt.pop_front();
+        }
+        self.recent_dt.push_back((self.frame, phase.dt));
+        let frames = &frame_list[..simulated];
+        if let Some(offset) = nonfinite::first_nonfinite(frames, &self.particles.vel) {
+            let time = schedule::time_at(&self.settings, self.frame)
+                + (offset + 1) as f64 * phase.dt as f64;
+            return Err(Error::NonFinite(Box::new(NonFiniteDump::new(
+                self.frame,
+                offset,
+                time,
+                frames,
+                &self.batch_start.1,
+                &self.particles,
+                self.recent_dt.iter().copied().collect(),
+            ))));
+        }
+        let first_frame = self.frame;
+        self.frame += simulated;
+        // what the observers allocate isn't the batch's, eg. lagrangian_radii sorts a copy
+        CountingAllocator::uncounted(|| {
+            self.notify(
+                first_frame,
+                phase.dt,
+                &frame_list[..simulated],
+                &energies,
+                simulated == frame_list.len(),
+                start,
+            )
+        });
+        Ok(energies)
+    }
+
+    /// Step on the GPU with gpu_integration, and bring the CPU copy up to date at the end. None
+    /// when the backend doesn't integrate on its device.
+    #[cfg(feature = "gpu")]
+    fn integrate_on_device(
+        &mut self,
+        frame_list: &mut [Vec<Vec3>],
+        energies: &mut Vec<Energy>,
+        dt: f32,
+    ) -> Option<usize> {
+        let gpu = device_integrator(&*self.backend, &self.settings)?;
+        // set every attempt, a recreated device starts from the settings' dt
+        gpu.set_dt(dt);
+        let output_every = Phase::at(&self.settings, self.frame).output_every;
+        let wanted = |frame: usize| {
+            frame.is_multiple_of(output_every)
+                || self.observers.iter().any(|o| o.wants_positions(frame))
+        };
+        let (synced, simulated) = integrate_on_gpu(
+            gpu,
+            &mut self.particles,
+            frame_list,
+            self.frame,
+            wanted,
+            energies,
+            &self.interrupted,
+        );
+        // keeps the CPU copy current, so a lost device can resume from the last batch
+        let sync_start = Instant::now();
+        match frame_list.last() {
+            // the velocities came with the last readback. The accelerations go stale, the device
+            // keeps its own and they're recomputed whenever the CPU copy is uploaded.
+            Some(positions) if synced && !gpu.is_lost() => {
+                self.particles.pos.copy_from_slice(positions)
+            }
+            _ => sync_particles_from_gpu(gpu, &mut self.particles),
+        }
+        self.timings.readback += sync_start.elapsed().as_secs_f64();
+        Some(simulated)
+    }
+
+    /// The simulating half of where the time went since the last call. The device's own figures
+    /// come from its timestamp queries when it has them.
+    pub fn take_timings(&mut self) -> BatchTimings {
+        let timings = std::mem::take(&mut self.timings);
+        #[cfg(feature = "gpu")]
+        let timings = with_device_timings(timings, &*self.backend);
+        timings
+    }
+
+    /// Whether the particles are integrated on the GPU rather than the CPU
+    pub fn integrates_on_device(&self) -> bool {
+        #[cfg(feature = "gpu")]
+        return device_integrator(&*self.backend, &self.settings).is_some();
+        #[cfg(not(feature = "gpu"))]
+        false
+    }
+
+    /// Host memory the run's large buffers take, for this backend
+    pub fn memory_plan(&self) -> HostMemoryPlan {
+        let n = self.particles.len();
+        let plan = HostMemoryPlan::new(&self.settings, self.integrates_on_device());
+        if !self.integrates_on_device() {
+            return plan.with("force pass scratch", self.backend.scratch_bytes(n));
+        }
+        plan
+    }
+
+    #[cfg(not(feature = "gpu"))]
+    fn integrate_on_device(
+        &mut self,
+        _frame_list: &mut [Vec<Vec3>],
+        _energies: &mut Vec<Energy>,
+        _dt: f32,
+    ) -> Option<usize> {
+        None
+    }
+
+    /// Copy the particles to a device that keeps them resident, after they changed on the CPU
+    fn upload_to_device(&mut self) {
+        // a force pass uploads the particles it's given, so only integration needs this
+        #[cfg(feature = "gpu")]
+        if let Some(gpu) = device_integrator(&*self.backend, &self.settings)
+            && gpu.is_resident()
+        {
+            upload_with_accelerations(gpu, &mut self.particles);
+        }
+    }
+
+    /// Hand the frames a batch simulated from `first_frame` to the observers
+    fn notify(
+        &mut self,
+        first_frame: usize,
+        dt: f32,
+        frames: &[Vec<Vec3>],
+        energies: &[Energy],
+        complete: bool,
+        start: Instant,
+    ) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let first_time = schedule::time_at(&self.settings, first_frame);
+        let mut report = BatchReport {
+            batch: first_frame / self.settings.frames_per_file,
+            first_frame,
+            frames: frames.len(),
+            complete,
+            first_time,
+            dt,
+            energies,
+            particles: &self.particles,
+            potential: None,
+            elapsed: start.elapsed(),
+        };
+        // a force pass of its own, the integration's only keep their energy sums
+        let potential = self
+            .observers
+            .iter()
+            .any(|observer| observer.needs_potential(&report))
+            .then(|| {
+                let start = Instant::now();
+                let forces = self.backend.compute_forces(&self.particles);
+                self.timings.compute += start.elapsed().as_secs_f64();
+                forces.potential
+            });
+        report.potential = potential.as_deref();
+        for observer in &mut self.observers {
+            for (index, positions) in frames.iter().enumerate() {
+                // the CPU copy is only current after the last frame
+                let velocities =
+                    (index + 1 == frames.len()).then_some(self.particles.vel.as_slice());
+                let time = first_time + (index + 1) as f64 * dt as f64;
+                observer.on_frame(first_frame + index, time, positions, velocities);
+            }
+            observer.on_batch_complete(&report);
+        }
+    }
+
+    /// Back to where the last `run_batch` started, for a batch cut short to be run again whole
+    pub fn rewind(&mut self) {
+        self.frame = self.batch_start.0;
+        // the batch start is stale from here on, the next run_batch overwrites it
+        std::mem::swap(&mut self.particles, &mut self.batch_start.1);
+        self.upload_to_device();
+    }
+
+    /// Write a checkpoint of the state at `frame`, which has to be at the start of a batch, for
+    /// `resume` to carry on from
+    pub fn checkpoint(&self) -> Result<(), Error> {
+        let _span = trace_span!("checkpoint").entered();
+        Checkpoint::save(
+            &self.settings.out_path,
+            &self.settings_hash,
+            self.frame / self.settings.frames_per_file,
+            self.frame,
+            &self.particles,
+        )
+    }
+
+    /// Sort the particles into Morton order, and the device copy with them.
+    pub fn reorder(&mut self) {
+        sort_particles(&mut self.particles);
+        // a resident device was synced at the end of the last batch, so the sorted vec is current
+        self.upload_to_device();
+    }
+
+    /// Write only the frames whose number is a multiple of `output_every` from the current frame
+    /// on, until the schedule changes it again. For `Settings::hot_reload`, it goes in the
+    /// schedule so `Phase::at` has it.
+    pub fn set_output_every(&mut self, output_every: usize) {
+        let frame = self.frame;
+        let schedule = &mut self.settings.schedule;
+        let at = schedule.partition_point(|entry| entry.at_frame <= frame);
+        match schedule[..at].last_mut() {
+            Some(entry) if entry.at_frame == frame => entry.output_every = Some(output_every),
+            _ => schedule.insert(
+                at,
+                ScheduleEntry {
+                    at_frame: frame,
+                    dt: None,
+                    output_every: Some(output_every),
+                },
+            ),
+        }
+        self.settings_changed();
+    }
+
+    /// Log only the steps whose number is a multiple of `diagnostics_every` from now on
+    pub fn set_diagnostics_every(&mut self, diagnostics_every: usize) {
+        self.settings.diagnostics_every = diagnostics_every;
+        self.settings_changed();
+    }
+
+    fn settings_changed(&mut self) {
+        for observer in &mut self.observers {
+            observer.on_settings_changed(&self.settings);
+        }
+    }
+}
+
+/// Simulate what `cli` asks for, a new run or a `resume`, to the end. Settings come from the
+/// command line and the files it names. A run stopped early for max_wall_time_minutes,
+/// max_output_gb or Ctrl-C returns `Error::Stopped` with EXIT_WALL_TIME, EXIT_OUTPUT_LIMIT or
+/// EXIT_INTERRUPTED.
+pub fn run(cli: Cli) -> Result<(), Error> {
+    let run_args = cli.run_args();
+    // a resumed run brings its own settings and particles
+    let resume = match &cli.command {
+        Some(Command::Resume {
+            dir,
+            frames_total,
+            allow_changed,
+        }) => {
+            let changed = allow_changed.then(|| load_settings(run_args)).transpose()?;
+            Some(
+                ResumePoint::load(dir, *frames_total, changed).map_err(|reason| Error::Resume {
+                    dir: dir.clone(),
+                    reason,
+                })?,
+            )
+        }
+        _ => None,
+    };
+    let settings = match &resume {
+        Some(resume) => resume.settings.clone(),
+        None => load_settings(run_args)?,
+    };
+    cpu_pool(&settings)?.install(|| simulate(run_args, settings, resume))
+}
+
+/// The resumed run's particles, or new ones from the initial conditions
+fn initial_particles(
+    settings: &Settings,
+    resume: Option<ResumePoint>,
+) -> Result<ParticleSet, Error> {
+    let particles = match resume {
+        Some(resume) => resume.particles,
+        None => init_particles(settings)?,
+    };
+    info!("Done with particle init");
+    Ok(particles)
+}
+
+/// Simulate a batch of frames and write it out. An interrupted batch writes the frames it got
+/// through, a shorter file that `resume` runs again whole.
+fn process_frame_group(
+    simulation: &mut Simulation,
+    writer: &mut BatchWriter,
+    batch_num: usize,
+) -> Result<(), Error> {
+    #[cfg(feature = "gpu")]
+    if let Some(gpu) = simulation.backend().as_gpu() {
+        gpu.reload_shader();
+    }
+    let settings = simulation.settings();
+    let frames = settings.frames_per_file;
+    let mut batch_file = BatchFile {
+        writer,
+        batch_num,
+        // frames come back in particle vec order, which only differs from id order once sorted
+        order: (settings.reorder_interval > 0).then(|| output_order(simulation.particles())),
+        phase: Phase::at(settings, simulation.frame_index()),
+    };
+    simulation.run_frames(frames, &mut batch_file)?;
+    writer.record_timings(batch_num, simulation.take_timings());
+    Ok(())
+}
+
+/// One batch file's frames, simulated into the writer's buffers and sent off to be written
+struct BatchFile<'a> {
+    writer: &'a mut BatchWriter,
+    batch_num: usize,
+    order: Option<Vec<u32>>,
+    phase: Phase,
+}
+
+impl FrameSink for BatchFile<'_> {
+    fn buffers(&mut self, _frames: usize, _num_particles: usize) -> Result<Vec<Vec<Vec3>>, Error> {
+        self.writer.buffers()
+    }
+
+    fn record(&mut self, _first_frame: usize, frames: Vec<Vec<Vec3>>) -> Result<(), Error> {
+        if frames.is_empty() {
+            self.writer.reuse(frames);
+            return Ok(());
+        }
+        let order = self.order.take();
+        self.writer.send(self.batch_num, frames, order, self.phase)
+    }
+}
+
+/// The GPU `backend` integrates the particles on, with gpu_integration and a backend that can
+#[cfg(feature = "gpu")]
+fn device_integrator<'a>(
+    backend: &'a dyn ForceBackend,
+    settings: &Settings,
+) -> Option<&'a GpuCompute> {
+    backend
+        .as_gpu()
+        .filter(|gpu| settings.gpu_integration && gpu.integrates_on_device())
+}
+
+/// Forces and integration both on the GPU, only the recorded positions come back each frame,
+/// copied straight into `frame_list`, whose first entry is frame `first_frame`. Only the frames
+/// `wanted` says are read back, the rest are left as they were, except the last one, which the
+/// CPU copy is brought up to date from.
+///
+/// The last submission reads back velocities as well, into `particles.vel`. Returns whether it
+/// got that far, and how many frames were simulated, fewer than asked when `interrupted`.
+#[cfg(feature = "gpu")]
+fn integrate_on_gpu(
+    gpu: &GpuCompute,
+    particles: &mut ParticleSet,
+    frame_list: &mut [Vec<Vec3>],
+    first_frame: usize,
+    wanted: impl Fn(usize) -> bool,
+    energies: &mut Vec<Energy>,
+    interrupted: &AtomicBool,
+) -> (bool, usize) {
+    let total = frame_list.len();
+    // particle state lives on the device for the rest of the run after the first upload
+    if !gpu.is_resident() {
+        upload_with_accelerations(gpu, particles);
+    }
+    // keep the next submission running on the device while the previous one maps and copies
+    let mut pending: VecDeque<(PendingSteps, &mut [Vec<Vec3>])> = VecDeque::new();
+    let mut read_velocities = false;
+    let submissions = frame_list.len().div_ceil(gpu.steps_per_submit);
+    let mut submitted = 0;
+    for (index, frames) in frame_list.chunks_mut(gpu.steps_per_submit).enumerate() {
+        // the rest of the batch gets rerun on a new device
+        if gpu.is_lost() || interrupted.load(Ordering::Relaxed) {
+            break;
+        }
+        if pending.len() == STAGING_RING {
+            let (steps, frames) = pending.pop_front().unwrap();
+            read_velocities |= gpu.finish_steps(steps, frames, &mut particles.vel, energies);
+        }
+        // output only needs positions
+        let last = index + 1 == submissions;
+        let start = submitted;
+        let keep = |step: usize| start + step + 1 == total || wanted(first_frame + start + step);
+        submitted += frames.len();
+        pending.push_back((gpu.submit_steps(frames.len(), last, keep), frames));
+    }
+
+    // drain whatever is still in flight before the batch is written
+    while let Some((steps, frames)) = pending.pop_front() {
+        read_velocities |= gpu.finish_steps(steps, frames, &mut particles.vel, energies);
+    }
+    (read_velocities, submitted)
+}
+
+#[cfg(feature = "gpu")]
+fn print_gpu_timings(timings: &GpuTimings, has_timestamps: bool) {
+    if has_timestamps {
+        debug!(
+            "GPU per submission (ms): force {:.3}, integrate {:.3}, copy {}, map {:.3}",
+            timings.force_ms,
+            timings.integrate_ms,
+            timings
+                .copy_ms
+                .map_or("n/a".to_string(), |ms| format!("{:.3}", ms)),
+            timings.map_ms
+        );
+    } else {
+        debug!("GPU per submission (ms): map {:.3}", timings.map_ms);
+    }
+}
+
+/// `timings` with the device's since they were last taken
+#[cfg(feature = "gpu")]
+fn with_device_timings(mut timings: BatchTimings, backend: &dyn ForceBackend) -> BatchTimings {
+    let Some(gpu) = backend.as_gpu() else {
+        return timings;
+    };
+    let Some(gpu_timings) = gpu.take_timings() else {
+        return timings;
+    };
+    print_gpu_timings(&gpu_timings, gpu.has_timestamps());
+    // averages per submission, back to totals
+    let seconds = |ms: f64| ms * gpu_timings.samples as f64 / 1000.0;
+    timings.compute += if gpu.has_timestamps() {
+        seconds(gpu_timings.force_ms + gpu_timings.integrate_ms)
+    } else {
+        seconds(gpu_timings.wait_ms)
+    };
+    timings.readback += seconds(gpu_timings.map_ms - gpu_timings.wait_ms);
+    timings
+}
+
+/// Upload `particles` for integration on `gpu`, their accelerations computed there first. The
+/// CPU copy's go stale while the device integrates, so they can't be trusted.
+#[cfg(feature = "gpu")]
+fn upload_with_accelerations(gpu: &GpuCompute, particles: &mut ParticleSet) {
+    let forces = backend::drive(gpu.compute_forces_async(particles));
+    particles.set_accelerations(&forces.force);
+    gpu.upload(particles);
+}
+
+/// Bring the CPU particle vec up to date with the device state
+#[cfg(feature = "gpu")]
+fn sync_particles_from_gpu(gpu: &GpuCompute, particles: &mut ParticleSet) {
+    let state = backend::drive(gpu.download());
+    (&mut particles.pos, &mut particles.vel, &mut particles.acc)
+        .into_par_iter()
+        .zip(state.par_iter())
+        .for_each(|((pos, vel, acc), gpu)| {
+            *pos = Vec3::from_array(gpu.pos);
+            *vel = Vec3::from_array(gpu.vel);
+            *acc = Vec3::from_array(gpu.acc);
+        });
+}
+
+/// Forces from the active backend, integration on the CPU.
+///
+/// Each frame's positions are copied out while the backend computes the forces at them, which
+/// overlaps with the device for GPU backends. Returns how many frames were simulated, fewer than
+/// asked when `interrupted`, and how long the forces and integration took.
+fn integrate_on_cpu(
+    backend: &dyn ForceBackend,
+    particles: &mut ParticleSet,
+    frame_list: &mut [Vec<Vec3>],
+    energies: &mut Vec<Energy>,
+    settings: &Settings,
+    dt: f32,
+    interrupted: &AtomicBool,
+) -> (usize, BatchTimings) {
+    let mut timings = BatchTimings::default();
+    let frame_count = frame_list.len();
+    for (index, frame) in frame_list.iter_mut().enumerate() {
+        if backend.is_lost() || interrupted.load(Ordering::Relaxed) {
+            return (index, timings);
+        }
+
+        let start = Instant::now();
+        particles.drift(settings.integrator, dt);
+        timings.integrate += start.elapsed().as_secs_f64();
+
+        // the kick only changes velocities, so the frame's positions are already final
+        let start = Instant::now();
+        let (forces, ()) = backend::drive(futures::future::join(
+            backend.compute_forces_async(particles),
+            async { frame.copy_from_slice(&particles.pos) },
+        ));
+        timings.compute += start.elapsed().as_secs_f64();
+
+        let start = Instant::now();
+        particles.kick(&forces.force, settings.integrator, dt);
+        timings.integrate += start.elapsed().as_secs_f64();
+
+        if settings.diagnostics {
+            energies.push(Energy::from_particles(particles, &forces.potential));
+        }
+    }
+    (frame_count, timings)
+}
+
+/// Reorder `particles` so particles close in space are close in memory. Their ids come along.
+pub(crate) fn sort_particles(particles: &mut ParticleSet) {
+    let order = tree::morton_order(&particles.pos);
+    *particles = particles.select(&order);
+}
+
+/// Index in `particles` of the particle with each id, ie. where output slot `id` is read from.
+pub(crate) fn output_order(particles: &ParticleSet) -> Vec<u32> {
+    let mut order = vec![0; particles.len()];
+    for (index, &id) in particles.id.iter().enumerate() {
+        order[id as usize] = index as u32;
+    }
+    order
+}
+
+/// Thread pool for the run's parallel sections, sized by cpu_threads. Everything rayon does
+/// inside `install` runs on it rather than the global pool.
+fn cpu_pool(settings: &Settings) -> Result<rayon::ThreadPool, Error> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(settings.cpu_threads.unwrap_or(0))
+        .thread_name(|index| format!("cpu-{}", index))
+        .build()
+        .map_err(Error::Threads)?;
+    info!("CPU threads: {}", pool.current_num_threads());
+    Ok(pool)
+}
+
+/// Exit status when a run stops for max_wall_time_minutes, EX_TEMPFAIL from sysexits.h
+pub const EXIT_WALL_TIME: i32 = 75;
+/// Exit status when a run stops at max_output_gb, EX_CANTCREAT from sysexits.h
+pub const EXIT_OUTPUT_LIMIT: i32 = 73;
+/// Exit status when a run stops for Ctrl-C, 128 + SIGINT as a shell reports it
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// Simulate, for `run` and `resume`
+fn simulate(args: &RunArgs, settings: Settings, resume: Option<ResumePoint>) -> Result<(), Error> {
+    let run_start = Instant::now();
+    if settings.log_level.is_some() {
+        logging::set_level(settings.log_level);
+    }
+    logging::open_log_file(&settings.out_path);
+    if args.dry_run {
+        return dry_run(&settings, resume);
+    }
+    #[cfg(feature = "gpu")]
+    if args.bench_kernel {
+        return autotune::bench_kernel(&settings).map_err(Error::Command);
+    }
+    #[cfg(not(feature = "gpu"))]
+    if args.bench_kernel {
+        return Err(Error::Command(
+            "--bench-kernel needs a build with the gpu feature".to_string(),
+        ));
+    }
+    #[cfg(not(feature = "preview"))]
+    if args.preview {
+        return Err(Error::Command(
+            "--preview needs a build with the preview feature".to_string(),
+        ));
+    }
+    #[cfg(not(feature = "status-server"))]
+    if settings.status_address.is_some() {
+        return Err(Error::Command(
+            "status_address needs a build with the status-server feature".to_string(),
+        ));
+    }
+    #[cfg(not(feature = "live-stream"))]
+    if settings.stream_address.is_some() {
+        return Err(Error::Command(
+            "stream_address needs a build with the live-stream feature".to_string(),
+        ));
+    }
+
+    if resume.is_none() {
+        clear_previous_run(&settings.out_path, args.force)?;
+    }
+    let first_batch = resume.as_ref().map_or(0, |resume| resume.next_batch);
+    output::check_free_space(&settings, first_batch)?;
+
+    // before anything else, so the output always says what it was produced with even if the
+    // settings file is edited while this runs
+    settings.save_resolved()?;
+    info!("Settings hash: {}", settings.hash());
+
+    let interrupted = interrupt::install();
+    let particles = initial_particles(&settings, resume)?;
+    let mut simulation = Simulation::new(settings.clone(), particles)?
+        .starting_at(first_batch * settings.frames_per_file)
+        .interrupted_by(interrupted.clone());
+    #[cfg(feature = "preview")]
+    if args.preview {
+        simulation = preview::open(simulation, args.preview_every)?;
+    }
+
+    let plan = simulation.memory_plan();
+    for line in plan.describe().lines() {
+        info!("{}", line);
+    }
+    if let Some(available) = memory::available_ram()
+        && plan.total() > available
+    {
+        warn!(
+            "The run's buffers need {} of host memory, only {} is available",
+            memory::format_bytes(plan.total()),
+            memory::format_bytes(available)
+        );
+    }
+    // debug builds count allocations of a frame's size or more, which a batch integrated on the
+    // device shouldn't make per frame. Smaller ones are gzip's and the driver's.
+    let frame_bytes = settings.num_particles * std::mem::size_of::<Vec3>();
+    CountingAllocator::count_from(frame_bytes.max(1 << 20));
+
+    let mut manifest = Manifest::new(&settings);
+    manifest.backend = simulation.backend().name();
+    #[cfg(feature = "gpu")]
+    {
+        manifest.adapter = simulation
+            .backend()
+            .as_gpu()
+            .map(|gpu| AdapterSummary::from_info(&gpu.adapter_info));
+    }
+    if first_batch > 0
+        && let Ok(previous) = Manifest::load(&settings.out_path)
+    {
+        manifest.events = previous.events;
+        manifest.energy_drift = previous.energy_drift;
+        manifest.tracked_particles = previous.tracked_particles;
+    }
+    if manifest.tracked_particles.is_empty() && !settings.tracked_particles.is_empty() {
+        manifest.tracked_particles = settings
+            .tracked_particles
+            .select(settings.seed, simulation.particles().len());
+    }
+    manifest.save(&settings.out_path);
+
+    let energy_drift = Arc::new(Mutex::new(manifest.energy_drift));
+    if settings.diagnostics {
+        let columns = DiagnosticsColumns::from_settings(&settings);
+        let log = match first_batch {
+            0 => DiagnosticsLog::create(&settings.out_path, &columns)?,
+            _ => DiagnosticsLog::append_to(
+                &settings.out_path,
+                first_batch * settings.frames_per_file,
+                &columns,
+            )?,
+        }
+        .logging_every(settings.diagnostics_every)
+        .warning_below(settings.softening);
+        simulation = simulation
+            .observed_by(Box::new(log))
+            .observed_by(Box::new(DriftWatch::new(&settings, energy_drift.clone())));
+        if settings.speed_histogram_bins > 0 {
+            let histogram = match first_batch {
+                0 => SpeedHistogramLog::create(&settings)?,
+                _ => {
+                    SpeedHistogramLog::append_to(&settings, first_batch * settings.frames_per_file)?
+                }
+            };
+            simulation = simulation.observed_by(Box::new(histogram));
+        }
+        if settings.density_profile_bins > 0 {
+            let profile = match first_batch {
+                0 => DensityProfileLog::create(&settings)?,
+                _ => {
+                    DensityProfileLog::append_to(&settings, first_batch * settings.frames_per_file)?
+                }
+            };
+            simulation = simulation.observed_by(Box::new(profile));
+        }
+        if settings.binding_energy_bins > 0 {
+            let energies = match first_batch {
+                0 => BindingEnergyLog::create(&settings)?,
+                _ => {
+                    BindingEnergyLog::append_to(&settings, first_batch * settings.frames_per_file)?
+                }
+            };
+            simulation = simulation.observed_by(Box::new(energies));
+        }
+        if settings.correlation_pairs > 0 {
+            let correlation = match first_batch {
+                0 => CorrelationLog::create(&settings)?,
+                _ => CorrelationLog::append_to(&settings, first_batch * settings.frames_per_file)?,
+            };
+            simulation = simulation.observed_by(Box::new(correlation));
+        }
+    }
+    if !manifest.tracked_particles.is_empty() {
+        let ids = manifest.tracked_particles.clone();
+        let tracked = match first_batch {
+            0 => TrackedLog::create(&settings, ids)?,
+            _ => TrackedLog::append_to(&settings, first_batch * settings.frames_per_file, ids)?,
+        };
+        simulation = simulation.observed_by(Box::new(tracked));
+    }
+    simulation = simulation
+        .observed_by(Box::new(HeartbeatFile::start(
+            &settings,
+            first_batch * settings.frames_per_file,
+        )))
+        .observed_by(Box::new(ProgressTracker::new(
+            args.progress_format.sink(!args.no_progress),
+            &settings,
+            first_batch * settings.frames_per_file,
+        )));
+    // for status_address, updated between batches and served from a thread of its own
+    let status = settings
+        .status_address
+        .as_ref()
+        .map(|_| StatusBoard::new(&settings, first_batch * settings.frames_per_file));
+    #[cfg(feature = "status-server")]
+    let _status_server = match (&settings.status_address, &status) {
+        (Some(address), Some(status)) => Some(StatusServer::start(address, status.clone())?),
+        _ => None,
+    };
+    if let Some(status) = &status {
+        status.update(|s| s.energy_drift = manifest.energy_drift);
+        simulation = simulation
+            .observed_by(Box::new(ProgressTracker::new(
+                Box::new(status.clone()),
+                &settings,
+                first_batch * settings.frames_per_file,
+            )))
+            .observed_by(Box::new(status.clone()));
+    }
+    #[cfg(feature = "live-stream")]
+    if let Some(address) = &settings.stream_address {
+        simulation = simulation.observed_by(Box::new(LiveStream::start(address, &settings)?));
+    }
+
+    let num_batches = settings.frames_total / settings.frames_per_file;
+    // where an uninterrupted run would sort next, so a resumed one sorts at the same frames
+    let mut next_reorder = 0;
+    for batch in 0..first_batch {
+        let first_frame = batch * settings.frames_per_file;
+        if settings.reorder_interval > 0 && first_frame >= next_reorder {
+            next_reorder = first_frame + settings.reorder_interval;
+        }
+    }
+    let mut reloader = settings
+        .hot_reload
+        .then(|| SettingsReloader::new(&settings));
+    let mut slowest_batch = 0.0f64;
+    let mut timings = match first_batch {
+        0 => TimingsLog::create(&settings.out_path)?,
+        _ => TimingsLog::append_to(&settings.out_path, first_batch)?,
+    };
+    if let Some(status) = &status {
+        timings = timings.reporting_to(status.clone());
+    }
+    let mut writer = BatchWriter::spawn(&settings, OutputLimit::new(&settings, first_batch))?
+        .logging_timings(timings);
+    for batch in first_batch..num_batches {
+        let _span = trace_span!("batch", batch).entered();
+        if writer.check_output(&settings, &mut manifest, batch) {
+            writer.finish()?;
+            return Err(stop_early(
+                &simulation,
+                &mut manifest,
+                (run_start, first_batch * settings.frames_per_file),
+                batch,
+                "the output reached max_output_gb",
+                EXIT_OUTPUT_LIMIT,
+            ));
+        }
+        let first_frame = batch * settings.frames_per_file;
+        if let Some(reloader) = &mut reloader {
+            for (reload, source) in reloader.check() {
+                let event = format!(
+                    "frame {}: {} from {}",
+                    first_frame,
+                    reload,
+                    source.display()
+                );
+                info!("Reloaded {}", event);
+                manifest.events.push(event);
+                manifest.save(&settings.out_path);
+                match reload {
+                    Reload::OutputEvery(every) => simulation.set_output_every(every),
+                    Reload::DiagnosticsEvery(every) => simulation.set_diagnostics_every(every),
+                    Reload::CompressionLevel(level) => writer.set_compression_level(level),
+                    Reload::LogLevel(level) => logging::set_level(level),
+                }
+            }
+        }
+        for entry in settings
+            .schedule
+            .iter()
+            .filter(|entry| entry.at_frame == first_frame)
+        {
+            let event = format!("frame {}: {}", first_frame, entry.describe());
+            info!("Schedule: {}", event);
+            manifest.events.push(event);
+            manifest.save(&settings.out_path);
+        }
+        if settings.reorder_interval > 0 && first_frame >= next_reorder {
+            simulation.reorder();
+            next_reorder = first_frame + settings.reorder_interval;
+        }
+
+        let time_start = Instant::now();
+        let large_allocations = CountingAllocator::large_allocations();
+        if let Err(e) = process_frame_group(&mut simulation, &mut writer, batch) {
+            // only once write_retry has run out; batches already written are picked up again
+            match &e {
+                Error::Writer { batch, .. } => {
+                    manifest.status = format!("failed writing batch {}", batch);
+                    manifest.save(&settings.out_path);
+                    save_summary(
+                        &simulation,
+                        &manifest,
+                        (run_start, first_batch * settings.frames_per_file),
+                    );
+                    info!(
+                        "Continue with: gravity-output resume {}",
+                        settings.out_path.display()
+                    );
+                }
+                Error::NonFinite(dump) => {
+                    manifest.status =
+                        format!("failed with a non-finite state at frame {}", dump.frame);
+                    manifest.save(&settings.out_path);
+                    match dump.save(&settings.out_path) {
+                        Ok(path) => info!("Particles that went non-finite: {}", path.display()),
+                        Err(e) => warn!("{}", e),
+                    }
+                    save_summary(
+                        &simulation,
+                        &manifest,
+                        (run_start, first_batch * settings.frames_per_file),
+                    );
+                }
+                _ => {}
+            }
+            return Err(e);
+        }
+        let drift = *energy_drift.lock().unwrap();
+        if let Some(status) = &status {
+            status.update(|s| s.energy_drift = drift);
+        }
+        if drift != manifest.energy_drift {
+            manifest.energy_drift = drift;
+            manifest.save(&settings.out_path);
+        }
+        if interrupted.load(Ordering::Relaxed) {
+            writer.finish()?;
+            // the rest of a batch cut short isn't written, it's run again whole on resume
+            if simulation.frame_index() < (batch + 1) * settings.frames_per_file {
+                simulation.rewind();
+            }
+            return Err(stop_early(
+                &simulation,
+                &mut manifest,
+                (run_start, first_batch * settings.frames_per_file),
+                simulation.frame_index() / settings.frames_per_file,
+                "interrupted",
+                EXIT_INTERRUPTED,
+            ));
+        }
+        // the live stream copies the frames it sends
+        if simulation.integrates_on_device()
+            && batch > first_batch
+            && settings.stream_address.is_none()
+        {
+            let made = CountingAllocator::large_allocations() - large_allocations;
+            debug_assert!(
+                made < settings.frames_per_file,
+                "{} allocations of a frame or more in batch {}, something allocates per frame",
+                made,
+                batch
+            );
+        }
+        slowest_batch = slowest_batch.max(time_start.elapsed().as_secs_f64());
+        let next_batch = batch + 1;
+        if settings.checkpoint_every > 0
+            && next_batch.is_multiple_of(settings.checkpoint_every)
+            && next_batch < num_batches
+        {
+            // a checkpoint past a batch still being written would be of no use to resume
+            writer.flush()?;
+            if let Err(e) = simulation.checkpoint() {
+                warn!("{}", e);
+            }
+        }
+        if let Some(minutes) = settings.max_wall_time_minutes
+            && next_batch < num_batches
+            && run_start.elapsed().as_secs_f64() + settings.wall_time_margin * slowest_batch
+                > minutes * 60.0
+        {
+            writer.finish()?;
+            return Err(stop_early(
+                &simulation,
+                &mut manifest,
+                (run_start, first_batch * settings.frames_per_file),
+                next_batch,
+                "another batch could run past max_wall_time_minutes",
+                EXIT_WALL_TIME,
+            ));
+        }
+    }
+
+    writer.finish()?;
+    manifest.status = "complete".to_string();
+    manifest.save(&settings.out_path);
+    save_summary(
+        &simulation,
+        &manifest,
+        (run_start, first_batch * settings.frames_per_file),
+    );
+    if let Some(peak) = memory::peak_rss() {
+        info!(
+            "Peak host memory: {}, {} planned",
+            memory::format_bytes(peak),
+            memory::format_bytes(plan.total())
+        );
+    }
+    info!("Finished!");
+    Ok(())
+}
+
+/// A new run in `dir` over one that's there already would leave batch files of both. With
+/// `force` the old run's manifest, batch files and checkpoints are deleted instead.
+fn clear_previous_run(dir: &Path, force: bool) -> Result<(), Error> {
+    let manifest = dir.join("manifest.json");
+    if !manifest.exists() {
+        return Ok(());
+    }
+    if !force {
+        return Err(Error::OutputInUse(dir.to_path_buf()));
+    }
+    warn!("Deleting the run already in {}", dir.display());
+    let batches = format::list_batches(dir).unwrap_or_default();
+    let files = batches.into_iter().map(|(_, path)| path).chain(
+        [CHECKPOINT_FILE, PREVIOUS_CHECKPOINT_FILE]
+            .into_iter()
+            .map(|name| dir.join(name)),
+    );
+    for path in files.chain([manifest]) {
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(Error::io("delete", &path)(e));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Checkpoint before `next_batch`, for a run to be picked up later with `resume`, and return the
+/// error to exit with `status` on
+fn stop_early(
+    simulation: &Simulation,
+    manifest: &mut Manifest,
+    segment_start: (Instant, usize),
+    next_batch: usize,
+    reason: &'static str,
+    status: i32,
+) -> Error {
+    let settings = simulation.settings();
+    let next_frame = next_batch * settings.frames_per_file;
+    warn!("Stopping at frame {}, {}", next_frame, reason);
+    if let Err(e) = simulation.checkpoint() {
+        warn!("{}, resume will rebuild the state from the batch files", e);
+    }
+    manifest.status = format!("interrupted at frame {}", next_frame);
+    manifest.save(&settings.out_path);
+    save_summary(simulation, manifest, segment_start);
+    info!(
+        "Continue with: gravity-output resume {}",
+        settings.out_path.display()
+    );
+    Error::Stopped {
+        frame: next_frame,
+        reason,
+        status,
+    }
+}
+
+/// Add this run or resume, started at `segment_start`'s time and frame, to summary.json with
+/// the state it stopped in, and log the lot
+fn save_summary(simulation: &Simulation, manifest: &Manifest, segment_start: (Instant, usize)) {
+    let settings = simulation.settings();
+    let (start, first_frame) = segment_start;
+    // a resume of a run from before summaries were kept starts one
+    let mut summary = match first_frame {
+        0 => RunSummary::default(),
+        _ => RunSummary::load(&settings.out_path).unwrap_or_default(),
+    };
+    let particles = simulation.particles();
+    // the log's last row rather than a force pass of its own, which would hold up stopping
+    let bound_fraction = BindingEnergyLog::last_bound_fraction(&settings.out_path)
+        .filter(|(_, fraction)| fraction.is_finite());
+    summary.settings_hash = manifest.settings_hash.clone();
+    summary.frames_total = settings.frames_total;
+    summary.bytes_written = summary::bytes_by_kind(&settings.out_path);
+    summary.energy_drift = manifest.energy_drift;
+    summary.bound_fraction = bound_fraction.map(|(_, fraction)| fraction);
+    summary.bound_fraction_frame = bound_fraction.map(|(frame, _)| frame);
+    summary.escaped = Some(status::escaped_count(particles, settings.g_const));
+    summary.backend = manifest.backend.clone();
+    summary.adapter = manifest.adapter.clone();
+    summary.push_segment(Segment {
+        first_frame,
+        end_frame: simulation.frame_index(),
+        wall_seconds: start.elapsed().as_secs_f64(),
+        warnings: logging::warnings(),
+        status: manifest.status.clone(),
+    });
+    summary.save(&settings.out_path);
+    for line in summary.describe().lines() {
+        info!("{}", line);
+    }
+}
+
+/// `--dry-run`: check the settings and the backend, and estimate the memory, output size and
+/// run time from a short calibration run that writes nothing. Settings are already validated by
+/// the time they load.
+fn dry_run(settings: &Settings, resume: Option<ResumePoint>) -> Result<(), Error> {
+    print_memory_estimate(settings);
+
+    // generating the initial conditions isn't part of the per-frame time
+    let particles = initial_particles(settings, resume)?;
+    let mut simulation = Simulation::new(settings.clone(), particles)?;
+    println!("{}", simulation.memory_plan().describe());
+    let files = settings.frames_total / settings.frames_per_file;
+    let frames = files * settings.frames_per_file;
+    if frames < settings.frames_total {
+        warn!(
+            "frames_total {} isn't a multiple of frames_per_file {}, only {} frames \
+             will be simulated",
+            settings.frames_total, settings.frames_per_file, frames
+        );
+    }
+
+    let calibration_frames = CALIBRATION_FRAMES.min(frames);
+    println!("Calibrating with {} frames", calibration_frames);
+    let mut frame_list = vec![vec![Vec3::ZERO; settings.num_particles]; calibration_frames];
+    let start = Instant::now();
+    simulation.run_batch(&mut frame_list)?;
+    let simulate_time = start.elapsed().as_secs_f64() / calibration_frames as f64;
+
+    // compressed the same way write_frame_group does, to measure rather than guess the ratio
+    let start = Instant::now();
+    let header = Header {
+        num_frames: calibration_frames,
+        num_particles: settings.num_particles,
+    };
+    let mut encoder = BatchEncoder::new(
+        Vec::new(),
+        header,
+        &BatchComment::default(),
+        Compression::new(settings.compression_level),
+    )
+    .unwrap();
+    for frame in &frame_list {
+        encoder.write_frame(frame).unwrap();
+    }
+    let compressed = encoder.finish().unwrap().len() as f64;
+    let compress_time = start.elapsed().as_secs_f64() / calibration_frames as f64;
+    let frame_bytes = (settings.num_particles * std::mem::size_of::<Vec3>()) as f64;
+    let ratio = compressed / (frame_bytes * calibration_frames as f64);
+
+    let raw_total = (files * format::HEADER_SIZE) as f64 + frame_bytes * frames as f64;
+    println!(
+        "Output: {} files of {} frames, {} raw, about {} compressed ({:.0}% in calibration)",
+        files,
+        settings.frames_per_file,
+        memory::format_bytes(raw_total as u64),
+        memory::format_bytes((raw_total * ratio) as u64),
+        ratio * 100.0
+    );
+    println!(
+        "Frame buffers: {} in host memory",
+        memory::format_bytes(
+            (writer::batch_buffers(settings) * settings.frames_per_file) as u64
+                * frame_bytes as u64
+        )
+    );
+    // the writer thread compresses a batch while the next one is simulated
+    let total_seconds = simulate_time.max(compress_time) * frames as f64;
+    println!(
+        "Time: {:.1} ms per frame simulating, {:.1} ms compressing alongside, about {} in total",
+        simulate_time * 1000.0,
+        compress_time * 1000.0,
+        progress::format_duration(total_seconds)
+    );
+    println!("Dry run OK");
+    Ok(())
+}
+
+/// Frames `--dry-run` simulates to estimate the run time
+const CALIBRATION_FRAMES: usize = 10;
+
+/// GPU memory estimate from the settings alone, without creating a device
+#[cfg(feature = "gpu")]
+fn print_memory_estimate(settings: &Settings) {
+    if settings.force_backend == backend::ForceBackendKind::Cpu {
+        println!("force_backend is cpu, no GPU memory needed");
+        return;
+    }
+    // without a device only the configured buffer cap is known, its limits may lower
+    // steps_per_submit or split the particles into more chunks
+    let chunk_len = settings
+        .max_buffer_size
+        .map(|size| (size / std::mem::size_of::<GpuParticle>() as u64).max(1) as usize)
+        .unwrap_or(usize::MAX);
+    let mut steps_per_submit = settings.steps_per_submit.max(1);
+    let mut chunk_len = chunk_len;
+    if settings.out_of_core {
+        steps_per_submit = 1;
+        if let Some(budget) = settings.gpu_memory_budget {
+            chunk_len = chunk_len.min(MemoryEstimate::max_tile_len(
+                settings,
+                settings.num_particles,
+                1,
+                budget,
+            ));
+        }
+        println!(
+            "out_of_core: streaming in tiles of {}",
+            chunk_len.min(settings.num_particles)
+        );
+    }
+    let estimate = MemoryEstimate::new(
+        settings,
+        settings.num_particles,
+        steps_per_submit,
+        chunk_len,
+    );
+    println!("{}", estimate.describe());
+    let Some(budget) = settings.gpu_memory_budget else {
+        println!("no gpu_memory_budget set");
+        return;
+    };
+    let fits = if estimate.total() <= budget {
+        "fits"
+    } else {
+        "does not fit"
+    };
+    if settings.out_of_core {
+        // the tile shrinks with the budget, so any particle count works
+        println!(
+            "gpu_memory_budget {}: {}",
+            memory::format_bytes(budget),
+            fits
+        );
+    } else {
+        println!(
+            "gpu_memory_budget {}: {}, at most {} particles fit",
+            memory::format_bytes(budget),
+            fits,
+            MemoryEstimate::max_particles(settings, steps_per_submit, chunk_len, budget)
+        );
+    }
+}
+
+#[cfg(not(feature = "gpu"))]
+fn print_memory_estimate(_settings: &Settings) {
+    println!("built without the gpu feature, no GPU memory needed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Particle;
+    use crate::backend::ForceBackendKind;
+    use crate::settings::Integrator;
+    use clap::Parser;
+
+    fn cpu_simulation(g_const: f32) -> Simulation {
+        let settings = Settings {
+            num_particles: 3,
+            frames_total: 10,
+            frames_per_file: 5,
+            g_const,
+            force_backend: ForceBackendKind::Cpu,
+            ..Settings::default()
+        };
+        let particles: ParticleSet = (0..3)
+            .map(|i| {
+                let t = i as f32;
+                let pos = Vec3::new(t, t * t, 0.0);
+                Particle::new(1.0, pos, Vec3::new(0.0, 1.0, t), Vec3::ZERO).with_id(i)
+            })
+            .collect();
+        Simulation::new(settings, particles).unwrap()
+    }
+
+    fn positions(simulation: &Simulation) -> Vec<Vec3> {
+        simulation.particles().pos.clone()
+    }
+
+    #[test]
+    fn simulations_keep_their_own_settings() {
+        let mut free = cpu_simulation(0.0);
+        let mut bound = cpu_simulation(1.0);
+        let start = positions(&free);
+        free.step().unwrap();
+        bound.step().unwrap();
+        assert_eq!(free.frame_index(), 1);
+
+        // without gravity an euler step only drifts
+        let dt = free.settings().dt;
+        for (particle, start) in free.particles().iter().zip(&start) {
+            assert_eq!(particle.pos(), *start + particle.vel() * dt);
+        }
+        assert_ne!(positions(&bound), positions(&free));
+    }
+
+    #[test]
+    fn steps_add_up_to_a_batch() {
+        let mut stepped = cpu_simulation(1.0);
+        let mut batched = cpu_simulation(1.0);
+        for _ in 0..5 {
+            stepped.step().unwrap();
+        }
+        let mut frame_list = vec![vec![Vec3::ZERO; 3]; 5];
+        batched.run_batch(&mut frame_list).unwrap();
+
+        assert_eq!(batched.frame_index(), stepped.frame_index());
+        assert_eq!(frame_list[4], positions(&batched));
+        for (a, b) in positions(&stepped).iter().zip(&positions(&batched)) {
+            assert!((*a - *b).length() <= 1e-5 * a.length(), "{} {}", a, b);
+        }
+    }
+
+    #[test]
+    fn async_steps_match_blocking_ones() {
+        let mut blocking = cpu_simulation(1.0);
+        let mut stepped = cpu_simulation(1.0);
+        for _ in 0..5 {
+            blocking.step().unwrap();
+            backend::drive(stepped.step_async()).unwrap();
+        }
+        assert_eq!(stepped.frame_index(), 5);
+        assert_eq!(positions(&stepped), positions(&blocking));
+        assert_eq!(stepped.particles().vel, blocking.particles().vel);
+    }
+
+    #[test]
+    fn run_frames_records_every_frame_across_batches() {
+        let mut simulation = cpu_simulation(1.0);
+        let mut frames = Vec::new();
+        // the first batch whole, then two frames into the next
+        assert_eq!(simulation.run_frames(7, &mut frames).unwrap(), 7);
+        assert_eq!(frames.len(), 7);
+        assert_eq!(simulation.frame_index(), 7);
+        assert_eq!(frames[6], positions(&simulation));
+        let dt = simulation.settings().dt as f64;
+        assert!((simulation.time() - 7.0 * dt).abs() < 1e-9);
+
+        let mut batched = cpu_simulation(1.0);
+        let mut frame_list = vec![vec![Vec3::ZERO; 3]; 5];
+        batched.run_batch(&mut frame_list).unwrap();
+        assert_eq!(frames[..5], frame_list);
+    }
+
+    #[test]
+    fn snapshots_are_in_id_order() {
+        let mut simulation = cpu_simulation(1.0);
+        let start = simulation.snapshot();
+        simulation.particles = simulation.particles.select(&[2, 0, 1]);
+        let shuffled = simulation.snapshot();
+        assert_eq!(shuffled.positions, start.positions);
+        assert_eq!(shuffled.velocities, start.velocities);
+        assert_eq!(shuffled.masses, [1.0; 3]);
+        assert_ne!(shuffled.positions, simulation.particles().pos);
+
+        simulation.step().unwrap();
+        let stepped = simulation.snapshot();
+        assert_eq!(stepped.frame_index, 1);
+        assert_ne!(stepped.positions, start.positions);
+    }
+
+    #[test]
+    fn interrupted_batches_stop_and_rewind() {
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let mut simulation = cpu_simulation(1.0).interrupted_by(interrupted.clone());
+        let start = positions(&simulation);
+        let mut frame_list = vec![vec![Vec3::ZERO; 3]; 5];
+        simulation.run_batch(&mut frame_list).unwrap();
+        assert_eq!(simulation.frame_index(), 5);
+
+        interrupted.store(true, Ordering::Relaxed);
+        let mut frame_list = vec![vec![Vec3::ZERO; 3]; 5];
+        simulation.run_batch(&mut frame_list).unwrap();
+        assert_eq!(simulation.frame_index(), 5);
+        assert!(frame_list.iter().flatten().all(|pos| *pos == Vec3::ZERO));
+
+        let after_first = positions(&simulation);
+        simulation.rewind();
+        assert_eq!(simulation.frame_index(), 5);
+        assert_eq!(positions(&simulation), after_first);
+        assert_ne!(after_first, start);
+    }
+
+    #[test]
+    fn resuming_from_a_checkpoint_matches_the_uninterrupted_run() {
+        let dir = std::env::temp_dir().join("gravity-output-exact-resume");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let settings = Settings {
+            num_particles: 16,
+            frames_total: 20,
+            frames_per_file: 5,
+            integrator: Integrator::Verlet,
+            force_backend: ForceBackendKind::Cpu,
+            reorder_interval: 15,
+            checkpoint_every: 2,
+            deterministic: true,
+            out_path: dir.clone(),
+            ..Settings::default()
+        };
+        let cli = Cli::try_parse_from(["gravity-output"]).unwrap();
+        let pool = cpu_pool(&settings).unwrap();
+        pool.install(|| simulate(cli.run_args(), settings.clone(), None))
+            .unwrap();
+        let batch = |num| dir.join(format::batch_file_name(num, None));
+        let read_batches = || -> Vec<Vec<u8>> {
+            (0..4)
+                .map(|num| std::fs::read(batch(num)).unwrap())
+                .collect()
+        };
+        let uninterrupted = read_batches();
+
+        // as if killed writing batch 3, two batches after the checkpoint
+        let written = std::fs::read(batch(3)).unwrap();
+        std::fs::write(batch(3), &written[..written.len() / 2]).unwrap();
+        let resume = ResumePoint::load(&dir, None, None).unwrap();
+        assert_eq!(resume.next_batch, 2);
+        pool.install(|| simulate(cli.run_args(), resume.settings.clone(), Some(resume)))
+            .unwrap();
+
+        let resumed = read_batches();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(resumed == uninterrupted, "resumed batches differ");
+    }
+
+    #[test]
+    fn a_run_at_max_output_gb_stops_with_its_exit_status() {
+        let dir = std::env::temp_dir().join("gravity-output-stop-early");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let settings = Settings {
+            num_particles: 16,
+            frames_total: 20,
+            frames_per_file: 5,
+            force_backend: ForceBackendKind::Cpu,
+            // less than one batch file
+            max_output_gb: Some(1e-7),
+            out_path: dir.clone(),
+            ..Settings::default()
+        };
+        let cli = Cli::try_parse_from(["gravity-output"]).unwrap();
+        let result = cpu_pool(&settings)
+            .unwrap()
+            .install(|| simulate(cli.run_args(), settings, None));
+        let manifest = std::fs::read_to_string(dir.join("manifest.json")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        // the writer is a batch behind, so the limit can show a batch late
+        match result {
+            Err(Error::Stopped { frame, status, .. }) => {
+                assert_eq!(status, EXIT_OUTPUT_LIMIT);
+                assert!(frame == 5 || frame == 10, "stopped at frame {}", frame);
+                let stopped = format!("interrupted at frame {}", frame);
+                assert!(manifest.contains(&stopped), "{}", manifest);
+            }
+            _ => panic!("expected the run to stop"),
+        }
+    }
+
+    #[test]
+    fn a_previous_run_needs_force_to_be_replaced() {
+        let dir = std::env::temp_dir().join("gravity-output-previous-run");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        clear_previous_run(&dir, false).unwrap();
+
+        let batch = dir.join(format::batch_file_name(0, None));
+        std::fs::write(dir.join("manifest.json"), "{}").unwrap();
+        std::fs::write(&batch, []).unwrap();
+        std::fs::write(dir.join(CHECKPOINT_FILE), []).unwrap();
+        assert!(matches!(
+            clear_previous_run(&dir, false),
+            Err(Error::OutputInUse(_))
+        ));
+        assert!(batch.exists());
+
+        clear_previous_run(&dir, true).unwrap();
+        let left = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(left, 0);
+    }
+
+    #[test]
+    fn deterministic_runs_match_on_any_thread_count() {
+        let cli = Cli::try_parse_from(["gravity-output"]).unwrap();
+        // one and three threads split the force and energy sums differently
+        let runs: Vec<(Vec<u32>, String)> = [1, 3]
+            .into_iter()
+            .map(|threads| {
+                let dir =
+                    std::env::temp_dir().join(format!("gravity-output-deterministic-{}", threads));
+                let _ = std::fs::remove_dir_all(&dir);
+                std::fs::create_dir_all(&dir).unwrap();
+                let settings = Settings {
+                    num_particles: 64,
+                    frames_total: 10,
+                    frames_per_file: 5,
+                    force_backend: ForceBackendKind::Cpu,
+                    deterministic: true,
+                    seed: Some(7),
+                    cpu_threads: Some(threads),
+                    diagnostics: true,
+                    out_path: dir.clone(),
+                    ..Settings::default()
+                };
+                cpu_pool(&settings)
+                    .unwrap()
+                    .install(|| simulate(cli.run_args(), settings, None))
+                    .unwrap();
+                // the files' headers differ by the settings hash, which includes cpu_threads
+                let bits = (0..2)
+                    .flat_map(|num| {
+                        let path = dir.join(format::batch_file_name(num, None));
+                        format::read_batch(&path).unwrap().frames
+                    })
+                    .flatten()
+                    .flat_map(|pos| pos.to_array().map(f32::to_bits))
+                    .collect();
+                let diagnostics = std::fs::read_to_string(dir.join("diagnostics.csv")).unwrap();
+                std::fs::remove_dir_all(&dir).unwrap();
+                (bits, diagnostics)
+            })
+            .collect();
+        assert!(runs[0] == runs[1], "deterministic runs differ");
+    }
+}
@@ -0,0 +1,258 @@
+use rayon::prelude::*;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::ParticleSet;
+use super::diagnostics::{self, EnergyDrift};
+use super::progress::{Progress, ProgressEvent, ProgressSink};
+use super::settings::Settings;
+use super::timings::BatchTimings;
+
+/// Where a run is at, as the status server serves it. Updated between batches.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct RunStatus {
+    pub settings_hash: String,
+    /// Frames done so far, moving a batch at a time
+    pub frame: usize,
+    pub frames_total: usize,
+    pub batches_done: usize,
+    pub batches_total: usize,
+    /// Seconds since the run started, as of `updated_at`
+    pub elapsed: f64,
+    pub eta_seconds: Option<f64>,
+    /// Seconds since the Unix epoch of the last update, for spotting a run that's stuck
+    pub updated_at: u64,
+    /// The last batch both simulated and written, and where its time went
+    pub timed_batch: Option<usize>,
+    pub last_batch_timings: Option<BatchTimings>,
+    /// With `diagnostics`, as in the manifest
+    pub energy_drift: Option<EnergyDrift>,
+    /// Particles faster than the escape velocity of the whole set's mass from where they are,
+    /// as of the last batch
+    pub escaped: Option<usize>,
+}
+
+/// The `RunStatus` the run updates and the status server reads, each only holding the lock
+/// long enough to copy it
+#[derive(Clone)]
+pub struct StatusBoard {
+    status: Arc<Mutex<RunStatus>>,
+    pub(crate) g_const: f32,
+}
+
+impl StatusBoard {
+    /// For a run of `settings` starting at `first_frame`
+    pub fn new(settings: &Settings, first_frame: usize) -> StatusBoard {
+        let status = RunStatus {
+            settings_hash: settings.hash(),
+            frame: first_frame,
+            frames_total: settings.frames_total,
+            batches_done: first_frame / settings.frames_per_file,
+            batches_total: settings.frames_total / settings.frames_per_file,
+            elapsed: 0.0,
+            eta_seconds: None,
+            updated_at: unix_seconds(),
+            timed_batch: None,
+            last_batch_timings: None,
+            energy_drift: None,
+            escaped: None,
+        };
+        StatusBoard {
+            status: Arc::new(Mutex::new(status)),
+            g_const: settings.g_const,
+        }
+    }
+
+    pub fn update(&self, change: impl FnOnce(&mut RunStatus)) {
+        let mut status = self.status.lock().unwrap();
+        change(&mut status);
+        status.updated_at = unix_seconds();
+    }
+
+    #[cfg(feature = "status-server")]
+    pub fn get(&self) -> RunStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+/// The counters and ETA, from a `ProgressTracker` of its own
+impl ProgressSink for StatusBoard {
+    fn report(&mut self, _event: &ProgressEvent, progress: &Progress) {
+        self.update(|status| {
+            status.frame = progress.frames_done;
+            status.batches_done = progress.batches_done;
+            status.elapsed = progress.elapsed;
+            status.eta_seconds = progress.eta_seconds;
+        });
+    }
+}
+
+pub(crate) fn unix_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Particles moving away from the centre of mass faster than the escape velocity of the set's
+/// total mass at their distance from it. Only a monopole estimate, softening and the mass
+/// further out are ignored.
+pub fn escaped_count(particles: &ParticleSet, g_const: f32) -> usize {
+    let (Some(center), Some(center_vel)) = (
+        diagnostics::mass_weighted_mean(&particles.mass, &particles.pos),
+        diagnostics::mass_weighted_mean(&particles.mass, &particles.vel),
+    ) else {
+        return 0;
+    };
+    let total: f64 = particles.mass.iter().map(|&mass| mass as f64).sum();
+    let gm = g_const as f64 * total;
+    particles
+        .pos
+        .par_iter()
+        .zip(&particles.vel)
+        .filter(|(pos, vel)| {
+            let r = (pos.as_dvec3() - center).length();
+            let v_sq = (vel.as_dvec3() - center_vel).length_squared();
+            r > 0.0 && 0.5 * v_sq > gm / r
+        })
+        .count()
+}
+
+/// Serves the `StatusBoard` as JSON on GET /status, and 200 on /healthz while the run is alive
+#[cfg(feature = "status-server")]
+pub struct StatusServer {
+    server: Arc<tiny_http::Server>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+#[cfg(feature = "status-server")]
+impl StatusServer {
+    pub fn start(address: &str, board: StatusBoard) -> Result<StatusServer, super::Error> {
+        let server = tiny_http::Server::http(address).map_err(|e| {
+            super::Error::Command(format!("Could not serve the status on {}: {}", address, e))
+        })?;
+        let server = Arc::new(server);
+        let serving = server.clone();
+        let thread = std::thread::Builder::new()
+            .name("status".to_string())
+            .spawn(move || {
+                for request in serving.incoming_requests() {
+                    respond(request, &board);
+                }
+            })
+            .map_err(|e| {
+                super::Error::Command(format!("Could not start the status server: {}", e))
+            })?;
+        let server = StatusServer {
+            server,
+            thread: Some(thread),
+        };
+        if let Some(address) = server.address() {
+            tracing::info!("Status on http://{}/status", address);
+        }
+        Ok(server)
+    }
+
+    /// Where it's listening, with the port picked when the address asked for port 0
+    pub fn address(&self) -> Option<std::net::SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+}
+
+#[cfg(feature = "status-server")]
+fn respond(request: tiny_http::Request, board: &StatusBoard) {
+    use tiny_http::{Header, Method, Response};
+
+    let path = request.url().split('?').next().unwrap_or_default();
+    let response = match (request.method(), path) {
+        (Method::Get, "/healthz") => Response::from_string("ok"),
+        (Method::Get, "/" | "/status") => {
+            let json = serde_json::to_string_pretty(&board.get()).unwrap_or_default();
+            let header = Header::from_bytes("Content-Type", "application/json")
+                .expect("the content type header is valid");
+            Response::from_string(json).with_header(header)
+        }
+        _ => Response::from_string("not found").with_status_code(404),
+    };
+    // a client that went away doesn't matter to the run
+    let _ = request.respond(response);
+}
+
+#[cfg(feature = "status-server")]
+impl Drop for StatusServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Particle;
+    use glam::Vec3;
+
+    #[test]
+    fn only_particles_past_escape_velocity_count() {
+        let mut particles = ParticleSet::with_capacity(3);
+        // a heavy body at rest, one in a circular orbit and one flung well past escape
+        particles.push(Particle::new_zero().with_mass(1000.0));
+        particles.push(
+            Particle::new_zero()
+                .with_mass(1.0)
+                .with_pos(Vec3::X * 10.0)
+                .with_vel(Vec3::Y * 10.0),
+        );
+        particles.push(
+            Particle::new_zero()
+                .with_mass(1.0)
+                .with_pos(Vec3::X * -10.0)
+                .with_vel(Vec3::Y * -100.0),
+        );
+        assert_eq!(escaped_count(&particles, 1.0), 1);
+        assert_eq!(escaped_count(&ParticleSet::with_capacity(0), 1.0), 0);
+    }
+
+    #[cfg(feature = "status-server")]
+    #[test]
+    fn serves_the_status_and_healthz() {
+        use std::io::{Read, Write};
+
+        let settings = Settings {
+            frames_total: 40,
+            frames_per_file: 10,
+            ..Settings::default()
+        };
+        let board = StatusBoard::new(&settings, 20);
+        board.update(|status| status.escaped = Some(3));
+        let server = StatusServer::start("127.0.0.1:0", board).unwrap();
+        let address = server.address().unwrap();
+        let get = |path: &str| {
+            let mut stream = std::net::TcpStream::connect(address).unwrap();
+            write!(
+                stream,
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                path
+            )
+            .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let healthz = get("/healthz");
+        assert!(healthz.starts_with("HTTP/1.1 200"), "{}", healthz);
+        let status = get("/status");
+        let (_, body) = status.split_once("\r\n\r\n").unwrap();
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["frame"], 20);
+        assert_eq!(body["batches_done"], 2);
+        assert_eq!(body["batches_total"], 4);
+        assert_eq!(body["escaped"], 3);
+        assert_eq!(body["settings_hash"], settings.hash());
+        assert!(get("/nothing").starts_with("HTTP/1.1 404"));
+        drop(server);
+    }
+}
@@ -0,0 +1,167 @@
+use std::ops::Range;
+
+use super::ParticleSet;
+use super::backend::Forces;
+use super::gpu::{GpuCompute, GpuParticle, SimParams};
+use super::settings::Settings;
+
+/// Out-of-core direct summation, for particle sets that don't fit on the device.
+///
+/// Only one block of targets and one tile of sources are resident at a time. Each source tile
+/// is uploaded and dispatched in its own submission, so the next tile's upload can't overwrite
+/// one still being read, and the force buffer accumulates across those submissions.
+pub struct StreamingPass {
+    /// Particles per target block and per source tile
+    tile_len: usize,
+    target_buffer: wgpu::Buffer,
+    source_buffer: wgpu::Buffer,
+    force_buffer: wgpu::Buffer,
+    params_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    /// For the per-tile SimParams
+    settings: Settings,
+}
+
+impl StreamingPass {
+    /// Buffers for tiles of `tile_len`, bound through the direct pass's `layout` so the same
+    /// pipeline runs on them. `energy_partials` and `energy_sum` only fill out the layout.
+    pub fn new(
+        device: &wgpu::Device,
+        settings: &Settings,
+        layout: &wgpu::BindGroupLayout,
+        tile_len: usize,
+        energy_partials: &wgpu::Buffer,
+        energy_sum: &wgpu::Buffer,
+    ) -> StreamingPass {
+        let particle_buffer = |label| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: (tile_len * std::mem::size_of::<GpuParticle>()) as u64,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        };
+        let target_buffer = particle_buffer("Streamed Targets");
+        let source_buffer = particle_buffer("Streamed Sources");
+        let force_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Streamed Forces"),
+            size: (tile_len * 16) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        // the force pass never writes positions, but the layout has a slot for them
+        let position_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Streamed Positions"),
+            size: 16,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let params_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Streamed Params"),
+            size: std::mem::size_of::<SimParams>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Streaming Bind Group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: target_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: force_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: position_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: params_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: source_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: energy_partials.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: energy_sum.as_entire_binding(),
+                },
+            ],
+        });
+
+        StreamingPass {
+            tile_len,
+            target_buffer,
+            source_buffer,
+            force_buffer,
+            params_buffer,
+            bind_group,
+            settings: settings.clone(),
+        }
+    }
+
+    /// Force on and potential at every particle in `gpu.targets`, streaming `particles` through
+    /// the device one tile at a time.
+    pub async fn compute_forces(&self, gpu: &GpuCompute, particles: &ParticleSet) -> Forces {
+        let tiles = |range: Range<usize>| {
+            range
+                .clone()
+                .step_by(self.tile_len)
+                .map(move |start| start..(start + self.tile_len).min(range.end))
+        };
+
+        let mut forces = Vec::with_capacity(gpu.targets.len());
+        for block in tiles(gpu.targets.clone()) {
+            gpu.write_particles(&self.target_buffer, particles, block.clone());
+
+            for (index, tile) in tiles(0..particles.len()).enumerate() {
+                // the first tile overwrites whatever the previous block left in the forces, the
+                // rest add on
+                let params = SimParams::for_chunk(&self.settings, &block, &block, &tile, index > 0);
+                gpu.queue
+                    .write_buffer(&self.params_buffer, 0, bytemuck::bytes_of(&params));
+                gpu.write_particles(&self.source_buffer, particles, tile);
+
+                let mut encoder =
+                    gpu.device
+                        .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                            label: Some("Streaming Encoder"),
+                        });
+                gpu.encode_pass(
+                    &mut encoder,
+                    &gpu.pipelines.read().unwrap().compute,
+                    "Streaming Pass",
+                    &[(&self.bind_group, block.len())],
+                    None,
+                    None,
+                );
+                // submitted on its own so the next tile's writes land after this pass
+                gpu.queue.submit(Some(encoder.finish()));
+            }
+
+            let encoder = gpu
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Streaming Readback"),
+                });
+            let block_forces = gpu
+                .read_vec4s(encoder, &[(&self.force_buffer, block.len())])
+                .await;
+            if block_forces.len() != block.len() {
+                // device lost, read_vec4s already flagged it
+                return Forces::default();
+            }
+            forces.extend(block_forces);
+        }
+        Forces::from_vec4s(&forces)
+    }
+}
@@ -0,0 +1,228 @@
+use gravity_output_format as format;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::warn;
+
+use super::adapter::AdapterSummary;
+use super::checkpoint::{CHECKPOINT_FILE, PREVIOUS_CHECKPOINT_FILE};
+use super::diagnostics::EnergyDrift;
+use super::manifest::write_atomic;
+use super::memory;
+use super::progress::format_duration;
+
+/// Written to the output directory whenever a run stops, finished or not
+pub const SUMMARY_FILE: &str = "summary.json";
+
+/// How a run went, all its segments together: the first and every resume after it. Each
+/// segment adds itself to the summary the one before left.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RunSummary {
+    pub settings_hash: String,
+    /// The manifest's status as of the last segment
+    pub status: String,
+    /// Frames simulated and written so far, out of `frames_total`
+    pub frames_done: usize,
+    pub frames_total: usize,
+    /// Wall time of the segments added up
+    pub wall_seconds: f64,
+    /// `wall_seconds` over the frames the segments simulated, setup and writing included
+    pub seconds_per_frame: Option<f64>,
+    /// Bytes in the output directory by kind of file: "batches", "checkpoints", and the rest by
+    /// their extension
+    pub bytes_written: BTreeMap<String, u64>,
+    /// With diagnostics, as in the manifest
+    pub energy_drift: Option<EnergyDrift>,
+    /// Fraction of the mass with a negative specific energy at `bound_fraction_frame`, the
+    /// last row of binding_energy.csv. None without binding_energy_bins.
+    pub bound_fraction: Option<f64>,
+    pub bound_fraction_frame: Option<usize>,
+    /// Particles past escape velocity at the end, see `status::escaped_count`
+    pub escaped: Option<usize>,
+    /// Force backend and GPU adapter of the last segment
+    pub backend: String,
+    pub adapter: Option<AdapterSummary>,
+    /// Warnings and errors logged over all the segments
+    pub warnings: usize,
+    pub segments: Vec<Segment>,
+}
+
+/// One `run` or `resume`
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Segment {
+    /// Frames it simulated, from `first_frame` up to `end_frame`
+    pub first_frame: usize,
+    pub end_frame: usize,
+    pub wall_seconds: f64,
+    pub warnings: usize,
+    /// The manifest's status it stopped with
+    pub status: String,
+}
+
+impl RunSummary {
+    pub fn load(out_path: &Path) -> Result<RunSummary, String> {
+        let path = out_path.join(SUMMARY_FILE);
+        let json = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        serde_json::from_str(&json)
+            .map_err(|e| format!("Could not parse {}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, out_path: &Path) {
+        let path = out_path.join(SUMMARY_FILE);
+        match serde_json::to_string_pretty(self) {
+            Ok(json) => {
+                if let Err(e) = write_atomic(&path, json.as_bytes()) {
+                    warn!("Could not write {}: {}", path.display(), e);
+                }
+            }
+            Err(e) => warn!("Could not serialize the summary: {}", e),
+        }
+    }
+
+    /// Add `segment` to the ones before and bring the totals up to date
+    pub fn push_segment(&mut self, segment: Segment) {
+        self.frames_done = segment.end_frame;
+        self.status = segment.status.clone();
+        self.segments.push(segment);
+        self.wall_seconds = self.segments.iter().map(|s| s.wall_seconds).sum();
+        self.warnings = self.segments.iter().map(|s| s.warnings).sum();
+        let frames: usize = self
+            .segments
+            .iter()
+            .map(|s| s.end_frame.saturating_sub(s.first_frame))
+            .sum();
+        self.seconds_per_frame = (frames > 0).then(|| self.wall_seconds / frames as f64);
+    }
+
+    /// A few lines for the log
+    pub fn describe(&self) -> String {
+        let mut lines = vec![format!(
+            "Run {}: {} of {} frames in {} over {} segment(s)",
+            self.status,
+            self.frames_done,
+            self.frames_total,
+            format_duration(self.wall_seconds),
+            self.segments.len()
+        )];
+        if let Some(seconds) = self.seconds_per_frame {
+            lines.push(format!("  {:.4}s per frame", seconds));
+        }
+        let bytes: Vec<String> = self
+            .bytes_written
+            .iter()
+            .map(|(kind, &bytes)| format!("{} {}", kind, memory::format_bytes(bytes)))
+            .collect();
+        if !bytes.is_empty() {
+            lines.push(format!("  written: {}", bytes.join(", ")));
+        }
+        if let Some(drift) = &self.energy_drift {
+            lines.push(format!(
+                "  energy drift: {:.3e} at most, at frame {}",
+                drift.max, drift.max_frame
+            ));
+        }
+        if let (Some(fraction), Some(frame)) = (self.bound_fraction, self.bound_fraction_frame) {
+            lines.push(format!(
+                "  bound mass: {:.1}% at frame {}",
+                fraction * 100.0,
+                frame
+            ));
+        }
+        if let Some(escaped) = self.escaped {
+            lines.push(format!("  escaped particles: {}", escaped));
+        }
+        let adapter = self
+            .adapter
+            .as_ref()
+            .map_or(String::new(), |adapter| format!(" on {}", adapter.name));
+        lines.push(format!("  backend: {}{}", self.backend, adapter));
+        lines.push(format!("  warnings: {}", self.warnings));
+        lines.join("\n")
+    }
+}
+
+/// Sizes of the files in `dir` added up by kind, as in `RunSummary::bytes_written`
+pub fn bytes_by_kind(dir: &Path) -> BTreeMap<String, u64> {
+    let batches: Vec<_> = format::list_batches(dir)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(_, path)| path)
+        .collect();
+    let mut bytes = BTreeMap::new();
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return bytes;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let kind = if batches.contains(&path) {
+            "batches".to_string()
+        } else if name == CHECKPOINT_FILE || name == PREVIOUS_CHECKPOINT_FILE {
+            "checkpoints".to_string()
+        } else {
+            match path.extension() {
+                Some(extension) => extension.to_string_lossy().into_owned(),
+                None => "other".to_string(),
+            }
+        };
+        *bytes.entry(kind).or_insert(0) += metadata.len();
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments_add_up_and_files_are_counted_by_kind() {
+        let dir = std::env::temp_dir().join("gravity-output-summary");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(format::batch_file_name(0, None)), [0; 10]).unwrap();
+        std::fs::write(dir.join(format::batch_file_name(1, None)), [0; 5]).unwrap();
+        std::fs::write(dir.join(CHECKPOINT_FILE), [0; 7]).unwrap();
+        std::fs::write(dir.join("diagnostics.csv"), [0; 3]).unwrap();
+        std::fs::write(dir.join("timings.csv"), [0; 2]).unwrap();
+        assert_eq!(
+            bytes_by_kind(&dir).into_iter().collect::<Vec<_>>(),
+            [
+                ("batches".to_string(), 15),
+                ("checkpoints".to_string(), 7),
+                ("csv".to_string(), 5),
+            ]
+        );
+
+        let mut summary = RunSummary::default();
+        summary.push_segment(Segment {
+            first_frame: 0,
+            end_frame: 300,
+            wall_seconds: 4.0,
+            warnings: 1,
+            status: "interrupted at frame 300".to_string(),
+        });
+        summary.save(&dir);
+        // a resume picks it up and adds its own
+        let mut summary = RunSummary::load(&dir).unwrap();
+        summary.push_segment(Segment {
+            first_frame: 300,
+            end_frame: 500,
+            wall_seconds: 6.0,
+            warnings: 0,
+            status: "complete".to_string(),
+        });
+        assert_eq!(summary.segments.len(), 2);
+        assert_eq!((summary.frames_done, summary.warnings), (500, 1));
+        assert_eq!(summary.status, "complete");
+        assert_eq!(summary.wall_seconds, 10.0);
+        assert_eq!(summary.seconds_per_frame, Some(0.02));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
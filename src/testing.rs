@@ -0,0 +1,37 @@
+//! What the unit tests of the observers share
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use super::ParticleSet;
+use super::observer::BatchReport;
+
+/// An empty `gravity-output-<name>` in the temp directory, for a test to write its files to
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("gravity-output-{}", name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A complete batch of `frames` from `first_frame`, a half step each, ending with `particles`
+pub fn batch_report<'a>(
+    first_frame: usize,
+    frames: usize,
+    particles: &'a ParticleSet,
+    potential: Option<&'a [f32]>,
+) -> BatchReport<'a> {
+    let dt = 0.5;
+    BatchReport {
+        batch: first_frame / frames,
+        first_frame,
+        frames,
+        complete: true,
+        first_time: first_frame as f64 * dt as f64,
+        dt,
+        energies: &[],
+        particles,
+        potential,
+        elapsed: Duration::ZERO,
+    }
+}
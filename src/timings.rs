@@ -0,0 +1,170 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::{info, warn};
+
+use super::diagnostics::CsvLog;
+use super::error::Error;
+use super::status::StatusBoard;
+
+/// Where a batch's time went, in seconds. The simulation fills in the first three, the writer
+/// thread the last two.
+#[derive(Serialize, Clone, Copy, Default, PartialEq, Debug)]
+pub struct BatchTimings {
+    /// Force passes, and integration on the device with gpu_integration. GPU timestamps where
+    /// the device has them, otherwise wall clock waiting on the backend.
+    pub compute: f64,
+    /// Copying frames out of mapped staging buffers, and syncing the particles off the device
+    pub readback: f64,
+    /// Integrating on the CPU, without gpu_integration
+    pub integrate: f64,
+    /// Gathering frames into id order for the batch file
+    pub serialize: f64,
+    /// gzip and writing the file, retries included
+    pub compress_write: f64,
+}
+
+impl BatchTimings {
+    pub fn total(&self) -> f64 {
+        self.compute + self.readback + self.integrate + self.serialize + self.compress_write
+    }
+}
+
+impl std::ops::AddAssign for BatchTimings {
+    fn add_assign(&mut self, other: BatchTimings) {
+        self.compute += other.compute;
+        self.readback += other.readback;
+        self.integrate += other.integrate;
+        self.serialize += other.serialize;
+        self.compress_write += other.compress_write;
+    }
+}
+
+/// eg. "compute 1.204s, readback 0.031s, integrate 0.000s, serialize 0.012s, compress/write 0.4s"
+impl std::fmt::Display for BatchTimings {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "compute {:.3}s, readback {:.3}s, integrate {:.3}s, serialize {:.3}s, compress/write {:.3}s",
+            self.compute, self.readback, self.integrate, self.serialize, self.compress_write
+        )
+    }
+}
+
+/// `timings.csv` in the output directory, a row per batch once it's been simulated and written
+pub struct TimingsLog {
+    csv: CsvLog,
+    /// Batches only one of the two halves has come in for
+    pending: HashMap<usize, BatchTimings>,
+    /// Also told about each complete batch, with `status_address`
+    status: Option<StatusBoard>,
+}
+
+impl TimingsLog {
+    const FILE: &str = "timings.csv";
+    const HEADER: &str = "batch,compute,readback,integrate,serialize,compress_write,total";
+
+    pub fn create(out_path: &Path) -> Result<TimingsLog, Error> {
+        let csv = CsvLog::create(out_path, Self::FILE, Self::HEADER)?;
+        Ok(TimingsLog::new(csv))
+    }
+
+    /// Continue the log of a resumed run from `first_batch`, dropping the rows of batches it
+    /// runs again, or start one if there isn't one
+    pub fn append_to(out_path: &Path, first_batch: usize) -> Result<TimingsLog, Error> {
+        let csv = CsvLog::append_to(out_path, Self::FILE, Self::HEADER, first_batch)?;
+        Ok(TimingsLog::new(csv))
+    }
+
+    fn new(csv: CsvLog) -> TimingsLog {
+        TimingsLog {
+            csv,
+            pending: HashMap::new(),
+            status: None,
+        }
+    }
+
+    pub fn reporting_to(mut self, status: StatusBoard) -> TimingsLog {
+        self.status = Some(status);
+        self
+    }
+
+    /// Add one half of `batch`'s timings. Once both are in, the breakdown is logged and its row
+    /// appended.
+    pub fn record(&mut self, batch: usize, timings: BatchTimings) {
+        let Some(mut complete) = self.pending.remove(&batch) else {
+            self.pending.insert(batch, timings);
+            return;
+        };
+        complete += timings;
+        info!("Batch {} timings: {}", batch, complete);
+        if let Some(status) = &self.status {
+            status.update(|status| {
+                status.timed_batch = Some(batch);
+                status.last_batch_timings = Some(complete);
+            });
+        }
+        let row = self.csv.write_line(&format!(
+            "{},{},{},{},{},{},{}",
+            batch,
+            complete.compute,
+            complete.readback,
+            complete.integrate,
+            complete.serialize,
+            complete.compress_write,
+            complete.total()
+        ));
+        // the run doesn't need them, so a failed write only warns
+        if let Err(e) = row.and_then(|()| self.csv.flush()) {
+            warn!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_wait_for_both_halves_and_resume_drops_the_rerun_ones() {
+        let dir = std::env::temp_dir().join("gravity-output-timings-log");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let simulated = BatchTimings {
+            compute: 1.0,
+            integrate: 0.5,
+            ..BatchTimings::default()
+        };
+        let written = BatchTimings {
+            compress_write: 0.25,
+            ..BatchTimings::default()
+        };
+
+        let mut log = TimingsLog::create(&dir).unwrap();
+        log.record(0, simulated);
+        log.record(1, written);
+        log.record(0, written);
+        log.record(1, simulated);
+        // only simulated, eg. interrupted before it was written
+        log.record(2, simulated);
+        drop(log);
+        let rows = |dir: &Path| {
+            std::fs::read_to_string(dir.join("timings.csv"))
+                .unwrap()
+                .lines()
+                .skip(1)
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            rows(&dir),
+            ["0,1,0,0.5,0,0.25,1.75", "1,1,0,0.5,0,0.25,1.75"]
+        );
+
+        let mut log = TimingsLog::append_to(&dir, 1).unwrap();
+        log.record(1, simulated);
+        log.record(1, BatchTimings::default());
+        assert_eq!(rows(&dir), ["0,1,0,0.5,0,0.25,1.75", "1,1,0,0.5,0,0,1.5"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,245 @@
+use glam::Vec3;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::ParticleSet;
+use super::diagnostics::CsvLog;
+use super::error::Error;
+use super::observer::{BatchReport, FrameObserver};
+use super::settings::Settings;
+
+/// Particles whose every step goes to tracked.csv, on top of the batch files' frames
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrackedParticles {
+    /// Particle ids, ie. their place in the output frames
+    pub ids: Vec<u32>,
+    /// This many more picked at random with `seed`, or 0 without one
+    pub sample: usize,
+}
+
+impl TrackedParticles {
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty() && self.sample == 0
+    }
+
+    /// The ids tracked out of `count` particles, sorted. The same for the same settings, so a
+    /// resumed run tracks the ones it started with.
+    pub fn select(&self, seed: Option<u64>, count: usize) -> Vec<u32> {
+        let mut selected: Vec<u32> = self
+            .ids
+            .iter()
+            .copied()
+            .filter(|&id| (id as usize) < count)
+            .collect();
+        if selected.len() < self.ids.len() {
+            warn!(
+                "Not tracking the tracked_particles ids past the last particle, {}",
+                count.saturating_sub(1)
+            );
+        }
+        selected.sort_unstable();
+        selected.dedup();
+        let explicit = selected.clone();
+        let mut rng = StdRng::seed_from_u64(seed.unwrap_or(0));
+        let drawn = (explicit.len() + self.sample).min(count);
+        let sample = rand::seq::index::sample(&mut rng, count, drawn)
+            .into_iter()
+            .map(|id| id as u32)
+            .filter(|id| explicit.binary_search(id).is_err())
+            .take(self.sample);
+        selected.extend(sample);
+        selected.sort_unstable();
+        selected
+    }
+}
+
+/// `tracked.csv` in the output directory: the position of each tracked particle after every
+/// step, a row per particle per step in id order, and its velocity over the step. That's
+/// (position - previous position) / dt, the same every backend: the integrator's own velocity
+/// with euler, the one at the middle of the step with verlet. Only as precise as the f32
+/// positions, so a few digits for particles far out taking short steps.
+pub struct TrackedLog {
+    csv: CsvLog,
+    ids: Vec<u32>,
+    /// Where each of `ids` is in the particles of the batch being simulated
+    indices: Vec<usize>,
+    /// The tracked positions at the start of the batch
+    start: Vec<Vec3>,
+    /// The batch's frames so far, the tracked positions of each
+    pending: Vec<(usize, Vec<Vec3>)>,
+}
+
+impl TrackedLog {
+    const FILE: &str = "tracked.csv";
+    const HEADER: &str = "frame,time,id,x,y,z,vx,vy,vz";
+
+    pub fn create(settings: &Settings, ids: Vec<u32>) -> Result<TrackedLog, Error> {
+        let csv = CsvLog::create(&settings.out_path, Self::FILE, Self::HEADER)?;
+        Ok(TrackedLog::new(csv, ids))
+    }
+
+    /// Continue the log of a resumed run from `first_frame`, dropping the rows it runs again,
+    /// or start one if it didn't keep one
+    pub fn append_to(
+        settings: &Settings,
+        first_frame: usize,
+        ids: Vec<u32>,
+    ) -> Result<TrackedLog, Error> {
+        let csv = CsvLog::append_to(&settings.out_path, Self::FILE, Self::HEADER, first_frame)?;
+        Ok(TrackedLog::new(csv, ids))
+    }
+
+    fn new(csv: CsvLog, ids: Vec<u32>) -> TrackedLog {
+        TrackedLog {
+            csv,
+            indices: Vec::with_capacity(ids.len()),
+            start: Vec::with_capacity(ids.len()),
+            pending: Vec::new(),
+            ids,
+        }
+    }
+
+    fn tracked(&self, positions: &[Vec3]) -> Vec<Vec3> {
+        self.indices.iter().map(|&index| positions[index]).collect()
+    }
+
+    /// Append the rows of the frames of a batch of steps `dt` long, `first_time` being the
+    /// simulated time at its start, and flush
+    fn append(&mut self, first_time: f64, dt: f32) -> Result<(), Error> {
+        let mut previous = std::mem::take(&mut self.start);
+        for (offset, (frame, positions)) in
+            std::mem::take(&mut self.pending).into_iter().enumerate()
+        {
+            let time = first_time + (offset + 1) as f64 * dt as f64;
+            for ((id, pos), before) in self.ids.iter().zip(&positions).zip(&previous) {
+                let vel = (*pos - *before) / dt;
+                self.csv.write_line(&format!(
+                    "{},{},{},{},{},{},{},{},{}",
+                    frame, time, id, pos.x, pos.y, pos.z, vel.x, vel.y, vel.z
+                ))?;
+            }
+            previous = positions;
+        }
+        self.csv.flush()
+    }
+}
+
+/// tracked.csv as an observer, the tracked positions kept until the batch's dt is known
+impl FrameObserver for TrackedLog {
+    fn on_batch_particles(&mut self, particles: &ParticleSet) {
+        // the particles only get reordered between batches
+        self.indices.clear();
+        self.indices.resize(self.ids.len(), 0);
+        for (index, id) in particles.id.iter().enumerate() {
+            if let Ok(tracked) = self.ids.binary_search(id) {
+                self.indices[tracked] = index;
+            }
+        }
+        self.start = self.tracked(&particles.pos);
+        self.pending.clear();
+    }
+
+    fn on_frame(
+        &mut self,
+        frame_index: usize,
+        _time: f64,
+        positions: &[Vec3],
+        _velocities: Option<&[Vec3]>,
+    ) {
+        let tracked = self.tracked(positions);
+        self.pending.push((frame_index, tracked));
+    }
+
+    fn wants_positions(&self, _frame_index: usize) -> bool {
+        true
+    }
+
+    fn on_batch_complete(&mut self, batch: &BatchReport) {
+        // a batch cut short runs again on resume, its rows would be logged twice
+        if !batch.complete {
+            self.pending.clear();
+            return;
+        }
+        if let Err(e) = self.append(batch.first_time, batch.dt) {
+            warn!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Particle;
+    use std::time::Duration;
+
+    #[test]
+    fn selection_is_the_ids_and_a_seeded_sample_of_the_rest() {
+        let tracked = TrackedParticles {
+            ids: vec![7, 3, 3, 500],
+            sample: 5,
+        };
+        let selected = tracked.select(Some(2), 100);
+        assert_eq!(selected.len(), 7);
+        assert!(selected.is_sorted());
+        assert!(selected.contains(&3) && selected.contains(&7));
+        assert!(selected.iter().all(|&id| id < 100));
+        assert_eq!(selected, tracked.select(Some(2), 100));
+        assert_ne!(selected, tracked.select(Some(3), 100));
+        // not enough to go round
+        assert_eq!(tracked.select(None, 4), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn rows_follow_the_tracked_ids_through_a_reordered_batch() {
+        let dir = std::env::temp_dir().join("gravity-output-tracked");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let settings = Settings {
+            out_path: dir.clone(),
+            ..Settings::default()
+        };
+        // sorted so id 2 comes first
+        let particles: ParticleSet = [2, 0, 1]
+            .into_iter()
+            .map(|id| {
+                Particle::new_zero()
+                    .with_id(id)
+                    .with_pos(Vec3::X * id as f32)
+            })
+            .collect();
+        let mut log = TrackedLog::create(&settings, vec![0, 2]).unwrap();
+        log.on_batch_particles(&particles);
+        for frame in 4..6 {
+            // everything moves 0.5 along y a step
+            let offset = Vec3::Y * 0.5 * (frame - 3) as f32;
+            let positions: Vec<Vec3> = particles.pos.iter().map(|pos| *pos + offset).collect();
+            log.on_frame(frame, 0.0, &positions, None);
+        }
+        log.on_batch_complete(&BatchReport {
+            batch: 2,
+            first_frame: 4,
+            frames: 2,
+            complete: true,
+            first_time: 1.0,
+            dt: 0.25,
+            energies: &[],
+            particles: &particles,
+            potential: None,
+            elapsed: Duration::ZERO,
+        });
+        let content = std::fs::read_to_string(dir.join("tracked.csv")).unwrap();
+        assert_eq!(
+            content.lines().collect::<Vec<_>>(),
+            [
+                "frame,time,id,x,y,z,vx,vy,vz",
+                "4,1.25,0,0,0.5,0,0,2,0",
+                "4,1.25,2,2,0.5,0,0,2,0",
+                "5,1.5,0,0,1,0,0,2,0",
+                "5,1.5,2,2,1,0,0,2,0",
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
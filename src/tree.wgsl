@@ -0,0 +1,110 @@
+// Barnes-Hut traversal of the octree built by tree.rs, one invocation per body.
+
+// Mirrors TreeNode in tree.rs. Nodes are depth first: an internal node's first child is the next
+// node, and `next` skips past the whole subtree.
+struct Node {
+    com: vec3<f32>,
+    mass: f32,
+    center: vec3<f32>,
+    size: f32,
+    next: u32,
+    first_body: u32,
+    // zero for internal nodes
+    body_count: u32,
+    _padding: u32,
+}
+
+// Mirrors GpuTreeParams in tree.rs
+struct TreeParams {
+    g_const: f32,
+    softening_sq: f32,
+    theta_sq: f32,
+    num_bodies: u32,
+    num_nodes: u32,
+    // Always 1, see accumulate_pair in nbody.wgsl
+    one: f32,
+}
+
+// Morton sorted bodies (xyz = pos, w = mass), forces (xyz = force, w = potential) are written in
+// the same order
+@group(0) @binding(0) var<storage, read> bodies: array<vec4<f32>>;
+@group(0) @binding(1) var<storage, read> nodes: array<Node>;
+@group(0) @binding(2) var<storage, read_write> forces: array<vec4<f32>>;
+@group(0) @binding(3) var<uniform> params: TreeParams;
+
+// Set at pipeline creation from Settings::workgroup_size
+override WORKGROUP_SIZE: u32 = 64u;
+
+// Compensated (Kahan) summation of the force contributions
+override COMPENSATED: bool = false;
+
+// `AccVec` is prepended by TreePass, same as for nbody.wgsl
+
+// Same as flat_index in nbody.wgsl, for dispatches that wrap into y
+fn flat_index(workgroup_id: vec3<u32>, num_workgroups: vec3<u32>, local_id: vec3<u32>) -> u32 {
+    return (workgroup_id.y * num_workgroups.x + workgroup_id.x) * WORKGROUP_SIZE + local_id.x;
+}
+
+var<private> force: AccVec;
+var<private> compensation: AccVec;
+
+fn add_source(pos: vec3<f32>, mass: f32, source: vec4<f32>) {
+    let diff = source.xyz - pos;
+    let dist_sq = dot(diff, diff) + params.softening_sq;
+    let dist = sqrt(dist_sq);
+    let contribution = AccVec(vec4<f32>(
+        diff * (params.g_const * mass * source.w / (dist_sq * dist)),
+        -params.g_const * source.w / dist
+    ));
+
+    if (COMPENSATED) {
+        let y = contribution - compensation;
+        let t = (force + y) * params.one;
+        compensation = (t - force) - y;
+        force = t;
+    } else {
+        force += contribution;
+    }
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn main(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>
+) {
+    let idx = flat_index(workgroup_id, num_workgroups, local_id);
+    if (idx >= params.num_bodies) {
+        return;
+    }
+    let pos = bodies[idx].xyz;
+    let mass = bodies[idx].w;
+
+    force = AccVec(0.0);
+    compensation = AccVec(0.0);
+
+    var node_idx = 0u;
+    while (node_idx < params.num_nodes) {
+        let node = nodes[node_idx];
+        let to_com = node.com - pos;
+        // a cell holding the body itself always gets opened
+        let inside = all(abs(pos - node.center) <= vec3<f32>(0.5 * node.size));
+
+        if (!inside && node.size * node.size < params.theta_sq * dot(to_com, to_com)) {
+            add_source(pos, mass, vec4<f32>(node.com, node.mass));
+            node_idx = node.next;
+        } else if (node.body_count > 0u) {
+            for (var b = node.first_body; b < node.first_body + node.body_count; b++) {
+                // the body itself would add a softening-sized potential, and NaN without softening
+                if (b != idx) {
+                    add_source(pos, mass, bodies[b]);
+                }
+            }
+            node_idx = node.next;
+        } else {
+            node_idx += 1u;
+        }
+    }
+
+    forces[idx] = vec4<f32>(force);
+}
@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+
+// resolved units are part of the batch file format
+pub use gravity_output_format::units::{LengthUnit, MassUnit, TimeUnit, UnitSystem};
+
+/// Physical units the simulation runs in, a preset with any of length, mass and time set
+/// over it. The particles stay plain f32 in these units, only g_const depends on them.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Units {
+    pub preset: Option<UnitPreset>,
+    pub length: Option<LengthUnit>,
+    pub mass: Option<MassUnit>,
+    pub time: Option<TimeUnit>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum UnitPreset {
+    /// kpc, Msun, Myr
+    Galactic,
+    /// pc, Msun, Myr
+    Cluster,
+    /// au, Msun, yr
+    SolarSystem,
+    /// m, kg, s
+    Si,
+}
+
+impl Units {
+    /// The preset with the explicitly set units applied, or the ones neither gives
+    pub fn system(&self) -> Result<UnitSystem, String> {
+        let preset = self.preset.map(UnitPreset::system);
+        let length = self.length.or(preset.map(|preset| preset.length));
+        let mass = self.mass.or(preset.map(|preset| preset.mass));
+        let time = self.time.or(preset.map(|preset| preset.time));
+        match (length, mass, time) {
+            (Some(length), Some(mass), Some(time)) => Ok(UnitSystem { length, mass, time }),
+            _ => {
+                let missing: Vec<&str> = [
+                    ("length", length.is_none()),
+                    ("mass", mass.is_none()),
+                    ("time", time.is_none()),
+                ]
+                .into_iter()
+                .filter(|(_, missing)| *missing)
+                .map(|(name, _)| name)
+                .collect();
+                Err(format!(
+                    "units needs a preset or all of length, mass and time, {} missing",
+                    missing.join(", ")
+                ))
+            }
+        }
+    }
+}
+
+impl UnitPreset {
+    pub fn system(self) -> UnitSystem {
+        let (length, mass, time) = match self {
+            UnitPreset::Galactic => (LengthUnit::Kpc, MassUnit::Msun, TimeUnit::Myr),
+            UnitPreset::Cluster => (LengthUnit::Pc, MassUnit::Msun, TimeUnit::Myr),
+            UnitPreset::SolarSystem => (LengthUnit::Au, MassUnit::Msun, TimeUnit::Yr),
+            UnitPreset::Si => (LengthUnit::M, MassUnit::Kg, TimeUnit::S),
+        };
+        UnitSystem { length, mass, time }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gravity_output_format::units::G_SI;
+
+    fn relative_error(value: f64, expected: f64) -> f64 {
+        ((value - expected) / expected).abs()
+    }
+
+    #[test]
+    fn presets_give_the_known_values_of_g() {
+        let galactic = UnitPreset::Galactic.system().gravitational_constant();
+        assert!(relative_error(galactic, 4.4985e-12) < 1e-4, "{}", galactic);
+        // Kepler's third law: one au around one solar mass takes about a year
+        let solar_system = UnitPreset::SolarSystem.system().gravitational_constant();
+        let four_pi_squared = 4.0 * std::f64::consts::PI.powi(2);
+        assert!(
+            relative_error(solar_system, four_pi_squared) < 1e-3,
+            "{}",
+            solar_system
+        );
+        assert_eq!(UnitPreset::Si.system().gravitational_constant(), G_SI);
+    }
+
+    #[test]
+    fn units_override_the_preset_and_are_all_needed_without_one() {
+        let units = Units {
+            preset: Some(UnitPreset::Galactic),
+            length: Some(LengthUnit::Pc),
+            ..Units::default()
+        };
+        assert_eq!(units.system().unwrap().to_string(), "pc, Msun, Myr");
+
+        let units = Units {
+            mass: Some(MassUnit::Kg),
+            ..Units::default()
+        };
+        let error = units.system().unwrap_err();
+        assert!(error.ends_with("length, time missing"), "{}", error);
+    }
+}
@@ -0,0 +1,247 @@
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::backend::ForceBackendKind;
+use super::settings::{Settings, SettingsFormat};
+
+/// Steps per dynamical time of the initial sphere when the wizard picks dt. The default settings
+/// work out to about 520.
+const STEPS_PER_DYNAMICAL_TIME: f32 = 500.0;
+
+/// Frames per output file the wizard aims for
+const FRAMES_PER_FILE: usize = 100;
+
+/// Whether there's someone to answer: stdin and stdout are both terminals. Anything else, eg.
+/// a pipe, a batch job or CI, must never block waiting for input.
+pub fn is_interactive() -> bool {
+    std::io::stdin().is_terminal() && std::io::stdout().is_terminal()
+}
+
+/// `init`: ask for the settings that matter most and write the settings file
+pub fn init(format: SettingsFormat) -> Result<(), String> {
+    if !is_interactive() {
+        return Err("init asks its questions on a terminal, stdin or stdout isn't one".to_string());
+    }
+    let stdin = std::io::stdin();
+    let (mut input, mut output) = (stdin.lock(), std::io::stdout());
+    let path = Path::new(format.file_name());
+    if path.exists() {
+        let overwrite = ask(
+            &mut input,
+            &mut output,
+            &format!("{} exists, overwrite it?", path.display()),
+            YesNo(false),
+        )?;
+        if !overwrite.0 {
+            return Ok(());
+        }
+    }
+    let settings = ask_settings(&mut input, &mut output)?;
+    write_settings(format, &settings)
+}
+
+/// Run the wizard when there's no settings file and a terminal to ask on, returning the file
+/// it wrote. Declining or closing stdin leaves load_settings to create the defaults.
+pub fn first_run(format: SettingsFormat) -> Option<PathBuf> {
+    if !is_interactive() {
+        return None;
+    }
+    let stdin = std::io::stdin();
+    let (mut input, mut output) = (stdin.lock(), std::io::stdout());
+    let start = ask(
+        &mut input,
+        &mut output,
+        "No settings file found. Set one up now?",
+        YesNo(true),
+    );
+    if !matches!(start, Ok(YesNo(true))) {
+        return None;
+    }
+    let written = ask_settings(&mut input, &mut output)
+        .and_then(|settings| write_settings(format, &settings));
+    match written {
+        Ok(()) => Some(PathBuf::from(format.file_name())),
+        Err(e) => {
+            println!("Error: {}", e);
+            None
+        }
+    }
+}
+
+/// Ask for particle count, length of the run, output directory and GPU use, and derive dt and
+/// the frame counts from them
+fn ask_settings(input: &mut impl BufRead, output: &mut impl Write) -> Result<Settings, String> {
+    let defaults = Settings::default();
+    let mut settings = Settings {
+        num_particles: ask(input, output, "Number of particles", defaults.num_particles)?,
+        ..defaults
+    };
+
+    // the sphere's mass is spread over the particles, so dt has to follow the count
+    let dynamical_time = dynamical_time(&settings);
+    settings.dt = dynamical_time / STEPS_PER_DYNAMICAL_TIME;
+    writeln!(
+        output,
+        "dt = {} ({} steps per dynamical time of {})",
+        settings.dt, STEPS_PER_DYNAMICAL_TIME, dynamical_time
+    )
+    .map_err(|e| e.to_string())?;
+
+    let time: Optional<f32> = ask(
+        input,
+        output,
+        "Simulated time, empty to give a frame count instead",
+        Optional(None),
+    )?;
+    let frames = match time.0 {
+        Some(time) => (time / settings.dt).ceil().max(1.0) as usize,
+        None => ask(input, output, "Frames to simulate", defaults.frames_total)?,
+    };
+    // whole files only, a partial last batch wouldn't be written
+    settings.frames_per_file = FRAMES_PER_FILE.min(frames.max(1));
+    settings.frames_total = frames.max(1).next_multiple_of(settings.frames_per_file);
+    writeln!(
+        output,
+        "{} frames in files of {}",
+        settings.frames_total, settings.frames_per_file
+    )
+    .map_err(|e| e.to_string())?;
+
+    let out_path: String = ask(input, output, "Output directory", "output".to_string())?;
+    settings.out_path = PathBuf::from(out_path);
+    let gpu = ask(input, output, "Use the GPU?", YesNo(true))?;
+    settings.force_backend = match gpu.0 {
+        true => ForceBackendKind::Auto,
+        false => ForceBackendKind::Cpu,
+    };
+    Ok(settings)
+}
+
+/// Time for a particle to fall across the initial sphere, sqrt(R^3 / GM)
+fn dynamical_time(settings: &Settings) -> f32 {
+    let total_mass = settings.num_particles as f32 * settings.mass;
+    (settings.arena.powi(3) / (settings.g_const * total_mass)).sqrt()
+}
+
+fn write_settings(format: SettingsFormat, settings: &Settings) -> Result<(), String> {
+    let contents = format.serialize(settings)?;
+    std::fs::write(format.file_name(), contents)
+        .map_err(|e| format!("Could not write {}: {}", format.file_name(), e))?;
+    println!("Wrote {}", format.file_name());
+    Ok(())
+}
+
+/// Prompt until the answer parses, taking `default` for an empty line. Running out of input is
+/// an error rather than a silent default.
+fn ask<T>(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    question: &str,
+    default: T,
+) -> Result<T, String>
+where
+    T: FromStr + std::fmt::Display,
+    T::Err: std::fmt::Display,
+{
+    loop {
+        write!(output, "{} [{}]: ", question, default).map_err(|e| e.to_string())?;
+        output.flush().map_err(|e| e.to_string())?;
+        let mut line = String::new();
+        if input.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+            return Err("no answer, stdin closed".to_string());
+        }
+        let answer = line.trim();
+        if answer.is_empty() {
+            return Ok(default);
+        }
+        match answer.parse() {
+            Ok(value) => return Ok(value),
+            Err(e) => writeln!(output, "  {}", e).map_err(|e| e.to_string())?,
+        }
+    }
+}
+
+struct YesNo(bool);
+
+impl FromStr for YesNo {
+    type Err = String;
+
+    fn from_str(answer: &str) -> Result<YesNo, String> {
+        match answer.to_lowercase().as_str() {
+            "y" | "yes" => Ok(YesNo(true)),
+            "n" | "no" => Ok(YesNo(false)),
+            _ => Err("answer y or n".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for YesNo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(if self.0 { "Y/n" } else { "y/N" })
+    }
+}
+
+/// Answer that may be left empty
+struct Optional<T>(Option<T>);
+
+impl<T: FromStr> FromStr for Optional<T> {
+    type Err = T::Err;
+
+    fn from_str(answer: &str) -> Result<Optional<T>, T::Err> {
+        answer.parse().map(|value| Optional(Some(value)))
+    }
+}
+
+impl<T: std::fmt::Display> std::fmt::Display for Optional<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.0 {
+            Some(value) => value.fmt(f),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(lines: &str) -> (Result<Settings, String>, String) {
+        let mut output = Vec::new();
+        let settings = ask_settings(&mut lines.as_bytes(), &mut output);
+        (settings, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn time_is_turned_into_whole_files_of_frames() {
+        let (settings, _) = answer("2000\n10\nruns\nn\n");
+        let settings = settings.unwrap();
+        assert_eq!(settings.num_particles, 2000);
+        assert_eq!(
+            settings.dt,
+            dynamical_time(&settings) / STEPS_PER_DYNAMICAL_TIME
+        );
+        assert_eq!(settings.frames_total % settings.frames_per_file, 0);
+        assert!(settings.frames_total as f32 * settings.dt >= 10.0);
+        assert_eq!(settings.out_path, PathBuf::from("runs"));
+        assert_eq!(settings.force_backend, ForceBackendKind::Cpu);
+    }
+
+    #[test]
+    fn empty_answers_take_the_defaults_and_bad_ones_are_asked_again() {
+        let (settings, output) = answer("lots\n\n\n50\n\nmaybe\n\n");
+        let settings = settings.unwrap();
+        assert_eq!(settings.num_particles, Settings::default().num_particles);
+        assert_eq!(settings.frames_total, 50);
+        assert_eq!(settings.frames_per_file, 50);
+        assert_eq!(settings.out_path, PathBuf::from("output"));
+        assert_eq!(settings.force_backend, ForceBackendKind::Auto);
+        assert!(output.contains("invalid digit"), "{}", output);
+        assert!(output.contains("answer y or n"), "{}", output);
+    }
+
+    #[test]
+    fn closed_stdin_is_an_error() {
+        assert!(answer("2000\n").0.is_err());
+    }
+}
@@ -0,0 +1,332 @@
+use glam::Vec3;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::JoinHandle;
+use std::time::Instant;
+use tracing::{debug, info, trace_span, warn};
+
+use super::error::Error;
+use super::manifest::Manifest;
+use super::memory;
+use super::output::{OutputLimit, write_frame_group};
+use super::schedule::Phase;
+use super::settings::Settings;
+use super::timings::{BatchTimings, TimingsLog};
+
+/// Batches worth of frame buffers the writer goes round: two to simulate a batch while the one
+/// before is written, see `Settings::write_overlap`, otherwise one
+pub fn batch_buffers(settings: &Settings) -> usize {
+    let batch_bytes =
+        (settings.frames_per_file * settings.num_particles * size_of::<Vec3>()) as u64;
+    let overlap = settings.write_overlap.unwrap_or_else(|| {
+        memory::available_ram().is_none_or(|available| 2 * batch_bytes <= available)
+    });
+    if overlap { 2 } else { 1 }
+}
+
+/// A simulated batch on its way to the writer thread
+struct Batch {
+    num: usize,
+    frames: Vec<Vec<Vec3>>,
+    /// From `output_order`, when the particles have been sorted
+    order: Option<Vec<u32>>,
+    phase: Phase,
+    /// Compression and decimation in effect when the batch was simulated
+    output: OutputLimit,
+}
+
+/// Writes the batch files on a thread of its own while the next batch is simulated, or before
+/// it without `batch_buffers` for both.
+///
+/// Frame buffers go round between the two. `send` only hands a batch over once the one before it
+/// is written, so a slow disk holds the simulation back instead of frames piling up in memory.
+pub struct BatchWriter {
+    batches: Option<SyncSender<Batch>>,
+    /// Buffers of the batches written, with the batch, the size of the file and how long it took
+    written: Receiver<Written>,
+    thread: Option<JoinHandle<Result<(), Error>>>,
+    free: Vec<Vec<Vec<Vec3>>>,
+    in_flight: usize,
+    output: OutputLimit,
+    frames_per_file: usize,
+    num_particles: usize,
+    /// Gets the writing half of each batch's timings, and the simulating half from
+    /// `record_timings`
+    timings: Option<TimingsLog>,
+}
+
+/// A batch the writer thread is done with
+struct Written {
+    frames: Vec<Vec<Vec3>>,
+    batch: usize,
+    bytes: u64,
+    timings: BatchTimings,
+}
+
+impl BatchWriter {
+    /// Counting `output` as written so far
+    pub fn spawn(settings: &Settings, output: OutputLimit) -> Result<BatchWriter, Error> {
+        // a rendezvous, the writer takes the next batch once it's done with the last
+        let (batches, to_write) = mpsc::sync_channel::<Batch>(0);
+        let (done, written) = mpsc::channel();
+        let thread_settings = settings.clone();
+        let hash = settings.hash();
+        let thread = std::thread::Builder::new()
+            .name("writer".to_string())
+            .spawn(move || {
+                for mut batch in to_write {
+                    let start = Instant::now();
+                    let (bytes, timings) = write_frame_group(
+                        &thread_settings,
+                        &hash,
+                        &mut batch.frames,
+                        batch.order.as_deref(),
+                        &batch.num,
+                        &batch.output,
+                        batch.phase,
+                    )
+                    .map_err(|e| Error::Writer {
+                        batch: batch.num,
+                        source: Box::new(e),
+                    })?;
+                    debug!("Took to save: {}", start.elapsed().as_secs_f32());
+                    let written = Written {
+                        frames: batch.frames,
+                        batch: batch.num,
+                        bytes,
+                        timings,
+                    };
+                    if done.send(written).is_err() {
+                        break;
+                    }
+                }
+                Ok(())
+            })
+            .map_err(Error::io("start the writer thread for", &settings.out_path))?;
+        let buffers = batch_buffers(settings);
+        if buffers == 1 && settings.write_overlap.is_none() {
+            info!(
+                "Not enough memory free to simulate a batch while writing the last, one at a time"
+            );
+        }
+        Ok(BatchWriter {
+            batches: Some(batches),
+            written,
+            thread: Some(thread),
+            // all allocated up front, so a run that's going to run out of memory does so
+            // before the first batch rather than partway through
+            free: vec![
+                vec![vec![Vec3::ZERO; settings.num_particles]; settings.frames_per_file];
+                buffers
+            ],
+            in_flight: 0,
+            output,
+            frames_per_file: settings.frames_per_file,
+            num_particles: settings.num_particles,
+            timings: None,
+        })
+    }
+
+    /// Write each batch's timings to `log` from now on
+    pub fn logging_timings(mut self, log: TimingsLog) -> BatchWriter {
+        self.timings = Some(log);
+        self
+    }
+
+    /// The simulating half of `batch`'s timings, see `TimingsLog::record`
+    pub fn record_timings(&mut self, batch: usize, timings: BatchTimings) {
+        if let Some(log) = &mut self.timings {
+            log.record(batch, timings);
+        }
+    }
+
+    /// A batch's worth of frames to simulate into, waiting for one to be written when both are
+    /// in use
+    pub fn buffers(&mut self) -> Result<Vec<Vec<Vec3>>, Error> {
+        while let Ok(written) = self.written.try_recv() {
+            self.returned(written);
+        }
+        if self.free.is_empty() {
+            self.wait()?;
+        }
+        let mut frames = self.free.pop().unwrap_or_default();
+        // only short after a batch cut short
+        frames.resize(self.frames_per_file, vec![Vec3::ZERO; self.num_particles]);
+        Ok(frames)
+    }
+
+    /// Buffers that weren't sent after all
+    pub fn reuse(&mut self, frames: Vec<Vec<Vec3>>) {
+        self.free.push(frames);
+    }
+
+    /// Queue `frames` to be written as batch `num`
+    pub fn send(
+        &mut self,
+        num: usize,
+        frames: Vec<Vec<Vec3>>,
+        order: Option<Vec<u32>>,
+        phase: Phase,
+    ) -> Result<(), Error> {
+        let batch = Batch {
+            num,
+            frames,
+            order,
+            phase,
+            output: self.output.clone(),
+        };
+        let sent = match &self.batches {
+            Some(batches) => batches.send(batch).is_ok(),
+            None => false,
+        };
+        if !sent {
+            return Err(self.failure());
+        }
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    /// `OutputLimit::check` against the batches written so far. One still being written is
+    /// counted by the next check.
+    pub fn check_output(
+        &mut self,
+        settings: &Settings,
+        manifest: &mut Manifest,
+        next_batch: usize,
+    ) -> bool {
+        while let Ok(written) = self.written.try_recv() {
+            self.returned(written);
+        }
+        self.output.check(settings, manifest, next_batch)
+    }
+
+    /// See `OutputLimit::set_compression_level`
+    pub fn set_compression_level(&mut self, level: u32) {
+        self.output.set_compression_level(level);
+    }
+
+    /// Wait for every batch sent to be written, eg. before a checkpoint
+    pub fn flush(&mut self) -> Result<(), Error> {
+        while self.in_flight > 0 {
+            self.wait()?;
+        }
+        Ok(())
+    }
+
+    /// Write what's still queued and stop the thread
+    pub fn finish(&mut self) -> Result<(), Error> {
+        self.batches = None;
+        self.flush()?;
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(panic)) => std::panic::resume_unwind(panic),
+            None => Ok(()),
+        }
+    }
+
+    fn wait(&mut self) -> Result<(), Error> {
+        let _span = trace_span!("wait_for_writer").entered();
+        match self.written.recv() {
+            Ok(written) => {
+                self.returned(written);
+                Ok(())
+            }
+            Err(_) => Err(self.failure()),
+        }
+    }
+
+    fn returned(&mut self, written: Written) {
+        self.in_flight -= 1;
+        self.output.record(written.bytes);
+        self.record_timings(written.batch, written.timings);
+        self.free.push(written.frames);
+    }
+
+    /// Why the thread stopped, once it has
+    fn failure(&mut self) -> Error {
+        self.batches = None;
+        self.in_flight = 0;
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(Err(e))) => e,
+            Some(Err(panic)) => std::panic::resume_unwind(panic),
+            _ => Error::Command("The writer thread stopped".to_string()),
+        }
+    }
+}
+
+impl Drop for BatchWriter {
+    /// Batches already simulated still get written when the run stops on an error
+    fn drop(&mut self) {
+        if self.thread.is_some()
+            && let Err(e) = self.finish()
+        {
+            warn!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gravity_output_format as format;
+
+    fn settings(name: &str) -> Settings {
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Settings {
+            num_particles: 8,
+            frames_per_file: 3,
+            out_path: dir,
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn finishing_writes_every_batch_sent() {
+        // with one buffer each batch is written before the next is simulated
+        for (write_overlap, buffers) in [(true, 2), (false, 1)] {
+            let settings = Settings {
+                write_overlap: Some(write_overlap),
+                ..settings("gravity-output-writer-drain")
+            };
+            assert_eq!(batch_buffers(&settings), buffers);
+            let mut writer = BatchWriter::spawn(&settings, OutputLimit::new(&settings, 0)).unwrap();
+            for batch in 0..5 {
+                let mut frames = writer.buffers().unwrap();
+                for frame in &mut frames {
+                    frame.fill(Vec3::splat(batch as f32));
+                }
+                writer
+                    .send(batch, frames, None, Phase::at(&settings, 0))
+                    .unwrap();
+            }
+            writer.finish().unwrap();
+            // the same buffers went round, every one back once written
+            assert_eq!(writer.free.len(), buffers);
+
+            let batches = format::list_batches(&settings.out_path).unwrap();
+            assert_eq!(batches.len(), 5);
+            for (batch, path) in batches {
+                let file = format::read_batch(&path).unwrap();
+                assert_eq!(file.frames[0][0], Vec3::splat(batch as f32));
+            }
+            std::fs::remove_dir_all(&settings.out_path).unwrap();
+        }
+    }
+
+    #[test]
+    fn a_failed_write_comes_back_from_the_next_send() {
+        let settings = settings("gravity-output-writer-failure");
+        let mut writer = BatchWriter::spawn(&settings, OutputLimit::new(&settings, 0)).unwrap();
+        std::fs::remove_dir_all(&settings.out_path).unwrap();
+        let phase = Phase::at(&settings, 0);
+
+        let frames = writer.buffers().unwrap();
+        writer.send(0, frames, None, phase).unwrap();
+        let result = writer
+            .buffers()
+            .and_then(|frames| writer.send(1, frames, None, phase))
+            .and_then(|()| writer.finish());
+        assert!(matches!(result, Err(Error::Writer { batch: 0, .. })));
+    }
+}
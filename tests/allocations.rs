@@ -0,0 +1,82 @@
+//! Batches integrated on the GPU allocate nothing particle-sized per frame. Skips without a
+//! usable adapter.
+#![cfg(feature = "gpu")]
+
+mod common;
+
+use glam::Vec3;
+use gravity_output::settings::{Settings, init_particles};
+use gravity_output::{CountingAllocator, FrameObserver, Simulation};
+use std::sync::Mutex;
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// The count is of every thread's allocations, so the tests take turns
+static COUNTING: Mutex<()> = Mutex::new(());
+
+/// Allocates a frame's worth every frame, like an observer keeping a copy
+struct Copying(Vec<Vec<Vec3>>);
+
+impl FrameObserver for Copying {
+    fn on_frame(&mut self, _: usize, _: f64, positions: &[Vec3], _: Option<&[Vec3]>) {
+        self.0.push(positions.to_vec());
+    }
+}
+
+#[test]
+fn batches_on_the_device_do_not_allocate_per_frame() {
+    let _counting = COUNTING.lock().unwrap();
+    let settings = Settings {
+        num_particles: 2000,
+        seed: Some(5),
+        ..common::gpu_settings()
+    };
+    let Some(gpu) = common::gpu(&settings) else {
+        return;
+    };
+    let particles = init_particles(&settings).unwrap();
+    let mut simulation = Simulation::with_backend(settings.clone(), particles, Box::new(gpu));
+    assert!(simulation.integrates_on_device());
+    let mut frame_list = vec![vec![Vec3::ZERO; settings.num_particles]; 16];
+    // the first batch uploads the particles
+    simulation.run_batch(&mut frame_list[..2]).unwrap();
+
+    CountingAllocator::count_from(settings.num_particles * std::mem::size_of::<Vec3>());
+    let mut allocations = |frames: usize| {
+        let before = CountingAllocator::large_allocations();
+        simulation.run_batch(&mut frame_list[..frames]).unwrap();
+        CountingAllocator::large_allocations() - before
+    };
+    let short = allocations(2);
+    let long = allocations(16);
+    assert_eq!(
+        long, short,
+        "frame sized allocations grow with the frames in a batch"
+    );
+}
+
+#[test]
+fn what_observers_allocate_is_not_counted() {
+    let _counting = COUNTING.lock().unwrap();
+    let settings = Settings {
+        num_particles: 2000,
+        seed: Some(5),
+        ..common::gpu_settings()
+    };
+    let Some(gpu) = common::gpu(&settings) else {
+        return;
+    };
+    let particles = init_particles(&settings).unwrap();
+    let mut simulation = Simulation::with_backend(settings.clone(), particles, Box::new(gpu))
+        .observed_by(Box::new(Copying(Vec::with_capacity(32))));
+    let mut frame_list = vec![vec![Vec3::ZERO; settings.num_particles]; 16];
+    simulation.run_batch(&mut frame_list[..2]).unwrap();
+
+    CountingAllocator::count_from(settings.num_particles * std::mem::size_of::<Vec3>());
+    let before = CountingAllocator::large_allocations();
+    simulation.run_batch(&mut frame_list).unwrap();
+    let made = CountingAllocator::large_allocations() - before;
+    // the run's check, which the observer's 16 copies would have failed
+    assert!(made < 16, "{} allocations counted", made);
+}
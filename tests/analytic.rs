@@ -0,0 +1,161 @@
+//! Runs checked against what the physics says they should do.
+
+mod common;
+
+use glam::Vec3;
+use gravity_output::settings::{Integrator, Settings, init_particles};
+use gravity_output::{Particle, Simulation};
+use std::f32::consts::PI;
+
+/// Two equal masses on a circular orbit around their barycentre at the origin, `separation`
+/// apart, with G = 1 and no softening. Returns the settings, the particles and the analytic
+/// period.
+fn two_body(integrator: Integrator, steps_per_orbit: usize) -> (Settings, Vec<Particle>, f32) {
+    let (mass, separation) = (1.0f32, 1.0);
+    let radius = separation / 2.0;
+    // G m^2 / d^2 = m v^2 / r
+    let speed = (mass / (2.0 * separation)).sqrt();
+    let period = 2.0 * PI * radius / speed;
+    // no accelerations, the simulation computes them before the first step
+    let particles = vec![
+        Particle::new_zero()
+            .with_mass(mass)
+            .with_pos(Vec3::new(radius, 0.0, 0.0))
+            .with_vel(Vec3::new(0.0, speed, 0.0)),
+        Particle::new_zero()
+            .with_mass(mass)
+            .with_pos(Vec3::new(-radius, 0.0, 0.0))
+            .with_vel(Vec3::new(0.0, -speed, 0.0)),
+    ];
+    let settings = Settings {
+        num_particles: 2,
+        frames_total: steps_per_orbit,
+        frames_per_file: steps_per_orbit,
+        dt: period / steps_per_orbit as f32,
+        g_const: 1.0,
+        softening: 0.0,
+        integrator,
+        ..common::cpu_settings()
+    };
+    (settings, particles, period)
+}
+
+/// Time the first particle takes to get back to its starting angle, interpolated between the
+/// frames either side
+fn measured_period(integrator: Integrator) -> f32 {
+    let steps_per_orbit = 1000;
+    let (settings, particles, _) = two_body(integrator, steps_per_orbit);
+    let dt = settings.dt;
+    let mut simulation = Simulation::new(settings, particles).unwrap();
+    // a little over an orbit, so a slow integrator still completes one
+    let mut frame_list = vec![vec![Vec3::ZERO; 2]; steps_per_orbit * 11 / 10];
+    simulation.run_batch(&mut frame_list).unwrap();
+
+    let mut angle = 0.0;
+    let mut last = Vec3::X;
+    for (index, frame) in frame_list.iter().enumerate() {
+        let pos = frame[0];
+        let step = last.x * pos.y - last.y * pos.x;
+        let swept = step.atan2(last.dot(pos));
+        if angle + swept >= 2.0 * PI {
+            let fraction = (2.0 * PI - angle) / swept;
+            return (index as f32 + fraction) * dt;
+        }
+        angle += swept;
+        last = pos;
+    }
+    panic!("{:?} didn't complete an orbit", integrator);
+}
+
+#[test]
+fn two_body_orbit_matches_the_analytic_period() {
+    for (integrator, tolerance) in [(Integrator::Euler, 1e-3), (Integrator::Verlet, 1e-4)] {
+        let (_, _, period) = two_body(integrator, 1000);
+        let measured = measured_period(integrator);
+        assert!(
+            (measured - period).abs() <= tolerance * period,
+            "{:?}: period {} analytic {}",
+            integrator,
+            measured,
+            period
+        );
+    }
+}
+
+#[test]
+fn two_body_orbit_stays_circular() {
+    for integrator in [Integrator::Euler, Integrator::Verlet] {
+        let (settings, particles, _) = two_body(integrator, 1000);
+        let mut simulation = Simulation::new(settings, particles).unwrap();
+        let mut frame_list = vec![vec![Vec3::ZERO; 2]; 1000];
+        simulation.run_batch(&mut frame_list).unwrap();
+        for frame in &frame_list {
+            let separation = frame[0].distance(frame[1]);
+            assert!(
+                (separation - 1.0).abs() <= 1e-2,
+                "{:?}: separation {}",
+                integrator,
+                separation
+            );
+        }
+    }
+}
+
+#[test]
+fn verlet_velocities_are_at_the_time_of_the_positions() {
+    let steps_per_orbit = 1000;
+    let (settings, particles, period) = two_body(Integrator::Verlet, steps_per_orbit);
+    let speed = particles[0].vel().length();
+    let mut simulation = Simulation::new(settings, particles).unwrap();
+    // a quarter orbit, a step of lag would be a 2 pi / 1000 turn off
+    let mut frame_list = vec![vec![Vec3::ZERO; 2]; steps_per_orbit / 4];
+    simulation.run_batch(&mut frame_list).unwrap();
+
+    let angle = 2.0 * PI * simulation.time() as f32 / period;
+    let expected = Vec3::new(-angle.sin(), angle.cos(), 0.0) * speed;
+    let vel = simulation.particles().vel[0];
+    assert!(
+        (vel - expected).length() <= 1e-4 * speed,
+        "velocity {} analytic {}",
+        vel,
+        expected
+    );
+}
+
+#[test]
+fn momentum_is_conserved_over_1000_steps() {
+    let settings = Settings {
+        num_particles: 100,
+        seed: Some(17),
+        ..common::cpu_settings()
+    };
+    let particles = init_particles(&settings).unwrap();
+    let momentum = |simulation: &Simulation| -> (Vec3, f32) {
+        simulation
+            .particles()
+            .iter()
+            .map(|particle| particle.vel() * particle.mass())
+            .fold((Vec3::ZERO, 0.0), |(sum, scale), p| {
+                (sum + p, scale + p.length())
+            })
+    };
+
+    for integrator in [Integrator::Euler, Integrator::Verlet] {
+        let settings = Settings {
+            integrator,
+            ..settings.clone()
+        };
+        let mut simulation = Simulation::new(settings, particles.clone()).unwrap();
+        let (start, scale) = momentum(&simulation);
+        let mut frame_list = vec![vec![Vec3::ZERO; 100]; 1000];
+        simulation.run_batch(&mut frame_list).unwrap();
+        let (end, _) = momentum(&simulation);
+        assert!(
+            (end - start).length() <= 1e-4 * scale,
+            "{:?}: momentum {} -> {}",
+            integrator,
+            start,
+            end
+        );
+    }
+}
@@ -0,0 +1,56 @@
+//! Helpers shared by the integration tests. Each test file only uses some of them.
+#![allow(dead_code)]
+
+use glam::Vec3;
+#[cfg(feature = "gpu")]
+use gravity_output::GpuCompute;
+use gravity_output::{ForceBackendKind, Settings};
+use std::path::PathBuf;
+
+/// Settings for a run on the CPU backend, which every machine has
+pub fn cpu_settings() -> Settings {
+    Settings {
+        force_backend: ForceBackendKind::Cpu,
+        ..Settings::default()
+    }
+}
+
+/// Settings for a GPU run, accepting whatever adapter the machine has. CI often only has a
+/// software one.
+#[cfg(feature = "gpu")]
+pub fn gpu_settings() -> Settings {
+    let mut settings = Settings {
+        force_backend: ForceBackendKind::Gpu,
+        // independent of whatever --bench-kernel cached on this machine
+        workgroup_size: Some(gravity_output::settings::DEFAULT_WORKGROUP_SIZE),
+        ..Settings::default()
+    };
+    settings.adapter.allow_software_adapter = true;
+    settings
+}
+
+/// A device for `settings`, None without a usable adapter so the test can skip
+#[cfg(feature = "gpu")]
+pub fn gpu(settings: &Settings) -> Option<GpuCompute> {
+    match pollster::block_on(GpuCompute::new(settings)) {
+        Ok(gpu) => Some(gpu),
+        Err(e) => {
+            println!("skipping, no GPU: {}", e);
+            None
+        }
+    }
+}
+
+/// Empty directory under the system temp dir for one test's output
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("gravity-output-it-{}", name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// `a` and `b` agree to within `tolerance` of the larger of the two, or of `floor` when both
+/// are smaller than it
+pub fn close(a: Vec3, b: Vec3, tolerance: f32, floor: f32) -> bool {
+    (a - b).length() <= tolerance * a.length().max(b.length()).max(floor)
+}
@@ -0,0 +1,131 @@
+//! Several simulations in one process, each on its own thread, against the same runs done alone.
+
+mod common;
+
+use glam::Vec3;
+use gravity_output::Simulation;
+use gravity_output::settings::{Settings, init_particles};
+#[cfg(feature = "gpu")]
+use gravity_output::{GpuCompute, GpuDevice};
+use std::thread;
+
+const FRAMES: usize = 20;
+
+/// Two runs that share nothing but the process, differing in size, seed, dt and softening
+fn runs(base: Settings) -> [Settings; 2] {
+    [
+        Settings {
+            num_particles: 100,
+            seed: Some(1),
+            dt: 0.01,
+            ..base.clone()
+        },
+        Settings {
+            num_particles: 257,
+            seed: Some(2),
+            dt: 0.02,
+            softening: 0.5,
+            ..base
+        },
+    ]
+}
+
+/// Positions of every frame of a batch of `FRAMES`
+fn run(mut simulation: Simulation) -> Vec<Vec<Vec3>> {
+    let num_particles = simulation.settings().num_particles;
+    let mut frame_list = vec![vec![Vec3::ZERO; num_particles]; FRAMES];
+    simulation.run_batch(&mut frame_list).unwrap();
+    frame_list
+}
+
+fn assert_same_run(concurrent: &[Vec<Vec3>], alone: &[Vec<Vec3>], tolerance: f32) {
+    assert_eq!(concurrent.len(), alone.len());
+    for (frame, (a, b)) in concurrent.iter().zip(alone).enumerate() {
+        assert_eq!(a.len(), b.len());
+        for (i, (a, b)) in a.iter().zip(b).enumerate() {
+            assert!(
+                common::close(*a, *b, tolerance, 1.0),
+                "frame {} particle {}: {} alone {}",
+                frame,
+                i,
+                a,
+                b
+            );
+        }
+    }
+}
+
+#[test]
+fn cpu_simulations_on_separate_threads_are_independent() {
+    let settings = runs(common::cpu_settings());
+    let alone: Vec<_> = settings
+        .iter()
+        .map(|settings| {
+            let particles = init_particles(settings).unwrap();
+            run(Simulation::new(settings.clone(), particles).unwrap())
+        })
+        .collect();
+
+    let concurrent: Vec<_> = thread::scope(|scope| {
+        let handles: Vec<_> = settings
+            .iter()
+            .map(|settings| {
+                scope.spawn(move || {
+                    let particles = init_particles(settings).unwrap();
+                    run(Simulation::new(settings.clone(), particles).unwrap())
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    for (concurrent, alone) in concurrent.iter().zip(&alone) {
+        // the CPU backend doesn't depend on what else is running
+        assert_same_run(concurrent, alone, 0.0);
+    }
+}
+
+#[test]
+#[cfg(feature = "gpu")]
+fn gpu_simulations_can_share_a_device() {
+    let settings = runs(common::gpu_settings());
+    let Some(_) = common::gpu(&settings[0]) else {
+        return;
+    };
+    let alone: Vec<_> = settings
+        .iter()
+        .map(|settings| {
+            let particles = init_particles(settings).unwrap();
+            run(Simulation::new(settings.clone(), particles).unwrap())
+        })
+        .collect();
+
+    let device = pollster::block_on(GpuDevice::open(&settings[0], &settings[0].adapter)).unwrap();
+    let concurrent: Vec<_> = thread::scope(|scope| {
+        let handles: Vec<_> = settings
+            .iter()
+            .map(|settings| {
+                let device = device.clone();
+                scope.spawn(move || {
+                    let gpu = pollster::block_on(GpuCompute::on_device(
+                        settings,
+                        &device,
+                        0..settings.num_particles,
+                    ))
+                    .unwrap();
+                    let particles = init_particles(settings).unwrap();
+                    run(Simulation::with_backend(
+                        settings.clone(),
+                        particles,
+                        Box::new(gpu),
+                    ))
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    for (concurrent, alone) in concurrent.iter().zip(&alone) {
+        assert_same_run(concurrent, alone, 1e-4);
+    }
+}
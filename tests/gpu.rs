@@ -0,0 +1,267 @@
+//! The GPU kernels against the CPU reference. Every test skips without a usable adapter.
+#![cfg(feature = "gpu")]
+
+mod common;
+
+use gravity_output::settings::{ForceKernel, Settings, init_particles};
+use gravity_output::{CpuBackend, ForceBackend};
+
+fn settings(num_particles: usize) -> Settings {
+    Settings {
+        num_particles,
+        seed: Some(3),
+        ..common::gpu_settings()
+    }
+}
+
+#[test]
+fn gpu_forces_match_the_cpu_reference() {
+    // not a multiple of the workgroup size, so the last workgroup is partly empty
+    for num_particles in [64, 1001] {
+        let settings = settings(num_particles);
+        let Some(gpu) = common::gpu(&settings) else {
+            return;
+        };
+        let particles = init_particles(&settings).unwrap();
+        let expected = CpuBackend::new(&settings).compute_forces(&particles);
+        let forces = gpu.compute_forces(&particles);
+
+        assert_eq!(forces.force.len(), num_particles);
+        for (i, (gpu, cpu)) in forces.force.iter().zip(&expected.force).enumerate() {
+            assert!(
+                common::close(*gpu, *cpu, 1e-3, 1e-3),
+                "particle {}: gpu {} cpu {}",
+                i,
+                gpu,
+                cpu
+            );
+        }
+        for (i, (gpu, cpu)) in forces.potential.iter().zip(&expected.potential).enumerate() {
+            assert!((gpu - cpu).abs() <= 1e-3 * cpu.abs(), "particle {}", i);
+        }
+    }
+}
+
+#[test]
+fn device_integration_matches_the_cpu() {
+    use glam::Vec3;
+    use gravity_output::Simulation;
+    use gravity_output::settings::Integrator;
+
+    for integrator in [Integrator::Euler, Integrator::Verlet] {
+        let settings = Settings {
+            integrator,
+            diagnostics: true,
+            ..settings(200)
+        };
+        let Some(gpu) = common::gpu(&settings) else {
+            return;
+        };
+        let particles = init_particles(&settings).unwrap();
+        let mut on_device =
+            Simulation::with_backend(settings.clone(), particles.clone(), Box::new(gpu));
+        let mut on_cpu = Simulation::with_backend(
+            settings.clone(),
+            particles,
+            Box::new(CpuBackend::new(&settings)),
+        );
+        assert!(on_device.integrates_on_device());
+
+        let mut frames = [
+            vec![vec![Vec3::ZERO; 200]; 20],
+            vec![vec![Vec3::ZERO; 200]; 20],
+        ];
+        let device_energies = on_device.run_batch(&mut frames[0]).unwrap();
+        let cpu_energies = on_cpu.run_batch(&mut frames[1]).unwrap();
+
+        for (frame, (gpu, cpu)) in frames[0].iter().zip(&frames[1]).enumerate() {
+            for (i, (gpu, cpu)) in gpu.iter().zip(cpu).enumerate() {
+                assert!(
+                    common::close(*gpu, *cpu, 1e-4, 1.0),
+                    "{:?} frame {} particle {}: gpu {} cpu {}",
+                    integrator,
+                    frame,
+                    i,
+                    gpu,
+                    cpu
+                );
+            }
+        }
+        let velocities = on_device
+            .particles()
+            .vel
+            .iter()
+            .zip(&on_cpu.particles().vel);
+        for (i, (gpu, cpu)) in velocities.enumerate() {
+            assert!(
+                common::close(*gpu, *cpu, 1e-3, 1e-3),
+                "{:?} particle {}: gpu {} cpu {}",
+                integrator,
+                i,
+                gpu,
+                cpu
+            );
+        }
+        // both are the energy of the state at the end of each frame
+        for (gpu, cpu) in device_energies.iter().zip(&cpu_energies) {
+            assert!(
+                (gpu.total() - cpu.total()).abs() <= 1e-3 * cpu.total().abs(),
+                "{:?}: gpu {:?} cpu {:?}",
+                integrator,
+                gpu,
+                cpu
+            );
+        }
+    }
+}
+
+#[test]
+fn frames_output_every_drops_are_not_read_back() {
+    use glam::Vec3;
+    use gravity_output::Simulation;
+
+    let every_frame = settings(200);
+    let Some(gpu) = common::gpu(&every_frame) else {
+        return;
+    };
+    let particles = init_particles(&every_frame).unwrap();
+    let mut expected = vec![vec![Vec3::ZERO; 200]; 10];
+    Simulation::with_backend(every_frame.clone(), particles.clone(), Box::new(gpu))
+        .run_batch(&mut expected)
+        .unwrap();
+
+    let settings = Settings {
+        output_every: 3,
+        ..every_frame
+    };
+    let gpu = common::gpu(&settings).unwrap();
+    // finite, a NaN would stop the run
+    let mut frames = vec![vec![Vec3::MAX; 200]; 10];
+    Simulation::with_backend(settings, particles, Box::new(gpu))
+        .run_batch(&mut frames)
+        .unwrap();
+    // frames 0, 3, 6 and 9, which is also the last
+    for (frame, (positions, expected)) in frames.iter().zip(&expected).enumerate() {
+        if frame % 3 == 0 {
+            assert_eq!(positions, expected, "frame {}", frame);
+        } else {
+            assert!(positions.iter().all(|p| *p == Vec3::MAX), "frame {}", frame);
+        }
+    }
+}
+
+#[test]
+fn tiled_kernel_matches_the_cpu_direct_sum() {
+    // the shared memory tiles, not the subgroup kernel, with the last tile partly padding and a
+    // lone particle that only has itself to skip
+    for num_particles in [1, 63, 65, 200] {
+        let settings = Settings {
+            force_kernel: ForceKernel::Tiled,
+            subgroups: false,
+            ..settings(num_particles)
+        };
+        let Some(gpu) = common::gpu(&settings) else {
+            return;
+        };
+        let particles = init_particles(&settings).unwrap();
+        let expected = CpuBackend::new(&settings).compute_forces(&particles);
+        let forces = gpu.compute_forces(&particles);
+
+        assert_eq!(forces.force.len(), num_particles);
+        for (i, (gpu, cpu)) in forces.force.iter().zip(&expected.force).enumerate() {
+            assert!(
+                gpu.is_finite() && common::close(*gpu, *cpu, 1e-3, 1e-3),
+                "{} particles, particle {}: gpu {} cpu {}",
+                num_particles,
+                i,
+                gpu,
+                cpu
+            );
+        }
+        for (i, (gpu, cpu)) in forces.potential.iter().zip(&expected.potential).enumerate() {
+            assert!(
+                (gpu - cpu).abs() <= 1e-3 * cpu.abs().max(1e-3),
+                "{} particles, particle {}: gpu {} cpu {}",
+                num_particles,
+                i,
+                gpu,
+                cpu
+            );
+        }
+    }
+}
+
+#[test]
+fn compensated_accumulation_is_closer_to_an_f64_sum_on_clustered_particles() {
+    use glam::{DVec3, Vec3};
+    use gravity_output::settings::ForceAccumulation;
+    use gravity_output::{Particle, ParticleSet};
+    use rand::prelude::*;
+
+    // a few tight clumps in a wide, sparse spread, so each particle's force is a sum of
+    // thousands of terms over a wide range of sizes, mostly cancelling
+    let mut rng = StdRng::seed_from_u64(9);
+    let centers: Vec<Vec3> = (0..4)
+        .map(|_| Vec3::new(rng.random(), rng.random(), rng.random()) * 100.0)
+        .collect();
+    let particles: ParticleSet = (0..4096)
+        .map(|i| {
+            let offset = Vec3::new(rng.random(), rng.random(), rng.random()) - 0.5;
+            let pos = match i % 8 {
+                0 => offset * 200.0,
+                _ => centers[i % 4] + offset * 0.5,
+            };
+            Particle::new_zero().with_mass(1.0).with_pos(pos)
+        })
+        .collect();
+    let base = settings(particles.len());
+
+    // direct sum in f64, same softening
+    let softening_sq = (base.softening as f64).powi(2);
+    let reference: Vec<DVec3> = particles
+        .pos
+        .iter()
+        .enumerate()
+        .map(|(i, pos)| {
+            let pos = pos.as_dvec3();
+            particles
+                .pos
+                .iter()
+                .enumerate()
+                .filter(|&(j, _)| j != i)
+                .map(|(j, other)| {
+                    let r = other.as_dvec3() - pos;
+                    let r_sq = r.length_squared() + softening_sq;
+                    r * (base.g_const as f64 * particles.mass[i] as f64 * particles.mass[j] as f64
+                        / (r_sq * r_sq.sqrt()))
+                })
+                .sum()
+        })
+        .collect();
+    let scale =
+        (reference.iter().map(|f| f.length_squared()).sum::<f64>() / reference.len() as f64).sqrt();
+
+    // RMS distance from the reference, over the RMS force
+    let mut errors = Vec::new();
+    for accumulation in [ForceAccumulation::F32, ForceAccumulation::Kahan] {
+        let settings = Settings {
+            force_accumulation: accumulation,
+            subgroups: false,
+            ..base.clone()
+        };
+        let Some(gpu) = common::gpu(&settings) else {
+            return;
+        };
+        let forces = gpu.compute_forces(&particles);
+        let squared: f64 = forces
+            .force
+            .iter()
+            .zip(&reference)
+            .map(|(force, reference)| (force.as_dvec3() - *reference).length_squared())
+            .sum();
+        errors.push((squared / reference.len() as f64).sqrt() / scale);
+    }
+    let (plain, compensated) = (errors[0], errors[1]);
+    println!("relative error: f32 {:e}, kahan {:e}", plain, compensated);
+    assert!(compensated < plain, "kahan {} f32 {}", compensated, plain);
+}
@@ -0,0 +1,315 @@
+//! Whole runs through `simulation::run`, checked through the files they leave behind.
+
+mod common;
+
+use clap::Parser;
+use flate2::read::GzDecoder;
+use glam::Vec3;
+use gravity_output::Error;
+use gravity_output::cli::Cli;
+use gravity_output::schedule::ScheduleEntry;
+use gravity_output::settings::{Integrator, Settings};
+use gravity_output::simulation;
+use std::io::Read;
+use std::path::Path;
+
+/// Frames of the batch file at `path`, decoded the way data_analyzer.py and the Unity player
+/// do: a u32 frame count and particle count, then every frame's positions
+fn decode_batch(path: &Path) -> Vec<Vec<Vec3>> {
+    let mut bytes = Vec::new();
+    GzDecoder::new(std::fs::File::open(path).unwrap())
+        .read_to_end(&mut bytes)
+        .unwrap();
+    let header = |index: usize| {
+        u32::from_le_bytes(bytes[index * 4..index * 4 + 4].try_into().unwrap()) as usize
+    };
+    let (frames, particles) = (header(0), header(1));
+    let frame_size = particles * 12;
+    assert_eq!(bytes.len(), 8 + frames * frame_size, "{}", path.display());
+    bytes[8..]
+        .chunks_exact(frame_size)
+        .map(bytemuck::pod_collect_to_vec)
+        .collect()
+}
+
+/// Run `settings` the way the binary would, from a settings file given with `--settings`
+fn run(settings: &Settings, dir: &Path) {
+    let path = dir.join("settings.json");
+    std::fs::write(&path, serde_json::to_string(settings).unwrap()).unwrap();
+    let cli = Cli::try_parse_from([
+        "gravity-output",
+        "run",
+        "--settings",
+        path.to_str().unwrap(),
+    ])
+    .unwrap();
+    simulation::run(cli).unwrap();
+}
+
+#[test]
+fn tiny_run_writes_decodable_batches() {
+    let dir = common::temp_dir("pipeline");
+    let out_path = dir.join("output");
+    let settings = Settings {
+        num_particles: 16,
+        frames_total: 10,
+        frames_per_file: 5,
+        seed: Some(1),
+        out_path: out_path.clone(),
+        ..common::cpu_settings()
+    };
+    run(&settings, &dir);
+
+    let batches: Vec<Vec<Vec<Vec3>>> = (0..2)
+        .map(|num| decode_batch(&out_path.join(format!("batch_{:04}.bin.gz", num))))
+        .collect();
+    for frames in &batches {
+        assert_eq!(frames.len(), 5);
+        for frame in frames {
+            assert_eq!(frame.len(), 16);
+            assert!(frame.iter().all(|pos| pos.is_finite()));
+        }
+    }
+    // every frame is a step on from the one before
+    let frames: Vec<&Vec<Vec3>> = batches.iter().flatten().collect();
+    assert!(frames.windows(2).all(|pair| pair[0] != pair[1]));
+
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(out_path.join("manifest.json")).unwrap())
+            .unwrap();
+    assert_eq!(manifest["status"], "complete");
+    let heartbeat: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(out_path.join("heartbeat.json")).unwrap())
+            .unwrap();
+    assert_eq!(
+        (&heartbeat["frame"], &heartbeat["batch"]),
+        (&10.into(), &1.into())
+    );
+
+    // a row per batch, once both simulated and written
+    let timings = std::fs::read_to_string(out_path.join("timings.csv")).unwrap();
+    let batches: Vec<&str> = timings
+        .lines()
+        .skip(1)
+        .map(|row| row.split(',').next().unwrap())
+        .collect();
+    assert_eq!(batches, ["0", "1"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn extending_a_run_adds_a_segment_to_its_summary() {
+    let dir = common::temp_dir("pipeline-summary");
+    let out_path = dir.join("output");
+    let settings = Settings {
+        num_particles: 16,
+        frames_total: 10,
+        frames_per_file: 5,
+        seed: Some(1),
+        out_path: out_path.clone(),
+        diagnostics: true,
+        binding_energy_bins: 4,
+        ..common::cpu_settings()
+    };
+    run(&settings, &dir);
+    let cli = Cli::try_parse_from([
+        "gravity-output",
+        "resume",
+        out_path.to_str().unwrap(),
+        "--frames",
+        "20",
+    ])
+    .unwrap();
+    simulation::run(cli).unwrap();
+
+    let summary: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(out_path.join("summary.json")).unwrap())
+            .unwrap();
+    assert_eq!(summary["status"], "complete");
+    assert_eq!(summary["frames_done"], 20);
+    let segments = summary["segments"].as_array().unwrap();
+    assert_eq!(segments.len(), 2);
+    assert_eq!(
+        (&segments[1]["first_frame"], &segments[1]["end_frame"]),
+        (&10.into(), &20.into())
+    );
+    let wall: f64 = segments
+        .iter()
+        .map(|segment| segment["wall_seconds"].as_f64().unwrap())
+        .sum();
+    assert!((summary["wall_seconds"].as_f64().unwrap() - wall).abs() < 1e-9);
+    assert!(summary["bytes_written"]["batches"].as_u64().unwrap() > 0);
+    // from binding_energy.csv's last row
+    let bound = summary["bound_fraction"].as_f64().unwrap();
+    assert!((0.0..=1.0).contains(&bound), "{}", bound);
+    assert_eq!(summary["bound_fraction_frame"], 19);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn overrides_file_is_applied_between_batches() {
+    let dir = common::temp_dir("pipeline-overrides");
+    let out_path = dir.join("output");
+    let settings = Settings {
+        num_particles: 16,
+        frames_total: 10,
+        frames_per_file: 5,
+        seed: Some(1),
+        out_path: out_path.clone(),
+        hot_reload: true,
+        ..common::cpu_settings()
+    };
+    // dt can't change and only warns
+    std::fs::create_dir_all(&out_path).unwrap();
+    std::fs::write(
+        out_path.join("overrides.toml"),
+        "output_every = 5\ndt = 1.0\n",
+    )
+    .unwrap();
+    run(&settings, &dir);
+
+    for num in 0..2 {
+        let frames = decode_batch(&out_path.join(format!("batch_{:04}.bin.gz", num)));
+        assert_eq!(frames.len(), 1);
+    }
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(out_path.join("manifest.json")).unwrap())
+            .unwrap();
+    assert_eq!(manifest["status"], "complete");
+    let events = manifest["events"].as_array().unwrap();
+    assert_eq!(events.len(), 1);
+    assert!(events[0].as_str().unwrap().contains("output_every 5"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn blown_up_run_stops_with_a_dump_and_keeps_the_batches_before() {
+    let dir = common::temp_dir("pipeline-nonfinite");
+    let out_path = dir.join("output");
+    let settings = Settings {
+        num_particles: 16,
+        frames_total: 15,
+        frames_per_file: 5,
+        seed: Some(1),
+        out_path: out_path.clone(),
+        // far past what an f32 position can hold within a step or two
+        schedule: vec![ScheduleEntry {
+            at_frame: 5,
+            dt: Some(1e37),
+            output_every: None,
+        }],
+        ..common::cpu_settings()
+    };
+    let path = dir.join("settings.json");
+    std::fs::write(&path, serde_json::to_string(&settings).unwrap()).unwrap();
+    let cli = Cli::try_parse_from([
+        "gravity-output",
+        "run",
+        "--settings",
+        path.to_str().unwrap(),
+    ])
+    .unwrap();
+    let frame = match simulation::run(cli) {
+        Err(Error::NonFinite(dump)) => dump.frame,
+        other => panic!("expected a non-finite error, not {:?}", other),
+    };
+    assert!((5..10).contains(&frame), "{}", frame);
+
+    assert_eq!(decode_batch(&out_path.join("batch_0000.bin.gz")).len(), 5);
+    assert!(!out_path.join("batch_0001.bin.gz").exists());
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(out_path.join("manifest.json")).unwrap())
+            .unwrap();
+    assert_eq!(
+        manifest["status"],
+        format!("failed with a non-finite state at frame {}", frame)
+    );
+    let dump: serde_json::Value = serde_json::from_str(
+        &std::fs::read_to_string(out_path.join("nonfinite_dump.json")).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(dump["frame"], frame);
+    assert_eq!(dump["recent_dt"][1][1], 1e37);
+    let particles = dump["particles"].as_array().unwrap();
+    assert!(!particles.is_empty());
+    assert_eq!(particles[0]["neighbors"].as_array().unwrap().len(), 8);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn cold_collapse_half_mass_radius_shrinks_and_rebounds() {
+    let dir = common::temp_dir("pipeline-cold-collapse");
+    let out_path = dir.join("output");
+    // a uniform sphere at rest, which falls in after about a free fall time,
+    // pi / 2 * sqrt(R^3 / 2GM) = 1.1, and bounces back out
+    let num_particles = 256;
+    let settings = Settings {
+        num_particles,
+        arena: 1.0,
+        mass: 1.0 / num_particles as f32,
+        init_vel: 0.0,
+        g_const: 1.0,
+        softening: 0.05,
+        dt: 0.005,
+        integrator: Integrator::Verlet,
+        frames_total: 600,
+        frames_per_file: 100,
+        seed: Some(3),
+        diagnostics: true,
+        diagnostics_every: 10,
+        lagrangian_radii: true,
+        nearest_neighbors: true,
+        binding_energy_bins: 8,
+        out_path: out_path.clone(),
+        ..common::cpu_settings()
+    };
+    run(&settings, &dir);
+
+    let diagnostics = std::fs::read_to_string(out_path.join("diagnostics.csv")).unwrap();
+    let mut lines = diagnostics.lines();
+    let header: Vec<&str> = lines.next().unwrap().split(',').collect();
+    let column = |name: &str| header.iter().position(|&column| column == name).unwrap();
+    let (r50, nn_min, nn_p50) = (column("r_0.5"), column("nn_min"), column("nn_p50"));
+    let rows: Vec<Vec<f64>> = lines
+        .map(|line| {
+            line.split(',')
+                .map(|value| value.parse().unwrap())
+                .collect()
+        })
+        .collect();
+    let radii: Vec<f64> = rows.iter().map(|row| row[r50]).collect();
+    assert_eq!(radii.len(), 60);
+    let (deepest, min) = radii
+        .iter()
+        .copied()
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap();
+    // a uniform sphere has half its mass inside 0.79 of its radius
+    assert!((radii[0] - 0.79).abs() < 0.1, "{:?}", radii);
+    assert!(min < radii[0] / 2.0, "{:?}", radii);
+    assert!(deepest > 0 && deepest < radii.len() - 1, "{:?}", radii);
+    assert!(*radii.last().unwrap() > 2.0 * min, "{:?}", radii);
+    // the particles crowd together with it
+    assert!(rows.iter().all(|row| row[nn_min] <= row[nn_p50]));
+    assert!(rows[deepest][nn_p50] < rows[0][nn_p50] / 2.0, "{:?}", rows);
+
+    // a row a batch, all of it bound while it falls in and most of it after
+    let energies = std::fs::read_to_string(out_path.join("binding_energy.csv")).unwrap();
+    let energies: Vec<Vec<f64>> = energies
+        .lines()
+        .skip(1)
+        .map(|line| {
+            line.split(',')
+                .map(|value| value.parse().unwrap())
+                .collect()
+        })
+        .collect();
+    assert_eq!(energies.len(), 6);
+    assert_eq!(energies[0][2], 1.0);
+    for row in &energies {
+        assert!(row[2] > 0.5, "{:?}", energies);
+        assert_eq!(row[5..].iter().sum::<f64>(), num_particles as f64);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,88 @@
+//! A few thousand particles stepped in the browser, for teaching demos. Settings come from the
+//! page rather than a settings file, and frames stay in memory for JS to draw instead of going
+//! to batch files.
+//!
+//! Build with `wasm-pack build --target web` in this directory and serve it with index.html.
+//! The forces come from the GPU backend over WebGPU, integrated on the CPU: every readback is
+//! awaited, so the browser gets control back to finish the mapping, and a step is a Promise.
+
+use gravity_output::settings::{Settings, init_particles};
+use gravity_output::{ForceBackendKind, Simulation};
+use std::cell::{Ref, RefCell};
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::future_to_promise;
+
+/// A simulation the page steps and reads the positions of
+#[wasm_bindgen]
+pub struct Demo {
+    /// Shared with the step in flight, if any
+    simulation: Rc<RefCell<Simulation>>,
+}
+
+#[wasm_bindgen]
+impl Demo {
+    /// `num_particles` in the default sphere, the same for the same `seed`. Fails without
+    /// WebGPU.
+    pub async fn create(num_particles: usize, seed: u64, dt: f32) -> Result<Demo, JsError> {
+        let settings = Settings {
+            num_particles,
+            seed: Some(seed),
+            dt,
+            force_backend: ForceBackendKind::Gpu,
+            // stepping on the device waits on its readbacks with a blocking receive
+            gpu_integration: false,
+            ..Settings::default()
+        };
+        let particles = init_particles(&settings)?;
+        let simulation = Simulation::new_async(settings, particles).await?;
+        Ok(Demo {
+            simulation: Rc::new(RefCell::new(simulation)),
+        })
+    }
+
+    /// Simulate `frames` frames, eg. one per animation frame. Resolves once they're done, and
+    /// rejects while the last call's are still running.
+    pub fn step(&self, frames: usize) -> js_sys::Promise {
+        let simulation = self.simulation.clone();
+        // the borrow is held across each step, it's what turns away the next call until then
+        #[allow(clippy::await_holding_refcell_ref)]
+        future_to_promise(async move {
+            let mut simulation = simulation
+                .try_borrow_mut()
+                .map_err(|_| JsError::new("a step is still running"))?;
+            for _ in 0..frames {
+                simulation
+                    .step_async()
+                    .await
+                    .map_err(|e| JsError::new(&e.to_string()))?;
+            }
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Positions after the last step as x, y, z per particle, a Float32Array in JS. Throws while
+    /// a step is running.
+    pub fn positions(&self) -> Result<Vec<f32>, JsError> {
+        Ok(self
+            .borrow()?
+            .particles()
+            .pos
+            .iter()
+            .flat_map(|pos| pos.to_array())
+            .collect())
+    }
+
+    /// Frames simulated so far. Throws while a step is running.
+    pub fn frame(&self) -> Result<usize, JsError> {
+        Ok(self.borrow()?.frame_index())
+    }
+}
+
+impl Demo {
+    fn borrow(&self) -> Result<Ref<'_, Simulation>, JsError> {
+        self.simulation
+            .try_borrow()
+            .map_err(|_| JsError::new("a step is still running"))
+    }
+}